@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// The stored results of an earlier run, by workload. Each line of a baseline
+// file is a workload name, its emulated MHz and its host ns/instruction,
+// separated by whitespace. Lines starting with '#' are ignored.
+#[derive(Debug, Default, PartialEq)]
+pub struct Baseline {
+    pub results: BTreeMap<String, (f64, f64)>,
+}
+
+impl Baseline {
+    pub fn load(path: &Path) -> io::Result<Baseline> {
+        Ok(Baseline::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(text: &str) -> Baseline {
+        let mut baseline = Baseline::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, mhz, ns] = fields[..] {
+                if let (Ok(mhz), Ok(ns)) = (mhz.parse(), ns.parse()) {
+                    baseline.results.insert(name.to_string(), (mhz, ns));
+                    continue;
+                }
+            }
+            eprintln!("Ignoring malformed baseline line: {:?}", line);
+        }
+        baseline
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::from("# workload mhz ns/instruction\n");
+        for (name, (mhz, ns)) in self.results.iter() {
+            text.push_str(&format!("{} {:.3} {:.3}\n", name, mhz, ns));
+        }
+        fs::write(path, text)
+    }
+
+    // The change in emulated MHz relative to the baseline, as a percentage.
+    // Positive is faster.
+    pub fn change(&self, name: &str, mhz: f64) -> Option<f64> {
+        self.results
+            .get(name)
+            .map(|(baseline, _)| (mhz - baseline) / baseline * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let baseline = Baseline::parse("# comment\nalu 400.5 2.25\n\nexm 300 4\nbad line\n");
+        assert_eq!(baseline.results.len(), 2);
+        assert_eq!(baseline.results["alu"], (400.5, 2.25));
+        assert_eq!(baseline.change("exm", 330.0), Some(10.0));
+        assert_eq!(baseline.change("invaders", 330.0), None);
+    }
+}
@@ -0,0 +1,130 @@
+mod baseline;
+mod workload;
+
+use crate::baseline::Baseline;
+use crate::workload::{Options, Stats, INVADERS_ROMS, WORKLOADS};
+
+use i8080::instruction::InstructionClass;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const DEFAULT_BASELINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/baseline.txt");
+
+fn usage() -> ! {
+    eprintln!("usage: bench [options] [workload...]");
+    eprintln!();
+    eprintln!("workloads: {}", WORKLOADS.join(", "));
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("  --frames N             frames of Space Invaders to run (default 600)");
+    eprintln!("  --invaders-roms DIR    directory holding invaders.e to invaders.h");
+    eprintln!("  --no-decode-cache      run without the decode cache");
+    eprintln!("  --baseline FILE        baseline to compare against (default baseline.txt)");
+    eprintln!("  --save-baseline        store the results as the new baseline");
+    eprintln!("  --max-regression PCT   exit with an error if any workload is more than");
+    eprintln!("                         PCT percent slower than the baseline");
+    process::exit(2);
+}
+
+fn report(name: &str, stats: &Stats, baseline: &Baseline) {
+    println!("{}:", name);
+    println!("  instructions:  {}", stats.instructions);
+    println!("  cycles:        {}", stats.cycles);
+    println!("  time:          {:.2?}", stats.elapsed);
+    println!("  emulated MHz:  {:.1}", stats.mhz());
+    println!("  ns/instr:      {:.2}", stats.ns_per_instruction());
+    if let Some(change) = baseline.change(name, stats.mhz()) {
+        println!("  vs baseline:   {:+.1}%", change);
+    }
+    for class in InstructionClass::ALL.iter() {
+        let count = stats.class_count(*class);
+        println!(
+            "  {:<14} {:>14} ({:.1}%)",
+            format!("{:?}:", class),
+            count,
+            count as f64 / stats.instructions as f64 * 100.0
+        );
+    }
+}
+
+fn main() {
+    let mut options = Options {
+        decode_cache: true,
+        frames: 600,
+        invaders_roms: PathBuf::from(INVADERS_ROMS),
+    };
+    let mut baseline_path = PathBuf::from(DEFAULT_BASELINE);
+    let mut save = false;
+    let mut max_regression = None;
+    let mut workloads = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--invaders-roms" => {
+                options.invaders_roms = args.next().map(PathBuf::from).unwrap_or_else(|| usage())
+            }
+            "--no-decode-cache" => options.decode_cache = false,
+            "--baseline" => {
+                baseline_path = args.next().map(PathBuf::from).unwrap_or_else(|| usage())
+            }
+            "--save-baseline" => save = true,
+            "--max-regression" => {
+                max_regression = Some(
+                    args.next()
+                        .and_then(|n| n.parse::<f64>().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            name if WORKLOADS.contains(&name) => workloads.push(name.to_string()),
+            _ => usage(),
+        }
+    }
+    if workloads.is_empty() {
+        workloads = WORKLOADS.iter().map(|name| name.to_string()).collect();
+    }
+
+    let mut baseline = Baseline::load(&baseline_path).unwrap_or_default();
+    let mut regressed = Vec::new();
+    let mut results = Vec::new();
+    for name in workloads.iter() {
+        let stats = match workload::run(name, &options) {
+            Some(stats) => stats,
+            None => {
+                println!("{}: skipped, rom not found", name);
+                continue;
+            }
+        };
+        report(name, &stats, &baseline);
+
+        if let (Some(max), Some(change)) = (max_regression, baseline.change(name, stats.mhz())) {
+            if -change > max {
+                regressed.push(name.clone());
+            }
+        }
+        results.push((name.clone(), stats.mhz(), stats.ns_per_instruction()));
+    }
+
+    if save {
+        for (name, mhz, ns) in results {
+            baseline.results.insert(name, (mhz, ns));
+        }
+        baseline
+            .save(&baseline_path)
+            .expect("Could not save the baseline");
+        println!("Saved baseline to {}", baseline_path.display());
+    }
+
+    if !regressed.is_empty() {
+        eprintln!("Slower than the baseline: {}", regressed.join(", "));
+        process::exit(1);
+    }
+}
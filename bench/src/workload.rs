@@ -0,0 +1,205 @@
+use i8080::instruction::InstructionClass;
+use i8080::machine::{ClockedIO, CpuView, MachineIO};
+use i8080::memory_bus::MemoryMap;
+use i8080::Cpu;
+use i8080_tests::cpm_system;
+use space_invaders::io::{SpaceInvadersIO, CYCLES_PER_FRAME};
+use space_invaders::memory::SpaceInvadersMemory;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const WORKLOADS: [&str; 3] = ["exm", "alu", "invaders"];
+
+const EXM_ROM: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../i8080-tests/test-roms/8080EXM.COM"
+);
+pub const INVADERS_ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../space-invaders/roms");
+
+// The number of cycles the ALU loop runs for, 50 seconds at 2 MHz.
+const ALU_CYCLES: u64 = 100_000_000;
+
+// The number of cycles to run 8080EXM for between checks for it halting.
+const EXM_SLICE: u64 = 1_000_000;
+
+pub struct Options {
+    pub decode_cache: bool,
+    // The number of frames of the Space Invaders attract mode to run.
+    pub frames: u32,
+    // The directory holding invaders.e through invaders.h.
+    pub invaders_roms: PathBuf,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub elapsed: Duration,
+    // Instructions executed per class, indexed by InstructionClass.
+    pub classes: [u64; 5],
+}
+
+impl Stats {
+    // The speed of the emulated cpu.
+    pub fn mhz(&self) -> f64 {
+        self.cycles as f64 / self.elapsed.as_secs_f64() / 1_000_000.0
+    }
+
+    // Host time per emulated instruction.
+    pub fn ns_per_instruction(&self) -> f64 {
+        self.elapsed.as_nanos() as f64 / self.instructions as f64
+    }
+
+    pub fn class_count(&self, class: InstructionClass) -> u64 {
+        self.classes[class as usize]
+    }
+}
+
+// Run a workload. Returns None if it needs files that are missing.
+pub fn run(name: &str, options: &Options) -> Option<Stats> {
+    match name {
+        "exm" => Some(exm(options)),
+        "alu" => Some(alu(options)),
+        "invaders" => invaders(options),
+        _ => panic!("Unknown workload {:?}", name),
+    }
+}
+
+// Run instructions until at least `cycles` more cycles have elapsed or the
+// cpu halts, in the same way as Cpu::step_clocked but counting instructions
+// by class.
+fn run_for<M: MemoryMap, IO: ClockedIO>(
+    cpu: &mut Cpu<M>,
+    io: &mut IO,
+    cycles: u64,
+    stats: &mut Stats,
+) {
+    let start_cycles = cpu.cycles;
+    let end = cpu.cycles + cycles;
+    let start = Instant::now();
+
+    while cpu.cycles < end && !cpu.is_halted {
+        let decoded = cpu.fetch_decoded();
+        stats.classes[decoded.instruction.class() as usize] += 1;
+        let (next_pc, cycles) = cpu.execute_decoded(&decoded, io);
+        cpu.pc = next_pc;
+        io.tick(cycles as u32);
+        cpu.deliver_events(io);
+        cpu.poll_interrupt(io);
+    }
+
+    stats.elapsed += start.elapsed();
+    stats.cycles += cpu.cycles - start_cycles;
+    stats.instructions = stats.classes.iter().sum();
+}
+
+fn exm(options: &Options) -> Stats {
+    let mut system = cpm_system(EXM_ROM);
+    if options.decode_cache {
+        system.cpu.enable_decode_cache();
+    }
+
+    let mut stats = Stats::default();
+    while !system.cpu.is_halted {
+        run_for(&mut system.cpu, &mut system.io, EXM_SLICE, &mut stats);
+    }
+    println!();
+    stats
+}
+
+struct Ram([u8; 0x10000]);
+
+impl MemoryMap for Ram {
+    fn load_rom(&mut self) {}
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        &self.0[addr as usize..]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.0[addr as usize] = val;
+    }
+}
+
+struct NoIO;
+
+impl MachineIO for NoIO {
+    fn machine_in(&mut self, _: u8) -> u8 {
+        0
+    }
+
+    fn machine_out(&mut self, _: &mut CpuView, _: u8, _: u8) {}
+}
+
+impl ClockedIO for NoIO {
+    fn tick(&mut self, _: u32) {}
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn interrupt_acknowledge(&mut self) {}
+}
+
+// A loop of register-only arithmetic and logic instructions.
+const ALU_LOOP: [u8; 33] = [
+    0x31, 0x00, 0x10, // LXI SP,1000H
+    0x06, 0x01, // MVI B,01H
+    0x0E, 0x03, // MVI C,03H
+    0x16, 0x07, // MVI D,07H
+    0x1E, 0x0F, // MVI E,0FH
+    0x80, // loop: ADD B
+    0x89, // ADC C
+    0x92, // SUB D
+    0x9B, // SBB E
+    0xE6, 0xFE, // ANI 0FEH
+    0xA9, // XRA C
+    0xB2, // ORA D
+    0xBB, // CMP E
+    0x07, // RLC
+    0x1F, // RAR
+    0x27, // DAA
+    0x04, // INR B
+    0x0D, // DCR C
+    0x23, // INX H
+    0x09, // DAD B
+    0xC3, 0x0B, 0x00, // JMP loop
+    0x00, 0x00, 0x00,
+];
+
+fn alu(options: &Options) -> Stats {
+    let mut ram = Ram([0; 0x10000]);
+    ram.0[..ALU_LOOP.len()].copy_from_slice(&ALU_LOOP);
+    let mut cpu = Cpu::new(ram);
+    if options.decode_cache {
+        cpu.enable_decode_cache();
+    }
+
+    let mut stats = Stats::default();
+    run_for(&mut cpu, &mut NoIO, ALU_CYCLES, &mut stats);
+    stats
+}
+
+// The attract mode runs without any input, so no controls are needed.
+fn invaders(options: &Options) -> Option<Stats> {
+    if !options.invaders_roms.join("invaders.h").exists() {
+        return None;
+    }
+
+    let mut cpu = Cpu::new(SpaceInvadersMemory::with_rom_dir(&options.invaders_roms));
+    let mut io = SpaceInvadersIO::new();
+    if options.decode_cache {
+        cpu.enable_decode_cache();
+    }
+
+    let mut stats = Stats::default();
+    for _ in 0..options.frames {
+        run_for(&mut cpu, &mut io, CYCLES_PER_FRAME as u64, &mut stats);
+    }
+    Some(stats)
+}
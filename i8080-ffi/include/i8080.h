@@ -0,0 +1,261 @@
+/*
+ * C API for the i8080 emulator core.
+ *
+ * Generated from src/lib.rs by cbindgen when the crate is built; do not edit.
+ * The tests in tests/c_api.rs check that it declares every exported function
+ * and that its structs, enum and constants match the Rust layout.
+ *
+ * Link against libi8080_ffi (static or shared). Every function taking an
+ * I8080Cpu, I8080View or I8080Events expects a valid, non-null pointer. A
+ * panic inside the core, e.g. on an invalid opcode, aborts the process.
+ */
+
+#ifndef I8080_H
+#define I8080_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Saved states are I8080_SAVE_STATE_SIZE bytes, followed by
+ * I8080_SAVE_EVENT_SIZE bytes for each pending scheduled event:
+ *
+ *   0   4  magic "I80S"
+ *   4   1  format version, currently 2
+ *   5   8  A, B, C, D, E, H, L and the flags byte
+ *   13  2  SP, little endian
+ *   15  2  PC, little endian
+ *   17  1  interrupts enabled (0 or 1)
+ *   18  1  halted (0 or 1)
+ *   19  1  pending interrupt request as an RST number, or 0xFF if none
+ *   20  8  cycle counter, little endian
+ *   28  4  number of pending events, little endian
+ *
+ * Each event, in the order they will fire:
+ *
+ *   0   8  cycles until it falls due, little endian
+ *   8   4  event, little endian
+ *
+ * Memory belongs to the caller and is not included.
+ */
+#define I8080_SAVE_STATE_SIZE 32
+
+#define I8080_SAVE_EVENT_SIZE 12
+
+/**
+ * A register or register pair, for i8080_get_reg and i8080_set_reg. F is the
+ * flags byte and PSW is A and the flags.
+ */
+typedef enum I8080Reg {
+  I8080_REG_A,
+  I8080_REG_B,
+  I8080_REG_C,
+  I8080_REG_D,
+  I8080_REG_E,
+  I8080_REG_H,
+  I8080_REG_L,
+  I8080_REG_F,
+  I8080_REG_BC,
+  I8080_REG_DE,
+  I8080_REG_HL,
+  I8080_REG_PSW,
+  I8080_REG_SP,
+  I8080_REG_PC,
+} I8080Reg;
+
+/**
+ * A cpu and the callbacks it is wired to. Opaque to C.
+ */
+typedef struct I8080Cpu I8080Cpu;
+
+/**
+ * The cpu's scheduler as seen by an event callback, which can schedule and
+ * cancel events. Only valid for the duration of the callback. Opaque to C.
+ */
+typedef struct I8080Events I8080Events;
+
+/**
+ * The cpu as seen by an output callback, which can read the registers and
+ * halt the cpu. Only valid for the duration of the callback. Opaque to C.
+ */
+typedef struct I8080View I8080View;
+
+typedef uint8_t (*I8080ReadFn)(void *user, uint16_t addr);
+
+typedef void (*I8080WriteFn)(void *user, uint16_t addr, uint8_t val);
+
+typedef uint8_t (*I8080InFn)(void *user, uint8_t port);
+
+typedef void (*I8080OutFn)(void *user, struct I8080View *view, uint8_t port, uint8_t val);
+
+typedef void (*I8080EventFn)(void *user, struct I8080Events *events, uint32_t event);
+
+/**
+ * The functions the cpu calls to reach the outside world. `read` is required.
+ * Without `write`, writes are ignored. Without `input`, IN reads 0xFF. Without
+ * `output`, OUT does nothing. Without `event`, events scheduled with
+ * i8080_schedule are dropped when they fall due. `user` is passed through to
+ * every call.
+ */
+typedef struct I8080Callbacks {
+  void *user;
+  I8080ReadFn read;
+  I8080WriteFn write;
+  I8080InFn input;
+  I8080OutFn output;
+  I8080EventFn event;
+} I8080Callbacks;
+
+/**
+ * The architectural state of the cpu. `flags` is laid out as pushed by
+ * PUSH PSW.
+ */
+typedef struct I8080State {
+  uint8_t a;
+  uint8_t b;
+  uint8_t c;
+  uint8_t d;
+  uint8_t e;
+  uint8_t h;
+  uint8_t l;
+  uint8_t flags;
+  uint16_t sp;
+  uint16_t pc;
+  bool interrupts_enabled;
+  bool halted;
+} I8080State;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a cpu with every register zeroed. Returns null if `callbacks` is
+ * null or has no read function. Free the cpu with i8080_free.
+ */
+struct I8080Cpu *i8080_new(const struct I8080Callbacks *callbacks);
+
+/**
+ * Free a cpu created by i8080_new. Does nothing if `cpu` is null.
+ */
+void i8080_free(struct I8080Cpu *cpu);
+
+/**
+ * Pulse the RESET pin: the pc is cleared, interrupts are disabled and a
+ * halted cpu resumes. Everything else is left as it was.
+ */
+void i8080_reset(struct I8080Cpu *cpu);
+
+/**
+ * Fill the registers, flags and stack pointer with random values from `seed`,
+ * as on a real board at power on, then reset. Memory belongs to the caller,
+ * which can fill it with garbage itself.
+ */
+void i8080_power_on(struct I8080Cpu *cpu, uint64_t seed);
+
+/**
+ * Execute one instruction, accepting a pending interrupt afterwards if
+ * interrupts are enabled. A halted cpu executes nothing but lets 4 cycles
+ * pass, and an interrupt wakes it. Returns the number of cycles taken.
+ */
+uint32_t i8080_step(struct I8080Cpu *cpu);
+
+/**
+ * Execute instructions until at least `cycles` cycles have elapsed. A halted
+ * cpu idles until an interrupt wakes it. Returns the number of cycles elapsed.
+ */
+uint64_t i8080_run(struct I8080Cpu *cpu, uint64_t cycles);
+
+/**
+ * The total number of cycles executed.
+ */
+uint64_t i8080_cycles(const struct I8080Cpu *cpu);
+
+bool i8080_is_halted(const struct I8080Cpu *cpu);
+
+void i8080_get_state(const struct I8080Cpu *cpu, struct I8080State *state);
+
+/**
+ * Set the architectural state. Pending scheduled events are kept.
+ */
+void i8080_set_state(struct I8080Cpu *cpu, const struct I8080State *state);
+
+/**
+ * Read a register or register pair. Eight-bit registers are zero extended.
+ */
+uint16_t i8080_get_reg(const struct I8080Cpu *cpu, enum I8080Reg reg);
+
+/**
+ * Write a register or register pair. Eight-bit registers take the low byte of
+ * `val`.
+ */
+void i8080_set_reg(struct I8080Cpu *cpu, enum I8080Reg reg, uint16_t val);
+
+/**
+ * Request an interrupt that places RST `rst` (0-7) on the data bus. The
+ * request is held until the cpu accepts it, which it does at the end of the
+ * next instruction executed with interrupts enabled.
+ */
+void i8080_raise_interrupt(struct I8080Cpu *cpu, uint8_t rst);
+
+/**
+ * Withdraw a pending interrupt request.
+ */
+void i8080_clear_interrupt(struct I8080Cpu *cpu);
+
+/**
+ * Schedule `event` to fall due `cycles` cycles from now. It is passed to the
+ * event callback once the instruction during which it fell due completes.
+ */
+void i8080_schedule(struct I8080Cpu *cpu, uint64_t cycles, uint32_t event);
+
+/**
+ * Remove every pending occurrence of `event`. Returns true if any was
+ * pending.
+ */
+bool i8080_cancel_event(struct I8080Cpu *cpu, uint32_t event);
+
+/**
+ * Write the cpu's state, including its pending scheduled events, into `buf`.
+ * Returns the number of bytes the saved state needs, I8080_SAVE_STATE_SIZE
+ * plus I8080_SAVE_EVENT_SIZE per event, writing nothing if `len` is smaller
+ * than that.
+ */
+size_t i8080_save_state(const struct I8080Cpu *cpu, uint8_t *buf, size_t len);
+
+/**
+ * Restore a state written by i8080_save_state. Returns 0 on success, or -1
+ * if the bytes are not a valid saved state, in which case the cpu is left
+ * untouched.
+ */
+int i8080_load_state(struct I8080Cpu *cpu, const uint8_t *buf, size_t len);
+
+/**
+ * Read a register or register pair from inside an output callback.
+ */
+uint16_t i8080_view_get_reg(const struct I8080View *view, enum I8080Reg reg);
+
+/**
+ * Halt the cpu from inside an output callback, as HLT does. i8080_is_halted
+ * reports it once the OUT instruction completes.
+ */
+void i8080_view_halt(struct I8080View *view);
+
+/**
+ * Schedule `event` from inside an event callback, `cycles` cycles from the
+ * cycle the callback's event was delivered on.
+ */
+void i8080_events_schedule(struct I8080Events *events, uint64_t cycles, uint32_t event);
+
+/**
+ * Cancel `event` from inside an event callback. Returns true if any was
+ * pending.
+ */
+bool i8080_events_cancel(struct I8080Events *events, uint32_t event);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* I8080_H */
@@ -0,0 +1,127 @@
+use i8080::machine::{ClockedIO, CpuView, MachineIO, SchedulerView};
+use i8080::memory_bus::MemoryMap;
+use i8080::scheduler::EventId;
+
+use std::os::raw::c_void;
+
+use crate::{I8080Events, I8080View};
+
+// The callbacks are nullable. cbindgen only turns an Option into a nullable
+// pointer when it wraps the fn type itself, not an alias for it, so the
+// Option is part of each alias.
+pub type I8080ReadFn = Option<extern "C" fn(user: *mut c_void, addr: u16) -> u8>;
+pub type I8080WriteFn = Option<extern "C" fn(user: *mut c_void, addr: u16, val: u8)>;
+pub type I8080InFn = Option<extern "C" fn(user: *mut c_void, port: u8) -> u8>;
+pub type I8080OutFn =
+    Option<extern "C" fn(user: *mut c_void, view: *mut I8080View, port: u8, val: u8)>;
+pub type I8080EventFn =
+    Option<extern "C" fn(user: *mut c_void, events: *mut I8080Events, event: u32)>;
+
+// The caller's read function, which unlike the others is required.
+type ReadFn = extern "C" fn(user: *mut c_void, addr: u16) -> u8;
+
+// Memory backed by the caller's read and write functions.
+pub struct CallbackMemory {
+    user: *mut c_void,
+    read: ReadFn,
+    write: I8080WriteFn,
+    // The bytes of the instruction being decoded. The decoder wants a slice,
+    // but the caller's memory can only be reached one byte at a time.
+    fetch: [u8; 3],
+}
+
+impl CallbackMemory {
+    pub fn new(user: *mut c_void, read: ReadFn, write: I8080WriteFn) -> Self {
+        CallbackMemory {
+            user,
+            read,
+            write,
+            fetch: [0; 3],
+        }
+    }
+}
+
+impl MemoryMap for CallbackMemory {
+    // The caller owns memory and loads it itself.
+    fn load_rom(&mut self) {}
+
+    fn read(&mut self, addr: u16) -> u8 {
+        (self.read)(self.user, addr)
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        for (i, byte) in self.fetch.iter_mut().enumerate() {
+            *byte = (self.read)(self.user, addr.wrapping_add(i as u16));
+        }
+        &self.fetch
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        if let Some(write) = self.write {
+            write(self.user, addr, val);
+        }
+    }
+}
+
+// I/O ports backed by the caller's input and output functions, the
+// interrupt request line driven by i8080_raise_interrupt, and the caller's
+// function for scheduled events.
+pub struct CallbackIO {
+    user: *mut c_void,
+    input: I8080InFn,
+    output: I8080OutFn,
+    event: I8080EventFn,
+    pub interrupt: Option<u8>,
+}
+
+impl CallbackIO {
+    pub fn new(
+        user: *mut c_void,
+        input: I8080InFn,
+        output: I8080OutFn,
+        event: I8080EventFn,
+    ) -> Self {
+        CallbackIO {
+            user,
+            input,
+            output,
+            event,
+            interrupt: None,
+        }
+    }
+}
+
+impl MachineIO for CallbackIO {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        match self.input {
+            Some(input) => input(self.user, port),
+            None => 0xFF,
+        }
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+        if let Some(output) = self.output {
+            let mut view = I8080View { cpu };
+            output(self.user, &mut view, port, val);
+        }
+    }
+}
+
+impl ClockedIO for CallbackIO {
+    fn tick(&mut self, _: u32) {}
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        self.interrupt
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        self.interrupt = None;
+    }
+
+    fn event(&mut self, scheduler: &mut SchedulerView, event: EventId) {
+        if let Some(callback) = self.event {
+            let mut events = I8080Events { scheduler };
+            callback(self.user, &mut events, event.0);
+        }
+    }
+}
@@ -0,0 +1,602 @@
+// A C API for the i8080 core. The caller provides memory and I/O as function
+// pointers, and drives the cpu with i8080_step and i8080_run. build.rs
+// generates the declarations in include/i8080.h from this file with cbindgen.
+// cbindgen only copies `///` comments into the header, so the items it
+// exports use those rather than `//`.
+//
+// Every function taking an I8080Cpu, I8080View or I8080Events expects a
+// valid, non-null pointer. A panic inside the core, e.g. on an invalid
+// opcode, aborts the process rather than unwinding into C.
+#![allow(clippy::missing_safety_doc)]
+
+mod callbacks;
+
+use i8080::cpu::Cpu;
+use i8080::machine::{CpuView, SchedulerView};
+use i8080::power_on::PowerOn;
+use i8080::scheduler::EventId;
+use i8080::state::CpuState;
+
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use crate::callbacks::{CallbackIO, CallbackMemory};
+pub use crate::callbacks::{I8080EventFn, I8080InFn, I8080OutFn, I8080ReadFn, I8080WriteFn};
+
+/// The functions the cpu calls to reach the outside world. `read` is required.
+/// Without `write`, writes are ignored. Without `input`, IN reads 0xFF. Without
+/// `output`, OUT does nothing. Without `event`, events scheduled with
+/// i8080_schedule are dropped when they fall due. `user` is passed through to
+/// every call.
+#[repr(C)]
+pub struct I8080Callbacks {
+    pub user: *mut c_void,
+    pub read: I8080ReadFn,
+    pub write: I8080WriteFn,
+    pub input: I8080InFn,
+    pub output: I8080OutFn,
+    pub event: I8080EventFn,
+}
+
+/// A cpu and the callbacks it is wired to. Opaque to C.
+pub struct I8080Cpu {
+    cpu: Cpu<CallbackMemory>,
+    io: CallbackIO,
+}
+
+/// The cpu as seen by an output callback, which can read the registers and
+/// halt the cpu. Only valid for the duration of the callback. Opaque to C.
+pub struct I8080View<'a, 'b> {
+    pub(crate) cpu: &'a mut CpuView<'b>,
+}
+
+/// The cpu's scheduler as seen by an event callback, which can schedule and
+/// cancel events. Only valid for the duration of the callback. Opaque to C.
+pub struct I8080Events<'a, 'b> {
+    pub(crate) scheduler: &'a mut SchedulerView<'b>,
+}
+
+/// The architectural state of the cpu. `flags` is laid out as pushed by
+/// PUSH PSW.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct I8080State {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub flags: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub interrupts_enabled: bool,
+    pub halted: bool,
+}
+
+impl From<&CpuState> for I8080State {
+    fn from(state: &CpuState) -> Self {
+        I8080State {
+            a: state.a(),
+            b: state.b(),
+            c: state.c(),
+            d: state.d(),
+            e: state.e(),
+            h: state.h(),
+            l: state.l(),
+            flags: state.flags(),
+            sp: state.sp(),
+            pc: state.pc(),
+            interrupts_enabled: state.interrupts_enabled(),
+            halted: state.is_halted(),
+        }
+    }
+}
+
+impl From<&I8080State> for CpuState {
+    fn from(state: &I8080State) -> Self {
+        let mut cpu_state = CpuState::new();
+        cpu_state.set_a(state.a);
+        cpu_state.set_b(state.b);
+        cpu_state.set_c(state.c);
+        cpu_state.set_d(state.d);
+        cpu_state.set_e(state.e);
+        cpu_state.set_h(state.h);
+        cpu_state.set_l(state.l);
+        cpu_state.set_flags(state.flags);
+        cpu_state.set_sp(state.sp);
+        cpu_state.set_pc(state.pc);
+        cpu_state.set_interrupts_enabled(state.interrupts_enabled);
+        cpu_state.set_halted(state.halted);
+        cpu_state
+    }
+}
+
+/// A register or register pair, for i8080_get_reg and i8080_set_reg. F is the
+/// flags byte and PSW is A and the flags.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum I8080Reg {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    F,
+    BC,
+    DE,
+    HL,
+    PSW,
+    SP,
+    PC,
+}
+
+fn get_reg(state: &CpuState, reg: I8080Reg) -> u16 {
+    match reg {
+        I8080Reg::A => state.a() as u16,
+        I8080Reg::B => state.b() as u16,
+        I8080Reg::C => state.c() as u16,
+        I8080Reg::D => state.d() as u16,
+        I8080Reg::E => state.e() as u16,
+        I8080Reg::H => state.h() as u16,
+        I8080Reg::L => state.l() as u16,
+        I8080Reg::F => state.flags() as u16,
+        I8080Reg::BC => state.bc(),
+        I8080Reg::DE => state.de(),
+        I8080Reg::HL => state.hl(),
+        I8080Reg::PSW => state.psw(),
+        I8080Reg::SP => state.sp(),
+        I8080Reg::PC => state.pc(),
+    }
+}
+
+fn set_reg(state: &mut CpuState, reg: I8080Reg, val: u16) {
+    match reg {
+        I8080Reg::A => state.set_a(val as u8),
+        I8080Reg::B => state.set_b(val as u8),
+        I8080Reg::C => state.set_c(val as u8),
+        I8080Reg::D => state.set_d(val as u8),
+        I8080Reg::E => state.set_e(val as u8),
+        I8080Reg::H => state.set_h(val as u8),
+        I8080Reg::L => state.set_l(val as u8),
+        I8080Reg::F => state.set_flags(val as u8),
+        I8080Reg::BC => state.set_bc(val),
+        I8080Reg::DE => state.set_de(val),
+        I8080Reg::HL => state.set_hl(val),
+        I8080Reg::PSW => state.set_psw(val),
+        I8080Reg::SP => state.set_sp(val),
+        I8080Reg::PC => state.set_pc(val),
+    }
+}
+
+/// Saved states are I8080_SAVE_STATE_SIZE bytes, followed by
+/// I8080_SAVE_EVENT_SIZE bytes for each pending scheduled event:
+///
+///   0   4  magic "I80S"
+///   4   1  format version, currently 2
+///   5   8  A, B, C, D, E, H, L and the flags byte
+///   13  2  SP, little endian
+///   15  2  PC, little endian
+///   17  1  interrupts enabled (0 or 1)
+///   18  1  halted (0 or 1)
+///   19  1  pending interrupt request as an RST number, or 0xFF if none
+///   20  8  cycle counter, little endian
+///   28  4  number of pending events, little endian
+///
+/// Each event, in the order they will fire:
+///
+///   0   8  cycles until it falls due, little endian
+///   8   4  event, little endian
+///
+/// Memory belongs to the caller and is not included.
+pub const I8080_SAVE_STATE_SIZE: usize = 32;
+pub const I8080_SAVE_EVENT_SIZE: usize = 12;
+
+const SAVE_STATE_MAGIC: &[u8; 4] = b"I80S";
+const SAVE_STATE_VERSION: u8 = 2;
+const NO_INTERRUPT: u8 = 0xFF;
+
+fn save_state(cpu: &I8080Cpu) -> Vec<u8> {
+    let cpu_state = cpu.cpu.state();
+    let state = I8080State::from(&cpu_state);
+    let mut bytes = Vec::with_capacity(save_state_size(&cpu_state));
+    bytes.extend_from_slice(SAVE_STATE_MAGIC);
+    bytes.push(SAVE_STATE_VERSION);
+    bytes.extend_from_slice(&[
+        state.a,
+        state.b,
+        state.c,
+        state.d,
+        state.e,
+        state.h,
+        state.l,
+        state.flags,
+    ]);
+    bytes.extend_from_slice(&state.sp.to_le_bytes());
+    bytes.extend_from_slice(&state.pc.to_le_bytes());
+    bytes.push(state.interrupts_enabled as u8);
+    bytes.push(state.halted as u8);
+    bytes.push(cpu.io.interrupt.unwrap_or(NO_INTERRUPT));
+    bytes.extend_from_slice(&cpu.cpu.cycles.to_le_bytes());
+    bytes.extend_from_slice(&(cpu_state.events().len() as u32).to_le_bytes());
+    for (cycles, event) in cpu_state.events() {
+        bytes.extend_from_slice(&cycles.to_le_bytes());
+        bytes.extend_from_slice(&event.0.to_le_bytes());
+    }
+    bytes
+}
+
+fn save_state_size(state: &CpuState) -> usize {
+    I8080_SAVE_STATE_SIZE + state.events().len() * I8080_SAVE_EVENT_SIZE
+}
+
+// Restore a state written by save_state. Returns false, leaving the cpu
+// untouched, if the bytes are not a saved state this version understands.
+fn load_state(cpu: &mut I8080Cpu, bytes: &[u8]) -> bool {
+    if bytes.len() < I8080_SAVE_STATE_SIZE
+        || &bytes[0..4] != SAVE_STATE_MAGIC
+        || bytes[4] != SAVE_STATE_VERSION
+        || bytes[17] > 1
+        || bytes[18] > 1
+    {
+        return false;
+    }
+    let count = u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]) as usize;
+    let events = &bytes[I8080_SAVE_STATE_SIZE..];
+    if Some(events.len()) != count.checked_mul(I8080_SAVE_EVENT_SIZE) {
+        return false;
+    }
+
+    let state = I8080State {
+        a: bytes[5],
+        b: bytes[6],
+        c: bytes[7],
+        d: bytes[8],
+        e: bytes[9],
+        h: bytes[10],
+        l: bytes[11],
+        flags: bytes[12],
+        sp: u16::from_le_bytes([bytes[13], bytes[14]]),
+        pc: u16::from_le_bytes([bytes[15], bytes[16]]),
+        interrupts_enabled: bytes[17] == 1,
+        halted: bytes[18] == 1,
+    };
+    let mut cycles = [0; 8];
+    cycles.copy_from_slice(&bytes[20..28]);
+    let events: Vec<(u64, EventId)> = events
+        .chunks(I8080_SAVE_EVENT_SIZE)
+        .map(|event| {
+            let mut cycles = [0; 8];
+            cycles.copy_from_slice(&event[0..8]);
+            let id = u32::from_le_bytes([event[8], event[9], event[10], event[11]]);
+            (u64::from_le_bytes(cycles), EventId(id))
+        })
+        .collect();
+
+    // The events are due relative to the cycle counter, so it is restored
+    // first.
+    cpu.cpu.cycles = u64::from_le_bytes(cycles);
+    let mut cpu_state = CpuState::from(&state);
+    cpu_state.set_events(&events);
+    cpu.cpu.set_state(&cpu_state);
+    cpu.io.interrupt = match bytes[19] {
+        NO_INTERRUPT => None,
+        rst => Some(rst & 0x7),
+    };
+    true
+}
+
+/// Create a cpu with every register zeroed. Returns null if `callbacks` is
+/// null or has no read function. Free the cpu with i8080_free.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_new(callbacks: *const I8080Callbacks) -> *mut I8080Cpu {
+    let callbacks = match callbacks.as_ref() {
+        Some(callbacks) => callbacks,
+        None => return ptr::null_mut(),
+    };
+    let read = match callbacks.read {
+        Some(read) => read,
+        None => return ptr::null_mut(),
+    };
+
+    let memory = CallbackMemory::new(callbacks.user, read, callbacks.write);
+    let io = CallbackIO::new(
+        callbacks.user,
+        callbacks.input,
+        callbacks.output,
+        callbacks.event,
+    );
+    Box::into_raw(Box::new(I8080Cpu {
+        cpu: Cpu::new(memory),
+        io,
+    }))
+}
+
+/// Free a cpu created by i8080_new. Does nothing if `cpu` is null.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_free(cpu: *mut I8080Cpu) {
+    if !cpu.is_null() {
+        drop(Box::from_raw(cpu));
+    }
+}
+
+/// Pulse the RESET pin: the pc is cleared, interrupts are disabled and a
+/// halted cpu resumes. Everything else is left as it was.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_reset(cpu: *mut I8080Cpu) {
+    (*cpu).cpu.reset();
+}
+
+/// Fill the registers, flags and stack pointer with random values from `seed`,
+/// as on a real board at power on, then reset. Memory belongs to the caller,
+/// which can fill it with garbage itself.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_power_on(cpu: *mut I8080Cpu, seed: u64) {
+    (*cpu).cpu.power_on(&PowerOn::new(seed));
+}
+
+/// Execute one instruction, accepting a pending interrupt afterwards if
+/// interrupts are enabled. A halted cpu executes nothing but lets 4 cycles
+/// pass, and an interrupt wakes it. Returns the number of cycles taken.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_step(cpu: *mut I8080Cpu) -> u32 {
+    let cpu = &mut *cpu;
+    cpu.cpu.step_clocked(&mut cpu.io)
+}
+
+/// Execute instructions until at least `cycles` cycles have elapsed. A halted
+/// cpu idles until an interrupt wakes it. Returns the number of cycles elapsed.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_run(cpu: *mut I8080Cpu, cycles: u64) -> u64 {
+    let cpu = &mut *cpu;
+    let start = cpu.cpu.cycles;
+    cpu.cpu.run_clocked(cycles, &mut cpu.io);
+    cpu.cpu.cycles - start
+}
+
+/// The total number of cycles executed.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_cycles(cpu: *const I8080Cpu) -> u64 {
+    (*cpu).cpu.cycles
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn i8080_is_halted(cpu: *const I8080Cpu) -> bool {
+    (*cpu).cpu.is_halted
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn i8080_get_state(cpu: *const I8080Cpu, state: *mut I8080State) {
+    *state = I8080State::from(&(*cpu).cpu.state());
+}
+
+/// Set the architectural state. Pending scheduled events are kept.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_set_state(cpu: *mut I8080Cpu, state: *const I8080State) {
+    let cpu = &mut (*cpu).cpu;
+    let mut cpu_state = CpuState::from(&*state);
+    cpu_state.set_events(cpu.state().events());
+    cpu.set_state(&cpu_state);
+}
+
+/// Read a register or register pair. Eight-bit registers are zero extended.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_get_reg(cpu: *const I8080Cpu, reg: I8080Reg) -> u16 {
+    get_reg(&(*cpu).cpu.state(), reg)
+}
+
+/// Write a register or register pair. Eight-bit registers take the low byte of
+/// `val`.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_set_reg(cpu: *mut I8080Cpu, reg: I8080Reg, val: u16) {
+    let cpu = &mut (*cpu).cpu;
+    let mut state = cpu.state();
+    set_reg(&mut state, reg, val);
+    cpu.set_state(&state);
+}
+
+/// Request an interrupt that places RST `rst` (0-7) on the data bus. The
+/// request is held until the cpu accepts it, which it does at the end of the
+/// next instruction executed with interrupts enabled.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_raise_interrupt(cpu: *mut I8080Cpu, rst: u8) {
+    (*cpu).io.interrupt = Some(rst & 0x7);
+}
+
+/// Withdraw a pending interrupt request.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_clear_interrupt(cpu: *mut I8080Cpu) {
+    (*cpu).io.interrupt = None;
+}
+
+/// Schedule `event` to fall due `cycles` cycles from now. It is passed to the
+/// event callback once the instruction during which it fell due completes.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_schedule(cpu: *mut I8080Cpu, cycles: u64, event: u32) {
+    (*cpu).cpu.schedule(cycles, EventId(event));
+}
+
+/// Remove every pending occurrence of `event`. Returns true if any was
+/// pending.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_cancel_event(cpu: *mut I8080Cpu, event: u32) -> bool {
+    (*cpu).cpu.scheduler.cancel(EventId(event))
+}
+
+/// Write the cpu's state, including its pending scheduled events, into `buf`.
+/// Returns the number of bytes the saved state needs, I8080_SAVE_STATE_SIZE
+/// plus I8080_SAVE_EVENT_SIZE per event, writing nothing if `len` is smaller
+/// than that.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_save_state(cpu: *const I8080Cpu, buf: *mut u8, len: usize) -> usize {
+    let cpu = &*cpu;
+    let size = save_state_size(&cpu.cpu.state());
+    if !buf.is_null() && len >= size {
+        let bytes = save_state(cpu);
+        slice::from_raw_parts_mut(buf, size).copy_from_slice(&bytes);
+    }
+    size
+}
+
+/// Restore a state written by i8080_save_state. Returns 0 on success, or -1
+/// if the bytes are not a valid saved state, in which case the cpu is left
+/// untouched.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_load_state(cpu: *mut I8080Cpu, buf: *const u8, len: usize) -> c_int {
+    if buf.is_null() {
+        return -1;
+    }
+    if load_state(&mut *cpu, slice::from_raw_parts(buf, len)) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Read a register or register pair from inside an output callback.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_view_get_reg(view: *const I8080View, reg: I8080Reg) -> u16 {
+    let cpu = &(*view).cpu;
+    let mut state = CpuState::new();
+    state.set_a(cpu.registers.a);
+    state.set_bc(cpu.registers.get_bc());
+    state.set_de(cpu.registers.get_de());
+    state.set_hl(cpu.registers.get_hl());
+    state.set_flags(cpu.condition_codes.flags_to_psw());
+    state.set_sp(cpu.sp);
+    state.set_pc(cpu.pc);
+    get_reg(&state, reg)
+}
+
+/// Halt the cpu from inside an output callback, as HLT does. i8080_is_halted
+/// reports it once the OUT instruction completes.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_view_halt(view: *mut I8080View) {
+    (*view).cpu.halt();
+}
+
+/// Schedule `event` from inside an event callback, `cycles` cycles from the
+/// cycle the callback's event was delivered on.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_events_schedule(events: *mut I8080Events, cycles: u64, event: u32) {
+    (*events).scheduler.schedule(cycles, EventId(event));
+}
+
+/// Cancel `event` from inside an event callback. Returns true if any was
+/// pending.
+#[no_mangle]
+pub unsafe extern "C" fn i8080_events_cancel(events: *mut I8080Events, event: u32) -> bool {
+    (*events).scheduler.cancel(EventId(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn read(user: *mut c_void, addr: u16) -> u8 {
+        let memory = unsafe { &*(user as *const [u8; 0x10000]) };
+        memory[addr as usize]
+    }
+
+    extern "C" fn write(user: *mut c_void, addr: u16, val: u8) {
+        let memory = unsafe { &mut *(user as *mut [u8; 0x10000]) };
+        memory[addr as usize] = val;
+    }
+
+    // Counts each event at FF00H plus the event, and reschedules it 100
+    // cycles later.
+    extern "C" fn event(user: *mut c_void, events: *mut I8080Events, event: u32) {
+        let memory = unsafe { &mut *(user as *mut [u8; 0x10000]) };
+        memory[0xFF00 + event as usize] += 1;
+        unsafe { i8080_events_schedule(events, 100, event) };
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut memory = Box::new([0u8; 0x10000]);
+        // LXI SP,2400H; MVI A,42H; PUSH PSW; EI; JMP 0007H
+        memory[..10].copy_from_slice(&[0x31, 0x00, 0x24, 0x3E, 0x42, 0xF5, 0xFB, 0xC3, 0x07, 0x00]);
+        let callbacks = I8080Callbacks {
+            user: memory.as_mut_ptr() as *mut c_void,
+            read: Some(read),
+            write: Some(write),
+            input: None,
+            output: None,
+            event: None,
+        };
+
+        unsafe {
+            let cpu = i8080_new(&callbacks);
+            i8080_run(cpu, 40);
+            i8080_raise_interrupt(cpu, 7);
+            assert_eq!(i8080_get_reg(cpu, I8080Reg::PSW), 0x4202);
+            assert_eq!(i8080_get_reg(cpu, I8080Reg::SP), 0x23FE);
+
+            let mut saved = [0; I8080_SAVE_STATE_SIZE];
+            assert_eq!(
+                i8080_save_state(cpu, saved.as_mut_ptr(), saved.len()),
+                I8080_SAVE_STATE_SIZE
+            );
+            let cycles = i8080_cycles(cpu);
+
+            i8080_step(cpu);
+            assert_eq!(i8080_get_reg(cpu, I8080Reg::PC), 0x38);
+
+            assert_eq!(i8080_load_state(cpu, saved.as_ptr(), saved.len()), 0);
+            assert_eq!(i8080_cycles(cpu), cycles);
+            assert_eq!(i8080_get_reg(cpu, I8080Reg::PC), 0x07);
+            i8080_step(cpu);
+            assert_eq!(i8080_get_reg(cpu, I8080Reg::PC), 0x38);
+
+            saved[4] = 1;
+            assert_eq!(i8080_load_state(cpu, saved.as_ptr(), saved.len()), -1);
+            i8080_free(cpu);
+        }
+    }
+
+    #[test]
+    fn test_events_are_saved() {
+        let mut memory = Box::new([0u8; 0x10000]);
+        // JMP 0000H
+        memory[..3].copy_from_slice(&[0xC3, 0x00, 0x00]);
+        let callbacks = I8080Callbacks {
+            user: memory.as_mut_ptr() as *mut c_void,
+            read: Some(read),
+            write: Some(write),
+            input: None,
+            output: None,
+            event: Some(event),
+        };
+
+        unsafe {
+            let cpu = i8080_new(&callbacks);
+            i8080_schedule(cpu, 50, 1);
+            i8080_schedule(cpu, 80, 2);
+            i8080_schedule(cpu, 90, 3);
+            assert!(i8080_cancel_event(cpu, 3));
+            i8080_run(cpu, 10);
+
+            let size = I8080_SAVE_STATE_SIZE + 2 * I8080_SAVE_EVENT_SIZE;
+            assert_eq!(i8080_save_state(cpu, ptr::null_mut(), 0), size);
+            let mut saved = vec![0; size];
+            assert_eq!(i8080_save_state(cpu, saved.as_mut_ptr(), size), size);
+
+            i8080_run(cpu, 200);
+            let fired = memory[0xFF01..=0xFF03].to_vec();
+            assert_eq!(fired, [2, 2, 0]);
+
+            // Loading the state brings back the events as they were pending.
+            memory[0xFF01..=0xFF03].fill(0);
+            assert_eq!(i8080_load_state(cpu, saved.as_ptr(), size), 0);
+            i8080_run(cpu, 200);
+            assert_eq!(memory[0xFF01..=0xFF03], fired[..]);
+
+            assert_eq!(i8080_load_state(cpu, saved.as_ptr(), size - 1), -1);
+            i8080_free(cpu);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use i8080_ffi::{
+    I8080Callbacks, I8080Reg, I8080State, I8080_SAVE_EVENT_SIZE, I8080_SAVE_STATE_SIZE,
+};
+
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+// The directory cargo put the static library in: the parent of the deps
+// directory holding this test.
+fn target_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+fn static_library() -> PathBuf {
+    let name = if cfg!(windows) {
+        "i8080_ffi.lib"
+    } else {
+        "libi8080_ffi.a"
+    };
+    let dir = target_dir();
+    // cargo test rebuilds the copy in deps, but only cargo build updates the
+    // one beside it.
+    [dir.join("deps").join(name), dir.join(name)]
+        .iter()
+        .find(|path| path.exists())
+        .unwrap_or_else(|| panic!("{} not found in {}", name, dir.display()))
+        .clone()
+}
+
+// Build tests/NAME.c against the header and the static library.
+fn compile(name: &str) -> PathBuf {
+    let manifest_dir = Path::new(MANIFEST_DIR);
+    let exe = target_dir().join(format!("{}-c", name));
+
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(cc)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(manifest_dir.join(format!("tests/{}.c", name)))
+        .arg(static_library())
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&exe)
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "{}.c did not compile", name);
+    exe
+}
+
+// Run tests/tst8080.c on TST8080.COM from i8080-tests.
+#[test]
+fn test_c_program_runs_tst8080() {
+    let exe = compile("tst8080");
+    let rom = Path::new(MANIFEST_DIR).join("../i8080-tests/test-roms/TST8080.COM");
+    let output = Command::new(&exe).arg(rom).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("CPU IS OPERATIONAL"), "{}", stdout);
+}
+
+// HLT leaves the cpu halted rather than ending the host process.
+#[test]
+fn test_c_program_sees_hlt() {
+    let exe = compile("halt");
+    let output = Command::new(&exe).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(stdout, "halted\n");
+}
+
+// The structs, enum and constants in the header are laid out as in
+// src/lib.rs. The header is generated, but this catches a cbindgen
+// configuration that no longer matches the Rust types.
+#[test]
+fn test_header_matches_layout() {
+    macro_rules! size {
+        ($type:ident) => {
+            format!("sizeof({}) {}", stringify!($type), mem::size_of::<$type>())
+        };
+    }
+    macro_rules! offset {
+        ($type:ident, $field:ident) => {
+            format!(
+                "{}.{} {}",
+                stringify!($type),
+                stringify!($field),
+                mem::offset_of!($type, $field)
+            )
+        };
+    }
+    let reg = |name: &str, reg: I8080Reg| format!("I8080_REG_{} {}", name, reg as i32);
+
+    let expected = [
+        size!(I8080Callbacks),
+        offset!(I8080Callbacks, user),
+        offset!(I8080Callbacks, read),
+        offset!(I8080Callbacks, write),
+        offset!(I8080Callbacks, input),
+        offset!(I8080Callbacks, output),
+        offset!(I8080Callbacks, event),
+        size!(I8080State),
+        offset!(I8080State, a),
+        offset!(I8080State, b),
+        offset!(I8080State, c),
+        offset!(I8080State, d),
+        offset!(I8080State, e),
+        offset!(I8080State, h),
+        offset!(I8080State, l),
+        offset!(I8080State, flags),
+        offset!(I8080State, sp),
+        offset!(I8080State, pc),
+        offset!(I8080State, interrupts_enabled),
+        offset!(I8080State, halted),
+        size!(I8080Reg),
+        reg("A", I8080Reg::A),
+        reg("B", I8080Reg::B),
+        reg("C", I8080Reg::C),
+        reg("D", I8080Reg::D),
+        reg("E", I8080Reg::E),
+        reg("H", I8080Reg::H),
+        reg("L", I8080Reg::L),
+        reg("F", I8080Reg::F),
+        reg("BC", I8080Reg::BC),
+        reg("DE", I8080Reg::DE),
+        reg("HL", I8080Reg::HL),
+        reg("PSW", I8080Reg::PSW),
+        reg("SP", I8080Reg::SP),
+        reg("PC", I8080Reg::PC),
+        format!("I8080_SAVE_STATE_SIZE {}", I8080_SAVE_STATE_SIZE),
+        format!("I8080_SAVE_EVENT_SIZE {}", I8080_SAVE_EVENT_SIZE),
+    ];
+
+    let output = Command::new(compile("layout")).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let actual: Vec<&str> = stdout.lines().collect();
+    assert_eq!(actual, expected);
+}
+
+// Every function exported from src/lib.rs is declared in the header.
+#[test]
+fn test_header_declares_every_function() {
+    let source = fs::read_to_string(Path::new(MANIFEST_DIR).join("src/lib.rs")).unwrap();
+    let header = fs::read_to_string(Path::new(MANIFEST_DIR).join("include/i8080.h")).unwrap();
+
+    let exported: Vec<&str> = source
+        .split("#[no_mangle]\npub unsafe extern \"C\" fn ")
+        .skip(1)
+        .map(|rest| rest.split('(').next().unwrap())
+        .collect();
+    assert!(!exported.is_empty());
+    for name in exported {
+        assert!(
+            header.contains(&format!(" {}(", name)) || header.contains(&format!("*{}(", name)),
+            "{} is not declared in include/i8080.h",
+            name
+        );
+    }
+}
@@ -0,0 +1,36 @@
+/*
+ * Runs a one-byte program, HLT, through the C API and checks that the cpu is
+ * left halted, idling for the cycles it was run for, and the host keeps
+ * running.
+ *
+ * Exits with 0 if i8080_is_halted reports the halt.
+ */
+#include <stdio.h>
+
+#include "i8080.h"
+
+static uint8_t memory[0x10000] = {0x76};
+
+static uint8_t read(void *user, uint16_t addr) {
+  (void)user;
+  return memory[addr];
+}
+
+int main(void) {
+  I8080Callbacks callbacks = {NULL, read, NULL, NULL, NULL};
+  I8080Cpu *cpu = i8080_new(&callbacks);
+
+  i8080_run(cpu, 1000);
+  I8080State state;
+  i8080_get_state(cpu, &state);
+  int halted = i8080_is_halted(cpu) && state.halted && state.pc == 1 &&
+               i8080_cycles(cpu) >= 1000;
+  i8080_free(cpu);
+
+  if (!halted) {
+    fprintf(stderr, "the cpu did not halt at HLT\n");
+    return 1;
+  }
+  printf("halted\n");
+  return 0;
+}
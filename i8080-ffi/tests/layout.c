@@ -0,0 +1,56 @@
+/*
+ * Prints the layout of the types in i8080.h as the C compiler sees it, one
+ * `name value` line each, for c_api.rs to compare with the Rust definitions.
+ */
+#include <stddef.h>
+#include <stdio.h>
+
+#include "i8080.h"
+
+#define SIZE(type) printf("sizeof(" #type ") %zu\n", sizeof(type))
+#define OFFSET(type, field) printf(#type "." #field " %zu\n", offsetof(type, field))
+#define VALUE(name) printf(#name " %d\n", (int)(name))
+
+int main(void) {
+  SIZE(I8080Callbacks);
+  OFFSET(I8080Callbacks, user);
+  OFFSET(I8080Callbacks, read);
+  OFFSET(I8080Callbacks, write);
+  OFFSET(I8080Callbacks, input);
+  OFFSET(I8080Callbacks, output);
+  OFFSET(I8080Callbacks, event);
+
+  SIZE(I8080State);
+  OFFSET(I8080State, a);
+  OFFSET(I8080State, b);
+  OFFSET(I8080State, c);
+  OFFSET(I8080State, d);
+  OFFSET(I8080State, e);
+  OFFSET(I8080State, h);
+  OFFSET(I8080State, l);
+  OFFSET(I8080State, flags);
+  OFFSET(I8080State, sp);
+  OFFSET(I8080State, pc);
+  OFFSET(I8080State, interrupts_enabled);
+  OFFSET(I8080State, halted);
+
+  SIZE(I8080Reg);
+  VALUE(I8080_REG_A);
+  VALUE(I8080_REG_B);
+  VALUE(I8080_REG_C);
+  VALUE(I8080_REG_D);
+  VALUE(I8080_REG_E);
+  VALUE(I8080_REG_H);
+  VALUE(I8080_REG_L);
+  VALUE(I8080_REG_F);
+  VALUE(I8080_REG_BC);
+  VALUE(I8080_REG_DE);
+  VALUE(I8080_REG_HL);
+  VALUE(I8080_REG_PSW);
+  VALUE(I8080_REG_SP);
+  VALUE(I8080_REG_PC);
+
+  VALUE(I8080_SAVE_STATE_SIZE);
+  VALUE(I8080_SAVE_EVENT_SIZE);
+  return 0;
+}
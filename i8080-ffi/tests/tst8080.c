@@ -0,0 +1,128 @@
+/*
+ * Runs a CP/M test rom such as TST8080.COM through the C API, with the same
+ * minimal CP/M as i8080-tests: OUT 0 at the warm boot vector halts the cpu
+ * and OUT 1 at the BDOS entry point prints a character or string.
+ *
+ * usage: tst8080 ROM
+ *
+ * Exits with 0 if the rom reports that the cpu is operational.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "i8080.h"
+
+#define MAX_CYCLES 100000000ULL
+
+static uint8_t memory[0x10000];
+static char output[4096];
+static size_t output_len;
+
+static uint8_t read(void *user, uint16_t addr) {
+  (void)user;
+  return memory[addr];
+}
+
+static void write(void *user, uint16_t addr, uint8_t val) {
+  (void)user;
+  memory[addr] = val;
+}
+
+static void print(char c) {
+  putchar(c);
+  if (output_len < sizeof(output) - 1) {
+    output[output_len++] = c;
+  }
+}
+
+static void out(void *user, I8080View *view, uint8_t port, uint8_t val) {
+  (void)user;
+  (void)val;
+  if (port == 0) {
+    i8080_view_halt(view);
+  } else if (port == 1) {
+    uint8_t c = i8080_view_get_reg(view, I8080_REG_C);
+    if (c == 9) {
+      for (uint16_t addr = i8080_view_get_reg(view, I8080_REG_DE); memory[addr] != '$'; addr++) {
+        print(memory[addr]);
+      }
+    } else if (c == 2) {
+      print(i8080_view_get_reg(view, I8080_REG_E));
+    }
+  }
+}
+
+static int load(const char *path) {
+  FILE *file = fopen(path, "rb");
+  if (file == NULL) {
+    perror(path);
+    return -1;
+  }
+  fread(&memory[0x100], 1, sizeof(memory) - 0x100, file);
+  fclose(file);
+
+  /* OUT 0 at the warm boot vector, OUT 1; RET at the BDOS entry point. */
+  memcpy(&memory[0x0], "\xD3\x00", 2);
+  memcpy(&memory[0x5], "\xD3\x01\xC9", 3);
+  return 0;
+}
+
+int main(int argc, char **argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s ROM\n", argv[0]);
+    return 2;
+  }
+  if (load(argv[1]) != 0) {
+    return 2;
+  }
+
+  I8080Callbacks callbacks = {NULL, read, write, NULL, out};
+  I8080Cpu *cpu = i8080_new(&callbacks);
+  /* Start from garbage rather than zeroed registers, as a real cpu would. */
+  i8080_power_on(cpu, 8080);
+  i8080_set_reg(cpu, I8080_REG_PC, 0x100);
+
+  /* Run the first part of the test twice, restoring a saved state in
+     between, to check that a saved state resumes where it left off. */
+  uint8_t saved[I8080_SAVE_STATE_SIZE];
+  i8080_save_state(cpu, saved, sizeof(saved));
+  uint8_t saved_memory[sizeof(memory)];
+  memcpy(saved_memory, memory, sizeof(memory));
+  i8080_run(cpu, 1000);
+  I8080State first;
+  i8080_get_state(cpu, &first);
+
+  if (i8080_load_state(cpu, saved, sizeof(saved)) != 0) {
+    fprintf(stderr, "could not load the saved state\n");
+    return 1;
+  }
+  memcpy(memory, saved_memory, sizeof(memory));
+  output_len = 0;
+  i8080_run(cpu, 1000);
+  I8080State second;
+  i8080_get_state(cpu, &second);
+  if (memcmp(&first, &second, sizeof(first)) != 0) {
+    fprintf(stderr, "the saved state did not resume where it left off\n");
+    return 1;
+  }
+
+  while (!i8080_is_halted(cpu) && i8080_cycles(cpu) < MAX_CYCLES) {
+    i8080_run(cpu, 100000);
+  }
+  printf("\n%llu cycles\n", (unsigned long long)i8080_cycles(cpu));
+
+  int halted = i8080_is_halted(cpu);
+  i8080_free(cpu);
+
+  output[output_len] = '\0';
+  if (!halted) {
+    fprintf(stderr, "did not finish within %llu cycles\n", MAX_CYCLES);
+    return 1;
+  }
+  if (strstr(output, "CPU IS OPERATIONAL") == NULL) {
+    fprintf(stderr, "the cpu failed the test\n");
+    return 1;
+  }
+  return 0;
+}
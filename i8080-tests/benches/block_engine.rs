@@ -0,0 +1,61 @@
+// Compares the speed of the interpreter and the block engine on the test
+// roms. That both give the same results is checked by tests/roms.rs. Runs
+// every rom by default, or the roms given as arguments. Each is run RUNS
+// times with each engine and the fastest run kept, as the timings vary a
+// good deal from run to run:
+//
+//     cargo bench --bench block_engine -- test-roms/TST8080.COM
+use i8080_tests::{run_rom_with, Engine, Limits, Outcome, RomRun};
+
+use std::env;
+use std::time::Duration;
+
+const ROMS: [&str; 4] = [
+    "test-roms/TST8080.COM",
+    "test-roms/CPUTEST.COM",
+    "test-roms/8080PRE.COM",
+    "test-roms/8080EXM.COM",
+];
+
+const RUNS: usize = 3;
+
+const LIMITS: Limits = Limits {
+    instructions: u64::MAX,
+    timeout: Duration::from_secs(600),
+};
+
+// The fastest of RUNS runs of the rom at `path` with `engine`.
+fn fastest(path: &str, engine: Engine) -> RomRun {
+    (0..RUNS)
+        .map(|_| run_rom_with(path, LIMITS, engine))
+        .min_by_key(|run| run.elapsed)
+        .unwrap()
+}
+
+fn main() {
+    // cargo bench passes --bench to every bench target.
+    let args: Vec<String> = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let roms: Vec<&str> = if args.is_empty() {
+        ROMS.to_vec()
+    } else {
+        args.iter().map(String::as_str).collect()
+    };
+
+    for path in roms {
+        let interpreted = fastest(path, Engine::Interpreter);
+        let blocks = fastest(path, Engine::Blocks);
+        assert_eq!(interpreted.outcome, Outcome::Finished, "{}", path);
+        assert_eq!(blocks.outcome, Outcome::Finished, "{}", path);
+
+        println!("\n{}: {} cycles", path, blocks.cycles);
+        println!("  interpreter: {:>8.2?}", interpreted.elapsed);
+        println!("  blocks:      {:>8.2?}", blocks.elapsed);
+        println!(
+            "  speedup:     {:.2}x",
+            interpreted.elapsed.as_secs_f64() / blocks.elapsed.as_secs_f64()
+        );
+    }
+}
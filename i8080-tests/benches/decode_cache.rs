@@ -0,0 +1,48 @@
+// Compares running a test rom with and without the decode cache. Runs
+// 8080EXM by default, or the rom given as an argument:
+//
+//     cargo bench --bench decode_cache -- test-roms/TST8080.COM
+use i8080_tests::{cpm_system, TestMachine};
+
+use std::env;
+use std::time::{Duration, Instant};
+
+fn run(path: &str, cached: bool) -> (Duration, u64) {
+    let mut cpu = cpm_system(path).cpu;
+    if cached {
+        cpu.enable_decode_cache();
+    }
+
+    let mut machine = TestMachine::new();
+    let start = Instant::now();
+    while !cpu.is_halted {
+        let decoded = cpu.fetch_decoded();
+        let (next_pc, _) = cpu.execute_decoded(&decoded, &mut machine);
+        cpu.pc = next_pc;
+    }
+    (start.elapsed(), cpu.cycles)
+}
+
+fn main() {
+    // cargo bench passes --bench to every bench target.
+    let path = env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| "test-roms/8080EXM.COM".to_string());
+
+    let (uncached, cycles) = run(&path, false);
+    let (cached, cached_cycles) = run(&path, true);
+    assert_eq!(
+        cycles, cached_cycles,
+        "decode cache changed the cycle count"
+    );
+
+    let mhz = |time: Duration| cycles as f64 / time.as_secs_f64() / 1_000_000.0;
+    println!("\n{}: {} cycles", path, cycles);
+    println!("  uncached: {:>8.2?} ({:.1} MHz)", uncached, mhz(uncached));
+    println!("  cached:   {:>8.2?} ({:.1} MHz)", cached, mhz(cached));
+    println!(
+        "  speedup:  {:.2}x",
+        uncached.as_secs_f64() / cached.as_secs_f64()
+    );
+}
@@ -0,0 +1,448 @@
+use i8080_tests::{run_rom, Limits, Outcome, RomRun};
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The number of lines of output shown when a ROM fails.
+const EXCERPT_LINES: usize = 8;
+
+fn usage() -> ! {
+    eprintln!("usage: run-roms [options] [DIR]");
+    eprintln!();
+    eprintln!("Run every .COM file in DIR, test-roms by default, as a CP/M test ROM.");
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("  --filter TEXT        only run ROMs whose name contains TEXT, ignoring case;");
+    eprintln!("                       may be repeated");
+    eprintln!("  --golden DIR         compare the output of each ROM with DIR/NAME.txt");
+    eprintln!("  --bless              write the output of each ROM to DIR/NAME.txt instead");
+    eprintln!("  --junit FILE         write the results to FILE as JUnit XML");
+    eprintln!("  --jobs N             run N ROMs at a time, by default one per cpu");
+    eprintln!("  --instructions N     fail a ROM that runs more than N instructions");
+    eprintln!("  --timeout SECS       fail a ROM that runs for more than SECS seconds");
+    process::exit(2);
+}
+
+struct Options {
+    dir: PathBuf,
+    filters: Vec<String>,
+    golden: Option<PathBuf>,
+    bless: bool,
+    junit: Option<PathBuf>,
+    jobs: usize,
+    limits: Limits,
+}
+
+impl Options {
+    fn parse(mut args: Vec<String>) -> Self {
+        let mut options = Options {
+            dir: PathBuf::from("test-roms"),
+            filters: Vec::new(),
+            golden: None,
+            bless: false,
+            junit: None,
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            limits: Limits {
+                instructions: 10_000_000_000,
+                timeout: Duration::from_secs(600),
+            },
+        };
+        let mut dir = None;
+        while !args.is_empty() {
+            match args[0].as_str() {
+                "--bless" => {
+                    options.bless = true;
+                    args.remove(0);
+                    continue;
+                }
+                arg if !arg.starts_with("--") && dir.is_none() => {
+                    dir = Some(PathBuf::from(args.remove(0)));
+                    continue;
+                }
+                _ => {}
+            }
+            if args.len() < 2 {
+                usage();
+            }
+            let value = args[1].clone();
+            match args[0].as_str() {
+                "--filter" => options.filters.push(value.to_lowercase()),
+                "--golden" => options.golden = Some(PathBuf::from(value)),
+                "--junit" => options.junit = Some(PathBuf::from(value)),
+                "--jobs" => options.jobs = number(&value).max(1) as usize,
+                "--instructions" => options.limits.instructions = number(&value),
+                "--timeout" => options.limits.timeout = Duration::from_secs(number(&value)),
+                _ => usage(),
+            }
+            args.drain(..2);
+        }
+        if options.bless && options.golden.is_none() {
+            eprintln!("--bless needs --golden");
+            process::exit(2);
+        }
+        if let Some(dir) = dir {
+            options.dir = dir;
+        }
+        options
+    }
+
+    fn selects(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.filters.is_empty() || self.filters.iter().any(|f| name.contains(f.as_str()))
+    }
+}
+
+fn number(text: &str) -> u64 {
+    text.replace('_', "").parse().unwrap_or_else(|_| usage())
+}
+
+// The .COM files in `dir` selected by the filters, by name.
+fn discover(options: &Options) -> Vec<(String, PathBuf)> {
+    let entries = fs::read_dir(&options.dir).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", options.dir.display(), err);
+        process::exit(1);
+    });
+    let mut roms: Vec<(String, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("com"))
+        })
+        .map(|path| {
+            (
+                path.file_stem().unwrap().to_string_lossy().into_owned(),
+                path,
+            )
+        })
+        .filter(|(name, _)| options.selects(name))
+        .collect();
+    roms.sort();
+    roms
+}
+
+struct RomResult {
+    name: String,
+    // The run, unless the emulator panicked.
+    run: Option<RomRun>,
+    elapsed: Duration,
+    // Why the ROM failed, with an excerpt of its output.
+    failure: Option<String>,
+}
+
+// The lines ending at `end`, or the last lines if there is no `end`.
+fn excerpt(output: &str, end: Option<usize>) -> String {
+    let lines: Vec<&str> = output.lines().collect();
+    let end = end.map_or(lines.len(), |end| (end + 1).min(lines.len()));
+    let start = end.saturating_sub(EXCERPT_LINES);
+    lines[start..end].join("\n")
+}
+
+// Where `output` first differs from `golden`, if it does.
+fn compare(output: &str, golden: &str) -> Option<String> {
+    if output == golden {
+        return None;
+    }
+    let actual: Vec<&str> = output.lines().collect();
+    let expected: Vec<&str> = golden.lines().collect();
+    let line = (0..)
+        .find(|&i| actual.get(i) != expected.get(i))
+        .unwrap_or(actual.len().min(expected.len()));
+    Some(format!(
+        "output differs from the golden file at line {}:\nexpected: {}\nactual:   {}\n{}",
+        line + 1,
+        expected.get(line).unwrap_or(&"<end of output>"),
+        actual.get(line).unwrap_or(&"<end of output>"),
+        excerpt(output, Some(line))
+    ))
+}
+
+fn run_one(name: &str, path: &Path, options: &Options) -> RomResult {
+    let start = Instant::now();
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_rom(&path.to_string_lossy(), options.limits)
+    }));
+    let elapsed = start.elapsed();
+    let run = match run {
+        Ok(run) => run,
+        Err(_) => {
+            return RomResult {
+                name: name.to_string(),
+                run: None,
+                elapsed,
+                failure: Some("the emulator panicked".to_string()),
+            }
+        }
+    };
+
+    let failure = match run.outcome {
+        Outcome::Finished => {
+            let golden_path = options
+                .golden
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.txt", name)));
+            match golden_path {
+                Some(golden_path) if options.bless => {
+                    fs::write(&golden_path, &run.output).unwrap_or_else(|err| {
+                        eprintln!("could not write {}: {}", golden_path.display(), err);
+                        process::exit(1);
+                    });
+                    None
+                }
+                Some(golden_path) => match fs::read(&golden_path) {
+                    Ok(golden) => compare(&run.output, &String::from_utf8_lossy(&golden)),
+                    Err(err) => Some(format!("could not read {}: {}", golden_path.display(), err)),
+                },
+                None => None,
+            }
+        }
+        Outcome::Halted => Some(format!(
+            "halted without returning to CP/M, with the pc at {:04X}H:\n{}",
+            run.state.pc(),
+            excerpt(&run.output, None)
+        )),
+        Outcome::OutOfInstructions => Some(format!(
+            "did not finish within {} instructions:\n{}",
+            options.limits.instructions,
+            excerpt(&run.output, None)
+        )),
+        Outcome::TimedOut => Some(format!(
+            "did not finish within {}s ({} instructions):\n{}",
+            options.limits.timeout.as_secs(),
+            run.instructions,
+            excerpt(&run.output, None)
+        )),
+    };
+    RomResult {
+        name: name.to_string(),
+        run: Some(run),
+        elapsed,
+        failure,
+    }
+}
+
+// Run the ROMs on `options.jobs` threads, printing each result as it
+// arrives. The results are returned in the order of `roms`.
+fn run_all(roms: &[(String, PathBuf)], options: &Options) -> Vec<RomResult> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<RomResult>>> = Mutex::new(roms.iter().map(|_| None).collect());
+    let width = roms.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.min(roms.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let (name, path) = match roms.get(i) {
+                    Some(rom) => rom,
+                    None => break,
+                };
+                let result = run_one(name, path, options);
+                println!("{}", summary(&result, width));
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+fn summary(result: &RomResult, width: usize) -> String {
+    let mut line = format!(
+        "{} {:width$} {:>9.3}s",
+        if result.failure.is_some() {
+            "FAIL"
+        } else {
+            "PASS"
+        },
+        result.name,
+        result.elapsed.as_secs_f64(),
+        width = width
+    );
+    if let Some(run) = &result.run {
+        write!(
+            line,
+            " {:>14} cycles {:>13} instructions",
+            run.cycles, run.instructions
+        )
+        .unwrap();
+    }
+    if let Some(failure) = &result.failure {
+        for failure_line in failure.lines() {
+            write!(line, "\n    {}", failure_line).unwrap();
+        }
+    }
+    line
+}
+
+// Escape text for XML, dropping the control characters XML cannot hold,
+// such as the NULs and BELs some ROMs print.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn junit(results: &[RomResult], elapsed: Duration) -> String {
+    let failures = results.iter().filter(|r| r.failure.is_some()).count();
+    let mut xml = String::new();
+    writeln!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(
+        xml,
+        "<testsuite name=\"i8080-roms\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        results.len(),
+        failures,
+        elapsed.as_secs_f64()
+    )
+    .unwrap();
+    for result in results {
+        writeln!(
+            xml,
+            "  <testcase classname=\"i8080-roms\" name=\"{}\" time=\"{:.3}\">",
+            escape(&result.name),
+            result.elapsed.as_secs_f64()
+        )
+        .unwrap();
+        if let Some(run) = &result.run {
+            writeln!(xml, "    <properties>").unwrap();
+            writeln!(
+                xml,
+                "      <property name=\"cycles\" value=\"{}\"/>",
+                run.cycles
+            )
+            .unwrap();
+            writeln!(
+                xml,
+                "      <property name=\"instructions\" value=\"{}\"/>",
+                run.instructions
+            )
+            .unwrap();
+            writeln!(xml, "    </properties>").unwrap();
+        }
+        if let Some(failure) = &result.failure {
+            let message = failure.lines().next().unwrap_or("");
+            writeln!(
+                xml,
+                "    <failure message=\"{}\">{}</failure>",
+                escape(message),
+                escape(failure)
+            )
+            .unwrap();
+        }
+        if let Some(run) = &result.run {
+            writeln!(xml, "    <system-out>{}</system-out>", escape(&run.output)).unwrap();
+        }
+        writeln!(xml, "  </testcase>").unwrap();
+    }
+    writeln!(xml, "</testsuite>").unwrap();
+    xml
+}
+
+fn main() {
+    let options = Options::parse(env::args().skip(1).collect());
+    let roms = discover(&options);
+    if roms.is_empty() {
+        eprintln!("no ROMs to run in {}", options.dir.display());
+        process::exit(1);
+    }
+
+    let start = Instant::now();
+    let results = run_all(&roms, &options);
+    let elapsed = start.elapsed();
+
+    let failures = results.iter().filter(|r| r.failure.is_some()).count();
+    println!(
+        "{} passed, {} failed in {:.3}s",
+        results.len() - failures,
+        failures,
+        elapsed.as_secs_f64()
+    );
+    if let Some(path) = &options.junit {
+        fs::write(path, junit(&results, elapsed)).unwrap_or_else(|err| {
+            eprintln!("could not write {}: {}", path.display(), err);
+            process::exit(1);
+        });
+    }
+    if failures > 0 {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ROM that halts is a failed test case, and the other ROMs still run.
+    #[test]
+    fn test_halting_rom_fails() {
+        let dir = env::temp_dir().join(format!("run-roms-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("test-roms/TST8080.COM", dir.join("TST8080.COM")).unwrap();
+        fs::write(dir.join("HALT.COM"), [0x76]).unwrap();
+
+        let options = Options::parse(vec![dir.to_string_lossy().into_owned()]);
+        let roms = discover(&options);
+        let results = run_all(&roms, &options);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "HALT");
+        assert_eq!(
+            results[0].failure.as_deref(),
+            Some("halted without returning to CP/M, with the pc at 0101H:\n")
+        );
+        assert_eq!(results[1].name, "TST8080");
+        assert_eq!(results[1].failure, None);
+        assert!(junit(&results, Duration::from_secs(1)).contains(
+            "<failure message=\"halted without returning to CP/M, with the pc at 0101H:\">"
+        ));
+    }
+
+    #[test]
+    fn test_compare_with_golden() {
+        assert_eq!(compare("a\nb\n", "a\nb\n"), None);
+        let diff = compare("a\nc\n", "a\nb\n").unwrap();
+        assert!(diff.starts_with("output differs from the golden file at line 2:"));
+        assert!(diff.contains("expected: b\nactual:   c"));
+        let diff = compare("a\n", "a\nb\n").unwrap();
+        assert!(diff.contains("expected: b\nactual:   <end of output>"));
+    }
+
+    #[test]
+    fn test_junit() {
+        let results = [RomResult {
+            name: "BAD".to_string(),
+            run: None,
+            elapsed: Duration::from_millis(1500),
+            failure: Some("<line \"1\">\u{7}".to_string()),
+        }];
+        assert_eq!(
+            junit(&results, Duration::from_secs(2)),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuite name=\"i8080-roms\" tests=\"1\" failures=\"1\" time=\"2.000\">\n  \
+             <testcase classname=\"i8080-roms\" name=\"BAD\" time=\"1.500\">\n    \
+             <failure message=\"&lt;line &quot;1&quot;&gt;\">&lt;line &quot;1&quot;&gt;</failure>\n  \
+             </testcase>\n\
+             </testsuite>\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use i8080::block::BlockEngine;
+use i8080::machine::{ClockedIO, CpuView, MachineIO, SchedulerView, System};
+use i8080::memory_bus::MemoryMap;
+use i8080::sanitizer::{MemoryLayout, Sanitizer};
+use i8080::scheduler::EventId;
+use i8080::CpuState;
+
+#[derive(Clone)]
+pub struct TestMemory {
+    pub memory: [u8; 0x10000],
+    rom_path: String,
+    // The length of the program loaded at 0x100.
+    pub rom_len: usize,
+}
+
+impl TestMemory {
+    pub fn new(path: &str) -> Self {
+        let buffer = [0; 0x10000];
+        let mut memory = Self {
+            memory: buffer,
+            rom_path: path.to_string(),
+            rom_len: 0,
+        };
+        memory.load_rom();
+        memory
+    }
+}
+
+impl MemoryMap for TestMemory {
+    fn load_rom(&mut self) {
+        let offset = 0x100;
+        let mut file = File::open(&self.rom_path).unwrap();
+        let mut r = Vec::new();
+        file.read_to_end(&mut r).unwrap();
+        self.memory[offset as usize..(r.len() + offset as usize)].copy_from_slice(&r);
+        self.rom_len = r.len();
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        &self.memory[addr as usize..]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.memory[addr as usize] = val;
+    }
+}
+
+// The CP/M console and warm boot. Console output is printed as it is written
+// unless captured with TestMachine::capturing.
+#[derive(Default)]
+pub struct TestMachine {
+    pub output: Option<Vec<u8>>,
+    // Whether the program has returned to CP/M through the warm boot vector,
+    // as opposed to halting some other way.
+    pub warm_boot: bool,
+}
+
+impl TestMachine {
+    pub fn new() -> Self {
+        TestMachine {
+            output: None,
+            warm_boot: false,
+        }
+    }
+
+    pub fn capturing() -> Self {
+        TestMachine {
+            output: Some(Vec::new()),
+            warm_boot: false,
+        }
+    }
+
+    fn console(&mut self, byte: u8) {
+        match self.output.as_mut() {
+            Some(output) => output.push(byte),
+            None => print!("{}", byte as char),
+        }
+    }
+}
+
+impl MachineIO for TestMachine {
+    fn machine_in(&mut self, _: u8) -> u8 {
+        0
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, _: u8) {
+        if port == 0 {
+            self.warm_boot = true;
+            cpu.halt();
+        } else if port == 1 {
+            if cpu.registers.c == 9 {
+                let mut addr = cpu.registers.get_de() as usize;
+                while cpu.memory.read(addr as u16) != b'$' {
+                    self.console(cpu.memory.read(addr as u16));
+                    addr += 1;
+                }
+            } else if cpu.registers.c == 2 {
+                self.console(cpu.registers.e);
+            }
+            if self.output.is_none() {
+                io::stdout().flush().expect("Could not flush stdout");
+            }
+        }
+    }
+}
+
+// The test machine has no timers and never interrupts.
+impl ClockedIO for TestMachine {
+    fn tick(&mut self, _: u32) {}
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn interrupt_acknowledge(&mut self) {}
+}
+
+// Load a CP/M .COM program and patch in just enough of CP/M for the test roms
+// to run: a warm boot that halts the cpu and the BDOS console output calls.
+pub fn cpm_system(path: &str) -> System<TestMemory, TestMachine> {
+    let mut system = System::new(TestMemory::new(path), TestMachine::new());
+    let cpu = &mut system.cpu;
+
+    // The tests begin at 0x100 so advance pc to address
+    cpu.pc = 0x100;
+
+    // Map OUT 0,a to memory address 0x0. When machine_out() receives port 0,
+    // the program will exit.
+    cpu.memory.write(0x0, 0xD3);
+    cpu.memory.write(0x1, 0x00);
+
+    // Map OUT 1,a to memory address 0x5. When machine_out() receives port 1,
+    // the program will output diagnostic or error messages from the test rom.
+    cpu.memory.write(0x5, 0xD3);
+    cpu.memory.write(0x6, 0x01);
+    cpu.memory.write(0x7, 0xC9);
+
+    system
+}
+
+// A sanitizer for the CP/M test machine. All of memory is RAM and programs
+// run from it. The program and the patched-in CP/M entry points count as
+// initialised.
+pub fn cpm_sanitizer(memory: &TestMemory) -> Sanitizer {
+    let mut sanitizer =
+        Sanitizer::new(MemoryLayout::new().ram(0x0000..=0xFFFF)).allow_ram_execution(true);
+    sanitizer.mark_initialized(0x0000..=0x0007);
+    if memory.rom_len > 0 {
+        sanitizer.mark_initialized(0x0100..=(0x100 + memory.rom_len - 1) as u16);
+    }
+    sanitizer
+}
+
+// Limits on a run of a test ROM, so that a cpu bug that sends the program
+// into a loop fails the run rather than hanging it.
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub instructions: u64,
+    pub timeout: Duration,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // The program returned to CP/M.
+    Finished,
+    // The cpu halted without returning to CP/M, e.g. at a HLT.
+    Halted,
+    OutOfInstructions,
+    TimedOut,
+}
+
+pub struct RomRun {
+    pub outcome: Outcome,
+    // The console output, without carriage returns.
+    pub output: String,
+    pub instructions: u64,
+    pub cycles: u64,
+    pub elapsed: Duration,
+    // The cpu when the run stopped.
+    pub state: CpuState,
+}
+
+// How run_rom_with executes a ROM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Engine {
+    // Cpu::step_clocked, with the decode cache.
+    Interpreter,
+    // The basic-block engine in i8080::block.
+    Blocks,
+}
+
+// Counts the instructions run, as both engines tick the machine once after
+// each of them.
+struct Counted<'a> {
+    machine: &'a mut TestMachine,
+    instructions: u64,
+}
+
+impl MachineIO for Counted<'_> {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        self.machine.machine_in(port)
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+        self.machine.machine_out(cpu, port, val)
+    }
+}
+
+impl ClockedIO for Counted<'_> {
+    fn tick(&mut self, _: u32) {
+        self.instructions += 1;
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        self.machine.interrupt_request()
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        self.machine.interrupt_acknowledge()
+    }
+
+    fn event(&mut self, scheduler: &mut SchedulerView, event: EventId) {
+        self.machine.event(scheduler, event)
+    }
+}
+
+// Run a CP/M test ROM to completion or until it exceeds `limits`, capturing
+// its console output.
+pub fn run_rom(path: &str, limits: Limits) -> RomRun {
+    run_rom_with(path, limits, Engine::Interpreter)
+}
+
+// As run_rom, executing the ROM with `engine`. The block engine checks the
+// limits between blocks, so it can overrun the instruction limit by up to a
+// block.
+pub fn run_rom_with(path: &str, limits: Limits, engine: Engine) -> RomRun {
+    // How many instructions or blocks to run between checks of the clock.
+    const CHECK_EVERY: u64 = 0x10000;
+
+    let mut system = cpm_system(path);
+    system.io = TestMachine::capturing();
+    let cpu = &mut system.cpu;
+    let mut blocks = BlockEngine::new();
+    cpu.enable_decode_cache();
+
+    let start = Instant::now();
+    let mut instructions = 0;
+    let mut steps = 0u64;
+    let outcome = loop {
+        if cpu.is_halted && system.io.warm_boot {
+            break Outcome::Finished;
+        }
+        if cpu.is_halted {
+            break Outcome::Halted;
+        }
+        if instructions >= limits.instructions {
+            break Outcome::OutOfInstructions;
+        }
+        if steps.is_multiple_of(CHECK_EVERY) && start.elapsed() > limits.timeout {
+            break Outcome::TimedOut;
+        }
+        let mut counted = Counted {
+            machine: &mut system.io,
+            instructions,
+        };
+        match engine {
+            Engine::Interpreter => {
+                cpu.step_clocked(&mut counted);
+            }
+            Engine::Blocks => {
+                blocks.step_clocked(cpu, &mut counted);
+            }
+        }
+        instructions = counted.instructions;
+        steps += 1;
+    };
+
+    let output = system.io.output.take().unwrap_or_default();
+    RomRun {
+        outcome,
+        output: String::from_utf8_lossy(&output).replace('\r', ""),
+        instructions,
+        cycles: cpu.cycles,
+        elapsed: start.elapsed(),
+        state: cpu.state(),
+    }
+}
@@ -0,0 +1,188 @@
+use i8080_tests::{run_rom, run_rom_with, Engine, Limits, Outcome, RomRun};
+
+use std::env;
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-roms");
+
+// The number of lines of output shown when a ROM fails.
+const EXCERPT_LINES: usize = 8;
+
+// The lines ending at `end`, or the last lines if there is no `end`.
+fn excerpt(output: &str, end: Option<usize>) -> String {
+    let lines: Vec<&str> = output.lines().collect();
+    let end = end.map_or(lines.len(), |end| end + 1);
+    let start = end.saturating_sub(EXCERPT_LINES);
+    lines[start..end].join("\n")
+}
+
+// Run a ROM, failing if it does not finish within the limits. The block
+// engine runs it alongside the interpreter and must finish in the same state
+// after the same number of cycles.
+fn run(name: &str, instructions: u64, timeout_secs: u64) -> RomRun {
+    let limits = Limits {
+        instructions,
+        timeout: Duration::from_secs(timeout_secs),
+    };
+    let path = format!("{}/{}", ROMS, name);
+    let (run, blocks) = thread::scope(|scope| {
+        let blocks = scope.spawn(|| run_rom_with(&path, limits, Engine::Blocks));
+        (run_rom(&path, limits), blocks.join().unwrap())
+    });
+    match run.outcome {
+        Outcome::Finished => {}
+        Outcome::Halted => panic!(
+            "{} halted without returning to CP/M, with the pc at {:04X}H:\n{}",
+            name,
+            run.state.pc(),
+            excerpt(&run.output, None)
+        ),
+        Outcome::OutOfInstructions => panic!(
+            "{} did not finish within {} instructions:\n{}",
+            name,
+            instructions,
+            excerpt(&run.output, None)
+        ),
+        Outcome::TimedOut => panic!(
+            "{} did not finish within {}s ({} instructions):\n{}",
+            name,
+            timeout_secs,
+            run.instructions,
+            excerpt(&run.output, None)
+        ),
+    }
+    assert_eq!(
+        blocks.outcome, run.outcome,
+        "{}: the block engine stopped differently",
+        name
+    );
+    assert_eq!(
+        blocks.state, run.state,
+        "{}: the block engine left a different cpu state",
+        name
+    );
+    assert_eq!(
+        blocks.cycles, run.cycles,
+        "{}: the block engine took a different number of cycles",
+        name
+    );
+    assert_eq!(
+        blocks.instructions, run.instructions,
+        "{}: the block engine ran a different number of instructions",
+        name
+    );
+    assert_eq!(
+        blocks.output, run.output,
+        "{}: the block engine printed different output",
+        name
+    );
+    run
+}
+
+fn assert_line(name: &str, run: &RomRun, expected: &str) {
+    if !run.output.lines().any(|line| line.trim() == expected) {
+        panic!(
+            "{} did not print `{}`:\n{}",
+            name,
+            expected,
+            excerpt(&run.output, None)
+        );
+    }
+}
+
+fn assert_no_line(name: &str, run: &RomRun, marker: &str) {
+    if let Some(i) = run.output.lines().position(|line| line.contains(marker)) {
+        panic!(
+            "{} printed `{}`:\n{}",
+            name,
+            marker,
+            excerpt(&run.output, Some(i))
+        );
+    }
+}
+
+// A ROM that halts other than by returning to CP/M fails rather than
+// stopping the tests.
+#[test]
+fn test_hlt_is_reported() {
+    // MVI C,9; LXI D,0109H; CALL 5; HLT; DB 'OOPS$'
+    let rom = [
+        0x0E, 0x09, 0x11, 0x09, 0x01, 0xCD, 0x05, 0x00, 0x76, b'O', b'O', b'P', b'S', b'$',
+    ];
+    let path = env::temp_dir().join(format!("i8080-tests-hlt-{}.COM", process::id()));
+    fs::write(&path, rom).unwrap();
+    let limits = Limits {
+        instructions: 1000,
+        timeout: Duration::from_secs(10),
+    };
+    let run = run_rom(&path.to_string_lossy(), limits);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(run.outcome, Outcome::Halted);
+    assert_eq!(run.state.pc(), 0x0109);
+    assert_eq!(run.output, "OOPS");
+}
+
+#[test]
+fn test_tst8080() {
+    let run = run("TST8080.COM", 10_000, 10);
+    assert_no_line("TST8080", &run, "CPU HAS FAILED");
+    assert_line("TST8080", &run, "CPU IS OPERATIONAL");
+}
+
+#[test]
+fn test_8080pre() {
+    let run = run("8080PRE.COM", 10_000, 10);
+    assert_no_line("8080PRE", &run, "ERROR");
+    assert_line("8080PRE", &run, "8080 Preliminary tests complete");
+}
+
+#[test]
+fn test_cputest() {
+    let run = run("CPUTEST.COM", 50_000_000, 120);
+    assert_no_line("CPUTEST", &run, "ERROR");
+    assert_line("CPUTEST", &run, "CPU IS 8080/8085");
+    assert_line("CPUTEST", &run, "CPU TESTS OK");
+}
+
+// The exerciser runs about 2.9 billion instructions, which is why this
+// crate's test profile is optimised.
+#[test]
+fn test_8080exm() {
+    let run = run("8080EXM.COM", 3_200_000_000, 600);
+    assert_no_line("8080EXM", &run, "ERROR");
+    for expected in [
+        "dad <b,d,h,sp>................  PASS! crc is:14474ba6",
+        "aluop nn......................  PASS! crc is:9e922f9e",
+        "aluop <b,c,d,e,h,l,m,a>.......  PASS! crc is:cf762c86",
+        "<daa,cma,stc,cmc>.............  PASS! crc is:bb3f030c",
+        "<inr,dcr> a...................  PASS! crc is:adb6460e",
+        "<inr,dcr> b...................  PASS! crc is:83ed1345",
+        "<inx,dcx> b...................  PASS! crc is:f79287cd",
+        "<inr,dcr> c...................  PASS! crc is:e5f6721b",
+        "<inr,dcr> d...................  PASS! crc is:15b5579a",
+        "<inx,dcx> d...................  PASS! crc is:7f4e2501",
+        "<inr,dcr> e...................  PASS! crc is:cf2ab396",
+        "<inr,dcr> h...................  PASS! crc is:12b2952c",
+        "<inx,dcx> h...................  PASS! crc is:9f2b23c0",
+        "<inr,dcr> l...................  PASS! crc is:ff57d356",
+        "<inr,dcr> m...................  PASS! crc is:92e963bd",
+        "<inx,dcx> sp..................  PASS! crc is:d5702fab",
+        "lhld nnnn.....................  PASS! crc is:a9c3d5cb",
+        "shld nnnn.....................  PASS! crc is:e8864f26",
+        "lxi <b,d,h,sp>,nnnn...........  PASS! crc is:fcf46e12",
+        "ldax <b,d>....................  PASS! crc is:2b821d5f",
+        "mvi <b,c,d,e,h,l,m,a>,nn......  PASS! crc is:eaa72044",
+        "mov <bcdehla>,<bcdehla>.......  PASS! crc is:10b58cee",
+        "sta nnnn / lda nnnn...........  PASS! crc is:ed57af72",
+        "<rlc,rrc,ral,rar>.............  PASS! crc is:e0d89235",
+        "stax <b,d>....................  PASS! crc is:2b0471e9",
+    ]
+    .iter()
+    {
+        assert_line("8080EXM", &run, expected);
+    }
+    assert_line("8080EXM", &run, "Tests complete");
+}
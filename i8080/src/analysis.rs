@@ -0,0 +1,621 @@
+use crate::instruction::{Flow, Instruction};
+use crate::symbols::SymbolLookup;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    // Execution runs on into the next block: the block was split because
+    // another one jumps into it, a conditional was not taken, or a call
+    // returned.
+    Next,
+    Jump,
+    // A conditional jump that was taken.
+    Branch,
+    // A call or restart. The block also has a Next edge to the return
+    // address.
+    Call,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: u16,
+}
+
+// A run of instructions entered only at the top and left only at the bottom.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u16,
+    // The instructions and their addresses, in order.
+    pub instructions: Vec<(u16, Instruction)>,
+    // The blocks control can pass to. Targets outside the image are left
+    // out; see ControlFlowGraph::external.
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock {
+    // The address after the last instruction.
+    pub fn end(&self) -> u16 {
+        let (addr, instruction) = self.instructions.last().unwrap();
+        addr.wrapping_add(instruction.size())
+    }
+}
+
+// Recovers the code in a ROM image by following control flow from its entry
+// points, rather than disassembling it from start to end, so that tables and
+// text between the routines are not mistaken for code. Every jump, call and
+// restart target is followed, both ways of a conditional are followed, and
+// calls are assumed to return. Targets computed at runtime, such as those of
+// PCHL and of jump tables, cannot be followed; they are reported in
+// ControlFlowGraph::indirect and can be given as further entry points.
+#[derive(Clone, Debug)]
+pub struct Analyzer<'a> {
+    image: &'a [u8],
+    base: u16,
+    entries: Vec<u16>,
+}
+
+impl<'a> Analyzer<'a> {
+    // An analyzer for `image`, loaded at `base`, with the reset vector and
+    // the restart vectors used for interrupts, 08H and 10H, as entry points
+    // where they fall inside the image.
+    pub fn new(image: &'a [u8], base: u16) -> Self {
+        let analyzer = Analyzer {
+            image,
+            base,
+            entries: Vec::new(),
+        };
+        let entries = [0x0000, 0x0008, 0x0010]
+            .iter()
+            .copied()
+            .filter(|&addr| analyzer.contains(addr))
+            .collect();
+        Analyzer {
+            entries,
+            ..analyzer
+        }
+    }
+
+    pub fn with_entry(mut self, addr: u16) -> Self {
+        if !self.entries.contains(&addr) {
+            self.entries.push(addr);
+        }
+        self
+    }
+
+    pub fn analyze(&self) -> ControlFlowGraph {
+        let mut decoded: BTreeMap<u16, Instruction> = BTreeMap::new();
+        // The start of the instruction each byte of the image belongs to.
+        let mut owner: Vec<Option<u16>> = vec![None; self.image.len()];
+        let mut leaders: BTreeSet<u16> = BTreeSet::new();
+        let mut subroutines = BTreeSet::new();
+        let mut external = BTreeSet::new();
+        let mut indirect = BTreeSet::new();
+        let mut overlaps = BTreeSet::new();
+        let mut work: Vec<u16> = Vec::new();
+
+        for &entry in self.entries.iter() {
+            leaders.insert(entry);
+            work.push(entry);
+        }
+
+        while let Some(start) = work.pop() {
+            let mut pc = start;
+            loop {
+                if !self.contains(pc) {
+                    external.insert(pc);
+                    break;
+                }
+                if decoded.contains_key(&pc) {
+                    // Two paths meet here, so a block must start here.
+                    leaders.insert(pc);
+                    break;
+                }
+                if owner[self.offset(pc)].is_some() {
+                    overlaps.insert(pc);
+                    break;
+                }
+                let instruction = match self.decode(pc) {
+                    Some(instruction) => instruction,
+                    None => {
+                        // The instruction runs off the end of the image.
+                        external.insert(pc);
+                        break;
+                    }
+                };
+                let next = pc.wrapping_add(instruction.size());
+                for i in 0..instruction.size() {
+                    owner[self.offset(pc.wrapping_add(i))] = Some(pc);
+                }
+                decoded.insert(pc, instruction);
+
+                let mut follow = |target: u16, leaders: &mut BTreeSet<u16>| {
+                    leaders.insert(target);
+                    work.push(target);
+                };
+                match instruction.flow() {
+                    Flow::Next => {}
+                    Flow::Jump => {
+                        follow(instruction.target().unwrap(), &mut leaders);
+                        break;
+                    }
+                    Flow::ConditionalJump => {
+                        follow(instruction.target().unwrap(), &mut leaders);
+                        leaders.insert(next);
+                    }
+                    Flow::Call | Flow::ConditionalCall | Flow::Restart => {
+                        let target = instruction.target().unwrap();
+                        follow(target, &mut leaders);
+                        subroutines.insert(target);
+                        leaders.insert(next);
+                    }
+                    // A halted cpu carries on after an interrupt.
+                    Flow::ConditionalReturn | Flow::Halt => {
+                        leaders.insert(next);
+                    }
+                    Flow::Return => break,
+                    Flow::Indirect => {
+                        indirect.insert(pc);
+                        break;
+                    }
+                }
+                pc = next;
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &leader in leaders.iter().filter(|addr| decoded.contains_key(addr)) {
+            let mut instructions = Vec::new();
+            let mut pc = leader;
+            let successors = loop {
+                let instruction = decoded[&pc];
+                instructions.push((pc, instruction));
+                let next = pc.wrapping_add(instruction.size());
+                let mut edges = Vec::new();
+                let mut edge = |kind, target| {
+                    if decoded.contains_key(&target) {
+                        edges.push(Edge { kind, target });
+                    }
+                };
+                let ends = match instruction.flow() {
+                    Flow::Next => {
+                        if leaders.contains(&next) {
+                            edge(EdgeKind::Next, next);
+                            true
+                        } else {
+                            !decoded.contains_key(&next)
+                        }
+                    }
+                    Flow::Jump => {
+                        edge(EdgeKind::Jump, instruction.target().unwrap());
+                        true
+                    }
+                    Flow::ConditionalJump => {
+                        edge(EdgeKind::Branch, instruction.target().unwrap());
+                        edge(EdgeKind::Next, next);
+                        true
+                    }
+                    Flow::Call | Flow::ConditionalCall | Flow::Restart => {
+                        edge(EdgeKind::Call, instruction.target().unwrap());
+                        edge(EdgeKind::Next, next);
+                        true
+                    }
+                    Flow::ConditionalReturn | Flow::Halt => {
+                        edge(EdgeKind::Next, next);
+                        true
+                    }
+                    Flow::Return | Flow::Indirect => true,
+                };
+                if ends {
+                    break edges;
+                }
+                pc = next;
+            };
+            blocks.insert(
+                leader,
+                BasicBlock {
+                    start: leader,
+                    instructions,
+                    successors,
+                },
+            );
+        }
+
+        ControlFlowGraph {
+            base: self.base,
+            image: self.image.to_vec(),
+            code: owner.iter().map(Option::is_some).collect(),
+            entries: self.entries.clone(),
+            blocks,
+            subroutines,
+            external,
+            indirect,
+            overlaps,
+        }
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        (addr.wrapping_sub(self.base) as usize) < self.image.len()
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        addr.wrapping_sub(self.base) as usize
+    }
+
+    // The instruction at `addr`, or None if it does not fit in the image.
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        let offset = self.offset(addr);
+        let mut bytes = [0; 3];
+        let available = (self.image.len() - offset).min(3);
+        bytes[..available].copy_from_slice(&self.image[offset..offset + available]);
+        let instruction = Instruction::from(&bytes[..]);
+        if instruction.size() as usize <= available {
+            Some(instruction)
+        } else {
+            None
+        }
+    }
+}
+
+// The result of Analyzer::analyze.
+#[derive(Clone, Debug)]
+pub struct ControlFlowGraph {
+    pub base: u16,
+    pub image: Vec<u8>,
+    // Whether each byte of the image is part of an instruction.
+    pub code: Vec<bool>,
+    pub entries: Vec<u16>,
+    // The basic blocks by start address.
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    // The targets of calls and restarts.
+    pub subroutines: BTreeSet<u16>,
+    // Addresses control reaches outside the image, or where an instruction
+    // runs off its end.
+    pub external: BTreeSet<u16>,
+    // The PCHL instructions, whose targets are unknown.
+    pub indirect: BTreeSet<u16>,
+    // Jump targets in the middle of an instruction already found.
+    pub overlaps: BTreeSet<u16>,
+}
+
+impl ControlFlowGraph {
+    pub fn is_code(&self, addr: u16) -> bool {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        self.code.get(offset).copied().unwrap_or(false)
+    }
+
+    pub fn code_bytes(&self) -> usize {
+        self.code.iter().filter(|&&code| code).count()
+    }
+
+    // The blocks that pass control to each block.
+    pub fn predecessors(&self) -> BTreeMap<u16, Vec<(u16, EdgeKind)>> {
+        let mut predecessors: BTreeMap<u16, Vec<(u16, EdgeKind)>> = BTreeMap::new();
+        for block in self.blocks.values() {
+            for edge in block.successors.iter() {
+                predecessors
+                    .entry(edge.target)
+                    .or_default()
+                    .push((block.start, edge.kind));
+            }
+        }
+        predecessors
+    }
+
+    // The labels used in the listing and graph: the name from `symbols` where
+    // there is one, otherwise SUB_XXXX for a subroutine and L_XXXX for any
+    // other block that is jumped to or is an entry point.
+    pub fn labels(&self, symbols: Option<&dyn SymbolLookup>) -> Labels {
+        let mut labels = BTreeMap::new();
+        let targets: BTreeSet<u16> = self
+            .blocks
+            .values()
+            .flat_map(|block| block.successors.iter())
+            .filter(|edge| edge.kind != EdgeKind::Next)
+            .map(|edge| edge.target)
+            .chain(self.entries.iter().copied())
+            .collect();
+        for &addr in targets.iter() {
+            let name = match symbols.and_then(|symbols| symbols.name_at(addr)) {
+                Some(name) => String::from(name),
+                None if self.subroutines.contains(&addr) => format!("SUB_{:04X}", addr),
+                None => format!("L_{:04X}", addr),
+            };
+            labels.insert(addr, name);
+        }
+        if let Some(symbols) = symbols {
+            for offset in 0..self.image.len() {
+                let addr = self.base.wrapping_add(offset as u16);
+                if let Some(name) = symbols.name_at(addr) {
+                    labels.entry(addr).or_insert_with(|| String::from(name));
+                }
+            }
+        }
+        Labels(labels)
+    }
+
+    // An assembly listing of the image: code as instructions, grouped into
+    // blocks with the blocks that lead to them noted, and everything else as
+    // DB lines.
+    pub fn listing(&self, symbols: Option<&dyn SymbolLookup>) -> String {
+        let labels = self.labels(symbols);
+        let predecessors = self.predecessors();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "; {} bytes from {:04X}H: {} of code in {} blocks, {} of data",
+            self.image.len(),
+            self.base,
+            self.code_bytes(),
+            self.blocks.len(),
+            self.image.len() - self.code_bytes()
+        );
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|addr| format!("{:04X}H", addr))
+            .collect();
+        let _ = writeln!(out, "; entry points: {}", entries.join(", "));
+        for &addr in self.external.iter() {
+            let _ = writeln!(out, "; leaves the image for {:04X}H", addr);
+        }
+
+        let mut offset = 0;
+        while offset < self.image.len() {
+            let addr = self.base.wrapping_add(offset as u16);
+            if let Some(block) = self.blocks.get(&addr) {
+                let _ = writeln!(out);
+                if let Some(from) = predecessors.get(&addr) {
+                    let from: Vec<String> = from
+                        .iter()
+                        .map(|(from, kind)| format!("{:04X}H {}", from, edge_name(*kind)))
+                        .collect();
+                    let _ = writeln!(out, "; from {}", from.join(", "));
+                }
+                for &(pc, instruction) in block.instructions.iter() {
+                    if let Some(name) = labels.0.get(&pc) {
+                        let _ = writeln!(out, "{}:", name);
+                    }
+                    let bytes: Vec<String> = (0..instruction.size())
+                        .map(|i| format!("{:02X}", self.image[offset + i as usize]))
+                        .collect();
+                    let _ = write!(
+                        out,
+                        "{:04X}H  {:<8}  {}",
+                        pc,
+                        bytes.join(" "),
+                        instruction.display(Some(&labels))
+                    );
+                    if self.indirect.contains(&pc) {
+                        let _ = write!(out, "  ; target unknown");
+                    }
+                    let _ = writeln!(out);
+                    offset += instruction.size() as usize;
+                }
+            } else if self.code[offset] {
+                // Only reached if a block overlaps another.
+                offset += 1;
+            } else {
+                let mut end = offset;
+                while end < self.image.len()
+                    && end - offset < 8
+                    && !self.code[end]
+                    && (end == offset
+                        || !labels.0.contains_key(&self.base.wrapping_add(end as u16)))
+                {
+                    end += 1;
+                }
+                if let Some(name) = labels.0.get(&addr) {
+                    let _ = writeln!(out, "{}:", name);
+                }
+                let bytes = &self.image[offset..end];
+                let hex: Vec<String> = bytes.iter().map(|&byte| hex8(byte)).collect();
+                let text: String = bytes
+                    .iter()
+                    .map(|&byte| {
+                        if (0x20..0x7F).contains(&byte) {
+                            byte as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                let _ = writeln!(out, "{:04X}H  DB {:<40} ; {}", addr, hex.join(","), text);
+                offset = end;
+            }
+        }
+        out
+    }
+
+    // The graph in Graphviz DOT format, one node per block.
+    pub fn dot(&self, symbols: Option<&dyn SymbolLookup>) -> String {
+        let labels = self.labels(symbols);
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph cfg {{");
+        let _ = writeln!(out, "  node [shape=box, fontname=\"monospace\"];");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(name) = labels.0.get(&block.start) {
+                let _ = write!(label, "{}:\\l", escape(name));
+            }
+            for &(pc, instruction) in block.instructions.iter() {
+                let text = format!("{}", instruction.display(Some(&labels)));
+                let _ = write!(label, "{:04X}H  {}\\l", pc, escape(&text));
+            }
+            let _ = writeln!(out, "  \"{:04X}\" [label=\"{}\"];", block.start, label);
+        }
+        for block in self.blocks.values() {
+            for edge in block.successors.iter() {
+                let style = match edge.kind {
+                    EdgeKind::Next => "",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Branch => " [label=\"taken\"]",
+                    EdgeKind::Call => " [style=dashed]",
+                };
+                let _ = writeln!(
+                    out,
+                    "  \"{:04X}\" -> \"{:04X}\"{};",
+                    block.start, edge.target, style
+                );
+            }
+        }
+        let _ = writeln!(out, "}}");
+        out
+    }
+}
+
+// Names for the addresses in a ControlFlowGraph. See ControlFlowGraph::labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Labels(pub BTreeMap<u16, String>);
+
+impl SymbolLookup for Labels {
+    fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        self.0
+            .range(..=addr)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), addr - start))
+    }
+}
+
+fn edge_name(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::Next => "next",
+        EdgeKind::Jump => "jump",
+        EdgeKind::Branch => "branch",
+        EdgeKind::Call => "call",
+    }
+}
+
+// A byte as an Intel hex literal, as in the instruction listing.
+fn hex8(byte: u8) -> String {
+    if byte >= 0xA0 {
+        format!("0{:02X}H", byte)
+    } else {
+        format!("{:02X}H", byte)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolTable;
+
+    // 0000H: JMP 0010H, with data in between.
+    // 0008H: PUSH PSW; POP PSW; EI; RET
+    // 0010H: CALL 0020H; JNZ 0010H; HLT; JMP 0010H
+    // 0020H: PCHL
+    // 0021H: "HI", never executed
+    fn image() -> Vec<u8> {
+        let mut image = vec![0xFF; 0x23];
+        image[0x00..0x03].copy_from_slice(&[0xC3, 0x10, 0x00]);
+        image[0x03..0x08].copy_from_slice(b"DATA!");
+        image[0x08..0x0C].copy_from_slice(&[0xF5, 0xF1, 0xFB, 0xC9]);
+        image[0x10..0x1A]
+            .copy_from_slice(&[0xCD, 0x20, 0x00, 0xC2, 0x10, 0x00, 0x76, 0xC3, 0x10, 0x00]);
+        image[0x20] = 0xE9;
+        image[0x21..0x23].copy_from_slice(b"HI");
+        image
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let image = image();
+        let cfg = Analyzer::new(&image, 0).analyze();
+        assert_eq!(cfg.entries, [0x0000, 0x0008, 0x0010]);
+
+        let starts: Vec<u16> = cfg.blocks.keys().copied().collect();
+        assert_eq!(
+            starts,
+            [0x0000, 0x0008, 0x0010, 0x0013, 0x0016, 0x0017, 0x0020]
+        );
+        assert_eq!(
+            cfg.blocks[&0x0010].successors,
+            [
+                Edge {
+                    kind: EdgeKind::Call,
+                    target: 0x0020
+                },
+                Edge {
+                    kind: EdgeKind::Next,
+                    target: 0x0013
+                }
+            ]
+        );
+        assert_eq!(cfg.blocks[&0x0013].successors.len(), 2);
+        assert_eq!(cfg.blocks[&0x0020].successors, []);
+        assert_eq!(
+            cfg.subroutines.iter().copied().collect::<Vec<_>>(),
+            [0x0020]
+        );
+        assert_eq!(cfg.indirect.iter().copied().collect::<Vec<_>>(), [0x0020]);
+
+        assert!(cfg.is_code(0x0000) && cfg.is_code(0x0002));
+        assert!(!cfg.is_code(0x0003) && !cfg.is_code(0x000C) && !cfg.is_code(0x0021));
+        assert_eq!(cfg.code_bytes(), 3 + 4 + 10 + 1);
+
+        // The data at 0003H decodes as code if given as an entry point.
+        let cfg = Analyzer::new(&image, 0).with_entry(0x0003).analyze();
+        assert!(cfg.is_code(0x0003));
+
+        // Entry points outside the image are dropped.
+        assert_eq!(Analyzer::new(&image, 0x0100).entries, []);
+    }
+
+    #[test]
+    fn test_listing_and_dot() {
+        let image = image();
+        let cfg = Analyzer::new(&image, 0).analyze();
+        let symbols = SymbolTable::parse("0008 RST1\n0020 DISPATCH").unwrap();
+        let listing = cfg.listing(Some(&symbols));
+        assert!(listing.starts_with(
+            "; 35 bytes from 0000H: 18 of code in 7 blocks, 17 of data\n; entry points: 0000H, 0008H, 0010H\n"
+        ), "{}", listing);
+        assert!(
+            listing.contains("L_0000:\n0000H  C3 10 00  JMP L_0010\n0003H  DB 44H,41H,54H,41H,21H"),
+            "{}",
+            listing
+        );
+        assert!(listing.contains(
+            "; from 0010H call\nDISPATCH:\n0020H  E9        PCHL  ; target unknown\n0021H  DB 48H,49H"
+        ), "{}", listing);
+        assert!(
+            listing.contains("0010H  CD 20 00  CALL DISPATCH\n"),
+            "{}",
+            listing
+        );
+        assert!(
+            listing.contains("; from 0013H next\n0016H  76        HLT\n"),
+            "{}",
+            listing
+        );
+
+        let dot = cfg.dot(None);
+        assert!(dot.starts_with("digraph cfg {\n"), "{}", dot);
+        assert!(
+            dot.contains("  \"0020\" [label=\"SUB_0020:\\l0020H  PCHL\\l\"];\n"),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains("  \"0010\" -> \"0020\" [style=dashed];\n"),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains("  \"0013\" -> \"0010\" [label=\"taken\"];\n"),
+            "{}",
+            dot
+        );
+        assert!(dot.ends_with("}\n"));
+    }
+}
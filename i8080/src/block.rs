@@ -0,0 +1,857 @@
+use crate::bus::BusCycleKind;
+use crate::cpu::Cpu;
+use crate::decode_cache::Decoded;
+use crate::instruction::{Access, Flow, IndexPair, Instruction, Operand, PushPair, RegisterPair};
+use crate::machine::{ClockedIO, MachineIO};
+use crate::memory_bus::MemoryMap;
+use crate::scheduler::EventId;
+
+use alloc::vec::Vec;
+
+// The most instructions translated into a single block.
+const MAX_BLOCK_INSTRUCTIONS: usize = 32;
+// The most bytes a single block can span.
+const MAX_BLOCK_BYTES: u16 = MAX_BLOCK_INSTRUCTIONS as u16 * 3;
+
+// Carries out one translated instruction, returning the next pc and the
+// cycles taken. The engine moves the pc on and counts the cycles.
+type Handler<M> = fn(&mut Cpu<M>, &Op<M>) -> (u16, u8);
+
+// A translated instruction: the handler for the instruction and its register
+// operands, chosen when the block is translated, and the rest of its
+// operands.
+struct Op<M: MemoryMap> {
+    // None for IN and OUT, which need the machine and are left to the
+    // interpreter.
+    handler: Option<Handler<M>>,
+    decoded: Decoded,
+    // The immediate byte or word, or the address, the instruction takes.
+    operand: u16,
+    // The address of the instruction after this one in memory.
+    next: u16,
+    // Whether the instruction can write to memory, directly or through an
+    // OUT handler.
+    writes: bool,
+}
+
+impl<M: MemoryMap> Op<M> {
+    fn fall_through(&self) -> (u16, u8) {
+        (self.next, self.decoded.cycles)
+    }
+}
+
+impl<M: MemoryMap> Clone for Op<M> {
+    fn clone(&self) -> Self {
+        Op {
+            handler: self.handler,
+            decoded: self.decoded,
+            operand: self.operand,
+            next: self.next,
+            writes: self.writes,
+        }
+    }
+}
+
+// A straight run of translated instructions. A block ends after the first
+// instruction that can transfer control (a jump, call, return, restart,
+// PCHL or HLT) or that performs I/O.
+struct Block<M: MemoryMap> {
+    ops: Vec<Op<M>>,
+    // The number of bytes of memory the block was decoded from.
+    length: u16,
+}
+
+impl<M: MemoryMap> Block<M> {
+    fn covers(&self, start: u16, addr: u16) -> bool {
+        addr.wrapping_sub(start) < self.length
+    }
+}
+
+impl<M: MemoryMap> Clone for Block<M> {
+    fn clone(&self) -> Self {
+        Block {
+            ops: self.ops.clone(),
+            length: self.length,
+        }
+    }
+}
+
+// An execution engine that translates code into blocks of threaded code and
+// runs a whole block per lookup. Each instruction becomes a call to a handler
+// specialised for it, with its operands decoded and its register operands
+// resolved, so running it neither fetches nor decodes anything.
+//
+// The results are identical to running the same cpu with Cpu::run_for or
+// Cpu::run_clocked, breakpoints included. After every instruction the engine
+// checks whether the instruction (or an OUT handler, device or scheduled
+// event) wrote to code, changed the pc, halted the cpu or used up the cycle
+// budget, and if so leaves the block early. A halted cpu idles as it does in
+// the interpreter. While the sanitizer, call stack, history or self-modifying
+// code detector is enabled every instruction goes through the interpreter,
+// which reports to them. Code writes are found through the cpu's decode
+// cache, which the engine enables, so the same rules apply: writes not made
+// by the cpu must be reported with Cpu::invalidate_decoded.
+pub struct BlockEngine<M: MemoryMap> {
+    // Translated blocks by start address.
+    blocks: Vec<Option<Block<M>>>,
+    invalidated: Vec<u16>,
+}
+
+impl<M: MemoryMap> BlockEngine<M> {
+    pub fn new() -> Self {
+        BlockEngine {
+            blocks: (0..0x10000).map(|_| None).collect(),
+            invalidated: Vec::new(),
+        }
+    }
+
+    // Forget every translated block.
+    pub fn clear(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = None;
+        }
+    }
+
+    // See Cpu::run_for.
+    pub fn run_for<IO, F>(
+        &mut self,
+        cpu: &mut Cpu<M>,
+        cycles: u64,
+        machine: &mut IO,
+        mut on_event: F,
+    ) where
+        IO: MachineIO + ?Sized,
+        F: FnMut(&mut Cpu<M>, &mut IO, EventId),
+    {
+        self.prepare(cpu);
+        let end = cpu.cycles + cycles;
+        while cpu.cycles < end {
+            if cpu.is_halted {
+                cpu.idle_until(end);
+            } else {
+                // Only `on_event` can schedule events here, so the block can
+                // run up to the next one before they are delivered.
+                let until = match cpu.scheduler.next_deadline() {
+                    Some(at) => at.min(end),
+                    None => end,
+                };
+                self.run_block(cpu, machine, until, |_, _, _| false);
+            }
+
+            while let Some(due) = cpu.scheduler.pop_due(cpu.cycles) {
+                on_event(cpu, machine, due.event);
+            }
+        }
+    }
+
+    // See Cpu::run_clocked.
+    pub fn run_clocked<IO>(&mut self, cpu: &mut Cpu<M>, cycles: u64, machine: &mut IO)
+    where
+        IO: ClockedIO + ?Sized,
+    {
+        self.prepare(cpu);
+        let end = cpu.cycles + cycles;
+        while cpu.cycles < end {
+            if self.step_clocked_until(cpu, machine, end, true) {
+                break;
+            }
+        }
+    }
+
+    // As Cpu::step_clocked, but runs the whole block at the pc. Returns the
+    // number of cycles elapsed.
+    pub fn step_clocked<IO>(&mut self, cpu: &mut Cpu<M>, machine: &mut IO) -> u32
+    where
+        IO: ClockedIO + ?Sized,
+    {
+        self.prepare(cpu);
+        let start = cpu.cycles;
+        self.step_clocked_until(cpu, machine, u64::MAX, false);
+        (cpu.cycles - start) as u32
+    }
+
+    // Returns true if the cpu stopped at a breakpoint, which is only checked
+    // for if `breakpoints` is set.
+    fn step_clocked_until<IO>(
+        &mut self,
+        cpu: &mut Cpu<M>,
+        machine: &mut IO,
+        end: u64,
+        breakpoints: bool,
+    ) -> bool
+    where
+        IO: ClockedIO + ?Sized,
+    {
+        if cpu.is_halted {
+            cpu.step_clocked(machine);
+            breakpoints && cpu.stop_at_breakpoint()
+        } else {
+            self.run_block(cpu, machine, end, |cpu, machine, cycles| {
+                machine.tick(cycles as u32);
+                cpu.deliver_events(machine);
+                cpu.poll_interrupt(machine);
+                breakpoints && cpu.stop_at_breakpoint()
+            })
+        }
+    }
+
+    // Enable the decode cache's invalidation log and drop the blocks made
+    // stale by writes since the engine last ran.
+    fn prepare(&mut self, cpu: &mut Cpu<M>) {
+        cpu.decode_cache_mut().log_invalidations();
+        if cpu.code_modified() {
+            self.invalidate(cpu, 0, None);
+        }
+    }
+
+    // Run the block at the pc, translating it first if need be, until it
+    // ends, or leave it early as described above. `after` is called after
+    // each instruction with the cycles it took, and returns true to stop
+    // there. Returns what `after` last returned.
+    fn run_block<IO, F>(
+        &mut self,
+        cpu: &mut Cpu<M>,
+        machine: &mut IO,
+        end: u64,
+        mut after: F,
+    ) -> bool
+    where
+        IO: MachineIO + ?Sized,
+        F: FnMut(&mut Cpu<M>, &mut IO, u8) -> bool,
+    {
+        let start = cpu.pc;
+        // The block is taken out of the table while it runs and put back
+        // unless it was invalidated.
+        let block = match self.blocks[start as usize].take() {
+            Some(block) => block,
+            None => Block::translate(cpu, start),
+        };
+        let interpret = cpu.sanitizer().is_some()
+            || cpu.call_stack().is_some()
+            || cpu.history().is_some()
+            || cpu.smc_detector().is_some();
+
+        let mut stop = false;
+        for op in block.ops.iter() {
+            let (next_pc, cycles) = match op.handler {
+                Some(handler) if !interpret => {
+                    let (next_pc, cycles) = handler(cpu, op);
+                    cpu.cycles += cycles as u64;
+                    (next_pc, cycles)
+                }
+                _ => cpu.execute_decoded(&op.decoded, machine),
+            };
+            cpu.pc = next_pc;
+            stop = after(cpu, machine, cycles);
+
+            // Only an instruction that can write can make the rest of the
+            // block stale. Anything else that writes (an accepted interrupt
+            // pushing the pc) also moves the pc.
+            if stop
+                || cpu.is_halted
+                || cpu.cycles >= end
+                || cpu.pc != op.next
+                || (op.writes && cpu.code_modified())
+            {
+                break;
+            }
+        }
+
+        if !cpu.code_modified() || !self.invalidate(cpu, start, Some(&block)) {
+            self.blocks[start as usize] = Some(block);
+        }
+        stop
+    }
+
+    // Drop the blocks containing instructions the decode cache has
+    // invalidated. Returns true if `current`, the block starting at `start`,
+    // was among them.
+    fn invalidate(&mut self, cpu: &mut Cpu<M>, start: u16, current: Option<&Block<M>>) -> bool {
+        self.invalidated.clear();
+        if cpu
+            .decode_cache_mut()
+            .drain_invalidations(&mut self.invalidated)
+        {
+            self.clear();
+            return true;
+        }
+
+        let mut hit = false;
+        for &addr in self.invalidated.iter() {
+            for offset in 0..MAX_BLOCK_BYTES {
+                let block_start = addr.wrapping_sub(offset);
+                let slot = &mut self.blocks[block_start as usize];
+                if slot
+                    .as_ref()
+                    .is_some_and(|block| block.covers(block_start, addr))
+                {
+                    *slot = None;
+                }
+            }
+            hit |= current.is_some_and(|block| block.covers(start, addr));
+        }
+        hit
+    }
+}
+
+impl<M: MemoryMap> Clone for BlockEngine<M> {
+    fn clone(&self) -> Self {
+        BlockEngine {
+            blocks: self.blocks.clone(),
+            invalidated: self.invalidated.clone(),
+        }
+    }
+}
+
+impl<M: MemoryMap> Default for BlockEngine<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: MemoryMap> Block<M> {
+    fn translate(cpu: &mut Cpu<M>, start: u16) -> Block<M> {
+        let mut ops = Vec::new();
+        let mut length = 0u16;
+        loop {
+            let pc = start.wrapping_add(length);
+            let decoded = cpu.decode_at(pc);
+            ops.push(translate(decoded, pc));
+            length += decoded.size as u16;
+
+            let instruction = decoded.instruction;
+            if instruction.flow() != Flow::Next
+                || instruction.io_access() != Access::None
+                || ops.len() == MAX_BLOCK_INSTRUCTIONS
+            {
+                break;
+            }
+        }
+
+        Block { ops, length }
+    }
+}
+
+// The handler `$handler` specialised for a register operand, passed as the
+// operand's index in OPERANDS after an optional leading const parameter.
+macro_rules! by_operand {
+    ($operand:expr, $handler:ident $(, $first:literal)?) => {
+        match $operand {
+            Operand::A => $handler::<M, $($first,)? 0> as Handler<M>,
+            Operand::B => $handler::<M, $($first,)? 1>,
+            Operand::C => $handler::<M, $($first,)? 2>,
+            Operand::D => $handler::<M, $($first,)? 3>,
+            Operand::E => $handler::<M, $($first,)? 4>,
+            Operand::H => $handler::<M, $($first,)? 5>,
+            Operand::L => $handler::<M, $($first,)? 6>,
+            Operand::M => $handler::<M, $($first,)? 7>,
+        }
+    };
+}
+
+// The handler `$handler` specialised for a register pair, passed as its index
+// in REGISTER_PAIRS.
+macro_rules! by_register_pair {
+    ($pair:expr, $handler:ident) => {
+        match $pair {
+            RegisterPair::BC => $handler::<M, 0> as Handler<M>,
+            RegisterPair::DE => $handler::<M, 1>,
+            RegisterPair::HL => $handler::<M, 2>,
+            RegisterPair::SP => $handler::<M, 3>,
+        }
+    };
+}
+
+// As by_register_pair, for PUSH_PAIRS.
+macro_rules! by_push_pair {
+    ($pair:expr, $handler:ident) => {
+        match $pair {
+            PushPair::BC => $handler::<M, 0> as Handler<M>,
+            PushPair::DE => $handler::<M, 1>,
+            PushPair::HL => $handler::<M, 2>,
+            PushPair::PSW => $handler::<M, 3>,
+        }
+    };
+}
+
+// As by_register_pair, for INDEX_PAIRS.
+macro_rules! by_index_pair {
+    ($pair:expr, $handler:ident) => {
+        match $pair {
+            IndexPair::BC => $handler::<M, 0> as Handler<M>,
+            IndexPair::DE => $handler::<M, 1>,
+        }
+    };
+}
+
+const OPERANDS: [Operand; 8] = [
+    Operand::A,
+    Operand::B,
+    Operand::C,
+    Operand::D,
+    Operand::E,
+    Operand::H,
+    Operand::L,
+    Operand::M,
+];
+
+const REGISTER_PAIRS: [RegisterPair; 4] = [
+    RegisterPair::BC,
+    RegisterPair::DE,
+    RegisterPair::HL,
+    RegisterPair::SP,
+];
+
+const PUSH_PAIRS: [PushPair; 4] = [PushPair::BC, PushPair::DE, PushPair::HL, PushPair::PSW];
+
+const INDEX_PAIRS: [IndexPair; 2] = [IndexPair::BC, IndexPair::DE];
+
+// Translate the instruction at `pc`.
+fn translate<M: MemoryMap>(decoded: Decoded, pc: u16) -> Op<M> {
+    let instruction = decoded.instruction;
+    let handler: Option<Handler<M>> = match instruction {
+        Instruction::NOP => Some(nop),
+        Instruction::MOV(dest, src) => Some(match dest {
+            Operand::A => by_operand!(src, mov, 0),
+            Operand::B => by_operand!(src, mov, 1),
+            Operand::C => by_operand!(src, mov, 2),
+            Operand::D => by_operand!(src, mov, 3),
+            Operand::E => by_operand!(src, mov, 4),
+            Operand::H => by_operand!(src, mov, 5),
+            Operand::L => by_operand!(src, mov, 6),
+            Operand::M => by_operand!(src, mov, 7),
+        }),
+        Instruction::MVI(dest, _) => Some(by_operand!(dest, mvi)),
+        Instruction::LXI(pair, _) => Some(by_register_pair!(pair, lxi)),
+        Instruction::STAX(pair) => Some(by_index_pair!(pair, stax)),
+        Instruction::LDAX(pair) => Some(by_index_pair!(pair, ldax)),
+        Instruction::STA(_) => Some(sta),
+        Instruction::LDA(_) => Some(lda),
+        Instruction::SHLD(_) => Some(shld),
+        Instruction::LHLD(_) => Some(lhld),
+        Instruction::XCHG => Some(xchg),
+        Instruction::XTHL => Some(xthl),
+        Instruction::SPHL => Some(sphl),
+        Instruction::PUSH(pair) => Some(by_push_pair!(pair, push)),
+        Instruction::POP(pair) => Some(by_push_pair!(pair, pop)),
+        Instruction::INX(pair) => Some(by_register_pair!(pair, inx)),
+        Instruction::DCX(pair) => Some(by_register_pair!(pair, dcx)),
+        Instruction::DAD(pair) => Some(by_register_pair!(pair, dad)),
+        Instruction::INR(operand) => Some(by_operand!(operand, inr)),
+        Instruction::DCR(operand) => Some(by_operand!(operand, dcr)),
+        Instruction::ADD(operand) => Some(by_operand!(operand, add)),
+        Instruction::ADC(operand) => Some(by_operand!(operand, adc)),
+        Instruction::SUB(operand) => Some(by_operand!(operand, sub)),
+        Instruction::SBB(operand) => Some(by_operand!(operand, sbb)),
+        Instruction::ANA(operand) => Some(by_operand!(operand, ana)),
+        Instruction::XRA(operand) => Some(by_operand!(operand, xra)),
+        Instruction::ORA(operand) => Some(by_operand!(operand, ora)),
+        Instruction::CMP(operand) => Some(by_operand!(operand, cmp)),
+        Instruction::ADI(_) => Some(adi),
+        Instruction::ACI(_) => Some(aci),
+        Instruction::SUI(_) => Some(sui),
+        Instruction::SBI(_) => Some(sbi),
+        Instruction::ANI(_) => Some(ani),
+        Instruction::XRI(_) => Some(xri),
+        Instruction::ORI(_) => Some(ori),
+        Instruction::CPI(_) => Some(cpi),
+        Instruction::RLC => Some(rlc),
+        Instruction::RRC => Some(rrc),
+        Instruction::RAL => Some(ral),
+        Instruction::RAR => Some(rar),
+        Instruction::DAA => Some(daa),
+        Instruction::CMA => Some(cma),
+        Instruction::STC => Some(stc),
+        Instruction::CMC => Some(cmc),
+        Instruction::EI => Some(ei),
+        Instruction::DI => Some(di),
+        Instruction::HLT => Some(hlt),
+        Instruction::JMP(_) => Some(jmp),
+        Instruction::CALL(_) => Some(call),
+        Instruction::RET => Some(ret),
+        Instruction::RST(_) => Some(rst),
+        Instruction::PCHL => Some(pchl),
+        Instruction::JNZ(_) => Some(jump_if::<M, 0>),
+        Instruction::JZ(_) => Some(jump_if::<M, 1>),
+        Instruction::JNC(_) => Some(jump_if::<M, 2>),
+        Instruction::JC(_) => Some(jump_if::<M, 3>),
+        Instruction::JPO(_) => Some(jump_if::<M, 4>),
+        Instruction::JPE(_) => Some(jump_if::<M, 5>),
+        Instruction::JP(_) => Some(jump_if::<M, 6>),
+        Instruction::JM(_) => Some(jump_if::<M, 7>),
+        Instruction::CNZ(_) => Some(call_if::<M, 0>),
+        Instruction::CZ(_) => Some(call_if::<M, 1>),
+        Instruction::CNC(_) => Some(call_if::<M, 2>),
+        Instruction::CC(_) => Some(call_if::<M, 3>),
+        Instruction::CPO(_) => Some(call_if::<M, 4>),
+        Instruction::CPE(_) => Some(call_if::<M, 5>),
+        Instruction::CP(_) => Some(call_if::<M, 6>),
+        Instruction::CM(_) => Some(call_if::<M, 7>),
+        Instruction::RNZ => Some(return_if::<M, 0>),
+        Instruction::RZ => Some(return_if::<M, 1>),
+        Instruction::RNC => Some(return_if::<M, 2>),
+        Instruction::RC => Some(return_if::<M, 3>),
+        Instruction::RPO => Some(return_if::<M, 4>),
+        Instruction::RPE => Some(return_if::<M, 5>),
+        Instruction::RP => Some(return_if::<M, 6>),
+        Instruction::RM => Some(return_if::<M, 7>),
+        Instruction::IN(_) | Instruction::OUT(_) => None,
+    };
+    let operand = match instruction {
+        Instruction::MVI(_, val)
+        | Instruction::ADI(val)
+        | Instruction::ACI(val)
+        | Instruction::SUI(val)
+        | Instruction::SBI(val)
+        | Instruction::ANI(val)
+        | Instruction::XRI(val)
+        | Instruction::ORI(val)
+        | Instruction::CPI(val)
+        | Instruction::RST(val) => val as u16,
+        Instruction::LXI(_, val) => val,
+        _ => instruction
+            .target()
+            .or(instruction.address_operand())
+            .unwrap_or(0),
+    };
+
+    Op {
+        handler,
+        decoded,
+        operand,
+        next: pc.wrapping_add(decoded.size as u16),
+        writes: instruction.memory_access().writes()
+            || instruction.stack_access().writes()
+            || instruction.io_access() != Access::None,
+    }
+}
+
+// Whether the condition a conditional jump, call or return tests holds. The
+// conditions are numbered in the order they are encoded in the opcode: NZ,
+// Z, NC, C, PO, PE, P and M.
+#[inline(always)]
+fn condition<M: MemoryMap>(cpu: &Cpu<M>, condition: u8) -> bool {
+    let flags = &cpu.condition_codes;
+    match condition {
+        0 => !flags.zero,
+        1 => flags.zero,
+        2 => !flags.carry,
+        3 => flags.carry,
+        4 => !flags.parity,
+        5 => flags.parity,
+        6 => !flags.sign,
+        _ => flags.sign,
+    }
+}
+
+// The handlers. Each carries out its instruction with the interpreter's own
+// operations, so the flags come out the same, quirks included.
+
+fn nop<M: MemoryMap>(_: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    op.fall_through()
+}
+
+fn mov<M: MemoryMap, const DEST: usize, const SRC: usize>(
+    cpu: &mut Cpu<M>,
+    op: &Op<M>,
+) -> (u16, u8) {
+    cpu.mov(OPERANDS[DEST], OPERANDS[SRC]);
+    op.fall_through()
+}
+
+fn mvi<M: MemoryMap, const DEST: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.mvi(OPERANDS[DEST], op.operand as u8);
+    op.fall_through()
+}
+
+fn lxi<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.lxi(REGISTER_PAIRS[PAIR], op.operand);
+    op.fall_through()
+}
+
+fn stax<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.stax(INDEX_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn ldax<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.ldax(INDEX_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn sta<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.sta(op.operand);
+    op.fall_through()
+}
+
+fn lda<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.lda(op.operand);
+    op.fall_through()
+}
+
+fn shld<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.shld(op.operand);
+    op.fall_through()
+}
+
+fn lhld<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.lhld(op.operand);
+    op.fall_through()
+}
+
+fn xchg<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.xchg();
+    op.fall_through()
+}
+
+fn xthl<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.xthl();
+    op.fall_through()
+}
+
+fn sphl<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.sphl();
+    op.fall_through()
+}
+
+fn push<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.push(PUSH_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn pop<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.pop(PUSH_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn inx<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.inx(REGISTER_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn dcx<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.dcx(REGISTER_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn dad<M: MemoryMap, const PAIR: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.dad(REGISTER_PAIRS[PAIR]);
+    op.fall_through()
+}
+
+fn inr<M: MemoryMap, const OPERAND: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.inr(OPERANDS[OPERAND]);
+    op.fall_through()
+}
+
+fn dcr<M: MemoryMap, const OPERAND: usize>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    cpu.dcr(OPERANDS[OPERAND]);
+    op.fall_through()
+}
+
+// The value of an 8-bit operand, reading memory for M.
+#[inline(always)]
+fn load<M: MemoryMap>(cpu: &mut Cpu<M>, operand: Operand) -> u8 {
+    match operand {
+        Operand::A => cpu.registers.a,
+        Operand::B => cpu.registers.b,
+        Operand::C => cpu.registers.c,
+        Operand::D => cpu.registers.d,
+        Operand::E => cpu.registers.e,
+        Operand::H => cpu.registers.h,
+        Operand::L => cpu.registers.l,
+        Operand::M => {
+            let hl = cpu.registers.get_hl();
+            cpu.bus_read(BusCycleKind::MemoryRead, hl)
+        }
+    }
+}
+
+// Handlers for an ALU operation on a register or M, and on an immediate byte.
+macro_rules! alu {
+    ($($register:ident, $immediate:ident => $operation:ident;)*) => {
+        $(
+            fn $register<M: MemoryMap, const OPERAND: usize>(
+                cpu: &mut Cpu<M>,
+                op: &Op<M>,
+            ) -> (u16, u8) {
+                let val = load(cpu, OPERANDS[OPERAND]);
+                cpu.$operation(val);
+                op.fall_through()
+            }
+
+            fn $immediate<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+                cpu.$operation(op.operand as u8);
+                op.fall_through()
+            }
+        )*
+    };
+}
+
+alu! {
+    add, adi => add;
+    adc, aci => adc;
+    sub, sui => sub;
+    sbb, sbi => sbb;
+    ana, ani => and;
+    xra, xri => xor;
+    ora, ori => or;
+    cmp, cpi => compare;
+}
+
+// Handlers for the instructions that only work on the accumulator and flags.
+macro_rules! accumulator {
+    ($($handler:ident),*) => {
+        $(
+            fn $handler<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+                cpu.$handler();
+                op.fall_through()
+            }
+        )*
+    };
+}
+
+accumulator!(rlc, rrc, ral, rar, daa, cma, stc, cmc, ei, di, hlt);
+
+fn jmp<M: MemoryMap>(_: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    (op.operand, op.decoded.cycles)
+}
+
+fn jump_if<M: MemoryMap, const CONDITION: u8>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    if condition(cpu, CONDITION) {
+        (op.operand, op.decoded.cycles)
+    } else {
+        op.fall_through()
+    }
+}
+
+fn call<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    (cpu.call(op.operand), op.decoded.cycles)
+}
+
+// A conditional call or return takes 6 more cycles if the condition holds.
+fn call_if<M: MemoryMap, const CONDITION: u8>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    if condition(cpu, CONDITION) {
+        (cpu.call(op.operand), op.decoded.cycles + 6)
+    } else {
+        op.fall_through()
+    }
+}
+
+fn ret<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    (cpu.ret(), op.decoded.cycles)
+}
+
+fn return_if<M: MemoryMap, const CONDITION: u8>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    if condition(cpu, CONDITION) {
+        (cpu.ret(), op.decoded.cycles + 6)
+    } else {
+        op.fall_through()
+    }
+}
+
+fn rst<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    (cpu.rst(op.operand as u8), op.decoded.cycles)
+}
+
+fn pchl<M: MemoryMap>(cpu: &mut Cpu<M>, op: &Op<M>) -> (u16, u8) {
+    (cpu.registers.get_hl(), op.decoded.cycles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::CpuState;
+    use crate::test_support::{cpu, MockMachine, MockMemory, Timer};
+
+    fn state(cpu: &Cpu<MockMemory>) -> (CpuState, u64, Vec<u8>) {
+        (cpu.state(), cpu.cycles, cpu.memory.memory.to_vec())
+    }
+
+    #[test]
+    fn test_self_modifying_code_matches_interpreter() {
+        let program: &[(u16, &[u8])] = &[
+            // LXI SP,0x2400; MVI B,10
+            (0x100, &[0x31, 0x00, 0x24, 0x06, 0x0A]),
+            // loop: LDA 0x0120; INR A; STA 0x0120; STA 0x0110
+            (
+                0x105,
+                &[0x3A, 0x20, 0x01, 0x3C, 0x32, 0x20, 0x01, 0x32, 0x10, 0x01],
+            ),
+            // MVI C,0 (patched above); DCR B; JNZ loop; OUT 0
+            (0x10F, &[0x0E, 0x00, 0x05, 0xC2, 0x05, 0x01, 0xD3, 0x00]),
+        ];
+
+        let mut interpreted = cpu(program);
+        interpreted.pc = 0x100;
+        interpreted.run_for(1_000_000, &mut MockMachine, |_, _, _| {});
+
+        let mut cpu = cpu(program);
+        cpu.pc = 0x100;
+        let mut engine = BlockEngine::new();
+        engine.run_for(&mut cpu, 1_000_000, &mut MockMachine, |_, _, _| {});
+
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.registers.c, 10);
+        assert_eq!(state(&cpu), state(&interpreted));
+    }
+
+    #[test]
+    fn test_interrupts_match_interpreter() {
+        let program: &[(u16, &[u8])] = &[
+            // RST 1: INR B; EI; RET
+            (0x08, &[0x04, 0xFB, 0xC9]),
+            // LXI SP,0x2400; EI; loop: INR A; INR C; INR D; JMP loop
+            (
+                0x100,
+                &[0x31, 0x00, 0x24, 0xFB, 0x3C, 0x0C, 0x14, 0xC3, 0x04, 0x01],
+            ),
+        ];
+
+        // Run in uneven slices so that blocks are also split at the end of
+        // the cycle budget.
+        let mut interpreted = cpu(program);
+        interpreted.pc = 0x100;
+        let mut timer = Timer::default();
+        for _ in 0..100 {
+            interpreted.run_clocked(97, &mut timer);
+        }
+
+        let mut cpu = cpu(program);
+        cpu.pc = 0x100;
+        let mut timer = Timer::default();
+        let mut engine = BlockEngine::new();
+        for _ in 0..100 {
+            engine.run_clocked(&mut cpu, 97, &mut timer);
+        }
+
+        assert!(cpu.registers.b > 0);
+        assert_eq!(state(&cpu), state(&interpreted));
+    }
+
+    #[test]
+    fn test_breakpoints_match_interpreter() {
+        // LXI SP,0x2400; loop: INR A; INR C; INR D; JMP loop
+        let program: &[(u16, &[u8])] = &[(
+            0x100,
+            &[0x31, 0x00, 0x24, 0x3C, 0x0C, 0x14, 0xC3, 0x03, 0x01],
+        )];
+
+        // INR D is in the middle of the loop's block.
+        let mut interpreted = cpu(program);
+        interpreted.pc = 0x100;
+        interpreted.add_breakpoint(0x105);
+        let mut cpu = cpu(program);
+        cpu.pc = 0x100;
+        cpu.add_breakpoint(0x105);
+        let mut engine = BlockEngine::new();
+        for _ in 0..3 {
+            interpreted.run_clocked(1000, &mut Timer::default());
+            engine.run_clocked(&mut cpu, 1000, &mut Timer::default());
+            assert_eq!(cpu.take_breakpoint_hit(), Some(0x105));
+            assert_eq!(interpreted.take_breakpoint_hit(), Some(0x105));
+            assert_eq!(state(&cpu), state(&interpreted));
+        }
+        assert_eq!(cpu.registers.d, 2);
+    }
+}
@@ -0,0 +1,83 @@
+// The kinds of machine cycle the 8080 places on the bus. These correspond to
+// the status words output on the data bus during T1 of each machine cycle.
+// source: http://kazojc.com/elementy_czynne/IC/INTEL-8080A.pdf
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusCycleKind {
+    // M1 of every instruction. The opcode is read from the address in the pc.
+    InstructionFetch,
+    MemoryRead,
+    MemoryWrite,
+    StackRead,
+    StackWrite,
+    // The port number is mirrored onto both halves of the address bus for IN
+    // and OUT.
+    InputRead,
+    OutputWrite,
+    // Replaces the instruction fetch when an interrupt is accepted. The
+    // interrupting device supplies the opcode (an RST) on the data bus.
+    InterruptAck,
+    HaltAck,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusCycle {
+    pub kind: BusCycleKind,
+    pub address: u16,
+    pub data: u8,
+    // The T-state, relative to the start of the instruction, at which T1 of
+    // this machine cycle begins. Wait states inserted by earlier machine
+    // cycles of the same instruction are included.
+    pub t_state: u32,
+    // The number of T-states in this machine cycle, not counting wait states.
+    pub length: u8,
+}
+
+impl BusCycle {
+    // The address of an IN or OUT machine cycle. The 8080 places the port
+    // number on both A0-A7 and A8-A15.
+    pub fn io_address(port: u8) -> u16 {
+        (port as u16) << 8 | port as u16
+    }
+}
+
+// A device observing the machine cycles issued by Cpu::execute_cycles. The
+// bus is notified of each machine cycle in the order the 8080 issues them.
+pub trait CycleBus {
+    // Called once per machine cycle. The returned value is the number of wait
+    // states (Tw) the device holds READY low for. The 8080 samples READY
+    // during T2, so each wait state delays T3 and every later machine cycle
+    // of the instruction by one T-state.
+    fn bus_cycle(&mut self, cycle: &BusCycle) -> u8;
+}
+
+// Issue the machine cycles of an instruction to the bus. The first cycle is
+// the opcode fetch (or interrupt acknowledge) whose length is whatever
+// remains of `cycles` once the remaining machine cycles have been accounted
+// for. `idle` is the number of T-states the instruction spends with the bus
+// idle, which is the case for DAD's two internal machine cycles. The
+// returned value is the number of T-states the instruction took, including
+// wait states.
+pub(crate) fn issue<B: CycleBus + ?Sized>(
+    bus: &mut B,
+    first: BusCycle,
+    rest: &[BusCycle],
+    cycles: u8,
+    idle: u8,
+) -> u32 {
+    let rest_length: u8 = rest.iter().map(|cycle| cycle.length).sum();
+    let first = BusCycle {
+        length: cycles - rest_length - idle,
+        ..first
+    };
+
+    let mut t_state = 0;
+    let mut waits = 0;
+    for cycle in core::iter::once(&first).chain(rest) {
+        let cycle = BusCycle { t_state, ..*cycle };
+        let tw = bus.bus_cycle(&cycle) as u32;
+        waits += tw;
+        t_state += cycle.length as u32 + tw;
+    }
+
+    cycles as u32 + waits
+}
@@ -0,0 +1,300 @@
+use crate::instruction::{Flow, Instruction};
+use crate::symbols::{Address, SymbolLookup};
+
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    // CALL or a conditional call that was taken.
+    Call,
+    Restart,
+    Interrupt,
+}
+
+// A call that has not yet returned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The address of the call, or of the instruction that was interrupted.
+    pub call_site: u16,
+    // The address called.
+    pub target: u16,
+    // The address the call will return to. A program can change this on the
+    // stack with XTHL.
+    pub return_address: u16,
+    // Where on the stack the return address is held.
+    pub sp: u16,
+}
+
+// A shadow of the calls on the 8080 stack, kept up to date as instructions
+// execute. Calls, restarts and interrupts push a frame and returns pop it.
+// Programs that play tricks with the stack are handled by the following
+// heuristics:
+//
+// - A return pops every frame whose return address is held at or below the
+//   address it returns from, so returning through a frame that was never
+//   popped, e.g. after a longjmp-style SP reload, unwinds it. A return from an
+//   address holding no return address is a computed jump and pops nothing.
+// - Any other instruction that moves SP up (POP, INX SP, SPHL, LXI SP) drops
+//   the frames whose return address is now above the top of the stack, as
+//   with the POP H that fetches the data following a CALL. Moving SP down,
+//   e.g. to switch to another stack, leaves the frames alone.
+// - XTHL on a return address updates the frame's return address.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    // The state before the instruction being executed.
+    pc: u16,
+    sp: u16,
+    hl: u16,
+    instruction: Option<Instruction>,
+}
+
+// The most frames kept. Beyond this, the oldest frames are forgotten, which
+// also bounds the cost of a program that calls without ever returning.
+const MAX_FRAMES: usize = 256;
+
+impl CallStack {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // The calls in progress, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Called before an instruction is executed.
+    pub(crate) fn begin(&mut self, pc: u16, sp: u16, hl: u16, instruction: &Instruction) {
+        self.pc = pc;
+        self.sp = sp;
+        self.hl = hl;
+        self.instruction = Some(*instruction);
+    }
+
+    // Called after an instruction has been executed, with the new pc and SP.
+    pub(crate) fn end(&mut self, next_pc: u16, sp: u16) {
+        let instruction = match self.instruction.take() {
+            Some(instruction) => instruction,
+            None => return,
+        };
+
+        match instruction.flow() {
+            Flow::Call | Flow::ConditionalCall | Flow::Restart if sp == self.sp.wrapping_sub(2) => {
+                let kind = match instruction {
+                    Instruction::RST(_) => FrameKind::Restart,
+                    _ => FrameKind::Call,
+                };
+                self.push(Frame {
+                    kind,
+                    call_site: self.pc,
+                    target: next_pc,
+                    return_address: self.pc.wrapping_add(instruction.size()),
+                    sp,
+                });
+            }
+            Flow::Return | Flow::ConditionalReturn if sp == self.sp.wrapping_add(2) => {
+                let from = self.sp;
+                while self.frames.last().is_some_and(|f| f.sp <= from) {
+                    self.frames.pop();
+                }
+            }
+            _ => {
+                if let Instruction::XTHL = instruction {
+                    // The stack now holds what was in HL.
+                    let hl = self.hl;
+                    if let Some(frame) = self.frames.iter_mut().rev().find(|f| f.sp == sp) {
+                        frame.return_address = hl;
+                    }
+                } else if sp > self.sp {
+                    while self.frames.last().is_some_and(|f| f.sp < sp) {
+                        self.frames.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    // Called when an interrupt is accepted at `pc`, with SP before the pc is
+    // pushed.
+    pub(crate) fn interrupt(&mut self, pc: u16, sp: u16, target: u16) {
+        self.push(Frame {
+            kind: FrameKind::Interrupt,
+            call_site: pc,
+            target,
+            return_address: pc,
+            sp: sp.wrapping_sub(2),
+        });
+    }
+
+    // A backtrace from `pc`, the address of the current instruction.
+    pub fn backtrace(&self, pc: u16) -> Backtrace {
+        Backtrace {
+            pc,
+            frames: self.frames.iter().rev().copied().collect(),
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+}
+
+// The calls leading to an instruction, innermost first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backtrace {
+    pub pc: u16,
+    pub frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    // Format with symbol names where `symbols` knows them.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> BacktraceDisplay<'a> {
+        BacktraceDisplay {
+            backtrace: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+pub struct BacktraceDisplay<'a> {
+    backtrace: &'a Backtrace,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for BacktraceDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#0  {}", Address::new(self.backtrace.pc, self.symbols))?;
+        for (i, frame) in self.backtrace.frames.iter().enumerate() {
+            write!(
+                f,
+                "\n#{:<2} {}",
+                i + 1,
+                Address::new(frame.call_site, self.symbols)
+            )?;
+            match frame.kind {
+                FrameKind::Call => {}
+                FrameKind::Restart => write!(f, " (restart)")?,
+                FrameKind::Interrupt => write!(f, " (interrupted)")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::test_support::{cpu, run, MockMemory};
+
+    use alloc::string::ToString;
+
+    struct TestSymbols;
+
+    impl SymbolLookup for TestSymbols {
+        fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+            [(0x0030, "leaf"), (0x0020, "inner"), (0x0010, "outer")]
+                .iter()
+                .find(|(start, _)| addr >= *start)
+                .map(|(start, name)| (*name, addr - start))
+        }
+    }
+
+    fn depth(cpu: &Cpu<MockMemory>) -> usize {
+        cpu.call_stack().unwrap().depth()
+    }
+
+    #[test]
+    fn test_calls_and_returns() {
+        let mut cpu = cpu(&[
+            // LXI SP,2400H; CALL 0010H
+            (0x0000, &[0x31, 0x00, 0x24, 0xCD, 0x10, 0x00]),
+            // CZ 0030H; CALL 0020H; RET
+            (0x0010, &[0xCC, 0x30, 0x00, 0xCD, 0x20, 0x00, 0xC9]),
+            // CALL 0030H; RET
+            (0x0020, &[0xCD, 0x30, 0x00, 0xC9]),
+            // NOP; RET
+            (0x0030, &[0x00, 0xC9]),
+        ]);
+        cpu.enable_call_stack();
+
+        // The conditional call is not taken.
+        run(&mut cpu, 5);
+        assert_eq!(cpu.pc, 0x0030);
+        let backtrace = cpu.backtrace().unwrap();
+        assert_eq!(
+            backtrace.to_string(),
+            "#0  0030H\n#1  0020H\n#2  0013H\n#3  0003H"
+        );
+        assert_eq!(
+            backtrace.display(Some(&TestSymbols)).to_string(),
+            "#0  0030H <leaf>\n#1  0020H <inner>\n#2  0013H <outer+3H>\n#3  0003H"
+        );
+
+        run(&mut cpu, 4);
+        assert_eq!((cpu.pc, depth(&cpu)), (0x0006, 0));
+
+        cpu.interrupts_enabled = true;
+        cpu.interrupt(0x0010);
+        assert_eq!(
+            cpu.backtrace().unwrap().to_string(),
+            "#0  0010H\n#1  0006H (interrupted)"
+        );
+    }
+
+    #[test]
+    fn test_stack_tricks() {
+        let mut cpu = cpu(&[
+            // LXI SP,2400H; CALL 0040H
+            (0x0000, &[0x31, 0x00, 0x24, 0xCD, 0x40, 0x00]),
+            // POP H; CALL 0050H
+            (0x0040, &[0xE1, 0xCD, 0x50, 0x00]),
+            // LXI H,0060H; XTHL; RET
+            (0x0050, &[0x21, 0x60, 0x00, 0xE3, 0xC9]),
+            // CALL 0080H
+            (0x0060, &[0xCD, 0x80, 0x00]),
+            // LXI H,0090H; PUSH H; RET
+            (0x0080, &[0x21, 0x90, 0x00, 0xE5, 0xC9]),
+            // LXI SP,2400H
+            (0x0090, &[0x31, 0x00, 0x24]),
+        ]);
+        cpu.enable_call_stack();
+
+        // Popping the return address drops the call.
+        run(&mut cpu, 3);
+        assert_eq!(depth(&cpu), 0);
+
+        // XTHL replaces the return address, and the return then pops it.
+        run(&mut cpu, 3);
+        assert_eq!(cpu.call_stack().unwrap().frames()[0].return_address, 0x0060);
+        run(&mut cpu, 1);
+        assert_eq!((cpu.pc, depth(&cpu)), (0x0060, 0));
+
+        // Returning to an address pushed by the program is a jump.
+        run(&mut cpu, 4);
+        assert_eq!((cpu.pc, depth(&cpu)), (0x0090, 1));
+
+        // Resetting the stack pointer abandons the call.
+        run(&mut cpu, 1);
+        assert_eq!(depth(&cpu), 0);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct ConditionCodes {
     pub carry: bool,
     pub zero: bool,
@@ -17,7 +17,7 @@ impl ConditionCodes {
     }
 
     pub fn set_zero(&mut self, val: u8) {
-        self.zero = (val & 0xFF) == 0
+        self.zero = val == 0
     }
 
     pub fn set_sign(&mut self, val: u8) {
@@ -25,7 +25,7 @@ impl ConditionCodes {
     }
 
     pub fn set_parity(&mut self, val: u8) {
-        self.parity = val.count_ones() % 2 == 0
+        self.parity = val.count_ones().is_multiple_of(2)
     }
 
     pub fn set_aux_carry(&mut self, aux_carry: bool) {
@@ -57,7 +57,10 @@ impl ConditionCodes {
     }
 }
 
+// The tests compare flags with assert_eq! against true and false, and set
+// them on a default instance.
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
 
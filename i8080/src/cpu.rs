@@ -1,10 +1,63 @@
+use crate::bus::{self, BusCycle, BusCycleKind, CycleBus};
+use crate::call_stack::{Backtrace, CallStack};
 use crate::condition_codes::ConditionCodes;
-use crate::instruction::{Instruction, Operand};
-use crate::machine::MachineIO;
+use crate::crash_dump::{self, CrashDump};
+use crate::decode_cache::{DecodeCache, Decoded, InvalidatingMemory};
+use crate::history::{Direction, Executed, History, IoAccess};
+use crate::instruction::{IndexPair, Instruction, Operand, PushPair, RegisterPair};
+use crate::machine::{ClockedIO, CpuView, MachineIO, SchedulerView};
 use crate::memory_bus::MemoryMap;
+use crate::power_on::{PowerOn, Rng};
 use crate::registers::Registers;
+use crate::sanitizer::Sanitizer;
+use crate::scheduler::{EventId, Scheduler};
+use crate::smc::SmcDetector;
+use crate::state::CpuState;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+// The cycles step_clocked lets pass while the cpu is halted, as long as a NOP.
+pub const HALT_IDLE_CYCLES: u32 = 4;
+
+// Where execute takes an instruction's size and base cycle count from. Working
+// them out inside each arm of execute's match lets the compiler fold them to
+// constants, which a single lookup up front would not; a decoded instruction
+// already carries them.
+trait Timing: Copy {
+    fn size(self, instruction: &Instruction) -> u16;
+
+    fn cycles(self, instruction: &Instruction) -> u8;
+}
+
+#[derive(Clone, Copy)]
+struct FromInstruction;
+
+impl Timing for FromInstruction {
+    #[inline(always)]
+    fn size(self, instruction: &Instruction) -> u16 {
+        instruction.size()
+    }
 
-use std::process;
+    #[inline(always)]
+    fn cycles(self, instruction: &Instruction) -> u8 {
+        instruction.cycles()
+    }
+}
+
+impl Timing for &Decoded {
+    #[inline(always)]
+    fn size(self, _: &Instruction) -> u16 {
+        self.size as u16
+    }
+
+    #[inline(always)]
+    fn cycles(self, _: &Instruction) -> u8 {
+        self.cycles
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -20,6 +73,21 @@ where
     pub interrupts_enabled: bool,
     pub next_interrupt_val: u16,
     pub is_halted: bool,
+    // The total number of cycles executed since the cpu was created. This
+    // only ever increases and is the time base for the scheduler.
+    pub cycles: u64,
+    pub scheduler: Scheduler,
+    // The memory and I/O accesses made by the instruction being executed.
+    // Only recorded while executing through execute_cycles.
+    bus_accesses: Option<Vec<BusCycle>>,
+    // Instructions already decoded, if enabled with enable_decode_cache.
+    decode_cache: Option<Box<DecodeCache>>,
+    sanitizer: Option<Box<Sanitizer>>,
+    call_stack: Option<Box<CallStack>>,
+    history: Option<Box<History>>,
+    smc_detector: Option<Box<SmcDetector>>,
+    breakpoints: BTreeSet<u16>,
+    breakpoint_hit: Option<u16>,
 }
 
 impl<M> Cpu<M>
@@ -36,10 +104,265 @@ where
             interrupts_enabled: false,
             next_interrupt_val: 0x08,
             is_halted: false,
+            cycles: 0,
+            scheduler: Scheduler::new(),
+            bus_accesses: None,
+            decode_cache: None,
+            sanitizer: None,
+            call_stack: None,
+            history: None,
+            smc_detector: None,
+            breakpoints: BTreeSet::new(),
+            breakpoint_hit: None,
+        }
+    }
+
+    // Cache decoded instructions by address so that each is only decoded
+    // once. The cpu invalidates the cache on its own writes and on writes
+    // made through the CpuView given to OUT handlers. Any other change to
+    // memory, including through a mirror of a writable address, must be
+    // reported with invalidate_decoded.
+    pub fn enable_decode_cache(&mut self) {
+        if self.decode_cache.is_none() {
+            self.decode_cache = Some(Box::new(DecodeCache::new()));
+        }
+    }
+
+    pub fn disable_decode_cache(&mut self) {
+        self.decode_cache = None;
+    }
+
+    // Report a write to memory made other than by the cpu.
+    pub fn invalidate_decoded(&mut self, addr: u16) {
+        if let Some(cache) = self.decode_cache.as_mut() {
+            cache.invalidate(addr);
+        }
+    }
+
+    // Forget every decoded instruction, e.g. after loading a new program.
+    pub fn clear_decode_cache(&mut self) {
+        if let Some(cache) = self.decode_cache.as_mut() {
+            cache.clear();
+        }
+    }
+
+    // Check memory accesses and execution against a memory layout. See
+    // Sanitizer.
+    pub fn enable_sanitizer(&mut self, sanitizer: Sanitizer) {
+        self.sanitizer = Some(Box::new(sanitizer));
+        self.enable_call_stack();
+    }
+
+    pub fn disable_sanitizer(&mut self) -> Option<Sanitizer> {
+        self.sanitizer.take().map(|sanitizer| *sanitizer)
+    }
+
+    pub fn sanitizer(&self) -> Option<&Sanitizer> {
+        self.sanitizer.as_deref()
+    }
+
+    pub fn sanitizer_mut(&mut self) -> Option<&mut Sanitizer> {
+        self.sanitizer.as_deref_mut()
+    }
+
+    // Report self-modifying code. See SmcDetector.
+    pub fn enable_smc_detector(&mut self, detector: SmcDetector) {
+        self.smc_detector = Some(Box::new(detector));
+    }
+
+    pub fn disable_smc_detector(&mut self) -> Option<SmcDetector> {
+        self.smc_detector.take().map(|detector| *detector)
+    }
+
+    pub fn smc_detector(&self) -> Option<&SmcDetector> {
+        self.smc_detector.as_deref()
+    }
+
+    pub fn smc_detector_mut(&mut self) -> Option<&mut SmcDetector> {
+        self.smc_detector.as_deref_mut()
+    }
+
+    // Keep a shadow of the calls on the stack, for backtraces. The calls made
+    // before it is enabled are not known.
+    pub fn enable_call_stack(&mut self) {
+        if self.call_stack.is_none() {
+            self.call_stack = Some(Box::new(CallStack::new()));
+        }
+    }
+
+    pub fn disable_call_stack(&mut self) {
+        self.call_stack = None;
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_deref()
+    }
+
+    // The calls leading to the current pc, if the call stack is enabled.
+    pub fn backtrace(&self) -> Option<Backtrace> {
+        self.call_stack
+            .as_ref()
+            .map(|call_stack| call_stack.backtrace(self.pc))
+    }
+
+    // Keep the most recent instructions and I/O, for crash dumps.
+    pub fn enable_history(&mut self, history: History) {
+        self.history = Some(Box::new(history));
+    }
+
+    pub fn disable_history(&mut self) -> Option<History> {
+        self.history.take().map(|history| *history)
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_deref()
+    }
+
+    // Stop run_clocked when the pc reaches `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // The breakpoint run_clocked last stopped at, if it has not been taken
+    // already.
+    pub fn take_breakpoint_hit(&mut self) -> Option<u16> {
+        self.breakpoint_hit.take()
+    }
+
+    pub(crate) fn decode_cache_mut(&mut self) -> &mut DecodeCache {
+        self.enable_decode_cache();
+        self.decode_cache.as_mut().unwrap()
+    }
+
+    // True if the decode cache has invalidated anything since the block
+    // engine last looked.
+    pub(crate) fn code_modified(&self) -> bool {
+        self.decode_cache
+            .as_ref()
+            .is_some_and(|cache| cache.has_invalidations())
+    }
+
+    // Decode the instruction at `addr` through the decode cache.
+    pub(crate) fn decode_at(&mut self, addr: u16) -> Decoded {
+        self.enable_decode_cache();
+        let cache = self.decode_cache.as_mut().unwrap();
+        cache.fetch(&mut self.memory, addr)
+    }
+
+    // Decode the instruction at the pc, using the decode cache if enabled.
+    pub fn fetch(&mut self) -> Instruction {
+        self.fetch_decoded().instruction
+    }
+
+    // As fetch, along with the instruction's size and cycles, which the decode
+    // cache keeps so that execute_decoded need not work them out again.
+    pub fn fetch_decoded(&mut self) -> Decoded {
+        match self.decode_cache.as_mut() {
+            Some(cache) => cache.fetch(&mut self.memory, self.pc),
+            None => Decoded::new(Instruction::from(self.memory.read_slice(self.pc))),
+        }
+    }
+
+    // A snapshot of the registers, flags, sp, pc, interrupt enable and halt
+    // state, and the pending scheduled events.
+    pub fn state(&self) -> CpuState {
+        CpuState::from_parts(
+            &self.registers,
+            &self.condition_codes,
+            self.sp,
+            self.pc,
+            self.interrupts_enabled,
+            self.is_halted,
+            self.scheduler
+                .pending()
+                .map(|e| (e.at.saturating_sub(self.cycles), e.event))
+                .collect(),
+        )
+    }
+
+    // Capture the cpu, its history and call stack, if enabled, and memory for
+    // post-mortem inspection. Memory is read with MemoryMap::peek.
+    pub fn crash_dump(&mut self, reason: &str) -> CrashDump {
+        let memory = &mut self.memory;
+        let (image, unmapped) = crash_dump::capture_memory(|addr| memory.peek(addr));
+        CrashDump {
+            reason: reason.to_string(),
+            state: self.state(),
+            cycles: self.cycles,
+            instructions: self.history.as_ref().map_or_else(Vec::new, |history| {
+                history.instructions().copied().collect()
+            }),
+            io: self
+                .history
+                .as_ref()
+                .map_or_else(Vec::new, |history| history.io().copied().collect()),
+            backtrace: self.backtrace(),
+            unmapped,
+            memory: image,
+        }
+    }
+
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.registers = state.registers.clone();
+        self.condition_codes = state.condition_codes.clone();
+        self.sp = state.sp();
+        self.pc = state.pc();
+        self.interrupts_enabled = state.interrupts_enabled();
+        self.is_halted = state.is_halted();
+        // The events are due relative to the cycle counter, which is left as
+        // it is.
+        self.scheduler = Scheduler::new();
+        for &(cycles, event) in state.events() {
+            self.schedule(cycles, event);
+        }
+    }
+
+    // Pulse the RESET pin: the pc is cleared, interrupts are disabled and a
+    // halted cpu resumes. The registers, flags, stack pointer and memory keep
+    // whatever they held.
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.interrupts_enabled = false;
+        self.is_halted = false;
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.reset();
+        }
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.clear();
+        }
+    }
+
+    // Fill the registers, flags, stack pointer and the given RAM with random
+    // values from the seed, as on a real board at power on, then reset.
+    pub fn power_on(&mut self, power_on: &PowerOn) {
+        let mut rng = Rng::new(power_on.seed);
+        let mut state = self.state();
+        state.set_psw(rng.next_u16());
+        state.set_bc(rng.next_u16());
+        state.set_de(rng.next_u16());
+        state.set_hl(rng.next_u16());
+        state.set_sp(rng.next_u16());
+        self.set_state(&state);
+
+        for range in power_on.ram.iter() {
+            for addr in range.clone() {
+                self.memory.write(addr, rng.next_u8());
+            }
         }
+        self.clear_decode_cache();
+        self.reset();
     }
 
-    pub fn step<IO: MachineIO>(&mut self, machine: &mut IO) {
+    pub fn step<IO: MachineIO + ?Sized>(&mut self, machine: &mut IO) {
+        #[cfg(feature = "std")]
         let debug = false;
         const HERTZ: i32 = 2_000_000;
         const FPS: u8 = 60;
@@ -49,12 +372,13 @@ where
         for _ in 0..2 {
             let mut cycles_complete = 0;
             while cycles_complete <= CYCLES_PER_HALF_FRAME {
-                let instr = Instruction::from(self.memory.read_slice(self.pc));
-                let (next_pc, cycles) = self.execute(&instr, machine);
+                let decoded = self.fetch_decoded();
+                let (next_pc, cycles) = self.execute_decoded(&decoded, machine);
                 self.pc = next_pc;
 
+                #[cfg(feature = "std")]
                 if debug {
-                    println!("{:?}", instr);
+                    println!("{:?}", decoded.instruction);
                     println! {"pc: {:#x?}, sp: {:#x?},", self.pc, self.sp};
                     println!("cycles: {}", cycles);
                     println!("{:#x?}", self.condition_codes);
@@ -69,24 +393,186 @@ where
             } else {
                 0x08
             };
+            #[cfg(feature = "std")]
             std::thread::sleep(std::time::Duration::from_millis(8));
         }
     }
 
-    pub fn execute<IO: MachineIO>(
+    // Run instructions until at least `cycles` more cycles have elapsed.
+    // Scheduled events are passed to `on_event` once the instruction during
+    // which they fell due has completed. Events due on the same cycle are
+    // delivered in the order they were scheduled. While the cpu is halted the
+    // clock skips ahead to the next event, which can wake it with
+    // Cpu::interrupt.
+    pub fn run_for<IO, F>(&mut self, cycles: u64, machine: &mut IO, mut on_event: F)
+    where
+        IO: MachineIO + ?Sized,
+        F: FnMut(&mut Self, &mut IO, EventId),
+    {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            if self.is_halted {
+                self.idle_until(end);
+            } else {
+                let decoded = self.fetch_decoded();
+                let (next_pc, _) = self.execute_decoded(&decoded, machine);
+                self.pc = next_pc;
+            }
+
+            while let Some(due) = self.scheduler.pop_due(self.cycles) {
+                on_event(self, machine, due.event);
+            }
+        }
+    }
+
+    // Advance the clock of a halted cpu to the next scheduled event, or to
+    // `end` if that is sooner.
+    pub(crate) fn idle_until(&mut self, end: u64) {
+        let until = match self.scheduler.next_deadline() {
+            Some(at) => at.min(end),
+            None => end,
+        };
+        self.cycles = self.cycles.max(until);
+    }
+
+    // Execute one instruction, advance the machine's devices by the cycles it
+    // took and pass them the scheduled events that fell due. If a device is
+    // requesting an interrupt and interrupts are enabled, the interrupt is
+    // accepted before returning and the devices are advanced by the cycles
+    // that took too. A halted cpu executes nothing but still advances the
+    // devices, by HALT_IDLE_CYCLES, and can be woken by an interrupt. Returns
+    // the number of cycles elapsed.
+    pub fn step_clocked<IO: ClockedIO + ?Sized>(&mut self, machine: &mut IO) -> u32 {
+        let start = self.cycles;
+        if self.is_halted {
+            self.cycles += HALT_IDLE_CYCLES as u64;
+            machine.tick(HALT_IDLE_CYCLES);
+        } else {
+            let decoded = self.fetch_decoded();
+            let (next_pc, cycles) = self.execute_decoded(&decoded, machine);
+            self.pc = next_pc;
+            machine.tick(cycles as u32);
+        }
+        self.deliver_events(machine);
+        self.poll_interrupt(machine);
+
+        (self.cycles - start) as u32
+    }
+
+    // Pass the scheduled events that have fallen due to the machine. With
+    // poll_interrupt, this is the second half of step_clocked.
+    pub fn deliver_events<IO: ClockedIO + ?Sized>(&mut self, machine: &mut IO) {
+        while let Some(due) = self.scheduler.pop_due(self.cycles) {
+            let mut view = SchedulerView::new(&mut self.scheduler, self.cycles);
+            machine.event(&mut view, due.event);
+        }
+    }
+
+    // Accept an interrupt if one is requested and interrupts are enabled,
+    // advancing the machine's devices by the cycles it takes. With
+    // deliver_events, this is the second half of step_clocked, for loops that
+    // execute instructions themselves.
+    pub fn poll_interrupt<IO: ClockedIO + ?Sized>(&mut self, machine: &mut IO) {
+        if self.interrupts_enabled {
+            if let Some(rst) = machine.interrupt_request() {
+                machine.interrupt_acknowledge();
+                self.interrupt((rst as u16 & 0x7) << 3);
+                machine.tick(Instruction::RST(rst).cycles() as u32);
+            }
+        }
+    }
+
+    // Run instructions with step_clocked until at least `cycles` more cycles
+    // have elapsed or the cpu reaches a breakpoint. A halted cpu keeps the
+    // devices running until an interrupt wakes it or the cycles run out. The
+    // instruction at the pc is always executed, so running again after
+    // stopping at a breakpoint continues past it.
+    pub fn run_clocked<IO: ClockedIO + ?Sized>(&mut self, cycles: u64, machine: &mut IO) {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            self.step_clocked(machine);
+            if self.stop_at_breakpoint() {
+                break;
+            }
+        }
+    }
+
+    // Whether run_clocked should stop before the instruction at the pc,
+    // recording the breakpoint if so. A halted cpu is never at one.
+    pub(crate) fn stop_at_breakpoint(&mut self) -> bool {
+        if !self.is_halted && !self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc) {
+            self.breakpoint_hit = Some(self.pc);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Schedule an event to fall due `cycles` cycles from now. It is passed to
+    // run_for's `on_event` or, under step_clocked and run_clocked, to
+    // ClockedIO::event.
+    pub fn schedule(&mut self, cycles: u64, event: EventId) {
+        self.scheduler.schedule_at(self.cycles + cycles, event);
+    }
+
+    pub fn execute<IO: MachineIO + ?Sized>(
+        &mut self,
+        instruction: &Instruction,
+        machine: &mut IO,
+    ) -> (u16, u8) {
+        self.execute_timed(instruction, FromInstruction, machine)
+    }
+
+    // As execute, taking the size and base cycle count of the instruction
+    // from `decoded` rather than working them out from the instruction.
+    pub fn execute_decoded<IO: MachineIO + ?Sized>(
+        &mut self,
+        decoded: &Decoded,
+        machine: &mut IO,
+    ) -> (u16, u8) {
+        self.execute_timed(&decoded.instruction, decoded, machine)
+    }
+
+    #[inline(always)]
+    fn execute_timed<IO: MachineIO + ?Sized, T: Timing>(
         &mut self,
         instruction: &Instruction,
+        timing: T,
         machine: &mut IO,
     ) -> (u16, u8) {
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.begin(self.pc, self.sp, self.registers.get_hl(), instruction);
+        }
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.begin(self.pc, instruction, self.call_stack.as_deref());
+        }
+        if self.smc_detector.is_some() {
+            let bytes = self.peek_bytes(3);
+            if let Some(detector) = self.smc_detector.as_mut() {
+                detector.begin(self.pc, instruction, &bytes);
+            }
+        }
+        if self.history.is_some() {
+            let executed = Executed {
+                pc: self.pc,
+                cycles: self.cycles,
+                bytes: self.peek_bytes(timing.size(instruction)),
+                len: timing.size(instruction) as u8,
+            };
+            if let Some(history) = self.history.as_mut() {
+                history.executed(executed);
+            }
+        }
+
         // Macro for unconditional instructions. This macro will call the
         // provided function name ($func) along with an address ($addr) if
         // provided. This will return a tuple of (next_pc, cycles).
         macro_rules! unconditional {
             ($func:ident, $addr:ident) => {
-                (self.$func($addr), instruction.cycles())
+                (self.$func($addr), timing.cycles(instruction))
             };
             ($func:ident) => {
-                (self.$func(), instruction.cycles())
+                (self.$func(), timing.cycles(instruction))
             };
         }
 
@@ -100,10 +586,10 @@ where
             ($func:ident, $addr:ident) => {
                 match self.$func($addr) {
                     None => (
-                        self.pc.wrapping_add(instruction.size()),
-                        instruction.cycles(),
+                        self.pc.wrapping_add(timing.size(instruction)),
+                        timing.cycles(instruction),
                     ),
-                    Some(next_pc) => (next_pc, instruction.cycles()),
+                    Some(next_pc) => (next_pc, timing.cycles(instruction)),
                 }
             };
         }
@@ -119,19 +605,19 @@ where
             ($func:ident, $addr:ident) => {
                 match self.$func($addr) {
                     None => (
-                        self.pc.wrapping_add(instruction.size()),
-                        instruction.cycles(),
+                        self.pc.wrapping_add(timing.size(instruction)),
+                        timing.cycles(instruction),
                     ),
-                    Some(next_pc) => (next_pc, instruction.cycles() + 6),
+                    Some(next_pc) => (next_pc, timing.cycles(instruction) + 6),
                 }
             };
             ($func:ident) => {
                 match self.$func() {
                     None => (
-                        self.pc.wrapping_add(instruction.size()),
-                        instruction.cycles(),
+                        self.pc.wrapping_add(timing.size(instruction)),
+                        timing.cycles(instruction),
                     ),
-                    Some(next_pc) => (next_pc, instruction.cycles() + 6),
+                    Some(next_pc) => (next_pc, timing.cycles(instruction) + 6),
                 }
             };
         }
@@ -151,16 +637,15 @@ where
                     Operand::E => self.registers.e,
                     Operand::H => self.registers.h,
                     Operand::L => self.registers.l,
-                    Operand::M => self.memory.read(self.registers.get_hl()),
-                    _ => panic!(
-                        "{:#x?} only accepts registers or a memory location",
-                        instruction
-                    ),
+                    Operand::M => {
+                        let hl = self.registers.get_hl();
+                        self.bus_read(BusCycleKind::MemoryRead, hl)
+                    }
                 };
                 self.$func(val);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }};
         }
@@ -173,8 +658,8 @@ where
             ($func:ident, $val: ident) => {{
                 self.$func($val);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }};
         }
@@ -187,30 +672,30 @@ where
             ($func:ident, $dst: ident, $src: ident) => {{
                 self.$func($dst, $src);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }};
             ($func:ident, $addr: ident) => {{
                 self.$func($addr);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }};
             ($func:ident) => {{
                 self.$func();
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }};
         }
 
         let (pc, cycles) = match *instruction {
             Instruction::NOP => (
-                self.pc.wrapping_add(instruction.size()),
-                instruction.cycles(),
+                self.pc.wrapping_add(timing.size(instruction)),
+                timing.cycles(instruction),
             ),
             Instruction::JMP(addr) => unconditional!(jmp, addr),
             Instruction::JC(addr) => conditional_branch!(jc, addr),
@@ -262,36 +747,36 @@ where
             Instruction::EI => {
                 self.ei();
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }
             Instruction::DI => {
                 self.di();
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }
             Instruction::HLT => {
                 self.hlt();
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }
             Instruction::IN(port) => {
                 self.input(machine, port);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }
             Instruction::OUT(port) => {
                 self.output(machine, port);
                 (
-                    self.pc.wrapping_add(instruction.size()),
-                    instruction.cycles(),
+                    self.pc.wrapping_add(timing.size(instruction)),
+                    timing.cycles(instruction),
                 )
             }
             Instruction::ADD(op) => alu_non_immediate!(add, op),
@@ -320,8 +805,154 @@ where
             Instruction::INX(reg) => flag_or_register_modify!(inx, reg),
             Instruction::DCX(reg) => flag_or_register_modify!(dcx, reg),
         };
+        self.cycles += cycles as u64;
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.end(self.sp);
+        }
+        if let Some(call_stack) = self.call_stack.as_mut() {
+            call_stack.end(pc, self.sp);
+        }
         (pc, cycles)
     }
+
+    // Execute an instruction in cycle-stepped mode. The instruction is
+    // executed as it is by execute(), and then each of its machine cycles
+    // (the opcode fetch, operand and data reads, writes, stack accesses and
+    // I/O) is issued to the bus along with the T-state at which it begins.
+    // Return a tuple with the next pc and the number of T-states taken,
+    // including any wait states the bus inserted.
+    pub fn execute_cycles<IO: MachineIO + ?Sized, B: CycleBus + ?Sized>(
+        &mut self,
+        instruction: &Instruction,
+        machine: &mut IO,
+        bus: &mut B,
+    ) -> (u16, u32) {
+        let pc = self.pc;
+        let fetch = BusCycle {
+            kind: BusCycleKind::InstructionFetch,
+            address: pc,
+            data: self.memory.read(pc),
+            t_state: 0,
+            length: 0,
+        };
+        let mut rest: Vec<BusCycle> = (1..instruction.size())
+            .map(|offset| {
+                let address = pc.wrapping_add(offset);
+                BusCycle {
+                    kind: BusCycleKind::MemoryRead,
+                    address,
+                    data: self.memory.read(address),
+                    t_state: 0,
+                    length: 3,
+                }
+            })
+            .collect();
+
+        self.bus_accesses = Some(Vec::new());
+        let (next_pc, cycles) = self.execute(instruction, machine);
+        rest.extend(self.bus_accesses.take().unwrap_or_default());
+
+        let idle = match *instruction {
+            // DAD spends two machine cycles adding with the bus idle.
+            Instruction::DAD(_) => 6,
+            // The final stack write of XTHL takes 5 T-states.
+            Instruction::XTHL => {
+                rest[3].length = 5;
+                0
+            }
+            _ => 0,
+        };
+
+        // execute() has already counted the cycles without wait states.
+        let total = bus::issue(bus, fetch, &rest, cycles, idle);
+        self.cycles += (total - cycles as u32) as u64;
+        (next_pc, total)
+    }
+
+    // Accept an interrupt in cycle-stepped mode. The interrupt acknowledge
+    // machine cycle takes the place of an opcode fetch and the interrupting
+    // device supplies an RST instruction, which pushes the pc. Returns the
+    // number of T-states taken, or 0 if interrupts are disabled.
+    pub fn interrupt_cycles<B: CycleBus + ?Sized>(&mut self, addr: u16, bus: &mut B) -> u32 {
+        if !self.interrupts_enabled {
+            return 0;
+        }
+        let ack = BusCycle {
+            kind: BusCycleKind::InterruptAck,
+            address: self.pc,
+            data: 0xC7 | (addr as u8 & 0x38),
+            t_state: 0,
+            length: 0,
+        };
+
+        self.bus_accesses = Some(Vec::new());
+        self.interrupt(addr);
+        let rest = self.bus_accesses.take().unwrap_or_default();
+
+        let cycles = Instruction::RST(0).cycles();
+        let total = bus::issue(bus, ack, &rest, cycles, 0);
+        self.cycles += (total - cycles as u32) as u64;
+        total
+    }
+
+    // Read a byte of memory on behalf of the executing instruction.
+    pub(crate) fn bus_read(&mut self, kind: BusCycleKind, addr: u16) -> u8 {
+        let val = self.memory.read(addr);
+        self.record(kind, addr, val);
+        val
+    }
+
+    // Write a byte of memory on behalf of the executing instruction.
+    pub(crate) fn bus_write(&mut self, kind: BusCycleKind, addr: u16, val: u8) {
+        self.memory.write(addr, val);
+        if let Some(cache) = self.decode_cache.as_mut() {
+            cache.invalidate(addr);
+        }
+        if let Some(detector) = self.smc_detector.as_mut() {
+            detector.write(addr);
+        }
+        self.record(kind, addr, val);
+    }
+
+    // The `len` bytes at the pc, without side effects. Bytes that cannot be
+    // peeked read as zero.
+    fn peek_bytes(&mut self, len: u16) -> [u8; 3] {
+        let mut bytes = [0; 3];
+        for (i, byte) in bytes.iter_mut().enumerate().take(len as usize) {
+            *byte = self
+                .memory
+                .peek(self.pc.wrapping_add(i as u16))
+                .unwrap_or(0);
+        }
+        bytes
+    }
+
+    fn record_io(&mut self, direction: Direction, port: u8) {
+        if let Some(history) = self.history.as_mut() {
+            history.io_access(IoAccess {
+                pc: self.pc,
+                cycles: self.cycles,
+                direction,
+                port,
+                val: self.registers.a,
+            });
+        }
+    }
+
+    fn record(&mut self, kind: BusCycleKind, address: u16, data: u8) {
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.access(kind, address, self.call_stack.as_deref());
+        }
+        if let Some(accesses) = self.bus_accesses.as_mut() {
+            accesses.push(BusCycle {
+                kind,
+                address,
+                data,
+                t_state: 0,
+                length: 3,
+            });
+        }
+    }
 }
 
 impl<M> Cpu<M>
@@ -526,7 +1157,7 @@ where
     // Call a subroutine. First, push a return address onto the stack and then
     // return the new address the pc will be set to.
     // Condition bits affected: None
-    fn call(&mut self, addr: u16) -> u16 {
+    pub(crate) fn call(&mut self, addr: u16) -> u16 {
         self.push_stack(self.pc + 3);
         addr
     }
@@ -614,91 +1245,91 @@ where
     // Unconditionally return from a subroutine, which pops an adress off the
     // stack.
     // Condition bits affected: None
-    fn ret(&mut self) -> u16 {
+    pub(crate) fn ret(&mut self) -> u16 {
         self.pop_stack()
     }
 
     // Restart instruction. Pushes the pc onto the stack and returns a return
     // address.
     // Condition bits affected: None
-    fn rst(&mut self, addr: u8) -> u16 {
+    pub(crate) fn rst(&mut self, addr: u8) -> u16 {
         self.call(addr as u16)
     }
 
     // The contents of the specified register pair are saved in two bytes of
     // memory indicated by the stack pointer SP.
     // Condition bits affected: None
-    fn push(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn push(&mut self, reg: PushPair) {
         match reg {
-            Operand::B => {
+            PushPair::BC => {
                 let val = self.registers.get_bc();
                 self.push_stack(val);
             }
-            Operand::D => {
+            PushPair::DE => {
                 let val = self.registers.get_de();
                 self.push_stack(val);
             }
-            Operand::H => {
+            PushPair::HL => {
                 let val = self.registers.get_hl();
                 self.push_stack(val);
             }
-            Operand::PSW => {
+            PushPair::PSW => {
                 let val =
                     (self.registers.a as u16) << 8 | self.condition_codes.flags_to_psw() as u16;
                 self.push_stack(val);
             }
-            _ => {
-                //TODO: write error message later
-                unimplemented!();
-            }
         };
     }
 
     // The contents of the specified register pair are restored from two
     // bytes of memory indicated by the stack pointer SP.
     // Condition bits affected: None
-    fn pop(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn pop(&mut self, reg: PushPair) {
         match reg {
-            Operand::B => {
+            PushPair::BC => {
                 let val = self.pop_stack();
                 self.registers.set_bc(val);
             }
-            Operand::D => {
+            PushPair::DE => {
                 let val = self.pop_stack();
                 self.registers.set_de(val);
             }
-            Operand::H => {
+            PushPair::HL => {
                 let val = self.pop_stack();
                 self.registers.set_hl(val);
             }
-            Operand::PSW => {
+            PushPair::PSW => {
                 let val = self.pop_stack();
                 self.registers.a = (val >> 8) as u8;
                 let psw = (val & 0xFF) as u8;
                 self.condition_codes.psw_to_flags(psw);
             }
-            _ => {
-                //TODO: write error message later
-                unimplemented!();
-            }
         };
     }
 
     // The contents of the specified value is pushed onto the stack and the
     // stack pointer is decremented by two.
     fn push_stack(&mut self, val: u16) {
-        self.memory
-            .write(self.sp.wrapping_sub(1), ((val & 0xFF00) >> 8) as u8);
-        self.memory
-            .write(self.sp.wrapping_sub(2), (val & 0xFF) as u8);
+        self.bus_write(
+            BusCycleKind::StackWrite,
+            self.sp.wrapping_sub(1),
+            ((val & 0xFF00) >> 8) as u8,
+        );
+        self.bus_write(
+            BusCycleKind::StackWrite,
+            self.sp.wrapping_sub(2),
+            (val & 0xFF) as u8,
+        );
         self.sp = self.sp.wrapping_sub(2);
     }
 
     // The contents of the memory pointed at by the stack pointer is popped off
     // the stack and the stack pointer is incremented by two.
     fn pop_stack(&mut self) -> u16 {
-        let lo = self.memory.read(self.sp) as u16;
-        let hi = self.memory.read(self.sp + 1) as u16;
+        let lo = self.bus_read(BusCycleKind::StackRead, self.sp) as u16;
+        let hi = self.bus_read(BusCycleKind::StackRead, self.sp.wrapping_add(1)) as u16;
         self.sp = self.sp.wrapping_add(2);
         hi << 8 | lo
     }
@@ -707,132 +1338,171 @@ where
     // 16-bit number held in the H and L registers using two's complement arithmetic.
     // The result replaces the contents of the H and L registers.
     // Condition bits affected: Carry
-    fn dad(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn dad(&mut self, reg: RegisterPair) {
         match reg {
-            Operand::B => {
+            RegisterPair::BC => {
                 let res = self.registers.get_bc();
                 self.condition_codes
                     .set_carry((res as u32 + self.registers.get_hl() as u32) > 0xFFFF);
                 self.registers
                     .set_hl(res.wrapping_add(self.registers.get_hl()));
             }
-            Operand::D => {
+            RegisterPair::DE => {
                 let res = self.registers.get_de();
                 self.condition_codes
                     .set_carry((res as u32 + self.registers.get_hl() as u32) > 0xFFFF);
                 self.registers
                     .set_hl(res.wrapping_add(self.registers.get_hl()));
             }
-            Operand::H => {
+            RegisterPair::HL => {
                 let res = self.registers.get_hl();
                 self.condition_codes
                     .set_carry((res as u32 + self.registers.get_hl() as u32) > 0xFFFF);
                 self.registers
                     .set_hl(res.wrapping_add(self.registers.get_hl()));
             }
-            Operand::SP => {
+            RegisterPair::SP => {
                 let res = self.sp;
                 self.condition_codes
                     .set_carry((res as u32 + self.registers.get_hl() as u32) > 0xFFFF);
                 self.registers
                     .set_hl(res.wrapping_add(self.registers.get_hl()));
             }
-            _ => {
-                //TODO: write error message later
-                unimplemented!();
-            }
         };
     }
 
     // Decrement Register Pair. The 16-bit number held in the specified
     // register pair is decremented by one.
     // Condition bits affected: None
-    fn dcx(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn dcx(&mut self, reg: RegisterPair) {
         match reg {
-            Operand::B => {
+            RegisterPair::BC => {
                 self.registers
                     .set_bc(self.registers.get_bc().wrapping_sub(1));
             }
-            Operand::D => {
+            RegisterPair::DE => {
                 self.registers
                     .set_de(self.registers.get_de().wrapping_sub(1));
             }
-            Operand::H => {
+            RegisterPair::HL => {
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_sub(1));
             }
-            Operand::SP => {
+            RegisterPair::SP => {
                 self.sp = self.sp.wrapping_sub(1);
             }
-            _ => {
-                //TODO: write error message later
-                unimplemented!();
-            }
         };
     }
 
     // Increment Register Pair. The 16-bit number held in the specified
     // register pair in incremented by one.
     // Condition bits affected: None
-    fn inx(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn inx(&mut self, reg: RegisterPair) {
         match reg {
-            Operand::B => {
+            RegisterPair::BC => {
                 self.registers
                     .set_bc(self.registers.get_bc().wrapping_add(1));
             }
-            Operand::D => {
+            RegisterPair::DE => {
                 self.registers
                     .set_de(self.registers.get_de().wrapping_add(1));
             }
-            Operand::H => {
+            RegisterPair::HL => {
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_add(1));
             }
-            Operand::SP => {
+            RegisterPair::SP => {
                 self.sp = self.sp.wrapping_add(1);
             }
-            _ => {
-                //TODO: write error message later
-                unimplemented!();
-            }
         };
     }
 
     // Halt instruction
-    // Emulator 101 says it may not be necessary to emulate and suggests exiting if encountered
-    fn hlt(&mut self) {
+    // The cpu stays halted until an interrupt, for the caller to notice.
+    // Emulator 101 suggests exiting if encountered, which is left to
+    // frontends that want it.
+    pub(crate) fn hlt(&mut self) {
         self.is_halted = true;
-        process::exit(1);
+        self.record(BusCycleKind::HaltAck, self.pc.wrapping_add(1), 0);
     }
 
     // An eight-bit data byte is read from input device number exp and replaces
     // the contents of the accumulator
-    fn input<IO: MachineIO>(&mut self, machine: &mut IO, port: u8) {
+    fn input<IO: MachineIO + ?Sized>(&mut self, machine: &mut IO, port: u8) {
         self.registers.a = machine.machine_in(port);
+        self.record_io(Direction::In, port);
+        self.record(
+            BusCycleKind::InputRead,
+            BusCycle::io_address(port),
+            self.registers.a,
+        );
     }
 
     // The contents of the accumulator are sent to output device number exp
-    fn output<IO: MachineIO>(&mut self, machine: &mut IO, port: u8) {
-        machine.machine_out(self, port, self.registers.a);
+    pub(crate) fn output<IO: MachineIO + ?Sized>(&mut self, machine: &mut IO, port: u8) {
+        self.record_io(Direction::Out, port);
+        self.record(
+            BusCycleKind::OutputWrite,
+            BusCycle::io_address(port),
+            self.registers.a,
+        );
+        let mut invalidating;
+        let memory: &mut dyn MemoryMap = match self.decode_cache.as_mut() {
+            Some(cache) => {
+                invalidating = InvalidatingMemory {
+                    memory: &mut self.memory,
+                    cache,
+                };
+                &mut invalidating
+            }
+            None => &mut self.memory,
+        };
+        let mut view = CpuView::new(
+            &self.registers,
+            &self.condition_codes,
+            self.pc,
+            self.sp,
+            memory,
+            &mut self.is_halted,
+        );
+        machine.machine_out(&mut view, port, self.registers.a);
     }
 
     // Enable Interrupts
     // Sets the interrupt flag
-    fn ei(&mut self) {
+    pub(crate) fn ei(&mut self) {
         self.interrupts_enabled = true;
     }
 
     // Disable Interrupts
     // Clears the interrupt flag
-    fn di(&mut self) {
+    pub(crate) fn di(&mut self) {
         self.interrupts_enabled = false;
     }
 
+    // Accept an interrupt if interrupts are enabled. The interrupting device
+    // supplies an RST instruction, so this takes as many cycles as RST. An
+    // interrupt also wakes a halted cpu, which returns to the instruction
+    // after the HLT.
     pub fn interrupt(&mut self, addr: u16) {
         if self.interrupts_enabled {
             self.interrupts_enabled = false;
+            self.is_halted = false;
+            if let Some(sanitizer) = self.sanitizer.as_mut() {
+                sanitizer.interrupt(self.pc, (addr >> 3) as u8 & 0x7);
+            }
+            if let Some(detector) = self.smc_detector.as_mut() {
+                detector.interrupt(self.pc, (addr >> 3) as u8 & 0x7);
+            }
+            if let Some(call_stack) = self.call_stack.as_mut() {
+                call_stack.interrupt(self.pc, self.sp, addr);
+            }
             self.push_stack(self.pc);
             self.pc = addr;
+            self.cycles += Instruction::RST(0).cycles() as u64;
         }
     }
 
@@ -841,19 +1511,20 @@ where
     // fn nop(&self) {}
 
     // Load SP From H and L
-    fn sphl(&mut self) {
+    pub(crate) fn sphl(&mut self) {
         self.sp = self.registers.get_hl();
     }
 
     // Exchange Stack
-    fn xthl(&mut self) {
+    pub(crate) fn xthl(&mut self) {
         let tmp_h = self.registers.h;
         let tmp_l = self.registers.l;
+        let sp_hi = self.sp.wrapping_add(1);
 
-        self.registers.h = self.memory.read(self.sp + 1);
-        self.registers.l = self.memory.read(self.sp);
-        self.memory.write(self.sp, tmp_l);
-        self.memory.write(self.sp + 1, tmp_h);
+        self.registers.l = self.bus_read(BusCycleKind::StackRead, self.sp);
+        self.registers.h = self.bus_read(BusCycleKind::StackRead, sp_hi);
+        self.bus_write(BusCycleKind::StackWrite, sp_hi, tmp_h);
+        self.bus_write(BusCycleKind::StackWrite, self.sp, tmp_l);
     }
 
     //fn rim(&self) {
@@ -915,7 +1586,7 @@ where
     // The specified byte is logically ANDed bit by bit with the contents of
     // the accumulator or immediate address. The Carry bit is reset to zero.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn and(&mut self, val: u8) {
+    pub(crate) fn and(&mut self, val: u8) {
         // The 8080 logical AND instructions set the flag to reflect the
         // logical OR of bit 3 of the values involved in the AND operation.
         let aux_carry = ((self.registers.a | val) & 0x8) != 0;
@@ -932,7 +1603,7 @@ where
     // the accumulator or immediate address. The Carry and Auxiliary Carry bits
     // are reset.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn xor(&mut self, val: u8) {
+    pub(crate) fn xor(&mut self, val: u8) {
         self.registers.a ^= val;
 
         self.condition_codes.reset_carry();
@@ -946,7 +1617,7 @@ where
     // accumulator or immediate address. The Carry and Auxiliary Carry bits are
     // reset.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn or(&mut self, val: u8) {
+    pub(crate) fn or(&mut self, val: u8) {
         self.registers.a |= val;
 
         self.condition_codes.reset_carry();
@@ -965,7 +1636,7 @@ where
     // indicating that the contents of REG are greater than the contents of
     // the accumulator, and reset otherwise.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn compare(&mut self, val: u8) {
+    pub(crate) fn compare(&mut self, val: u8) {
         let val = self.registers.a.wrapping_sub(val);
 
         self.condition_codes.set_carry(self.registers.a < val);
@@ -983,7 +1654,7 @@ where
     // rotated one bit position to the left, with the high-order bit being
     // transferred to the low-order bit position of the accumulator.
     // Condition bits affected: Carry
-    fn rlc(&mut self) {
+    pub(crate) fn rlc(&mut self) {
         self.registers.a = self.registers.a.rotate_left(1);
         self.condition_codes.carry = (self.registers.a & 0x1) > 0;
     }
@@ -993,7 +1664,7 @@ where
     // rotated one bit position to the right, with the low-order bit being
     // transferred to the high-order bit position of the accumulator.
     // Condition bits affected: Carry
-    fn rrc(&mut self) {
+    pub(crate) fn rrc(&mut self) {
         self.registers.a = self.registers.a.rotate_right(1);
         self.condition_codes.carry = (self.registers.a & 0x80) > 0;
     }
@@ -1003,7 +1674,7 @@ where
     // of the accumulator replaces the Carry bit, while the Carry bit replaces
     // the high-order bit of the accumulator.
     // Condition bits affected: Carry
-    fn ral(&mut self) {
+    pub(crate) fn ral(&mut self) {
         let carry_bit = if self.condition_codes.carry { 1 } else { 0 };
         let high_bit = self.registers.a & 0x80;
         self.registers.a = (self.registers.a << 1) | carry_bit;
@@ -1015,7 +1686,7 @@ where
     // of the accumulator replaces the carry bit, while the carry bit replaces
     // the high-order bit of the accumulator.
     // Condition bits affected: Carry
-    fn rar(&mut self) {
+    pub(crate) fn rar(&mut self) {
         let carry_bit = if self.condition_codes.carry { 1 } else { 0 };
         let low_bit = self.registers.a & 0x1;
         self.registers.a = (self.registers.a >> 1) | (carry_bit << 7);
@@ -1025,27 +1696,27 @@ where
     // Each bit of the contents of the accumulator is complemented (producing
     // the one's complement).
     // Condition bits affected: None
-    fn cma(&mut self) {
+    pub(crate) fn cma(&mut self) {
         self.registers.a = !self.registers.a
     }
 
     // Set the carry bit is set to one.
     // Condition bits affected: Carry
-    fn stc(&mut self) {
+    pub(crate) fn stc(&mut self) {
         self.condition_codes.carry = true
     }
 
     // Complement carry. If the Carry bit is not set, set it. If the Carry
     // bit is set, reset it.
     // Condition bits affected: Carry
-    fn cmc(&mut self) {
+    pub(crate) fn cmc(&mut self) {
         self.condition_codes.carry = !self.condition_codes.carry
     }
 
     // The eight-bit hexadecimal number in the accumulator is adjusted to form
     // two four-bit binary encoded digits.
     // Condition bits affected: Zero, Sign, Parity, Carry, Auxiliary Carry
-    fn daa(&mut self) {
+    pub(crate) fn daa(&mut self) {
         let mut val = 0;
         let mut carry = self.condition_codes.carry;
 
@@ -1074,7 +1745,7 @@ where
 
     // The specified byte is added to the contents of the accumulator.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn add(&mut self, val: u8) {
+    pub(crate) fn add(&mut self, val: u8) {
         let reg_a = self.registers.a;
         let res: u16 = (reg_a as u16).wrapping_add(val as u16);
         // put result in accumulator
@@ -1089,7 +1760,8 @@ where
 
     // The specified register or memory byte is incremented by one.
     // Condition bits affected: Zero, Sign, Parity, Auxiliary Carry
-    fn inr(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn inr(&mut self, reg: Operand) {
         let res = match reg {
             Operand::A => {
                 self.registers.a = self.registers.a.wrapping_add(1);
@@ -1121,11 +1793,10 @@ where
             }
             Operand::M => {
                 let hl = self.registers.get_hl();
-                let val = self.memory.read(hl).wrapping_add(1);
-                self.memory.write(hl, val);
-                self.memory.read(hl)
+                let val = self.bus_read(BusCycleKind::MemoryRead, hl).wrapping_add(1);
+                self.bus_write(BusCycleKind::MemoryWrite, hl, val);
+                val
             }
-            _ => panic!("INR only accepts registers or a memory location"),
         };
         // update flags
         self.condition_codes.set_zero(res);
@@ -1136,7 +1807,8 @@ where
 
     // The specified register or memory byte is decremented by one.
     // Condition bits affected: Zero, Sign, Parity, Auxiliary Carry
-    fn dcr(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn dcr(&mut self, reg: Operand) {
         let res = match reg {
             Operand::A => {
                 self.registers.a = self.registers.a.wrapping_sub(1);
@@ -1168,11 +1840,10 @@ where
             }
             Operand::M => {
                 let hl = self.registers.get_hl();
-                let val = self.memory.read(hl).wrapping_sub(1);
-                self.memory.write(hl, val);
-                self.memory.read(hl)
+                let val = self.bus_read(BusCycleKind::MemoryRead, hl).wrapping_sub(1);
+                self.bus_write(BusCycleKind::MemoryWrite, hl, val);
+                val
             }
-            _ => panic!("DCR only accepts registers or a memory location"),
         };
         // update flags
         self.condition_codes.set_zero(res);
@@ -1184,7 +1855,7 @@ where
     // The specified byte plus the content of the Carry bit is added to the contents
     // of the accumulator.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn adc(&mut self, val: u8) {
+    pub(crate) fn adc(&mut self, val: u8) {
         let reg_a = self.registers.a;
         let carry: u8 = if self.condition_codes.carry { 1 } else { 0 };
         let res = (reg_a as u16)
@@ -1205,7 +1876,7 @@ where
     // out of the high-order bit position, indicating that a borrow occurred, the
     // Carry bit is set; otherwise it is reset.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn sub(&mut self, val: u8) {
+    pub(crate) fn sub(&mut self, val: u8) {
         let reg_a = self.registers.a;
         let res: u16 = (reg_a as u16).wrapping_sub(val as u16);
         // put result in accumulator
@@ -1222,7 +1893,7 @@ where
     // The Carry bit is internally added to the contents of the specified byte. This
     // value is then subtracted from the accumulator.
     // Condition bits affected: Carry, Zero, Sign, Parity, Auxiliary Carry
-    fn sbb(&mut self, val: u8) {
+    pub(crate) fn sbb(&mut self, val: u8) {
         let reg_a = self.registers.a as u16;
         let borrow: u16 = if self.condition_codes.carry { 1 } else { 0 };
         let res: u16 = (reg_a).wrapping_sub(val as u16).wrapping_sub(borrow);
@@ -1266,7 +1937,8 @@ where
     // The data re- places the contents of the destination register; the source
     // remains unchanged.
     // Condition bits affected: None
-    fn mov(&mut self, dest: Operand, src: Operand) {
+    #[inline(always)]
+    pub(crate) fn mov(&mut self, dest: Operand, src: Operand) {
         let src = match src {
             Operand::A => self.registers.a,
             Operand::B => self.registers.b,
@@ -1275,8 +1947,10 @@ where
             Operand::E => self.registers.e,
             Operand::H => self.registers.h,
             Operand::L => self.registers.l,
-            Operand::M => self.memory.read(self.registers.get_hl()),
-            _ => panic!("MOV only accepts registers or a memory location",),
+            Operand::M => {
+                let hl = self.registers.get_hl();
+                self.bus_read(BusCycleKind::MemoryRead, hl)
+            }
         };
 
         match dest {
@@ -1287,15 +1961,18 @@ where
             Operand::E => self.registers.e = src,
             Operand::H => self.registers.h = src,
             Operand::L => self.registers.l = src,
-            Operand::M => self.memory.write(self.registers.get_hl(), src),
-            _ => panic!("MOV only accepts registers or a memory location",),
+            Operand::M => {
+                let hl = self.registers.get_hl();
+                self.bus_write(BusCycleKind::MemoryWrite, hl, src)
+            }
         }
     }
 
     // The byte of immediate data is stored in the specified register or memory
     // byte.
     // Condition bits affected: None
-    fn mvi(&mut self, dest: Operand, val: u8) {
+    #[inline(always)]
+    pub(crate) fn mvi(&mut self, dest: Operand, val: u8) {
         match dest {
             Operand::A => self.registers.a = val,
             Operand::B => self.registers.b = val,
@@ -1304,8 +1981,10 @@ where
             Operand::E => self.registers.e = val,
             Operand::H => self.registers.h = val,
             Operand::L => self.registers.l = val,
-            Operand::M => self.memory.write(self.registers.get_hl(), val),
-            _ => panic!("MVI only accepts registers or a memory location",),
+            Operand::M => {
+                let hl = self.registers.get_hl();
+                self.bus_write(BusCycleKind::MemoryWrite, hl, val)
+            }
         }
     }
 
@@ -1318,116 +1997,139 @@ where
     // significant 8 bits of the stack pointer, while the third byte of the
     // instruction replaces the most significant 8 bits of the stack pointer.
     // Condition bits affected: None
-    fn lxi(&mut self, dest: Operand, val: u16) {
+    #[inline(always)]
+    pub(crate) fn lxi(&mut self, dest: RegisterPair, val: u16) {
         match dest {
-            Operand::B => self.registers.set_bc(val),
-            Operand::D => self.registers.set_de(val),
-            Operand::H => self.registers.set_hl(val),
-            Operand::SP => self.sp = val,
-            _ => panic!("LXI only accepts B, D, H, or SP as destinations",),
+            RegisterPair::BC => self.registers.set_bc(val),
+            RegisterPair::DE => self.registers.set_de(val),
+            RegisterPair::HL => self.registers.set_hl(val),
+            RegisterPair::SP => self.sp = val,
         }
     }
 
     // The contents of the accumulator are stored in the memory location
     // addressed by registers B and C, or by registers D and E.
     // Condition bits affected: None
-    fn stax(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn stax(&mut self, reg: IndexPair) {
         match reg {
-            Operand::B => self.memory.write(self.registers.get_bc(), self.registers.a),
-            Operand::D => self.memory.write(self.registers.get_de(), self.registers.a),
-            _ => panic!("STAX only accepts B and D as operands",),
+            IndexPair::BC => {
+                let bc = self.registers.get_bc();
+                self.bus_write(BusCycleKind::MemoryWrite, bc, self.registers.a)
+            }
+            IndexPair::DE => {
+                let de = self.registers.get_de();
+                self.bus_write(BusCycleKind::MemoryWrite, de, self.registers.a)
+            }
         }
     }
 
     // The contents of the memory location addressed by registers B and C, or
     // by registers D and E, replace the contents of the accumulator.
     // Condition bits affected: None
-    fn ldax(&mut self, reg: Operand) {
+    #[inline(always)]
+    pub(crate) fn ldax(&mut self, reg: IndexPair) {
         match reg {
-            Operand::B => self.registers.a = self.memory.read(self.registers.get_bc()),
-            Operand::D => self.registers.a = self.memory.read(self.registers.get_de()),
-            _ => panic!("LDAX only accepts B and D as operands",),
+            IndexPair::BC => {
+                let bc = self.registers.get_bc();
+                self.registers.a = self.bus_read(BusCycleKind::MemoryRead, bc)
+            }
+            IndexPair::DE => {
+                let de = self.registers.get_de();
+                self.registers.a = self.bus_read(BusCycleKind::MemoryRead, de)
+            }
         }
     }
 
     // The contents of the accumulator replace the byte at the memory address given
     // Condition bits affected: None
-    fn sta(&mut self, addr: u16) {
-        self.memory.write(addr, self.registers.a);
+    pub(crate) fn sta(&mut self, addr: u16) {
+        self.bus_write(BusCycleKind::MemoryWrite, addr, self.registers.a);
     }
 
     // The contents at the memory address given replaces the contents of the accumulator
     // Condition bits affected: None
-    fn lda(&mut self, addr: u16) {
-        self.registers.a = self.memory.read(addr);
+    pub(crate) fn lda(&mut self, addr: u16) {
+        self.registers.a = self.bus_read(BusCycleKind::MemoryRead, addr);
     }
 
     // The contents of the L register are stored at the memory address given and the
     // contents of the H register are stored at the next higher memory address.
     // Condition bits affected: None
-    fn shld(&mut self, addr: u16) {
-        self.memory.write(addr, self.registers.l);
-        self.memory.write(addr.wrapping_add(1), self.registers.h);
+    pub(crate) fn shld(&mut self, addr: u16) {
+        self.bus_write(BusCycleKind::MemoryWrite, addr, self.registers.l);
+        self.bus_write(
+            BusCycleKind::MemoryWrite,
+            addr.wrapping_add(1),
+            self.registers.h,
+        );
     }
 
     // The byte at the memory address formed replaces the contents of the L register.
     // The byte at the next higher memory address replaces the contents of the H register.
     // Condition bits affected: None
-    fn lhld(&mut self, addr: u16) {
-        self.registers.l = self.memory.read(addr);
-        self.registers.h = self.memory.read(addr.wrapping_add(1));
+    pub(crate) fn lhld(&mut self, addr: u16) {
+        self.registers.l = self.bus_read(BusCycleKind::MemoryRead, addr);
+        self.registers.h = self.bus_read(BusCycleKind::MemoryRead, addr.wrapping_add(1));
     }
 
     // The 16 bits of data held in the H and L registers are exchanged with the 16 bits
     // of data held in the D and E registers.
     // Condition bits affected: None
-    fn xchg(&mut self) {
+    pub(crate) fn xchg(&mut self) {
         let temp = self.registers.get_hl();
         self.registers.set_hl(self.registers.get_de());
         self.registers.set_de(temp);
     }
 }
 
+// The tests compare flags with assert_eq! against true and false.
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::machine::{Machine, System};
+    use crate::test_support::{self, MockMachine, MockMemory, Timer};
 
-    struct MockMemory {
-        pub memory: [u8; 0xFFFF],
+    #[derive(Default)]
+    struct MockClockedMachine {
+        ticks: u32,
+        request: Option<u8>,
+        acknowledged: u32,
+        // The events delivered and the cycle each was delivered on.
+        events: Vec<(EventId, u64)>,
     }
 
-    impl MockMemory {
-        fn new() -> Self {
-            Self {
-                memory: [0; 0xFFFF],
-            }
+    impl MachineIO for MockClockedMachine {
+        fn machine_in(&mut self, _: u8) -> u8 {
+            0
         }
-    }
 
-    impl MemoryMap for MockMemory {
-        fn load_rom(&mut self) {}
+        fn machine_out(&mut self, _: &mut CpuView, _: u8, _: u8) {}
+    }
 
-        fn read(&mut self, addr: u16) -> u8 {
-            self.memory[addr as usize]
+    impl ClockedIO for MockClockedMachine {
+        fn tick(&mut self, cycles: u32) {
+            self.ticks += cycles;
         }
 
-        fn read_slice(&mut self, addr: u16) -> &[u8] {
-            &self.memory[addr as usize..]
+        fn interrupt_request(&mut self) -> Option<u8> {
+            self.request
         }
 
-        fn write(&mut self, addr: u16, val: u8) {
-            self.memory[addr as usize] = val;
+        fn interrupt_acknowledge(&mut self) {
+            self.request = None;
+            self.acknowledged += 1;
         }
-    }
-
-    struct MockMachine;
 
-    impl MachineIO for MockMachine {
-        fn machine_in(&mut self, _: u8) -> u8 {
-            0
+        // EventId(1) requests RST 1 and recurs every 100 cycles.
+        fn event(&mut self, scheduler: &mut SchedulerView, event: EventId) {
+            self.events.push((event, scheduler.now()));
+            if event == EventId(1) {
+                self.request = Some(1);
+                scheduler.schedule(100, EventId(1));
+            }
         }
-
-        fn machine_out<M: MemoryMap>(&mut self, _: &mut Cpu<M>, _: u8, _: u8) {}
     }
 
     #[test]
@@ -2085,11 +2787,11 @@ mod tests {
         let mut cpu = Cpu::new(MockMemory::new());
         cpu.registers.d = 0x38;
         cpu.registers.e = 0xFF;
-        cpu.execute(&Instruction::INX(Operand::D), &mut MockMachine);
+        cpu.execute(&Instruction::INX(RegisterPair::DE), &mut MockMachine);
         assert_eq!(cpu.registers.d, 0x39);
         assert_eq!(cpu.registers.e, 0x00);
         cpu.sp = 0xFFFF;
-        cpu.execute(&Instruction::INX(Operand::SP), &mut MockMachine);
+        cpu.execute(&Instruction::INX(RegisterPair::SP), &mut MockMachine);
         assert_eq!(cpu.sp, 0x0000);
     }
 
@@ -2098,7 +2800,7 @@ mod tests {
         let mut cpu = Cpu::new(MockMemory::new());
         cpu.registers.h = 0x98;
         cpu.registers.l = 0x00;
-        cpu.execute(&Instruction::DCX(Operand::H), &mut MockMachine);
+        cpu.execute(&Instruction::DCX(RegisterPair::HL), &mut MockMachine);
         assert_eq!(cpu.registers.h, 0x97);
         assert_eq!(cpu.registers.l, 0xFF);
     }
@@ -2111,7 +2813,7 @@ mod tests {
         cpu.registers.h = 0xA1;
         cpu.registers.l = 0x7B;
         cpu.condition_codes.carry = true;
-        cpu.execute(&Instruction::DAD(Operand::B), &mut MockMachine);
+        cpu.execute(&Instruction::DAD(RegisterPair::BC), &mut MockMachine);
         assert_eq!(cpu.registers.h, 0xD5);
         assert_eq!(cpu.registers.l, 0x1A);
         assert_eq!(cpu.condition_codes.carry, false);
@@ -2123,7 +2825,7 @@ mod tests {
         cpu.registers.d = 0x8F;
         cpu.registers.e = 0x9D;
         cpu.sp = 0x3A2C;
-        cpu.execute(&Instruction::PUSH(Operand::D), &mut MockMachine);
+        cpu.execute(&Instruction::PUSH(PushPair::DE), &mut MockMachine);
         assert_eq!(cpu.memory.read(0x3A2B), 0x8F);
         assert_eq!(cpu.memory.read(0x3A2A), 0x9D);
         assert_eq!(cpu.sp, 0x3A2A);
@@ -2137,7 +2839,7 @@ mod tests {
         cpu.condition_codes.sign = false;
         cpu.condition_codes.aux_carry = false;
 
-        cpu.execute(&Instruction::PUSH(Operand::PSW), &mut MockMachine);
+        cpu.execute(&Instruction::PUSH(PushPair::PSW), &mut MockMachine);
         assert_eq!(cpu.memory.read(0x5029), 0x1F);
         assert_eq!(cpu.memory.read(0x5028), 0x47);
         assert_eq!(cpu.sp, 0x5028);
@@ -2149,7 +2851,7 @@ mod tests {
         cpu.memory.write(0x1239, 0x3D);
         cpu.memory.write(0x123A, 0x93);
         cpu.sp = 0x1239;
-        cpu.execute(&Instruction::POP(Operand::H), &mut MockMachine);
+        cpu.execute(&Instruction::POP(PushPair::HL), &mut MockMachine);
         assert_eq!(cpu.registers.l, 0x3D);
         assert_eq!(cpu.registers.h, 0x93);
         assert_eq!(cpu.sp, 0x123B);
@@ -2158,7 +2860,7 @@ mod tests {
         cpu.memory.write(0x2C00, 0xC3);
         cpu.memory.write(0x2C01, 0xFF);
         cpu.sp = 0x2C00;
-        cpu.execute(&Instruction::POP(Operand::PSW), &mut MockMachine);
+        cpu.execute(&Instruction::POP(PushPair::PSW), &mut MockMachine);
         assert_eq!(cpu.registers.a, 0xFF);
         assert_eq!(cpu.condition_codes.carry, true);
         assert_eq!(cpu.condition_codes.zero, true);
@@ -2183,6 +2885,19 @@ mod tests {
         assert_eq!(cpu.interrupts_enabled, false);
     }
 
+    #[test]
+    fn test_hlt() {
+        let mut memory = MockMemory::new();
+        memory.memory[0x0100] = 0x76;
+        let mut cpu = Cpu::new(memory);
+        cpu.pc = 0x0100;
+        let instr = cpu.fetch();
+        assert_eq!(instr, Instruction::HLT);
+        let (next_pc, _) = cpu.execute(&instr, &mut MockMachine);
+        assert!(cpu.is_halted);
+        assert_eq!(next_pc, 0x0101);
+    }
+
     #[test]
     fn test_sphl() {
         let mut cpu = Cpu::new(MockMemory::new());
@@ -2207,17 +2922,58 @@ mod tests {
         assert_eq!(cpu.memory.read(0x10AE), 0x0B);
     }
 
-    //TODO: main function not yet implemented
-    //#[test]
-    //fn test_input() { //IN opcode ('in' is a reserved keyword)
+    // Echoes IN port numbers back and, on OUT, records the value written
+    // along with what DE points at in memory.
+    #[derive(Default)]
+    struct PortMachine {
+        outputs: Vec<(u8, u8, u8)>,
+    }
 
-    //}
+    impl MachineIO for PortMachine {
+        fn machine_in(&mut self, port: u8) -> u8 {
+            port.wrapping_mul(2)
+        }
 
-    //TODO: main function not yet implemented
-    //#[test]
-    //fn test_output() { //OUT opcode
+        fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+            let addr = cpu.registers.get_de();
+            self.outputs.push((port, val, cpu.memory.read(addr)));
+            if port == 0xFF {
+                cpu.halt();
+            }
+        }
+    }
 
-    //}
+    #[test]
+    fn test_input() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.execute(&Instruction::IN(0x21), &mut PortMachine::default());
+        assert_eq!(cpu.registers.a, 0x42);
+    }
+
+    #[test]
+    fn test_output() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut machine = PortMachine::default();
+        cpu.registers.a = 0x7E;
+        cpu.registers.set_de(0x2000);
+        cpu.memory.write(0x2000, 0x99);
+        cpu.execute(&Instruction::OUT(0x01), &mut machine);
+        assert_eq!(machine.outputs, vec![(0x01, 0x7E, 0x99)]);
+        assert!(!cpu.is_halted);
+
+        cpu.execute(&Instruction::OUT(0xFF), &mut machine);
+        assert!(cpu.is_halted);
+    }
+
+    #[test]
+    fn test_boxed_machine() {
+        let mut cpu: Cpu<Box<dyn MemoryMap>> = Cpu::new(Box::new(MockMemory::new()));
+        let mut machine: Box<dyn MachineIO> = Box::new(PortMachine::default());
+        cpu.execute(&Instruction::IN(0x10), &mut machine);
+        assert_eq!(cpu.registers.a, 0x20);
+        cpu.execute(&Instruction::OUT(0xFF), machine.as_mut());
+        assert!(cpu.is_halted);
+    }
 
     //#[test]
     //fn test_rim() {
@@ -2364,7 +3120,7 @@ mod tests {
     #[test]
     fn test_lxi() {
         let mut cpu = Cpu::new(MockMemory::new());
-        cpu.execute(&Instruction::LXI(Operand::H, 0x103), &mut MockMachine);
+        cpu.execute(&Instruction::LXI(RegisterPair::HL, 0x103), &mut MockMachine);
         assert_eq!(cpu.registers.h, 0x1);
         assert_eq!(cpu.registers.l, 0x3);
     }
@@ -2375,7 +3131,7 @@ mod tests {
         cpu.registers.a = 0x5C;
         cpu.registers.b = 0x3F;
         cpu.registers.c = 0x16;
-        cpu.execute(&Instruction::STAX(Operand::B), &mut MockMachine);
+        cpu.execute(&Instruction::STAX(IndexPair::BC), &mut MockMachine);
         assert_eq!(cpu.memory.read(0x3F16), 0x5C);
     }
 
@@ -2385,7 +3141,7 @@ mod tests {
         cpu.registers.d = 0x93;
         cpu.registers.e = 0x8B;
         cpu.memory.write(0x938B, 0x5C);
-        cpu.execute(&Instruction::LDAX(Operand::D), &mut MockMachine);
+        cpu.execute(&Instruction::LDAX(IndexPair::DE), &mut MockMachine);
         assert_eq!(cpu.registers.a, 0x5C);
     }
 
@@ -2438,4 +3194,494 @@ mod tests {
         assert_eq!(cpu.registers.h, 0x33);
         assert_eq!(cpu.registers.l, 0x55);
     }
+
+    struct MockBus {
+        cycles: Vec<BusCycle>,
+        waits: u8,
+    }
+
+    impl CycleBus for MockBus {
+        fn bus_cycle(&mut self, cycle: &BusCycle) -> u8 {
+            self.cycles.push(*cycle);
+            match cycle.kind {
+                BusCycleKind::MemoryWrite => self.waits,
+                _ => 0,
+            }
+        }
+    }
+
+    fn mock_bus(waits: u8) -> MockBus {
+        MockBus {
+            cycles: Vec::new(),
+            waits,
+        }
+    }
+
+    #[test]
+    fn test_execute_cycles_mvi_m() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut bus = mock_bus(0);
+        cpu.pc = 0x100;
+        cpu.memory.write(0x100, 0x36);
+        cpu.memory.write(0x101, 0x5A);
+        cpu.registers.set_hl(0x2400);
+        let instr = Instruction::from(cpu.memory.read_slice(cpu.pc));
+        let (next_pc, cycles) = cpu.execute_cycles(&instr, &mut MockMachine, &mut bus);
+
+        assert_eq!(next_pc, 0x102);
+        assert_eq!(cycles, 10);
+        let summary: Vec<_> = bus
+            .cycles
+            .iter()
+            .map(|c| (c.kind, c.address, c.data, c.t_state, c.length))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (BusCycleKind::InstructionFetch, 0x100, 0x36, 0, 4),
+                (BusCycleKind::MemoryRead, 0x101, 0x5A, 4, 3),
+                (BusCycleKind::MemoryWrite, 0x2400, 0x5A, 7, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_cycles_wait_states() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut bus = mock_bus(2);
+        cpu.sp = 0x2400;
+        let (next_pc, cycles) =
+            cpu.execute_cycles(&Instruction::CALL(0x1234), &mut MockMachine, &mut bus);
+
+        // Stack writes are not affected by the memory write wait states.
+        assert_eq!(next_pc, 0x1234);
+        assert_eq!(cycles, 17);
+
+        cpu.registers.set_hl(0x2000);
+        let mut bus = mock_bus(2);
+        let (_, cycles) =
+            cpu.execute_cycles(&Instruction::SHLD(0x2000), &mut MockMachine, &mut bus);
+        assert_eq!(cycles, 20);
+        let t_states: Vec<_> = bus.cycles.iter().map(|c| c.t_state).collect();
+        assert_eq!(t_states, vec![0, 4, 7, 10, 15]);
+    }
+
+    #[test]
+    fn test_execute_cycles_stack() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut bus = mock_bus(0);
+        cpu.sp = 0x2400;
+        cpu.pc = 0x0200;
+        cpu.execute_cycles(&Instruction::CALL(0x1234), &mut MockMachine, &mut bus);
+        let summary: Vec<_> = bus
+            .cycles
+            .iter()
+            .map(|c| (c.kind, c.address, c.data, c.length))
+            .collect();
+        assert_eq!(
+            summary[3..],
+            [
+                (BusCycleKind::StackWrite, 0x23FF, 0x02, 3),
+                (BusCycleKind::StackWrite, 0x23FE, 0x03, 3),
+            ]
+        );
+        assert_eq!(bus.cycles[0].length, 5);
+
+        let mut bus = mock_bus(0);
+        let (_, cycles) = cpu.execute_cycles(&Instruction::XTHL, &mut MockMachine, &mut bus);
+        assert_eq!(cycles, 18);
+        let lengths: Vec<_> = bus.cycles.iter().map(|c| (c.kind, c.length)).collect();
+        assert_eq!(
+            lengths,
+            vec![
+                (BusCycleKind::InstructionFetch, 4),
+                (BusCycleKind::StackRead, 3),
+                (BusCycleKind::StackRead, 3),
+                (BusCycleKind::StackWrite, 3),
+                (BusCycleKind::StackWrite, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_cycles_io() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut bus = mock_bus(0);
+        cpu.registers.a = 0x42;
+        cpu.execute_cycles(&Instruction::OUT(0x05), &mut MockMachine, &mut bus);
+        let out = bus.cycles[2];
+        assert_eq!(out.kind, BusCycleKind::OutputWrite);
+        assert_eq!(out.address, 0x0505);
+        assert_eq!(out.data, 0x42);
+        assert_eq!(out.t_state, 7);
+    }
+
+    #[test]
+    fn test_interrupt_cycles() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut bus = mock_bus(0);
+        cpu.sp = 0x2400;
+        cpu.pc = 0x1A2B;
+        assert_eq!(cpu.interrupt_cycles(0x10, &mut bus), 0);
+        assert!(bus.cycles.is_empty());
+
+        cpu.interrupts_enabled = true;
+        assert_eq!(cpu.interrupt_cycles(0x10, &mut bus), 11);
+        assert_eq!(cpu.pc, 0x10);
+        let ack = bus.cycles[0];
+        assert_eq!(ack.kind, BusCycleKind::InterruptAck);
+        assert_eq!(ack.address, 0x1A2B);
+        assert_eq!(ack.data, 0xD7);
+        assert_eq!(ack.length, 5);
+        assert_eq!(bus.cycles.len(), 3);
+    }
+
+    #[test]
+    fn test_cycle_counter() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.sp = 0x2400;
+        cpu.execute(&Instruction::NOP, &mut MockMachine);
+        cpu.execute(&Instruction::CALL(0x1000), &mut MockMachine);
+        assert_eq!(cpu.cycles, 21);
+
+        cpu.condition_codes.zero = true;
+        cpu.execute(&Instruction::RZ, &mut MockMachine);
+        assert_eq!(cpu.cycles, 32);
+
+        let mut bus = mock_bus(3);
+        cpu.execute_cycles(&Instruction::STA(0x2000), &mut MockMachine, &mut bus);
+        assert_eq!(cpu.cycles, 48);
+
+        cpu.interrupts_enabled = true;
+        cpu.interrupt(0x08);
+        assert_eq!(cpu.cycles, 59);
+    }
+
+    #[test]
+    fn test_run_for_events() {
+        // An infinite loop of NOPs at 0x0000 followed by JMP 0x0000.
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.memory.write(0x0003, 0xC3);
+        cpu.schedule(30, EventId(1));
+        cpu.schedule(30, EventId(2));
+        cpu.schedule(5, EventId(3));
+
+        let mut fired = Vec::new();
+        cpu.run_for(100, &mut MockMachine, |cpu, _, event| {
+            fired.push((event, cpu.cycles));
+            if event == EventId(3) {
+                cpu.schedule(40, EventId(4));
+            }
+        });
+
+        // 4 cycles per NOP and 10 for the JMP, so each pass is 22 cycles.
+        assert_eq!(
+            fired,
+            vec![
+                (EventId(3), 8),
+                (EventId(1), 30),
+                (EventId(2), 30),
+                (EventId(4), 48),
+            ]
+        );
+        assert!(cpu.cycles >= 100);
+        assert!(cpu.scheduler.is_empty());
+    }
+
+    #[test]
+    fn test_cloned_cpu_replays_events() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.schedule(1000, EventId(9));
+        cpu.run_for(400, &mut MockMachine, |_, _, _| {});
+        let mut snapshot = cpu.clone();
+
+        let mut first = Vec::new();
+        cpu.run_for(1000, &mut MockMachine, |cpu, _, event| {
+            first.push((event, cpu.cycles))
+        });
+        let mut second = Vec::new();
+        snapshot.run_for(1000, &mut MockMachine, |cpu, _, event| {
+            second.push((event, cpu.cycles))
+        });
+        assert_eq!(first.len(), 1);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_step_clocked_ticks() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut machine = MockClockedMachine::default();
+        cpu.memory.write(0x0003, 0xC3);
+        cpu.run_clocked(100, &mut machine);
+        assert_eq!(machine.ticks as u64, cpu.cycles);
+        assert!(cpu.cycles >= 100);
+    }
+
+    #[test]
+    fn test_step_clocked_interrupt() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut machine = MockClockedMachine {
+            request: Some(2),
+            ..Default::default()
+        };
+        cpu.sp = 0x2400;
+
+        // The request is held while interrupts are disabled.
+        assert_eq!(cpu.step_clocked(&mut machine), 4);
+        assert_eq!(machine.acknowledged, 0);
+        assert_eq!(cpu.pc, 0x0001);
+
+        // EI, then the interrupt is accepted.
+        cpu.memory.write(0x0001, 0xFB);
+        assert_eq!(cpu.step_clocked(&mut machine), 15);
+        assert_eq!(machine.acknowledged, 1);
+        assert_eq!(machine.ticks, 19);
+        assert_eq!(cpu.pc, 0x10);
+        assert_eq!(cpu.memory.read(0x23FE), 0x02);
+        assert!(!cpu.interrupts_enabled);
+    }
+
+    #[test]
+    fn test_interrupt_wakes_halted_cpu() {
+        // EI; HLT; MVI A,1; OUT 0
+        // 0008H: INR B; EI; RET
+        let program: &[(u16, &[u8])] = &[
+            (0x0000, &[0xFB, 0x76, 0x3E, 0x01, 0xD3, 0x00]),
+            (0x0008, &[0x04, 0xFB, 0xC9]),
+        ];
+
+        // Halted, the cpu only burns cycles until the timer's RST 1.
+        let mut cpu = test_support::cpu(program);
+        let mut timer = Timer::default();
+        cpu.step_clocked(&mut timer);
+        cpu.step_clocked(&mut timer);
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cpu.step_clocked(&mut timer), HALT_IDLE_CYCLES);
+        assert_eq!(cpu.pc, 0x0002);
+
+        // The interrupt wakes it, and its handler returns after the HLT.
+        cpu.run_clocked(140, &mut timer);
+        assert_eq!(cpu.registers.b, 1);
+        assert_eq!(cpu.registers.a, 1);
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.pc, 0x0006);
+
+        // Under run_for, a scheduled event can wake it.
+        let mut cpu = test_support::cpu(program);
+        cpu.schedule(1000, EventId(1));
+        cpu.run_for(100, &mut MockMachine, |_, _, _| {});
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.cycles, 100);
+        cpu.run_for(2000, &mut MockMachine, |cpu, _, _| cpu.interrupt(0x08));
+        assert_eq!(cpu.registers.b, 1);
+        assert!(cpu.is_halted);
+        assert_eq!(cpu.pc, 0x0006);
+        assert_eq!(cpu.cycles, 2100);
+    }
+
+    #[test]
+    fn test_run_clocked_events() {
+        // EI; loop: HLT; JMP loop
+        // 0008H: INR B; EI; RET
+        let mut cpu = test_support::cpu(&[
+            (0x0000, &[0xFB, 0x76, 0xC3, 0x01, 0x00]),
+            (0x0008, &[0x04, 0xFB, 0xC9]),
+        ]);
+        let mut machine = MockClockedMachine::default();
+        cpu.schedule(100, EventId(1));
+        cpu.schedule(50, EventId(2));
+        cpu.run_clocked(1000, &mut machine);
+
+        // EI and HLT take 11 cycles, then the cpu idles 4 at a time.
+        assert_eq!(machine.events[..2], [(EventId(2), 51), (EventId(1), 103)]);
+        // Each EventId(1) woke the cpu with an interrupt.
+        let interrupts = machine.events.len() as u32 - 1;
+        assert!(interrupts > 5);
+        assert_eq!(machine.acknowledged, interrupts);
+        assert!(cpu.registers.b as u32 >= interrupts - 1);
+        assert_eq!(machine.ticks as u64, cpu.cycles);
+    }
+
+    #[test]
+    fn test_dyn_machine() {
+        let mut machines: Vec<Box<dyn Machine>> = vec![
+            Box::new(System::new(
+                MockMemory::new(),
+                MockClockedMachine::default(),
+            )),
+            Box::new(System::new(
+                Box::new(MockMemory::new()) as Box<dyn MemoryMap>,
+                Box::new(MockClockedMachine::default()) as Box<dyn ClockedIO>,
+            )),
+        ];
+
+        for machine in machines.iter_mut() {
+            // OUT 0xFF followed by a jump back to 0x0000.
+            machine.memory().write(0x0000, 0xD3);
+            machine.memory().write(0x0001, 0xFF);
+            machine.memory().write(0x0002, 0xC3);
+            machine.run_for(100);
+            assert!(machine.cycles() >= 100);
+            assert!(!machine.is_halted());
+            assert_eq!(machine.io().interrupt_request(), None);
+            assert!(machine.cpu_debug().sanitizer_mut().is_none());
+            assert!(machine.cpu_debug().backtrace().is_none());
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut state = cpu.state();
+        state.set_pc(0x0100);
+        state.set_sp(0x2400);
+        state.set_hl(0x2000);
+        state.set_a(0x0F);
+        state.set_carry(true);
+        cpu.set_state(&state);
+
+        // ADC M
+        cpu.memory.write(0x0100, 0x8E);
+        cpu.memory.write(0x2000, 0xF0);
+        let instr = Instruction::from(cpu.memory.read_slice(cpu.pc));
+        let (next_pc, _) = cpu.execute(&instr, &mut MockMachine);
+        cpu.pc = next_pc;
+
+        let mut expected = state.clone();
+        expected.set_pc(0x0101);
+        expected.set_a(0x00);
+        expected.set_carry(true);
+        expected.set_zero(true);
+        expected.set_parity(true);
+        expected.set_aux_carry(true);
+        assert_eq!(cpu.state(), expected);
+    }
+
+    #[test]
+    fn test_state_events() {
+        // NOPs, 4 cycles each.
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.schedule(50, EventId(2));
+        cpu.schedule(20, EventId(3));
+        cpu.run_for(12, &mut MockMachine, |_, _, _| {});
+        let state = cpu.state();
+        assert_eq!(state.events(), [(8, EventId(3)), (38, EventId(2))]);
+
+        // The events fire as far ahead of a restored cpu's cycle counter.
+        let mut restored = Cpu::new(MockMemory::new());
+        restored.cycles = 1000;
+        restored.set_state(&state);
+        let mut fired = Vec::new();
+        restored.run_for(100, &mut MockMachine, |cpu, _, event| {
+            fired.push((event, cpu.cycles))
+        });
+        assert_eq!(fired, [(EventId(3), 1008), (EventId(2), 1040)]);
+    }
+
+    #[test]
+    fn test_decode_cache_self_modifying_code() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.enable_decode_cache();
+        let step = |cpu: &mut Cpu<MockMemory>| {
+            let instr = cpu.fetch();
+            let (next_pc, _) = cpu.execute(&instr, &mut MockMachine);
+            cpu.pc = next_pc;
+        };
+
+        // MVI B,0x01
+        cpu.memory.write(0x100, 0x06);
+        cpu.memory.write(0x101, 0x01);
+        // MVI A,0x07; STA 0x0101
+        cpu.memory.write(0x200, 0x3E);
+        cpu.memory.write(0x201, 0x07);
+        cpu.memory.write(0x202, 0x32);
+        cpu.memory.write(0x203, 0x01);
+        cpu.memory.write(0x204, 0x01);
+
+        cpu.pc = 0x100;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0x01);
+
+        cpu.pc = 0x200;
+        step(&mut cpu);
+        step(&mut cpu);
+        cpu.pc = 0x100;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0x07);
+
+        // Writes made directly to memory must be reported.
+        cpu.memory.write(0x101, 0x09);
+        cpu.pc = 0x100;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0x07);
+        cpu.invalidate_decoded(0x101);
+        cpu.pc = 0x100;
+        step(&mut cpu);
+        assert_eq!(cpu.registers.b, 0x09);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.pc = 0x1234;
+        cpu.sp = 0x2400;
+        cpu.registers.a = 0x42;
+        cpu.interrupts_enabled = true;
+        cpu.is_halted = true;
+
+        cpu.reset();
+        assert_eq!(cpu.pc, 0);
+        assert!(!cpu.interrupts_enabled);
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.sp, 0x2400);
+        assert_eq!(cpu.registers.a, 0x42);
+    }
+
+    #[test]
+    fn test_power_on() {
+        let power_on = PowerOn::new(0x8080).with_ram(0x2000..=0x23FF);
+        let mut cpu = Cpu::new(MockMemory::new());
+        cpu.pc = 0x1234;
+        cpu.power_on(&power_on);
+        assert_eq!(cpu.pc, 0);
+        assert!(!cpu.interrupts_enabled);
+        assert_ne!(cpu.state(), CpuState::new());
+        assert!(cpu.memory.memory[0x2000..=0x23FF].iter().any(|&b| b != 0));
+        assert!(cpu.memory.memory[..0x2000].iter().all(|&b| b == 0));
+        assert!(cpu.memory.memory[0x2400..].iter().all(|&b| b == 0));
+
+        // The same seed gives the same state, a different seed does not.
+        let mut same = Cpu::new(MockMemory::new());
+        same.power_on(&power_on);
+        assert_eq!(same.state(), cpu.state());
+        assert_eq!(&same.memory.memory[..], &cpu.memory.memory[..]);
+
+        let mut other = Cpu::new(MockMemory::new());
+        other.power_on(&PowerOn::new(0x8085));
+        assert_ne!(other.state(), cpu.state());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut cpu = Cpu::new(MockMemory::new());
+        let mut machine = MockClockedMachine::default();
+        // NOPs up to a JMP 0000H at 0010H.
+        cpu.memory.write(0x0010, 0xC3);
+        cpu.add_breakpoint(0x0004);
+        cpu.add_breakpoint(0x0010);
+
+        cpu.run_clocked(1000, &mut machine);
+        assert_eq!(cpu.pc, 0x0004);
+        assert_eq!(cpu.take_breakpoint_hit(), Some(0x0004));
+        assert_eq!(cpu.take_breakpoint_hit(), None);
+
+        // Running again continues past the breakpoint.
+        cpu.run_clocked(1000, &mut machine);
+        assert_eq!(cpu.take_breakpoint_hit(), Some(0x0010));
+        cpu.remove_breakpoint(0x0010);
+        cpu.run_clocked(1000, &mut machine);
+        assert_eq!(cpu.take_breakpoint_hit(), Some(0x0004));
+        assert_eq!(cpu.breakpoints().collect::<Vec<_>>(), [0x0004]);
+    }
 }
@@ -0,0 +1,476 @@
+use crate::call_stack::{Backtrace, Frame, FrameKind};
+use crate::history::{Direction, Executed, IoAccess};
+use crate::state::CpuState;
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+// The state of a machine when it crashed, for post-mortem inspection: the
+// cpu, the instructions and I/O leading up to the crash if history was
+// enabled, the call stack if it was enabled, and an image of memory. Captured
+// with Cpu::crash_dump.
+//
+// A dump is stored as follows. Every multi-byte number is little-endian.
+//
+//   offset  size  contents
+//   0       4     "I80D"
+//   4       1     format version, 1
+//   5       7     A, B, C, D, E, H, L
+//   12      1     flags, as pushed by PUSH PSW
+//   13      2     SP
+//   15      2     PC
+//   17      1     interrupts enabled, 0 or 1
+//   18      1     halted, 0 or 1
+//   19      8     cycles executed
+//   27      2     length of the reason in bytes, N
+//   29      N     reason, UTF-8
+//
+// followed by these sections in order, each a 2-byte count of entries and
+// then the entries:
+//
+//   instructions, oldest first, 14 bytes each:
+//       PC (2), cycle count when it started (8), length (1), bytes (3, unused
+//       bytes zero)
+//   I/O, oldest first, 13 bytes each:
+//       PC (2), cycle count (8), direction (1, 0 for IN and 1 for OUT),
+//       port (1), value (1)
+//   call stack frames, innermost first, 9 bytes each:
+//       kind (1, 0 for a call, 1 for a restart and 2 for an interrupt), call
+//       site (2), target (2), return address (2), SP (2)
+//   unmapped ranges, 4 bytes each:
+//       first address (2), last address (2)
+//
+// and finally the 65536 bytes of memory, with unmapped addresses zero. The
+// frame count is FFFFH, with no frames, if the call stack was not enabled.
+//
+// A section holds at most FFFFH entries, so only the newest FFFFH
+// instructions and I/O accesses and the innermost FFFEH frames of a larger
+// history or call stack are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrashDump {
+    pub reason: String,
+    pub state: CpuState,
+    pub cycles: u64,
+    pub instructions: Vec<Executed>,
+    pub io: Vec<IoAccess>,
+    pub backtrace: Option<Backtrace>,
+    // The address ranges where nothing is mapped.
+    pub unmapped: Vec<RangeInclusive<u16>>,
+    pub memory: Vec<u8>,
+}
+
+const MAGIC: &[u8; 4] = b"I80D";
+const VERSION: u8 = 1;
+const NO_CALL_STACK: u16 = 0xFFFF;
+// The most entries a section can hold.
+const MAX_ENTRIES: usize = 0xFFFF;
+
+impl CrashDump {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state = &self.state;
+        let mut out = Vec::with_capacity(0x10000 + 0x1000);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&[
+            state.a(),
+            state.b(),
+            state.c(),
+            state.d(),
+            state.e(),
+            state.h(),
+            state.l(),
+            state.flags(),
+        ]);
+        out.extend_from_slice(&state.sp().to_le_bytes());
+        out.extend_from_slice(&state.pc().to_le_bytes());
+        out.push(state.interrupts_enabled() as u8);
+        out.push(state.is_halted() as u8);
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        let reason = &self.reason.as_bytes()[..self.reason.len().min(0xFFFF)];
+        out.extend_from_slice(&(reason.len() as u16).to_le_bytes());
+        out.extend_from_slice(reason);
+
+        let count = |out: &mut Vec<u8>, n: usize| out.extend_from_slice(&(n as u16).to_le_bytes());
+
+        let instructions = &self.instructions[newest(self.instructions.len())..];
+        count(&mut out, instructions.len());
+        for executed in instructions.iter() {
+            out.extend_from_slice(&executed.pc.to_le_bytes());
+            out.extend_from_slice(&executed.cycles.to_le_bytes());
+            out.push(executed.len);
+            out.extend_from_slice(&executed.bytes);
+        }
+
+        let io = &self.io[newest(self.io.len())..];
+        count(&mut out, io.len());
+        for access in io.iter() {
+            out.extend_from_slice(&access.pc.to_le_bytes());
+            out.extend_from_slice(&access.cycles.to_le_bytes());
+            out.push(match access.direction {
+                Direction::In => 0,
+                Direction::Out => 1,
+            });
+            out.push(access.port);
+            out.push(access.val);
+        }
+
+        match self.backtrace.as_ref() {
+            Some(backtrace) => {
+                // Leave FFFFH for NO_CALL_STACK.
+                let frames = &backtrace.frames[..backtrace.frames.len().min(MAX_ENTRIES - 1)];
+                count(&mut out, frames.len());
+                for frame in frames.iter() {
+                    out.push(match frame.kind {
+                        FrameKind::Call => 0,
+                        FrameKind::Restart => 1,
+                        FrameKind::Interrupt => 2,
+                    });
+                    for word in [
+                        frame.call_site,
+                        frame.target,
+                        frame.return_address,
+                        frame.sp,
+                    ] {
+                        out.extend_from_slice(&word.to_le_bytes());
+                    }
+                }
+            }
+            None => count(&mut out, NO_CALL_STACK as usize),
+        }
+
+        let unmapped = &self.unmapped[..self.unmapped.len().min(MAX_ENTRIES)];
+        count(&mut out, unmapped.len());
+        for range in unmapped.iter() {
+            out.extend_from_slice(&range.start().to_le_bytes());
+            out.extend_from_slice(&range.end().to_le_bytes());
+        }
+
+        out.extend_from_slice(&self.memory);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CrashDump, CrashDumpError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(CrashDumpError::new("not a crash dump"));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(CrashDumpError::new(&format!(
+                "unsupported crash dump version {}",
+                version
+            )));
+        }
+
+        let mut state = CpuState::new();
+        state.set_a(reader.u8()?);
+        state.set_b(reader.u8()?);
+        state.set_c(reader.u8()?);
+        state.set_d(reader.u8()?);
+        state.set_e(reader.u8()?);
+        state.set_h(reader.u8()?);
+        state.set_l(reader.u8()?);
+        state.set_flags(reader.u8()?);
+        state.set_sp(reader.u16()?);
+        state.set_pc(reader.u16()?);
+        state.set_interrupts_enabled(reader.u8()? != 0);
+        state.set_halted(reader.u8()? != 0);
+        let cycles = reader.u64()?;
+        let len = reader.u16()? as usize;
+        let reason = String::from_utf8_lossy(reader.take(len)?).to_string();
+
+        let mut instructions = Vec::new();
+        for _ in 0..reader.u16()? {
+            let pc = reader.u16()?;
+            let cycles = reader.u64()?;
+            let len = reader.u8()?;
+            let mut bytes = [0; 3];
+            bytes.copy_from_slice(reader.take(3)?);
+            if !(1..=3).contains(&len) {
+                return Err(CrashDumpError::new(&format!(
+                    "instruction at {:04X}H has length {}",
+                    pc, len
+                )));
+            }
+            instructions.push(Executed {
+                pc,
+                cycles,
+                bytes,
+                len,
+            });
+        }
+
+        let mut io = Vec::new();
+        for _ in 0..reader.u16()? {
+            let pc = reader.u16()?;
+            let cycles = reader.u64()?;
+            let direction = match reader.u8()? {
+                0 => Direction::In,
+                1 => Direction::Out,
+                n => return Err(CrashDumpError::new(&format!("unknown I/O direction {}", n))),
+            };
+            io.push(IoAccess {
+                pc,
+                cycles,
+                direction,
+                port: reader.u8()?,
+                val: reader.u8()?,
+            });
+        }
+
+        let backtrace = match reader.u16()? {
+            NO_CALL_STACK => None,
+            n => {
+                let mut frames = Vec::new();
+                for _ in 0..n {
+                    let kind = match reader.u8()? {
+                        0 => FrameKind::Call,
+                        1 => FrameKind::Restart,
+                        2 => FrameKind::Interrupt,
+                        n => return Err(CrashDumpError::new(&format!("unknown frame kind {}", n))),
+                    };
+                    frames.push(Frame {
+                        kind,
+                        call_site: reader.u16()?,
+                        target: reader.u16()?,
+                        return_address: reader.u16()?,
+                        sp: reader.u16()?,
+                    });
+                }
+                Some(Backtrace {
+                    pc: state.pc(),
+                    frames,
+                })
+            }
+        };
+
+        let mut unmapped = Vec::new();
+        for _ in 0..reader.u16()? {
+            let start = reader.u16()?;
+            let end = reader.u16()?;
+            unmapped.push(start..=end);
+        }
+
+        let memory = reader.take(0x10000)?.to_vec();
+        if reader.offset != bytes.len() {
+            return Err(CrashDumpError::new(
+                "unexpected data after the memory image",
+            ));
+        }
+
+        Ok(CrashDump {
+            reason,
+            state,
+            cycles,
+            instructions,
+            io,
+            backtrace,
+            unmapped,
+            memory,
+        })
+    }
+
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        !self.unmapped.iter().any(|range| range.contains(&addr))
+    }
+}
+
+// Builds the memory image and unmapped ranges of a dump from a function
+// peeking at each address.
+pub(crate) fn capture_memory(
+    mut peek: impl FnMut(u16) -> Option<u8>,
+) -> (Vec<u8>, Vec<RangeInclusive<u16>>) {
+    let mut memory = vec![0; 0x10000];
+    let mut unmapped: Vec<RangeInclusive<u16>> = Vec::new();
+    for addr in 0..=0xFFFF {
+        match peek(addr) {
+            Some(val) => memory[addr as usize] = val,
+            None => match unmapped.last_mut() {
+                Some(range) if range.end().wrapping_add(1) == addr => {
+                    *range = *range.start()..=addr;
+                }
+                _ => unmapped.push(addr..=addr),
+            },
+        }
+    }
+    (memory, unmapped)
+}
+
+// An error from reading a crash dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrashDumpError {
+    pub message: String,
+}
+
+impl CrashDumpError {
+    fn new(message: &str) -> Self {
+        CrashDumpError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CrashDumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CrashDumpError {}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CrashDumpError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| CrashDumpError::new("crash dump is truncated"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CrashDumpError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CrashDumpError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, CrashDumpError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+// The index of the first of the newest MAX_ENTRIES of `len` entries.
+fn newest(len: usize) -> usize {
+    len.saturating_sub(MAX_ENTRIES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::history::History;
+    use crate::memory_bus::MemoryMap;
+    use crate::test_support::{cpu, run, MockMemory};
+
+    // MockMemory with nothing mapped from 8000H.
+    struct HalfMapped(MockMemory);
+
+    impl MemoryMap for HalfMapped {
+        fn load_rom(&mut self) {}
+
+        fn read(&mut self, addr: u16) -> u8 {
+            assert!(addr < 0x8000, "read from unmapped {:04X}H", addr);
+            self.0.read(addr)
+        }
+
+        fn read_slice(&mut self, addr: u16) -> &[u8] {
+            self.0.read_slice(addr)
+        }
+
+        fn write(&mut self, addr: u16, val: u8) {
+            self.0.write(addr, val);
+        }
+
+        fn peek(&mut self, addr: u16) -> Option<u8> {
+            if addr < 0x8000 {
+                Some(self.read(addr))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_crash_dump_round_trip() {
+        let program = cpu(&[
+            // LXI SP,2400H; CALL 0010H
+            (0x0000, &[0x31, 0x00, 0x24, 0xCD, 0x10, 0x00]),
+            // IN 07H; OUT 09H; LDA 9000H
+            (0x0010, &[0xDB, 0x07, 0xD3, 0x09, 0x3A, 0x00, 0x90]),
+        ]);
+        let mut cpu = Cpu::new(HalfMapped(program.memory));
+        cpu.enable_call_stack();
+        cpu.enable_history(History::new(3, 8));
+        run(&mut cpu, 4);
+
+        let dump = cpu.crash_dump("read from unmapped 9000H");
+        assert_eq!(dump.state.pc(), 0x0014);
+        assert_eq!(dump.unmapped, [0x8000..=0xFFFF]);
+        assert_eq!(&dump.memory[0x10..0x12], &[0xDB, 0x07]);
+        let pcs: Vec<u16> = dump.instructions.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, [0x0003, 0x0010, 0x0012]);
+        let io: Vec<(Direction, u8, u8)> = dump
+            .io
+            .iter()
+            .map(|a| (a.direction, a.port, a.val))
+            .collect();
+        assert_eq!(io, [(Direction::In, 7, 0), (Direction::Out, 9, 0)]);
+        assert_eq!(dump.backtrace.as_ref().unwrap().frames.len(), 1);
+
+        let bytes = dump.to_bytes();
+        assert_eq!(&bytes[..5], b"I80D\x01");
+        assert_eq!(CrashDump::from_bytes(&bytes), Ok(dump.clone()));
+
+        cpu.disable_call_stack();
+        let dump = cpu.crash_dump("");
+        assert_eq!(dump.backtrace, None);
+        assert_eq!(CrashDump::from_bytes(&dump.to_bytes()), Ok(dump));
+
+        assert!(CrashDump::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(CrashDump::from_bytes(b"I80S").is_err());
+    }
+
+    #[test]
+    fn test_crash_dump_limits_sections() {
+        let executed = |pc: u32| Executed {
+            pc: pc as u16,
+            cycles: pc as u64,
+            bytes: [0; 3],
+            len: 1,
+        };
+        let frame = |sp: u32| Frame {
+            kind: FrameKind::Call,
+            call_site: 0,
+            target: 0,
+            return_address: 0,
+            sp: sp as u16,
+        };
+        let dump = CrashDump {
+            reason: String::new(),
+            state: CpuState::new(),
+            cycles: 0,
+            instructions: (0..0x10001).map(executed).collect(),
+            io: Vec::new(),
+            backtrace: Some(Backtrace {
+                pc: 0,
+                frames: (0..0x10000).map(frame).collect(),
+            }),
+            unmapped: Vec::new(),
+            memory: vec![0; 0x10000],
+        };
+
+        // Only the newest instructions and the innermost frames are kept.
+        let parsed = CrashDump::from_bytes(&dump.to_bytes()).unwrap();
+        assert_eq!(parsed.instructions.len(), 0xFFFF);
+        assert_eq!(parsed.instructions[0], executed(2));
+        assert_eq!(parsed.instructions[0xFFFE], executed(0x10000));
+        let frames = parsed.backtrace.unwrap().frames;
+        assert_eq!(frames.len(), 0xFFFE);
+        assert_eq!(frames[0], frame(0));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::instruction::Instruction;
+use crate::memory_bus::MemoryMap;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+// An instruction as decoded from memory, along with its size in bytes and
+// its base cycle count.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub size: u8,
+    pub cycles: u8,
+}
+
+impl Decoded {
+    pub fn new(instruction: Instruction) -> Self {
+        Decoded {
+            instruction,
+            size: instruction.size() as u8,
+            cycles: instruction.cycles(),
+        }
+    }
+}
+
+// Decoded instructions keyed by the address they were decoded from. An entry
+// is only valid while the bytes it was decoded from are unchanged, so every
+// write to memory must be reported with invalidate. The cpu does this for
+// its own writes; anything else that writes to memory behind the cpu's back
+// (e.g. a DMA device, or mirrored or banked memory) must do so itself.
+#[derive(Clone)]
+pub struct DecodeCache {
+    entries: Vec<Option<Decoded>>,
+    // The addresses of cached instructions that have been invalidated, for
+    // anything built on top of the cache (i.e. the block engine). Only kept
+    // once enabled with log_invalidations.
+    invalidated: Option<Vec<u16>>,
+    cleared: bool,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        DecodeCache {
+            entries: vec![None; 0x10000],
+            invalidated: None,
+            cleared: false,
+        }
+    }
+
+    pub fn get(&self, addr: u16) -> Option<Decoded> {
+        self.entries[addr as usize]
+    }
+
+    // Decode the instruction at `addr`, or return the cached decoding if the
+    // bytes have not been written since.
+    pub fn fetch<M: MemoryMap + ?Sized>(&mut self, memory: &mut M, addr: u16) -> Decoded {
+        match self.entries[addr as usize] {
+            Some(decoded) => decoded,
+            None => {
+                let decoded = Decoded::new(Instruction::from(memory.read_slice(addr)));
+                self.entries[addr as usize] = Some(decoded);
+                decoded
+            }
+        }
+    }
+
+    // Forget every instruction that includes the byte at `addr`. Instructions
+    // are at most three bytes long, so these start at most two bytes before.
+    pub fn invalidate(&mut self, addr: u16) {
+        for offset in 0..3 {
+            let start = addr.wrapping_sub(offset);
+            if let Some(decoded) = self.entries[start as usize] {
+                if decoded.size as u16 > offset {
+                    self.entries[start as usize] = None;
+                    if let Some(invalidated) = self.invalidated.as_mut() {
+                        invalidated.push(start);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = None;
+        }
+        if let Some(invalidated) = self.invalidated.as_mut() {
+            invalidated.clear();
+            self.cleared = true;
+        }
+    }
+
+    pub(crate) fn log_invalidations(&mut self) {
+        if self.invalidated.is_none() {
+            self.invalidated = Some(Vec::new());
+        }
+    }
+
+    // True if anything has been invalidated since the log was last drained.
+    pub(crate) fn has_invalidations(&self) -> bool {
+        self.cleared || self.invalidated.as_ref().is_some_and(|log| !log.is_empty())
+    }
+
+    // Move the logged addresses into `out`. Returns true if the whole cache
+    // was cleared, in which case `out` is left empty.
+    pub(crate) fn drain_invalidations(&mut self, out: &mut Vec<u16>) -> bool {
+        let cleared = self.cleared;
+        self.cleared = false;
+        if let Some(invalidated) = self.invalidated.as_mut() {
+            out.append(invalidated);
+        }
+        if cleared {
+            out.clear();
+        }
+        cleared
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Memory that reports every write to a decode cache. Used to hand the cpu's
+// memory to code outside of the cpu, such as an OUT handler.
+pub(crate) struct InvalidatingMemory<'a, M: MemoryMap + ?Sized> {
+    pub memory: &'a mut M,
+    pub cache: &'a mut DecodeCache,
+}
+
+impl<'a, M: MemoryMap + ?Sized> MemoryMap for InvalidatingMemory<'a, M> {
+    fn load_rom(&mut self) {
+        self.memory.load_rom();
+        self.cache.clear();
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory.read(addr)
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        self.memory.read_slice(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.memory.write(addr, val);
+        self.cache.invalidate(addr);
+    }
+
+    fn peek(&mut self, addr: u16) -> Option<u8> {
+        self.memory.peek(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockMemory;
+
+    #[test]
+    fn test_fetch_caches() {
+        let mut ram = MockMemory::new();
+        let mut cache = DecodeCache::new();
+        // MVI A,0x3F
+        ram.write(0x100, 0x3E);
+        ram.write(0x101, 0x3F);
+        let decoded = cache.fetch(&mut ram, 0x100);
+        assert_eq!(decoded.size, 2);
+        assert_eq!(decoded.cycles, 7);
+
+        // Without invalidating, the stale decoding is returned.
+        ram.write(0x101, 0x00);
+        assert_eq!(cache.fetch(&mut ram, 0x100), decoded);
+    }
+
+    #[test]
+    fn test_invalidate_covers_operand_bytes() {
+        let mut ram = MockMemory::new();
+        let mut cache = DecodeCache::new();
+        // JMP 0x0000 at 0x100, NOP at 0x103.
+        ram.write(0x100, 0xC3);
+        cache.fetch(&mut ram, 0x100);
+        cache.fetch(&mut ram, 0x103);
+
+        let mut memory = InvalidatingMemory {
+            memory: &mut ram,
+            cache: &mut cache,
+        };
+        memory.write(0x102, 0x12);
+        assert_eq!(cache.get(0x100), None);
+        assert!(cache.get(0x103).is_some());
+
+        assert_eq!(
+            cache.fetch(&mut ram, 0x100).instruction,
+            Instruction::JMP(0x1200)
+        );
+    }
+}
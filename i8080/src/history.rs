@@ -0,0 +1,235 @@
+use crate::instruction::Instruction;
+use crate::symbols::{Address, SymbolLookup};
+
+use alloc::collections::VecDeque;
+use alloc::string::ToString;
+use core::fmt;
+
+// An instruction as it was executed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Executed {
+    pub pc: u16,
+    // The cycle count when the instruction started.
+    pub cycles: u64,
+    // The instruction's bytes, of which the first `len` are used. These are
+    // the bytes at the time, so code that has since been overwritten still
+    // shows what ran.
+    pub bytes: [u8; 3],
+    pub len: u8,
+}
+
+impl Executed {
+    pub fn instruction(&self) -> Instruction {
+        Instruction::from(&self.bytes[..])
+    }
+
+    // Format with symbol names where `symbols` knows them.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> ExecutedDisplay<'a> {
+        ExecutedDisplay {
+            executed: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for Executed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+pub struct ExecutedDisplay<'a> {
+    executed: &'a Executed,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for ExecutedDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let executed = self.executed;
+        write!(f, "{} ", Address::new(executed.pc, self.symbols))?;
+        for i in 0..3 {
+            if i < executed.len as usize {
+                write!(f, " {:02X}", executed.bytes[i])?;
+            } else {
+                write!(f, "   ")?;
+            }
+        }
+        let instruction = executed.instruction();
+        let instruction = instruction.display(self.symbols).to_string();
+        write!(f, "  {:<14} ; cycle {}", instruction, executed.cycles)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+// An IN or OUT instruction and the byte transferred.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IoAccess {
+    pub pc: u16,
+    pub cycles: u64,
+    pub direction: Direction,
+    pub port: u8,
+    pub val: u8,
+}
+
+impl IoAccess {
+    // Format with symbol names where `symbols` knows them.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> IoAccessDisplay<'a> {
+        IoAccessDisplay {
+            access: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for IoAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+pub struct IoAccessDisplay<'a> {
+    access: &'a IoAccess,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for IoAccessDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = self.access;
+        let direction = match access.direction {
+            Direction::In => "IN ",
+            Direction::Out => "OUT",
+        };
+        write!(
+            f,
+            "{}  {} port {:02X}H = {:02X}H ; cycle {}",
+            Address::new(access.pc, self.symbols),
+            direction,
+            access.port,
+            access.val,
+            access.cycles
+        )
+    }
+}
+
+// The most recent instructions executed and I/O performed, kept so that the
+// lead up to a crash can be inspected. Each is a ring buffer: once full, the
+// oldest entry is dropped for each new one.
+#[derive(Clone, Debug)]
+pub struct History {
+    instructions: VecDeque<Executed>,
+    io: VecDeque<IoAccess>,
+    instruction_capacity: usize,
+    io_capacity: usize,
+}
+
+impl History {
+    pub fn new(instruction_capacity: usize, io_capacity: usize) -> Self {
+        History {
+            instructions: VecDeque::with_capacity(instruction_capacity),
+            io: VecDeque::with_capacity(io_capacity),
+            instruction_capacity,
+            io_capacity,
+        }
+    }
+
+    // The instructions executed, oldest first.
+    pub fn instructions(&self) -> impl Iterator<Item = &Executed> {
+        self.instructions.iter()
+    }
+
+    // The I/O performed, oldest first.
+    pub fn io(&self) -> impl Iterator<Item = &IoAccess> {
+        self.io.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.io.clear();
+    }
+
+    pub(crate) fn executed(&mut self, executed: Executed) {
+        push(&mut self.instructions, self.instruction_capacity, executed);
+    }
+
+    pub(crate) fn io_access(&mut self, access: IoAccess) {
+        push(&mut self.io, self.io_capacity, access);
+    }
+}
+
+impl Default for History {
+    // The last 256 instructions and 64 I/O accesses.
+    fn default() -> Self {
+        History::new(256, 64)
+    }
+}
+
+fn push<T>(entries: &mut VecDeque<T>, capacity: usize, entry: T) {
+    if capacity == 0 {
+        return;
+    }
+    if entries.len() == capacity {
+        entries.pop_front();
+    }
+    entries.push_back(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolTable;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_history_keeps_the_most_recent() {
+        let mut history = History::new(2, 1);
+        for pc in 0..3 {
+            history.executed(Executed {
+                pc,
+                cycles: pc as u64 * 4,
+                bytes: [0x00, 0, 0],
+                len: 1,
+            });
+        }
+        history.io_access(IoAccess {
+            pc: 0,
+            cycles: 0,
+            direction: Direction::In,
+            port: 1,
+            val: 2,
+        });
+        history.io_access(IoAccess {
+            pc: 0x10,
+            cycles: 10,
+            direction: Direction::Out,
+            port: 3,
+            val: 0xFF,
+        });
+
+        let pcs: Vec<u16> = history.instructions().map(|e| e.pc).collect();
+        assert_eq!(pcs, [1, 2]);
+        let io: Vec<IoAccess> = history.io().copied().collect();
+        assert_eq!(io.len(), 1);
+        assert_eq!(io[0].to_string(), "0010H  OUT port 03H = FFH ; cycle 10");
+
+        let executed = Executed {
+            pc: 0x0100,
+            cycles: 7,
+            bytes: [0xC3, 0x34, 0x12],
+            len: 3,
+        };
+        assert_eq!(
+            executed.to_string(),
+            "0100H  C3 34 12  JMP 1234H      ; cycle 7"
+        );
+        let symbols = SymbolTable::parse("0100 START\n1234 LOOP").unwrap();
+        assert_eq!(
+            executed.display(Some(&symbols)).to_string(),
+            "0100H <START>  C3 34 12  JMP LOOP       ; cycle 7"
+        );
+    }
+}
@@ -1,6 +1,15 @@
-use std::fmt;
+use crate::symbols::SymbolLookup;
 
-#[derive(Copy, Clone, Debug)]
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::BitOr;
+use core::str::FromStr;
+
+// An 8-bit operand: one of the registers, or M, the memory location
+// addressed by H and L.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Operand {
     A,
     B,
@@ -10,26 +19,233 @@ pub enum Operand {
     H,
     L,
     M,
+}
+
+// The register pairs accepted by LXI, INX, DCX and DAD.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegisterPair {
+    BC,
+    DE,
+    HL,
     SP,
+}
+
+// The register pairs accepted by PUSH and POP. PSW is the accumulator and
+// the flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PushPair {
+    BC,
+    DE,
+    HL,
     PSW,
 }
 
+// The register pairs STAX and LDAX can address memory through.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexPair {
+    BC,
+    DE,
+}
+
+// A set of condition flags. The bits are those of the flags byte pushed by
+// PUSH PSW.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Flags(pub u8);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    pub const CARRY: Flags = Flags(0x01);
+    pub const PARITY: Flags = Flags(0x04);
+    pub const AUX_CARRY: Flags = Flags(0x10);
+    pub const ZERO: Flags = Flags(0x40);
+    pub const SIGN: Flags = Flags(0x80);
+    pub const ALL: Flags = Flags(0xD5);
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+// A set of registers. A register pair is the union of its two halves.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegisterSet(pub u16);
+
+impl RegisterSet {
+    pub const NONE: RegisterSet = RegisterSet(0);
+    pub const A: RegisterSet = RegisterSet(0x001);
+    pub const B: RegisterSet = RegisterSet(0x002);
+    pub const C: RegisterSet = RegisterSet(0x004);
+    pub const D: RegisterSet = RegisterSet(0x008);
+    pub const E: RegisterSet = RegisterSet(0x010);
+    pub const H: RegisterSet = RegisterSet(0x020);
+    pub const L: RegisterSet = RegisterSet(0x040);
+    pub const SP: RegisterSet = RegisterSet(0x080);
+    pub const BC: RegisterSet = RegisterSet(0x006);
+    pub const DE: RegisterSet = RegisterSet(0x018);
+    pub const HL: RegisterSet = RegisterSet(0x060);
+
+    pub fn contains(self, other: RegisterSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for RegisterSet {
+    type Output = RegisterSet;
+
+    fn bitor(self, rhs: RegisterSet) -> RegisterSet {
+        RegisterSet(self.0 | rhs.0)
+    }
+}
+
+impl From<Operand> for RegisterSet {
+    // The register an 8-bit operand names. M names no register; see
+    // Operand::address_registers for the registers it reads.
+    fn from(operand: Operand) -> RegisterSet {
+        match operand {
+            Operand::A => RegisterSet::A,
+            Operand::B => RegisterSet::B,
+            Operand::C => RegisterSet::C,
+            Operand::D => RegisterSet::D,
+            Operand::E => RegisterSet::E,
+            Operand::H => RegisterSet::H,
+            Operand::L => RegisterSet::L,
+            Operand::M => RegisterSet::NONE,
+        }
+    }
+}
+
+impl From<RegisterPair> for RegisterSet {
+    fn from(pair: RegisterPair) -> RegisterSet {
+        match pair {
+            RegisterPair::BC => RegisterSet::BC,
+            RegisterPair::DE => RegisterSet::DE,
+            RegisterPair::HL => RegisterSet::HL,
+            RegisterPair::SP => RegisterSet::SP,
+        }
+    }
+}
+
+impl From<PushPair> for RegisterSet {
+    // PSW is the accumulator plus the flags, which are tracked separately.
+    fn from(pair: PushPair) -> RegisterSet {
+        match pair {
+            PushPair::BC => RegisterSet::BC,
+            PushPair::DE => RegisterSet::DE,
+            PushPair::HL => RegisterSet::HL,
+            PushPair::PSW => RegisterSet::A,
+        }
+    }
+}
+
+impl From<IndexPair> for RegisterSet {
+    fn from(pair: IndexPair) -> RegisterSet {
+        match pair {
+            IndexPair::BC => RegisterSet::BC,
+            IndexPair::DE => RegisterSet::DE,
+        }
+    }
+}
+
+impl Operand {
+    // The registers read to form the operand's address, i.e. H and L for M.
+    fn address_registers(self) -> RegisterSet {
+        match self {
+            Operand::M => RegisterSet::HL,
+            _ => RegisterSet::NONE,
+        }
+    }
+}
+
+// How an instruction accesses memory, the stack or an I/O port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn reads(self) -> bool {
+        self == Access::Read || self == Access::ReadWrite
+    }
+
+    pub fn writes(self) -> bool {
+        self == Access::Write || self == Access::ReadWrite
+    }
+}
+
+// The instruction groups of the 8080 Assembly Language Programming Manual.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InstructionClass {
+    DataTransfer,
+    Arithmetic,
+    Logical,
+    Branch,
+    // Stack, I/O and machine control.
+    Control,
+}
+
+impl InstructionClass {
+    pub const ALL: [InstructionClass; 5] = [
+        InstructionClass::DataTransfer,
+        InstructionClass::Arithmetic,
+        InstructionClass::Logical,
+        InstructionClass::Branch,
+        InstructionClass::Control,
+    ];
+}
+
+// Where execution continues after an instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Flow {
+    // The next instruction in memory.
+    Next,
+    Jump,
+    ConditionalJump,
+    Call,
+    ConditionalCall,
+    Return,
+    ConditionalReturn,
+    Restart,
+    // PCHL, whose target is only known at runtime.
+    Indirect,
+    Halt,
+}
+
 // source: https://altairclone.com/downloads/manuals/8080%20Programmers%20Manual.pdf
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Instruction {
     NOP,
     JMP(u16),
-    PUSH(Operand),
+    PUSH(PushPair),
     MVI(Operand, u8),
     STA(u16),
-    LXI(Operand, u16),
-    STAX(Operand),
-    INX(Operand),
+    LXI(RegisterPair, u16),
+    STAX(IndexPair),
+    INX(RegisterPair),
     INR(Operand),
     DCR(Operand),
     RLC,
-    DAD(Operand),
-    LDAX(Operand),
-    DCX(Operand),
+    DAD(RegisterPair),
+    LDAX(IndexPair),
+    DCX(RegisterPair),
     RRC,
     RAL,
     RAR,
@@ -77,7 +293,7 @@ pub enum Instruction {
     ORI(u8),
     OUT(u8),
     PCHL,
-    POP(Operand),
+    POP(PushPair),
     RC,
     RET,
     RM,
@@ -102,58 +318,58 @@ impl Instruction {
 
         let instruction = match opcode {
             0x00 | 0x10 | 0x20 | 0x30 | 0x08 | 0x18 | 0x28 | 0x38 => Instruction::NOP,
-            0x01 => Instruction::LXI(Operand::B, Instruction::read_imm16(bytes)),
-            0x02 => Instruction::STAX(Operand::B),
-            0x03 => Instruction::INX(Operand::B),
+            0x01 => Instruction::LXI(RegisterPair::BC, Instruction::read_imm16(bytes)),
+            0x02 => Instruction::STAX(IndexPair::BC),
+            0x03 => Instruction::INX(RegisterPair::BC),
             0x04 => Instruction::INR(Operand::B),
             0x05 => Instruction::DCR(Operand::B),
             0x06 => Instruction::MVI(Operand::B, Instruction::read_imm8(bytes)),
             0x07 => Instruction::RLC,
-            0x09 => Instruction::DAD(Operand::B),
-            0x0a => Instruction::LDAX(Operand::B),
-            0x0b => Instruction::DCX(Operand::B),
+            0x09 => Instruction::DAD(RegisterPair::BC),
+            0x0a => Instruction::LDAX(IndexPair::BC),
+            0x0b => Instruction::DCX(RegisterPair::BC),
             0x0c => Instruction::INR(Operand::C),
             0x0d => Instruction::DCR(Operand::C),
             0x0e => Instruction::MVI(Operand::C, Instruction::read_imm8(bytes)),
             0x0f => Instruction::RRC,
-            0x11 => Instruction::LXI(Operand::D, Instruction::read_imm16(bytes)),
-            0x12 => Instruction::STAX(Operand::D),
-            0x13 => Instruction::INX(Operand::D),
+            0x11 => Instruction::LXI(RegisterPair::DE, Instruction::read_imm16(bytes)),
+            0x12 => Instruction::STAX(IndexPair::DE),
+            0x13 => Instruction::INX(RegisterPair::DE),
             0x14 => Instruction::INR(Operand::D),
             0x15 => Instruction::DCR(Operand::D),
             0x16 => Instruction::MVI(Operand::D, Instruction::read_imm8(bytes)),
             0x17 => Instruction::RAL,
-            0x19 => Instruction::DAD(Operand::D),
-            0x1a => Instruction::LDAX(Operand::D),
-            0x1b => Instruction::DCX(Operand::D),
+            0x19 => Instruction::DAD(RegisterPair::DE),
+            0x1a => Instruction::LDAX(IndexPair::DE),
+            0x1b => Instruction::DCX(RegisterPair::DE),
             0x1c => Instruction::INR(Operand::E),
             0x1d => Instruction::DCR(Operand::E),
             0x1e => Instruction::MVI(Operand::E, Instruction::read_imm8(bytes)),
             0x1f => Instruction::RAR,
-            0x21 => Instruction::LXI(Operand::H, Instruction::read_imm16(bytes)),
+            0x21 => Instruction::LXI(RegisterPair::HL, Instruction::read_imm16(bytes)),
             0x22 => Instruction::SHLD(Instruction::read_imm16(bytes)),
-            0x23 => Instruction::INX(Operand::H),
+            0x23 => Instruction::INX(RegisterPair::HL),
             0x24 => Instruction::INR(Operand::H),
             0x25 => Instruction::DCR(Operand::H),
             0x26 => Instruction::MVI(Operand::H, Instruction::read_imm8(bytes)),
             0x27 => Instruction::DAA,
-            0x29 => Instruction::DAD(Operand::H),
+            0x29 => Instruction::DAD(RegisterPair::HL),
             0x2a => Instruction::LHLD(Instruction::read_imm16(bytes)),
-            0x2b => Instruction::DCX(Operand::H),
+            0x2b => Instruction::DCX(RegisterPair::HL),
             0x2c => Instruction::INR(Operand::L),
             0x2d => Instruction::DCR(Operand::L),
             0x2e => Instruction::MVI(Operand::L, Instruction::read_imm8(bytes)),
             0x2f => Instruction::CMA,
-            0x31 => Instruction::LXI(Operand::SP, Instruction::read_imm16(bytes)),
+            0x31 => Instruction::LXI(RegisterPair::SP, Instruction::read_imm16(bytes)),
             0x32 => Instruction::STA(Instruction::read_imm16(bytes)),
-            0x33 => Instruction::INX(Operand::SP),
+            0x33 => Instruction::INX(RegisterPair::SP),
             0x34 => Instruction::INR(Operand::M),
             0x35 => Instruction::DCR(Operand::M),
             0x36 => Instruction::MVI(Operand::M, Instruction::read_imm8(bytes)),
             0x37 => Instruction::STC,
-            0x39 => Instruction::DAD(Operand::SP),
+            0x39 => Instruction::DAD(RegisterPair::SP),
             0x3a => Instruction::LDA(Instruction::read_imm16(bytes)),
-            0x3b => Instruction::DCX(Operand::SP),
+            0x3b => Instruction::DCX(RegisterPair::SP),
             0x3c => Instruction::INR(Operand::A),
             0x3d => Instruction::DCR(Operand::A),
             0x3e => Instruction::MVI(Operand::A, Instruction::read_imm8(bytes)),
@@ -290,11 +506,11 @@ impl Instruction {
             //next to cycles where applicable.
             //https://pastraiser.com/cpu/i8080/i8080_opcodes.html
             0xc0 => Instruction::RNZ,
-            0xc1 => Instruction::POP(Operand::B),
+            0xc1 => Instruction::POP(PushPair::BC),
             0xc2 => Instruction::JNZ(Instruction::read_imm16(bytes)),
             0xc3 | 0xcb => Instruction::JMP(Instruction::read_imm16(bytes)),
             0xc4 => Instruction::CNZ(Instruction::read_imm16(bytes)),
-            0xc5 => Instruction::PUSH(Operand::B),
+            0xc5 => Instruction::PUSH(PushPair::BC),
             0xc6 => Instruction::ADI(Instruction::read_imm8(bytes)),
             0xc7 => Instruction::RST(0),
             0xc8 => Instruction::RZ,
@@ -305,11 +521,11 @@ impl Instruction {
             0xce => Instruction::ACI(Instruction::read_imm8(bytes)),
             0xcf => Instruction::RST(1),
             0xd0 => Instruction::RNC,
-            0xd1 => Instruction::POP(Operand::D),
+            0xd1 => Instruction::POP(PushPair::DE),
             0xd2 => Instruction::JNC(Instruction::read_imm16(bytes)),
             0xd3 => Instruction::OUT(Instruction::read_imm8(bytes)),
             0xd4 => Instruction::CNC(Instruction::read_imm16(bytes)),
-            0xd5 => Instruction::PUSH(Operand::D),
+            0xd5 => Instruction::PUSH(PushPair::DE),
             0xd6 => Instruction::SUI(Instruction::read_imm8(bytes)),
             0xd7 => Instruction::RST(2),
             0xd8 => Instruction::RC,
@@ -319,11 +535,11 @@ impl Instruction {
             0xde => Instruction::SBI(Instruction::read_imm8(bytes)),
             0xdf => Instruction::RST(3),
             0xe0 => Instruction::RPO,
-            0xe1 => Instruction::POP(Operand::H),
+            0xe1 => Instruction::POP(PushPair::HL),
             0xe2 => Instruction::JPO(Instruction::read_imm16(bytes)),
             0xe3 => Instruction::XTHL,
             0xe4 => Instruction::CPO(Instruction::read_imm16(bytes)),
-            0xe5 => Instruction::PUSH(Operand::H),
+            0xe5 => Instruction::PUSH(PushPair::HL),
             0xe6 => Instruction::ANI(Instruction::read_imm8(bytes)),
             0xe7 => Instruction::RST(4),
             0xe8 => Instruction::RPE,
@@ -334,11 +550,11 @@ impl Instruction {
             0xee => Instruction::XRI(Instruction::read_imm8(bytes)),
             0xef => Instruction::RST(5),
             0xf0 => Instruction::RP,
-            0xf1 => Instruction::POP(Operand::PSW),
+            0xf1 => Instruction::POP(PushPair::PSW),
             0xf2 => Instruction::JP(Instruction::read_imm16(bytes)),
             0xf3 => Instruction::DI,
             0xf4 => Instruction::CP(Instruction::read_imm16(bytes)),
-            0xf5 => Instruction::PUSH(Operand::PSW),
+            0xf5 => Instruction::PUSH(PushPair::PSW),
             0xf6 => Instruction::ORI(Instruction::read_imm8(bytes)),
             0xf7 => Instruction::RST(6),
             0xf8 => Instruction::RM,
@@ -556,6 +772,377 @@ impl Instruction {
         }
     }
 
+    // The flags an instruction's result depends on, including the condition
+    // tested by a conditional jump, call or return.
+    pub fn flags_read(&self) -> Flags {
+        match *self {
+            Instruction::ADC(_)
+            | Instruction::SBB(_)
+            | Instruction::ACI(_)
+            | Instruction::SBI(_)
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::CMC => Flags::CARRY,
+            Instruction::DAA => Flags::CARRY | Flags::AUX_CARRY,
+            Instruction::PUSH(PushPair::PSW) => Flags::ALL,
+            Instruction::JC(_)
+            | Instruction::JNC(_)
+            | Instruction::CC(_)
+            | Instruction::CNC(_)
+            | Instruction::RC
+            | Instruction::RNC => Flags::CARRY,
+            Instruction::JZ(_)
+            | Instruction::JNZ(_)
+            | Instruction::CZ(_)
+            | Instruction::CNZ(_)
+            | Instruction::RZ
+            | Instruction::RNZ => Flags::ZERO,
+            Instruction::JM(_)
+            | Instruction::JP(_)
+            | Instruction::CM(_)
+            | Instruction::CP(_)
+            | Instruction::RM
+            | Instruction::RP => Flags::SIGN,
+            Instruction::JPE(_)
+            | Instruction::JPO(_)
+            | Instruction::CPE(_)
+            | Instruction::CPO(_)
+            | Instruction::RPE
+            | Instruction::RPO => Flags::PARITY,
+            _ => Flags::NONE,
+        }
+    }
+
+    pub fn flags_written(&self) -> Flags {
+        match *self {
+            Instruction::ADD(_)
+            | Instruction::ADC(_)
+            | Instruction::SUB(_)
+            | Instruction::SBB(_)
+            | Instruction::ANA(_)
+            | Instruction::XRA(_)
+            | Instruction::ORA(_)
+            | Instruction::CMP(_)
+            | Instruction::ADI(_)
+            | Instruction::ACI(_)
+            | Instruction::SUI(_)
+            | Instruction::SBI(_)
+            | Instruction::ANI(_)
+            | Instruction::XRI(_)
+            | Instruction::ORI(_)
+            | Instruction::CPI(_)
+            | Instruction::DAA
+            | Instruction::POP(PushPair::PSW) => Flags::ALL,
+            Instruction::INR(_) | Instruction::DCR(_) => {
+                Flags::ZERO | Flags::SIGN | Flags::PARITY | Flags::AUX_CARRY
+            }
+            Instruction::RLC
+            | Instruction::RRC
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::STC
+            | Instruction::CMC
+            | Instruction::DAD(_) => Flags::CARRY,
+            _ => Flags::NONE,
+        }
+    }
+
+    // The registers an instruction reads, including H and L when addressing
+    // memory through M and SP when using the stack.
+    pub fn registers_read(&self) -> RegisterSet {
+        match *self {
+            Instruction::MOV(target, source) => {
+                RegisterSet::from(source) | source.address_registers() | target.address_registers()
+            }
+            Instruction::MVI(target, _) => target.address_registers(),
+            Instruction::INR(target) | Instruction::DCR(target) => {
+                RegisterSet::from(target) | target.address_registers()
+            }
+            Instruction::ADD(source)
+            | Instruction::ADC(source)
+            | Instruction::SUB(source)
+            | Instruction::SBB(source)
+            | Instruction::ANA(source)
+            | Instruction::XRA(source)
+            | Instruction::ORA(source)
+            | Instruction::CMP(source) => {
+                RegisterSet::A | RegisterSet::from(source) | source.address_registers()
+            }
+            Instruction::ADI(_)
+            | Instruction::ACI(_)
+            | Instruction::SUI(_)
+            | Instruction::SBI(_)
+            | Instruction::ANI(_)
+            | Instruction::XRI(_)
+            | Instruction::ORI(_)
+            | Instruction::CPI(_)
+            | Instruction::RLC
+            | Instruction::RRC
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::CMA
+            | Instruction::DAA
+            | Instruction::STA(_)
+            | Instruction::OUT(_) => RegisterSet::A,
+            Instruction::STAX(pair) => RegisterSet::A | RegisterSet::from(pair),
+            Instruction::LDAX(pair) => RegisterSet::from(pair),
+            Instruction::INX(pair) | Instruction::DCX(pair) => RegisterSet::from(pair),
+            Instruction::DAD(pair) => RegisterSet::HL | RegisterSet::from(pair),
+            Instruction::SHLD(_) | Instruction::PCHL | Instruction::SPHL => RegisterSet::HL,
+            Instruction::XCHG => RegisterSet::DE | RegisterSet::HL,
+            Instruction::XTHL => RegisterSet::HL | RegisterSet::SP,
+            Instruction::PUSH(pair) => RegisterSet::SP | RegisterSet::from(pair),
+            _ if self.stack_access() != Access::None => RegisterSet::SP,
+            _ => RegisterSet::NONE,
+        }
+    }
+
+    pub fn registers_written(&self) -> RegisterSet {
+        match *self {
+            Instruction::MOV(target, _)
+            | Instruction::MVI(target, _)
+            | Instruction::INR(target)
+            | Instruction::DCR(target) => RegisterSet::from(target),
+            Instruction::ADD(_)
+            | Instruction::ADC(_)
+            | Instruction::SUB(_)
+            | Instruction::SBB(_)
+            | Instruction::ANA(_)
+            | Instruction::XRA(_)
+            | Instruction::ORA(_)
+            | Instruction::ADI(_)
+            | Instruction::ACI(_)
+            | Instruction::SUI(_)
+            | Instruction::SBI(_)
+            | Instruction::ANI(_)
+            | Instruction::XRI(_)
+            | Instruction::ORI(_)
+            | Instruction::RLC
+            | Instruction::RRC
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::CMA
+            | Instruction::DAA
+            | Instruction::LDA(_)
+            | Instruction::LDAX(_)
+            | Instruction::IN(_) => RegisterSet::A,
+            Instruction::LXI(pair, _) | Instruction::INX(pair) | Instruction::DCX(pair) => {
+                RegisterSet::from(pair)
+            }
+            Instruction::DAD(_) | Instruction::LHLD(_) | Instruction::XTHL => RegisterSet::HL,
+            Instruction::SPHL => RegisterSet::SP,
+            Instruction::XCHG => RegisterSet::DE | RegisterSet::HL,
+            Instruction::POP(pair) => RegisterSet::SP | RegisterSet::from(pair),
+            _ if self.stack_access() != Access::None => RegisterSet::SP,
+            _ => RegisterSet::NONE,
+        }
+    }
+
+    // Accesses to memory other than through the stack pointer.
+    pub fn memory_access(&self) -> Access {
+        match *self {
+            Instruction::MOV(Operand::M, _)
+            | Instruction::MVI(Operand::M, _)
+            | Instruction::STA(_)
+            | Instruction::STAX(_)
+            | Instruction::SHLD(_) => Access::Write,
+            Instruction::MOV(_, Operand::M)
+            | Instruction::ADD(Operand::M)
+            | Instruction::ADC(Operand::M)
+            | Instruction::SUB(Operand::M)
+            | Instruction::SBB(Operand::M)
+            | Instruction::ANA(Operand::M)
+            | Instruction::XRA(Operand::M)
+            | Instruction::ORA(Operand::M)
+            | Instruction::CMP(Operand::M)
+            | Instruction::LDA(_)
+            | Instruction::LDAX(_)
+            | Instruction::LHLD(_) => Access::Read,
+            Instruction::INR(Operand::M) | Instruction::DCR(Operand::M) => Access::ReadWrite,
+            _ => Access::None,
+        }
+    }
+
+    // Accesses to memory through the stack pointer. Conditional calls and
+    // returns only access the stack when the condition is met.
+    pub fn stack_access(&self) -> Access {
+        match *self {
+            Instruction::PUSH(_)
+            | Instruction::CALL(_)
+            | Instruction::CC(_)
+            | Instruction::CNC(_)
+            | Instruction::CZ(_)
+            | Instruction::CNZ(_)
+            | Instruction::CM(_)
+            | Instruction::CP(_)
+            | Instruction::CPE(_)
+            | Instruction::CPO(_)
+            | Instruction::RST(_) => Access::Write,
+            Instruction::POP(_)
+            | Instruction::RET
+            | Instruction::RC
+            | Instruction::RNC
+            | Instruction::RZ
+            | Instruction::RNZ
+            | Instruction::RM
+            | Instruction::RP
+            | Instruction::RPE
+            | Instruction::RPO => Access::Read,
+            Instruction::XTHL => Access::ReadWrite,
+            _ => Access::None,
+        }
+    }
+
+    pub fn io_access(&self) -> Access {
+        match *self {
+            Instruction::IN(_) => Access::Read,
+            Instruction::OUT(_) => Access::Write,
+            _ => Access::None,
+        }
+    }
+
+    pub fn flow(&self) -> Flow {
+        match *self {
+            Instruction::JMP(_) => Flow::Jump,
+            Instruction::JC(_)
+            | Instruction::JNC(_)
+            | Instruction::JZ(_)
+            | Instruction::JNZ(_)
+            | Instruction::JM(_)
+            | Instruction::JP(_)
+            | Instruction::JPE(_)
+            | Instruction::JPO(_) => Flow::ConditionalJump,
+            Instruction::CALL(_) => Flow::Call,
+            Instruction::CC(_)
+            | Instruction::CNC(_)
+            | Instruction::CZ(_)
+            | Instruction::CNZ(_)
+            | Instruction::CM(_)
+            | Instruction::CP(_)
+            | Instruction::CPE(_)
+            | Instruction::CPO(_) => Flow::ConditionalCall,
+            Instruction::RET => Flow::Return,
+            Instruction::RC
+            | Instruction::RNC
+            | Instruction::RZ
+            | Instruction::RNZ
+            | Instruction::RM
+            | Instruction::RP
+            | Instruction::RPE
+            | Instruction::RPO => Flow::ConditionalReturn,
+            Instruction::RST(_) => Flow::Restart,
+            Instruction::PCHL => Flow::Indirect,
+            Instruction::HLT => Flow::Halt,
+            _ => Flow::Next,
+        }
+    }
+
+    pub fn class(&self) -> InstructionClass {
+        match *self {
+            Instruction::MOV(_, _)
+            | Instruction::MVI(_, _)
+            | Instruction::LXI(_, _)
+            | Instruction::LDA(_)
+            | Instruction::STA(_)
+            | Instruction::LHLD(_)
+            | Instruction::SHLD(_)
+            | Instruction::LDAX(_)
+            | Instruction::STAX(_)
+            | Instruction::XCHG => InstructionClass::DataTransfer,
+            Instruction::ADD(_)
+            | Instruction::ADI(_)
+            | Instruction::ADC(_)
+            | Instruction::ACI(_)
+            | Instruction::SUB(_)
+            | Instruction::SUI(_)
+            | Instruction::SBB(_)
+            | Instruction::SBI(_)
+            | Instruction::INR(_)
+            | Instruction::DCR(_)
+            | Instruction::INX(_)
+            | Instruction::DCX(_)
+            | Instruction::DAD(_)
+            | Instruction::DAA => InstructionClass::Arithmetic,
+            Instruction::ANA(_)
+            | Instruction::ANI(_)
+            | Instruction::XRA(_)
+            | Instruction::XRI(_)
+            | Instruction::ORA(_)
+            | Instruction::ORI(_)
+            | Instruction::CMP(_)
+            | Instruction::CPI(_)
+            | Instruction::RLC
+            | Instruction::RRC
+            | Instruction::RAL
+            | Instruction::RAR
+            | Instruction::CMA
+            | Instruction::CMC
+            | Instruction::STC => InstructionClass::Logical,
+            Instruction::PUSH(_)
+            | Instruction::POP(_)
+            | Instruction::XTHL
+            | Instruction::SPHL
+            | Instruction::IN(_)
+            | Instruction::OUT(_)
+            | Instruction::EI
+            | Instruction::DI
+            | Instruction::HLT
+            | Instruction::NOP => InstructionClass::Control,
+            _ => InstructionClass::Branch,
+        }
+    }
+
+    // The address a jump, call or restart transfers control to. None for
+    // every other instruction, including returns and PCHL.
+    pub fn target(&self) -> Option<u16> {
+        match *self {
+            Instruction::JMP(addr)
+            | Instruction::JC(addr)
+            | Instruction::JNC(addr)
+            | Instruction::JZ(addr)
+            | Instruction::JNZ(addr)
+            | Instruction::JM(addr)
+            | Instruction::JP(addr)
+            | Instruction::JPE(addr)
+            | Instruction::JPO(addr)
+            | Instruction::CALL(addr)
+            | Instruction::CC(addr)
+            | Instruction::CNC(addr)
+            | Instruction::CZ(addr)
+            | Instruction::CNZ(addr)
+            | Instruction::CM(addr)
+            | Instruction::CP(addr)
+            | Instruction::CPE(addr)
+            | Instruction::CPO(addr) => Some(addr),
+            Instruction::RST(n) => Some((n as u16) << 3),
+            _ => None,
+        }
+    }
+
+    // The 16-bit operand of an instruction that has one: the address a jump
+    // or call transfers to, the address LDA, STA, LHLD or SHLD accesses, or
+    // the value LXI loads, which is often an address.
+    pub fn address_operand(&self) -> Option<u16> {
+        match *self {
+            Instruction::RST(_) => None,
+            Instruction::LDA(addr)
+            | Instruction::STA(addr)
+            | Instruction::LHLD(addr)
+            | Instruction::SHLD(addr)
+            | Instruction::LXI(_, addr) => Some(addr),
+            _ => self.target(),
+        }
+    }
+
+    // Format with the address operand, if any, replaced by the name of the
+    // symbol at that address.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> InstructionDisplay<'a> {
+        InstructionDisplay {
+            instruction: self,
+            symbols,
+        }
+    }
+
     fn read_imm8(bytes: &[u8]) -> u8 {
         u8::from_le_bytes([bytes[1]])
     }
@@ -656,6 +1243,607 @@ impl fmt::Debug for Instruction {
     }
 }
 
+// Canonical Intel syntax, e.g. "MVI A,3FH" or "LXI SP,2400H". Numbers are
+// printed in hex with an H suffix, and with a leading 0 when they would
+// otherwise start with a letter.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::NOP => write!(f, "NOP"),
+            Instruction::RLC => write!(f, "RLC"),
+            Instruction::RRC => write!(f, "RRC"),
+            Instruction::RAL => write!(f, "RAL"),
+            Instruction::RAR => write!(f, "RAR"),
+            Instruction::DAA => write!(f, "DAA"),
+            Instruction::CMA => write!(f, "CMA"),
+            Instruction::STC => write!(f, "STC"),
+            Instruction::CMC => write!(f, "CMC"),
+            Instruction::HLT => write!(f, "HLT"),
+            Instruction::RNZ => write!(f, "RNZ"),
+            Instruction::RZ => write!(f, "RZ"),
+            Instruction::RET => write!(f, "RET"),
+            Instruction::RNC => write!(f, "RNC"),
+            Instruction::RC => write!(f, "RC"),
+            Instruction::RPO => write!(f, "RPO"),
+            Instruction::XTHL => write!(f, "XTHL"),
+            Instruction::RPE => write!(f, "RPE"),
+            Instruction::PCHL => write!(f, "PCHL"),
+            Instruction::XCHG => write!(f, "XCHG"),
+            Instruction::RP => write!(f, "RP"),
+            Instruction::DI => write!(f, "DI"),
+            Instruction::RM => write!(f, "RM"),
+            Instruction::SPHL => write!(f, "SPHL"),
+            Instruction::EI => write!(f, "EI"),
+            Instruction::JMP(addr) => write!(f, "JMP {}", Hex16(addr)),
+            Instruction::STA(addr) => write!(f, "STA {}", Hex16(addr)),
+            Instruction::SHLD(addr) => write!(f, "SHLD {}", Hex16(addr)),
+            Instruction::LHLD(addr) => write!(f, "LHLD {}", Hex16(addr)),
+            Instruction::LDA(addr) => write!(f, "LDA {}", Hex16(addr)),
+            Instruction::CALL(addr) => write!(f, "CALL {}", Hex16(addr)),
+            Instruction::CC(addr) => write!(f, "CC {}", Hex16(addr)),
+            Instruction::CM(addr) => write!(f, "CM {}", Hex16(addr)),
+            Instruction::CNC(addr) => write!(f, "CNC {}", Hex16(addr)),
+            Instruction::CP(addr) => write!(f, "CP {}", Hex16(addr)),
+            Instruction::CPE(addr) => write!(f, "CPE {}", Hex16(addr)),
+            Instruction::CPO(addr) => write!(f, "CPO {}", Hex16(addr)),
+            Instruction::CNZ(addr) => write!(f, "CNZ {}", Hex16(addr)),
+            Instruction::CZ(addr) => write!(f, "CZ {}", Hex16(addr)),
+            Instruction::JC(addr) => write!(f, "JC {}", Hex16(addr)),
+            Instruction::JM(addr) => write!(f, "JM {}", Hex16(addr)),
+            Instruction::JNC(addr) => write!(f, "JNC {}", Hex16(addr)),
+            Instruction::JNZ(addr) => write!(f, "JNZ {}", Hex16(addr)),
+            Instruction::JP(addr) => write!(f, "JP {}", Hex16(addr)),
+            Instruction::JPE(addr) => write!(f, "JPE {}", Hex16(addr)),
+            Instruction::JPO(addr) => write!(f, "JPO {}", Hex16(addr)),
+            Instruction::JZ(addr) => write!(f, "JZ {}", Hex16(addr)),
+            Instruction::ACI(val) => write!(f, "ACI {}", Hex8(val)),
+            Instruction::ADI(val) => write!(f, "ADI {}", Hex8(val)),
+            Instruction::ANI(val) => write!(f, "ANI {}", Hex8(val)),
+            Instruction::CPI(val) => write!(f, "CPI {}", Hex8(val)),
+            Instruction::IN(val) => write!(f, "IN {}", Hex8(val)),
+            Instruction::ORI(val) => write!(f, "ORI {}", Hex8(val)),
+            Instruction::OUT(val) => write!(f, "OUT {}", Hex8(val)),
+            Instruction::SBI(val) => write!(f, "SBI {}", Hex8(val)),
+            Instruction::SUI(val) => write!(f, "SUI {}", Hex8(val)),
+            Instruction::XRI(val) => write!(f, "XRI {}", Hex8(val)),
+            Instruction::INR(op) => write!(f, "INR {}", op),
+            Instruction::DCR(op) => write!(f, "DCR {}", op),
+            Instruction::ADD(op) => write!(f, "ADD {}", op),
+            Instruction::ANA(op) => write!(f, "ANA {}", op),
+            Instruction::ADC(op) => write!(f, "ADC {}", op),
+            Instruction::SUB(op) => write!(f, "SUB {}", op),
+            Instruction::SBB(op) => write!(f, "SBB {}", op),
+            Instruction::XRA(op) => write!(f, "XRA {}", op),
+            Instruction::ORA(op) => write!(f, "ORA {}", op),
+            Instruction::CMP(op) => write!(f, "CMP {}", op),
+            Instruction::PUSH(op) => write!(f, "PUSH {}", op),
+            Instruction::POP(op) => write!(f, "POP {}", op),
+            Instruction::STAX(op) => write!(f, "STAX {}", op),
+            Instruction::LDAX(op) => write!(f, "LDAX {}", op),
+            Instruction::INX(op) => write!(f, "INX {}", op),
+            Instruction::DCX(op) => write!(f, "DCX {}", op),
+            Instruction::DAD(op) => write!(f, "DAD {}", op),
+            Instruction::MOV(target, source) => write!(f, "MOV {},{}", target, source),
+            Instruction::MVI(target, val) => write!(f, "MVI {},{}", target, Hex8(val)),
+            Instruction::LXI(pair, val) => write!(f, "LXI {},{}", pair, Hex16(val)),
+            Instruction::RST(n) => write!(f, "RST {}", n),
+        }
+    }
+}
+
+pub struct InstructionDisplay<'a> {
+    instruction: &'a Instruction,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for InstructionDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addr = self.instruction.address_operand();
+        let name = addr
+            .zip(self.symbols)
+            .and_then(|(addr, symbols)| symbols.name_at(addr));
+        match (addr, name) {
+            (Some(addr), Some(name)) => {
+                // The address is always the last operand.
+                let text = self.instruction.to_string();
+                let hex = Hex16(addr).to_string();
+                write!(f, "{}{}", &text[..text.len() - hex.len()], name)
+            }
+            _ => write!(f, "{}", self.instruction),
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = ParseError;
+
+    // Parse a single line of Intel assembly. Mnemonics and registers are case
+    // insensitive and anything after a ';' is a comment, unless the ';' is a
+    // quoted character.
+    fn from_str(s: &str) -> Result<Instruction, ParseError> {
+        let line = split_unquoted(s, ';')[0].trim();
+        let (mnemonic, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        if mnemonic.is_empty() {
+            return Err(ParseError::new("expected an instruction"));
+        }
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operands: Vec<&str> = if rest.is_empty() {
+            Vec::new()
+        } else {
+            split_unquoted(rest, ',')
+                .into_iter()
+                .map(str::trim)
+                .collect()
+        };
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                Err(ParseError::new(&format!(
+                    "{} expects {} operand{}, found {}",
+                    mnemonic,
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    operands.len()
+                )))
+            }
+        };
+
+        let instruction = match mnemonic.as_str() {
+            "NOP" => expect(0).map(|_| Instruction::NOP)?,
+            "RLC" => expect(0).map(|_| Instruction::RLC)?,
+            "RRC" => expect(0).map(|_| Instruction::RRC)?,
+            "RAL" => expect(0).map(|_| Instruction::RAL)?,
+            "RAR" => expect(0).map(|_| Instruction::RAR)?,
+            "DAA" => expect(0).map(|_| Instruction::DAA)?,
+            "CMA" => expect(0).map(|_| Instruction::CMA)?,
+            "STC" => expect(0).map(|_| Instruction::STC)?,
+            "CMC" => expect(0).map(|_| Instruction::CMC)?,
+            "HLT" => expect(0).map(|_| Instruction::HLT)?,
+            "RNZ" => expect(0).map(|_| Instruction::RNZ)?,
+            "RZ" => expect(0).map(|_| Instruction::RZ)?,
+            "RET" => expect(0).map(|_| Instruction::RET)?,
+            "RNC" => expect(0).map(|_| Instruction::RNC)?,
+            "RC" => expect(0).map(|_| Instruction::RC)?,
+            "RPO" => expect(0).map(|_| Instruction::RPO)?,
+            "XTHL" => expect(0).map(|_| Instruction::XTHL)?,
+            "RPE" => expect(0).map(|_| Instruction::RPE)?,
+            "PCHL" => expect(0).map(|_| Instruction::PCHL)?,
+            "XCHG" => expect(0).map(|_| Instruction::XCHG)?,
+            "RP" => expect(0).map(|_| Instruction::RP)?,
+            "DI" => expect(0).map(|_| Instruction::DI)?,
+            "RM" => expect(0).map(|_| Instruction::RM)?,
+            "SPHL" => expect(0).map(|_| Instruction::SPHL)?,
+            "EI" => expect(0).map(|_| Instruction::EI)?,
+            "JMP" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JMP)?,
+            "STA" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::STA)?,
+            "SHLD" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::SHLD)?,
+            "LHLD" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::LHLD)?,
+            "LDA" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::LDA)?,
+            "CALL" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CALL)?,
+            "CC" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CC)?,
+            "CM" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CM)?,
+            "CNC" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CNC)?,
+            "CP" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CP)?,
+            "CPE" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CPE)?,
+            "CPO" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CPO)?,
+            "CNZ" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CNZ)?,
+            "CZ" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::CZ)?,
+            "JC" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JC)?,
+            "JM" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JM)?,
+            "JNC" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JNC)?,
+            "JNZ" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JNZ)?,
+            "JP" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JP)?,
+            "JPE" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JPE)?,
+            "JPO" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JPO)?,
+            "JZ" => expect(1)
+                .and_then(|_| parse_u16(operands[0]))
+                .map(Instruction::JZ)?,
+            "ACI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::ACI)?,
+            "ADI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::ADI)?,
+            "ANI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::ANI)?,
+            "CPI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::CPI)?,
+            "IN" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::IN)?,
+            "ORI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::ORI)?,
+            "OUT" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::OUT)?,
+            "SBI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::SBI)?,
+            "SUI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::SUI)?,
+            "XRI" => expect(1)
+                .and_then(|_| parse_u8(operands[0]))
+                .map(Instruction::XRI)?,
+            "INR" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::INR)?,
+            "DCR" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::DCR)?,
+            "ADD" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::ADD)?,
+            "ANA" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::ANA)?,
+            "ADC" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::ADC)?,
+            "SUB" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::SUB)?,
+            "SBB" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::SBB)?,
+            "XRA" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::XRA)?,
+            "ORA" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::ORA)?,
+            "CMP" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::CMP)?,
+            "PUSH" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::PUSH)?,
+            "POP" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::POP)?,
+            "STAX" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::STAX)?,
+            "LDAX" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::LDAX)?,
+            "INX" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::INX)?,
+            "DCX" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::DCX)?,
+            "DAD" => expect(1)
+                .and_then(|_| operands[0].parse())
+                .map(Instruction::DAD)?,
+            "MOV" => {
+                expect(2)?;
+                let target = operands[0].parse()?;
+                let source = operands[1].parse()?;
+                if target == Operand::M && source == Operand::M {
+                    return Err(ParseError::new(
+                        "MOV M,M is not an instruction, it encodes HLT",
+                    ));
+                }
+                Instruction::MOV(target, source)
+            }
+            "MVI" => {
+                expect(2)?;
+                Instruction::MVI(operands[0].parse()?, parse_u8(operands[1])?)
+            }
+            "LXI" => {
+                expect(2)?;
+                Instruction::LXI(operands[0].parse()?, parse_u16(operands[1])?)
+            }
+            "RST" => {
+                expect(1)?;
+                match parse_number(operands[0])? {
+                    n @ 0..=7 => Instruction::RST(n as u8),
+                    n => {
+                        return Err(ParseError::new(&format!(
+                            "RST expects a restart number from 0 to 7, found {}",
+                            n
+                        )))
+                    }
+                }
+            }
+            _ => return Err(ParseError::new(&format!("unknown mnemonic '{}'", mnemonic))),
+        };
+
+        Ok(instruction)
+    }
+}
+
+// An error from parsing an instruction, operand or number in Intel syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(message: &str) -> Self {
+        ParseError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+// Formats a byte or a word as an Intel hex literal.
+struct Hex8(u8);
+
+struct Hex16(u16);
+
+impl fmt::Display for Hex8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 >= 0xA0 {
+            write!(f, "0{:02X}H", self.0)
+        } else {
+            write!(f, "{:02X}H", self.0)
+        }
+    }
+}
+
+impl fmt::Display for Hex16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 >= 0xA000 {
+            write!(f, "0{:04X}H", self.0)
+        } else {
+            write!(f, "{:04X}H", self.0)
+        }
+    }
+}
+
+// Split `s` at each `sep` outside quotes, so that a quoted ';' or ',' is kept
+// as an operand.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Parse a number in any of the forms Intel assemblers accept: a suffix of H
+// (hex), D (decimal), O or Q (octal) or B (binary), or no suffix for decimal.
+// A 0x prefix, a leading minus sign and a quoted character are also accepted.
+fn parse_number(s: &str) -> Result<i64, ParseError> {
+    let invalid = || ParseError::new(&format!("invalid number '{}'", s));
+
+    let bytes = s.as_bytes();
+    if bytes.len() == 3 && bytes[0] == b'\'' && bytes[2] == b'\'' {
+        return Ok(bytes[1] as i64);
+    }
+
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let upper = digits.to_ascii_uppercase();
+    let (digits, radix) = if let Some(hex) = upper.strip_prefix("0X") {
+        (hex, 16)
+    } else if let Some(hex) = upper.strip_suffix('H') {
+        (hex, 16)
+    } else if let Some(oct) = upper.strip_suffix('O').or_else(|| upper.strip_suffix('Q')) {
+        (oct, 8)
+    } else if let Some(bin) = upper.strip_suffix('B') {
+        (bin, 2)
+    } else if let Some(dec) = upper.strip_suffix('D') {
+        (dec, 10)
+    } else {
+        (upper.as_str(), 10)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+
+    let val = i64::from_str_radix(digits, radix).map_err(|_| invalid())?;
+    Ok(if negative { -val } else { val })
+}
+
+fn parse_u8(s: &str) -> Result<u8, ParseError> {
+    match parse_number(s)? {
+        val @ 0..=0xFF => Ok(val as u8),
+        val @ -0x80..=-1 => Ok(val as u8),
+        _ => Err(ParseError::new(&format!("'{}' does not fit in 8 bits", s))),
+    }
+}
+
+fn parse_u16(s: &str) -> Result<u16, ParseError> {
+    match parse_number(s)? {
+        val @ 0..=0xFFFF => Ok(val as u16),
+        val @ -0x8000..=-1 => Ok(val as u16),
+        _ => Err(ParseError::new(&format!("'{}' does not fit in 16 bits", s))),
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operand::A => "A",
+            Operand::B => "B",
+            Operand::C => "C",
+            Operand::D => "D",
+            Operand::E => "E",
+            Operand::H => "H",
+            Operand::L => "L",
+            Operand::M => "M",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Operand {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Operand, ParseError> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(Operand::A),
+            "B" => Ok(Operand::B),
+            "C" => Ok(Operand::C),
+            "D" => Ok(Operand::D),
+            "E" => Ok(Operand::E),
+            "H" => Ok(Operand::H),
+            "L" => Ok(Operand::L),
+            "M" => Ok(Operand::M),
+            _ => Err(ParseError::new(&format!(
+                "invalid register '{}', expected one of A, B, C, D, E, H, L or M",
+                s
+            ))),
+        }
+    }
+}
+
+// Register pairs are printed by the name of their first register, as Intel
+// syntax has it. BC, DE and HL are accepted when parsing.
+impl fmt::Display for RegisterPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RegisterPair::BC => "B",
+            RegisterPair::DE => "D",
+            RegisterPair::HL => "H",
+            RegisterPair::SP => "SP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for RegisterPair {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<RegisterPair, ParseError> {
+        match s.to_ascii_uppercase().as_str() {
+            "B" | "BC" => Ok(RegisterPair::BC),
+            "D" | "DE" => Ok(RegisterPair::DE),
+            "H" | "HL" => Ok(RegisterPair::HL),
+            "SP" => Ok(RegisterPair::SP),
+            _ => Err(ParseError::new(&format!(
+                "invalid register pair '{}', expected one of B, D, H or SP",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PushPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PushPair::BC => "B",
+            PushPair::DE => "D",
+            PushPair::HL => "H",
+            PushPair::PSW => "PSW",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for PushPair {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<PushPair, ParseError> {
+        match s.to_ascii_uppercase().as_str() {
+            "B" | "BC" => Ok(PushPair::BC),
+            "D" | "DE" => Ok(PushPair::DE),
+            "H" | "HL" => Ok(PushPair::HL),
+            "PSW" => Ok(PushPair::PSW),
+            _ => Err(ParseError::new(&format!(
+                "invalid register pair '{}', expected one of B, D, H or PSW",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for IndexPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IndexPair::BC => "B",
+            IndexPair::DE => "D",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for IndexPair {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<IndexPair, ParseError> {
+        match s.to_ascii_uppercase().as_str() {
+            "B" | "BC" => Ok(IndexPair::BC),
+            "D" | "DE" => Ok(IndexPair::DE),
+            _ => Err(ParseError::new(&format!(
+                "invalid register pair '{}', expected B or D",
+                s
+            ))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -677,6 +1865,161 @@ mod tests {
 
     #[test]
     fn test_size() {
-        assert_eq!(Instruction::PUSH(Operand::C).size(), 1);
+        assert_eq!(Instruction::PUSH(PushPair::BC).size(), 1);
+    }
+
+    #[test]
+    fn test_decode_register_pairs() {
+        assert!(matches!(
+            Instruction::from(&[0x31, 0x00, 0x24][..]),
+            Instruction::LXI(RegisterPair::SP, 0x2400)
+        ));
+        assert!(matches!(
+            Instruction::from(&[0xf5][..]),
+            Instruction::PUSH(PushPair::PSW)
+        ));
+        assert!(matches!(
+            Instruction::from(&[0x1a][..]),
+            Instruction::LDAX(IndexPair::DE)
+        ));
+        assert!(matches!(
+            Instruction::from(&[0x29][..]),
+            Instruction::DAD(RegisterPair::HL)
+        ));
+    }
+
+    #[test]
+    fn test_flags_metadata() {
+        assert_eq!(Instruction::ADC(Operand::B).flags_read(), Flags::CARRY);
+        assert!(Instruction::ADC(Operand::B)
+            .flags_written()
+            .contains(Flags::ALL));
+        assert_eq!(Instruction::JPO(0).flags_read(), Flags::PARITY);
+        assert!(!Instruction::INR(Operand::A)
+            .flags_written()
+            .contains(Flags::CARRY));
+        assert!(Instruction::MOV(Operand::A, Operand::B)
+            .flags_written()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_registers_metadata() {
+        let mov = Instruction::MOV(Operand::M, Operand::C);
+        assert_eq!(mov.registers_read(), RegisterSet::C | RegisterSet::HL);
+        assert!(mov.registers_written().is_empty());
+        assert_eq!(mov.memory_access(), Access::Write);
+
+        let dad = Instruction::DAD(RegisterPair::SP);
+        assert_eq!(dad.registers_read(), RegisterSet::HL | RegisterSet::SP);
+        assert_eq!(dad.registers_written(), RegisterSet::HL);
+
+        let pop = Instruction::POP(PushPair::PSW);
+        assert_eq!(pop.registers_written(), RegisterSet::A | RegisterSet::SP);
+        assert_eq!(pop.flags_written(), Flags::ALL);
+        assert_eq!(pop.stack_access(), Access::Read);
+    }
+
+    #[test]
+    fn test_flow_metadata() {
+        assert_eq!(Instruction::CNZ(0x1234).flow(), Flow::ConditionalCall);
+        assert_eq!(Instruction::CNZ(0x1234).target(), Some(0x1234));
+        assert_eq!(Instruction::RST(7).target(), Some(0x38));
+        assert_eq!(Instruction::PCHL.flow(), Flow::Indirect);
+        assert_eq!(Instruction::PCHL.target(), None);
+        assert_eq!(Instruction::OUT(1).io_access(), Access::Write);
+        assert!(Instruction::RET.registers_read().contains(RegisterSet::SP));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::MVI(Operand::A, 0x3F).to_string(), "MVI A,3FH");
+        assert_eq!(
+            Instruction::LXI(RegisterPair::SP, 0xF000).to_string(),
+            "LXI SP,0F000H"
+        );
+        assert_eq!(Instruction::PUSH(PushPair::PSW).to_string(), "PUSH PSW");
+        assert_eq!(Instruction::STAX(IndexPair::DE).to_string(), "STAX D");
+        assert_eq!(
+            Instruction::MOV(Operand::M, Operand::A).to_string(),
+            "MOV M,A"
+        );
+        assert_eq!(Instruction::OUT(0xFE).to_string(), "OUT 0FEH");
+        assert_eq!(Instruction::RST(7).to_string(), "RST 7");
+    }
+
+    #[test]
+    fn test_display_with_symbols() {
+        let symbols = crate::symbols::SymbolTable::parse("0005 BDOS\n0100 START").unwrap();
+        let display = |instr: Instruction| instr.display(Some(&symbols)).to_string();
+        assert_eq!(display(Instruction::CALL(0x0005)), "CALL BDOS");
+        assert_eq!(
+            display(Instruction::LXI(RegisterPair::SP, 0x0100)),
+            "LXI SP,START"
+        );
+        assert_eq!(display(Instruction::JMP(0x0101)), "JMP 0101H");
+        assert_eq!(display(Instruction::MVI(Operand::C, 0x05)), "MVI C,05H");
+        assert_eq!(
+            Instruction::CALL(0x0005).display(None).to_string(),
+            "CALL 0005H"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = |s: &str| s.parse::<Instruction>().unwrap().to_string();
+        assert_eq!(parse("MVI A,3FH"), "MVI A,3FH");
+        assert_eq!(parse("  mvi   a , 63 ; comment"), "MVI A,3FH");
+        assert_eq!(parse("lxi h,0x2400"), "LXI H,2400H");
+        assert_eq!(parse("LXI HL,-1"), "LXI H,0FFFFH");
+        assert_eq!(parse("MVI C,'$'"), "MVI C,24H");
+        assert_eq!(parse("MVI A,';'"), "MVI A,3BH");
+        assert_eq!(parse("CPI ';' ; semicolon"), "CPI 3BH");
+        assert_eq!(parse("MVI C,','"), "MVI C,2CH");
+        assert_eq!(parse("ANI 11110000B"), "ANI 0F0H");
+        assert_eq!(parse("JMP 177777Q"), "JMP 0FFFFH");
+        assert_eq!(parse("xthl"), "XTHL");
+    }
+
+    #[test]
+    fn test_display_parse_round_trip() {
+        for opcode in 0..=0xFFu8 {
+            let bytes = [opcode, 0x34, 0xA2];
+            let instr = Instruction::from(&bytes[..]);
+            let text = instr.to_string();
+            let parsed: Instruction = text.parse().unwrap();
+            assert_eq!(parsed.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |s: &str| s.parse::<Instruction>().unwrap_err().message;
+        assert_eq!(error("MVX A,1"), "unknown mnemonic 'MVX'");
+        assert_eq!(error("MVI A"), "MVI expects 2 operands, found 1");
+        assert_eq!(
+            error("LXI A,1"),
+            "invalid register pair 'A', expected one of B, D, H or SP"
+        );
+        assert_eq!(error("MVI A,100H"), "'100H' does not fit in 8 bits");
+        assert_eq!(error("JMP 12G4H"), "invalid number '12G4H'");
+        assert_eq!(
+            error("RST 8"),
+            "RST expects a restart number from 0 to 7, found 8"
+        );
+        assert_eq!(error(""), "expected an instruction");
+    }
+
+    #[test]
+    fn test_class() {
+        assert_eq!(Instruction::XCHG.class(), InstructionClass::DataTransfer);
+        assert_eq!(
+            Instruction::DAD(RegisterPair::BC).class(),
+            InstructionClass::Arithmetic
+        );
+        assert_eq!(Instruction::CPI(0).class(), InstructionClass::Logical);
+        assert_eq!(Instruction::PCHL.class(), InstructionClass::Branch);
+        assert_eq!(Instruction::RST(0).class(), InstructionClass::Branch);
+        assert_eq!(Instruction::XTHL.class(), InstructionClass::Control);
     }
 }
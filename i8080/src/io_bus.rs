@@ -0,0 +1,342 @@
+use crate::machine::{ClockedIO, CpuView, MachineIO};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+
+// A device attached to an IoBus. A device only sees the ports it has been
+// mapped to. The timing and interrupt methods are optional and let an IoBus
+// be run with Cpu::run_clocked.
+pub trait PortDevice {
+    fn port_in(&mut self, port: u8) -> u8;
+
+    fn port_out(&mut self, port: u8, val: u8);
+
+    // See ClockedIO::tick.
+    fn tick(&mut self, _cycles: u32) {}
+
+    // See ClockedIO::interrupt_request.
+    fn interrupt_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    // See ClockedIO::interrupt_acknowledge.
+    fn interrupt_acknowledge(&mut self) {}
+}
+
+// What an IoBus does with an access to a port no device is mapped to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnmappedPolicy {
+    // Reads return the given value, typically 0xFF for a data bus pulled
+    // high, and writes are ignored.
+    OpenBus(u8),
+    // Reads return 0 and writes are ignored.
+    Ignore,
+    // Panic, naming the port and direction.
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    In,
+    Out,
+    InOut,
+}
+
+// A set of port numbers. A port matches if it equals `value` in every bit
+// set in `mask`, which allows for partially decoded port addresses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PortMatch {
+    pub value: u8,
+    pub mask: u8,
+}
+
+impl PortMatch {
+    pub fn port(port: u8) -> Self {
+        PortMatch {
+            value: port,
+            mask: 0xFF,
+        }
+    }
+
+    pub fn masked(value: u8, mask: u8) -> Self {
+        PortMatch { value, mask }
+    }
+
+    pub fn matches(&self, port: u8) -> bool {
+        port & self.mask == self.value & self.mask
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+// PortDevice plus downcasting, so the owner of an IoBus can get back at the
+// concrete device (e.g. to feed it key presses).
+trait AnyPortDevice: PortDevice {
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: PortDevice + 'static> AnyPortDevice for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// An I/O bus that routes IN and OUT to the devices mapped to each port. Each
+// port has at most one device per direction. Mapping a port that is already
+// mapped replaces the earlier mapping.
+pub struct IoBus {
+    devices: Vec<Box<dyn AnyPortDevice>>,
+    inputs: [Option<DeviceId>; 256],
+    outputs: [Option<DeviceId>; 256],
+    unmapped: UnmappedPolicy,
+}
+
+impl IoBus {
+    pub fn new(unmapped: UnmappedPolicy) -> Self {
+        IoBus {
+            devices: Vec::new(),
+            inputs: [None; 256],
+            outputs: [None; 256],
+            unmapped,
+        }
+    }
+
+    // Add a device to the bus. The device does not respond to any ports until
+    // it is mapped.
+    pub fn attach<D: PortDevice + 'static>(&mut self, device: D) -> DeviceId {
+        self.devices.push(Box::new(device));
+        DeviceId(self.devices.len() - 1)
+    }
+
+    pub fn map(&mut self, ports: PortMatch, access: Access, device: DeviceId) {
+        assert!(device.0 < self.devices.len(), "Unknown device {:?}", device);
+        for port in 0..=255u8 {
+            if !ports.matches(port) {
+                continue;
+            }
+            if access != Access::Out {
+                self.inputs[port as usize] = Some(device);
+            }
+            if access != Access::In {
+                self.outputs[port as usize] = Some(device);
+            }
+        }
+    }
+
+    // Attach a device and map it to the given ports in one go.
+    pub fn attach_at<D: PortDevice + 'static>(
+        &mut self,
+        ports: PortMatch,
+        access: Access,
+        device: D,
+    ) -> DeviceId {
+        let id = self.attach(device);
+        self.map(ports, access, id);
+        id
+    }
+
+    pub fn device<D: PortDevice + 'static>(&self, id: DeviceId) -> Option<&D> {
+        self.devices.get(id.0)?.as_any().downcast_ref()
+    }
+
+    pub fn device_mut<D: PortDevice + 'static>(&mut self, id: DeviceId) -> Option<&mut D> {
+        self.devices.get_mut(id.0)?.as_any_mut().downcast_mut()
+    }
+
+    pub fn set_unmapped_policy(&mut self, unmapped: UnmappedPolicy) {
+        self.unmapped = unmapped;
+    }
+
+    pub fn port_in(&mut self, port: u8) -> u8 {
+        match self.inputs[port as usize] {
+            Some(id) => self.devices[id.0].port_in(port),
+            None => match self.unmapped {
+                UnmappedPolicy::OpenBus(val) => val,
+                UnmappedPolicy::Ignore => 0,
+                UnmappedPolicy::Error => panic!("Invalid port {:?} for IN", port),
+            },
+        }
+    }
+
+    pub fn port_out(&mut self, port: u8, val: u8) {
+        match self.outputs[port as usize] {
+            Some(id) => self.devices[id.0].port_out(port, val),
+            None => match self.unmapped {
+                UnmappedPolicy::OpenBus(_) | UnmappedPolicy::Ignore => (),
+                UnmappedPolicy::Error => panic!("Invalid port {:?} for OUT", port),
+            },
+        }
+    }
+}
+
+impl MachineIO for IoBus {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        self.port_in(port)
+    }
+
+    fn machine_out(&mut self, _: &mut CpuView, port: u8, val: u8) {
+        self.port_out(port, val)
+    }
+}
+
+// Every device is ticked. When several devices request an interrupt, the one
+// attached first wins.
+impl ClockedIO for IoBus {
+    fn tick(&mut self, cycles: u32) {
+        for device in self.devices.iter_mut() {
+            device.tick(cycles);
+        }
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        self.devices
+            .iter_mut()
+            .find_map(|device| device.interrupt_request())
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        for device in self.devices.iter_mut() {
+            if device.interrupt_request().is_some() {
+                device.interrupt_acknowledge();
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Latch {
+        val: u8,
+        writes: Vec<(u8, u8)>,
+    }
+
+    impl PortDevice for Latch {
+        fn port_in(&mut self, port: u8) -> u8 {
+            self.val.wrapping_add(port)
+        }
+
+        fn port_out(&mut self, port: u8, val: u8) {
+            self.val = val;
+            self.writes.push((port, val));
+        }
+    }
+
+    #[derive(Default)]
+    struct Timer {
+        cycles: u32,
+        pending: bool,
+    }
+
+    impl PortDevice for Timer {
+        fn port_in(&mut self, _: u8) -> u8 {
+            0
+        }
+
+        fn port_out(&mut self, _: u8, _: u8) {}
+
+        fn tick(&mut self, cycles: u32) {
+            self.cycles += cycles;
+            if self.cycles >= 100 {
+                self.cycles -= 100;
+                self.pending = true;
+            }
+        }
+
+        fn interrupt_request(&mut self) -> Option<u8> {
+            if self.pending {
+                Some(7)
+            } else {
+                None
+            }
+        }
+
+        fn interrupt_acknowledge(&mut self) {
+            self.pending = false;
+        }
+    }
+
+    #[test]
+    fn test_port_routing() {
+        let mut bus = IoBus::new(UnmappedPolicy::Ignore);
+        let input = bus.attach_at(PortMatch::port(1), Access::In, Latch::default());
+        let output = bus.attach_at(PortMatch::port(1), Access::Out, Latch::default());
+
+        bus.port_out(1, 0x40);
+        assert_eq!(bus.port_in(1), 1);
+        assert_eq!(bus.device::<Latch>(output).unwrap().writes, vec![(1, 0x40)]);
+        assert!(bus.device::<Latch>(input).unwrap().writes.is_empty());
+
+        bus.device_mut::<Latch>(input).unwrap().val = 0x10;
+        assert_eq!(bus.port_in(1), 0x11);
+        assert!(bus.device::<Timer>(input).is_none());
+    }
+
+    #[test]
+    fn test_masked_ports() {
+        let mut bus = IoBus::new(UnmappedPolicy::Ignore);
+        // A device that only decodes A0 and A1 responds to 0x10-0x13.
+        let id = bus.attach_at(
+            PortMatch::masked(0x10, 0xFC),
+            Access::InOut,
+            Latch::default(),
+        );
+        for port in 0x10..=0x13 {
+            bus.port_out(port, port);
+        }
+        bus.port_out(0x14, 0xFF);
+        assert_eq!(bus.device::<Latch>(id).unwrap().writes.len(), 4);
+        assert_eq!(bus.port_in(0x12), 0x13 + 0x12);
+    }
+
+    #[test]
+    fn test_later_mapping_replaces_earlier() {
+        let mut bus = IoBus::new(UnmappedPolicy::Ignore);
+        let first = bus.attach_at(PortMatch::masked(0, 0), Access::InOut, Latch::default());
+        let second = bus.attach_at(PortMatch::port(5), Access::Out, Latch::default());
+        bus.port_out(5, 1);
+        bus.port_out(6, 2);
+        assert_eq!(bus.device::<Latch>(first).unwrap().writes, vec![(6, 2)]);
+        assert_eq!(bus.device::<Latch>(second).unwrap().writes, vec![(5, 1)]);
+    }
+
+    #[test]
+    fn test_unmapped_policy() {
+        let mut bus = IoBus::new(UnmappedPolicy::OpenBus(0xFF));
+        assert_eq!(bus.port_in(0x20), 0xFF);
+        bus.port_out(0x20, 0x00);
+        bus.set_unmapped_policy(UnmappedPolicy::Ignore);
+        assert_eq!(bus.port_in(0x20), 0x00);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid port 32 for OUT")]
+    fn test_unmapped_error() {
+        let mut bus = IoBus::new(UnmappedPolicy::Error);
+        bus.port_out(0x20, 0x00);
+    }
+
+    #[test]
+    fn test_clocked_devices() {
+        let mut bus = IoBus::new(UnmappedPolicy::Ignore);
+        let timer = bus.attach(Timer::default());
+        assert_eq!(bus.interrupt_request(), None);
+        bus.tick(60);
+        bus.tick(60);
+        assert_eq!(bus.interrupt_request(), Some(7));
+        bus.interrupt_acknowledge();
+        assert_eq!(bus.interrupt_request(), None);
+        assert_eq!(bus.device::<Timer>(timer).unwrap().cycles, 20);
+    }
+}
@@ -1,10 +1,32 @@
 #![allow(dead_code)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+pub mod analysis;
+pub mod block;
+pub mod bus;
+pub mod call_stack;
 mod condition_codes;
 pub mod cpu;
+pub mod crash_dump;
+pub mod decode_cache;
+pub mod history;
 pub mod instruction;
+pub mod io_bus;
 pub mod machine;
 pub mod memory_bus;
+pub mod power_on;
+pub mod recompiled;
+pub mod recompiler;
 mod registers;
+pub mod sanitizer;
+pub mod scheduler;
+pub mod smc;
+pub mod state;
+pub mod symbols;
+#[cfg(test)]
+pub(crate) mod test_support;
 
 pub use cpu::Cpu;
+pub use state::CpuState;
@@ -1,8 +1,307 @@
+use crate::call_stack::Backtrace;
+use crate::condition_codes::ConditionCodes;
 use crate::cpu::Cpu;
+use crate::crash_dump::CrashDump;
 use crate::memory_bus::MemoryMap;
+use crate::power_on::PowerOn;
+use crate::registers::Registers;
+use crate::sanitizer::Sanitizer;
+use crate::scheduler::{EventId, Scheduler};
+use crate::smc::SmcDetector;
 
+use alloc::boxed::Box;
+
+// The I/O ports of a machine. MachineIO is object safe, so a machine can be
+// boxed and chosen at runtime.
 pub trait MachineIO {
     fn machine_in(&mut self, port: u8) -> u8;
 
-    fn machine_out<M: MemoryMap>(&mut self, cpu: &mut Cpu<M>, port: u8, val: u8);
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8);
+}
+
+// A view of the cpu given to MachineIO::machine_out. Output handlers can read
+// the cpu's registers and flags, access memory and halt the cpu, but cannot
+// otherwise change its state. This is enough for, e.g., a CP/M BDOS shim
+// that prints the string addressed by DE when C is 9.
+pub struct CpuView<'a> {
+    pub registers: &'a Registers,
+    pub condition_codes: &'a ConditionCodes,
+    pub pc: u16,
+    pub sp: u16,
+    pub memory: &'a mut dyn MemoryMap,
+    is_halted: &'a mut bool,
+}
+
+impl<'a> CpuView<'a> {
+    pub(crate) fn new(
+        registers: &'a Registers,
+        condition_codes: &'a ConditionCodes,
+        pc: u16,
+        sp: u16,
+        memory: &'a mut dyn MemoryMap,
+        is_halted: &'a mut bool,
+    ) -> Self {
+        CpuView {
+            registers,
+            condition_codes,
+            pc,
+            sp,
+            memory,
+            is_halted,
+        }
+    }
+
+    // Halt the cpu, as HLT does. It stays halted until an interrupt wakes it,
+    // which callers can check with Cpu::is_halted once a run returns.
+    pub fn halt(&mut self) {
+        *self.is_halted = true;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        *self.is_halted
+    }
+}
+
+// A view of the cpu's scheduler given to ClockedIO::event, so that a device
+// can schedule its next event or cancel others.
+pub struct SchedulerView<'a> {
+    scheduler: &'a mut Scheduler,
+    now: u64,
+}
+
+impl<'a> SchedulerView<'a> {
+    pub(crate) fn new(scheduler: &'a mut Scheduler, now: u64) -> Self {
+        SchedulerView { scheduler, now }
+    }
+
+    // The cpu's cycle counter.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    // Schedule an event to fall due `cycles` cycles from now.
+    pub fn schedule(&mut self, cycles: u64, event: EventId) {
+        self.scheduler.schedule_at(self.now + cycles, event);
+    }
+
+    // See Scheduler::cancel.
+    pub fn cancel(&mut self, event: EventId) -> bool {
+        self.scheduler.cancel(event)
+    }
+}
+
+impl<T: MachineIO + ?Sized> MachineIO for &mut T {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        (**self).machine_in(port)
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+        (**self).machine_out(cpu, port, val)
+    }
+}
+
+impl<T: MachineIO + ?Sized> MachineIO for Box<T> {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        (**self).machine_in(port)
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+        (**self).machine_out(cpu, port, val)
+    }
+}
+
+// A machine with devices that advance with the cpu, such as timers, baud rate
+// clocks, sound generators or video hardware. Run it with Cpu::step_clocked
+// or Cpu::run_clocked, which keep the devices in time with the cpu and accept
+// the interrupts they request.
+pub trait ClockedIO: MachineIO {
+    // Advance the machine's devices by the number of cycles the cpu has just
+    // spent executing an instruction or accepting an interrupt.
+    fn tick(&mut self, cycles: u32);
+
+    // The interrupt the machine is requesting, if any, given as the RST
+    // number (0-7) the interrupting device places on the data bus. The
+    // request stays asserted until it is acknowledged, so a request made
+    // while interrupts are disabled is accepted once they are enabled.
+    fn interrupt_request(&mut self) -> Option<u8>;
+
+    // Called when the cpu accepts the requested interrupt.
+    fn interrupt_acknowledge(&mut self);
+
+    // Called when an event scheduled with Cpu::schedule falls due, once the
+    // instruction during which it fell due has completed and the devices
+    // have been advanced past it. An interrupt requested here is accepted
+    // straight away. Events due on the same cycle are delivered in the order
+    // they were scheduled.
+    fn event(&mut self, _scheduler: &mut SchedulerView, _event: EventId) {}
+}
+
+impl<T: ClockedIO + ?Sized> ClockedIO for &mut T {
+    fn tick(&mut self, cycles: u32) {
+        (**self).tick(cycles)
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        (**self).interrupt_request()
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        (**self).interrupt_acknowledge()
+    }
+
+    fn event(&mut self, scheduler: &mut SchedulerView, event: EventId) {
+        (**self).event(scheduler, event)
+    }
+}
+
+impl<T: ClockedIO + ?Sized> ClockedIO for Box<T> {
+    fn tick(&mut self, cycles: u32) {
+        (**self).tick(cycles)
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        (**self).interrupt_request()
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        (**self).interrupt_acknowledge()
+    }
+
+    fn event(&mut self, scheduler: &mut SchedulerView, event: EventId) {
+        (**self).event(scheduler, event)
+    }
+}
+
+// A complete machine behind a trait object, so that a frontend can choose
+// which machine to run at runtime. Dynamic dispatch happens once per call to
+// run_for rather than per instruction or memory access, provided the machine
+// is a System of concrete memory and I/O types. A System of boxed parts also
+// implements Machine for machines composed entirely at runtime, at the cost
+// of a virtual call per memory access.
+pub trait Machine {
+    // Run until at least `cycles` more cycles have elapsed or the cpu reaches
+    // a breakpoint. A halted cpu idles until an interrupt wakes it.
+    fn run_for(&mut self, cycles: u64);
+
+    fn is_halted(&self) -> bool;
+
+    // The total number of cycles the cpu has executed.
+    fn cycles(&self) -> u64;
+
+    fn memory(&mut self) -> &mut dyn MemoryMap;
+
+    fn io(&mut self) -> &mut dyn ClockedIO;
+
+    // Pulse the cpu's RESET pin. See Cpu::reset.
+    fn reset(&mut self);
+
+    // Randomise the cpu and RAM as at power on, then reset. See
+    // Cpu::power_on.
+    fn power_on(&mut self, power_on: &PowerOn);
+
+    // The cpu's debugging aids.
+    fn cpu_debug(&mut self) -> &mut dyn DebugHooks;
+}
+
+// The debugging aids of a cpu, for frontends that only have a Machine. See
+// the Cpu methods of the same names.
+pub trait DebugHooks {
+    // The sanitizer, if enabled.
+    fn sanitizer_mut(&mut self) -> Option<&mut Sanitizer>;
+
+    // The self-modifying code detector, if enabled.
+    fn smc_detector_mut(&mut self) -> Option<&mut SmcDetector>;
+
+    // The calls leading to the current instruction, if the call stack is
+    // enabled.
+    fn backtrace(&self) -> Option<Backtrace>;
+
+    // Capture the cpu and memory for post-mortem inspection.
+    fn crash_dump(&mut self, reason: &str) -> CrashDump;
+
+    // The breakpoint a run last stopped at.
+    fn take_breakpoint_hit(&mut self) -> Option<u16>;
+}
+
+impl<M: MemoryMap> DebugHooks for Cpu<M> {
+    fn sanitizer_mut(&mut self) -> Option<&mut Sanitizer> {
+        Cpu::sanitizer_mut(self)
+    }
+
+    fn smc_detector_mut(&mut self) -> Option<&mut SmcDetector> {
+        Cpu::smc_detector_mut(self)
+    }
+
+    fn backtrace(&self) -> Option<Backtrace> {
+        Cpu::backtrace(self)
+    }
+
+    fn crash_dump(&mut self, reason: &str) -> CrashDump {
+        Cpu::crash_dump(self, reason)
+    }
+
+    fn take_breakpoint_hit(&mut self) -> Option<u16> {
+        Cpu::take_breakpoint_hit(self)
+    }
+}
+
+// A cpu together with the I/O it is wired to.
+pub struct System<M, IO>
+where
+    M: MemoryMap,
+    IO: ClockedIO,
+{
+    pub cpu: Cpu<M>,
+    pub io: IO,
+}
+
+impl<M, IO> System<M, IO>
+where
+    M: MemoryMap,
+    IO: ClockedIO,
+{
+    pub fn new(memory: M, io: IO) -> Self {
+        System {
+            cpu: Cpu::new(memory),
+            io,
+        }
+    }
+}
+
+impl<M, IO> Machine for System<M, IO>
+where
+    M: MemoryMap,
+    IO: ClockedIO,
+{
+    fn run_for(&mut self, cycles: u64) {
+        self.cpu.run_clocked(cycles, &mut self.io);
+    }
+
+    fn is_halted(&self) -> bool {
+        self.cpu.is_halted
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    fn memory(&mut self) -> &mut dyn MemoryMap {
+        &mut self.cpu.memory
+    }
+
+    fn io(&mut self) -> &mut dyn ClockedIO {
+        &mut self.io
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    fn power_on(&mut self, power_on: &PowerOn) {
+        self.cpu.power_on(power_on);
+    }
+
+    fn cpu_debug(&mut self) -> &mut dyn DebugHooks {
+        &mut self.cpu
+    }
 }
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+
 pub trait MemoryMap {
     fn load_rom(&mut self);
 
@@ -6,4 +8,55 @@ pub trait MemoryMap {
     fn read_slice(&mut self, addr: u16) -> &[u8];
 
     fn write(&mut self, addr: u16, val: u8);
+
+    // Read a byte for inspection, e.g. by a debugger or a crash dump, rather
+    // than on behalf of the cpu. Returns None where nothing is mapped, so that
+    // a map that panics on such reads can be inspected safely.
+    fn peek(&mut self, addr: u16) -> Option<u8> {
+        Some(self.read(addr))
+    }
+}
+
+impl<T: MemoryMap + ?Sized> MemoryMap for &mut T {
+    fn load_rom(&mut self) {
+        (**self).load_rom()
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        (**self).read(addr)
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        (**self).read_slice(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        (**self).write(addr, val)
+    }
+
+    fn peek(&mut self, addr: u16) -> Option<u8> {
+        (**self).peek(addr)
+    }
+}
+
+impl<T: MemoryMap + ?Sized> MemoryMap for Box<T> {
+    fn load_rom(&mut self) {
+        (**self).load_rom()
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        (**self).read(addr)
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        (**self).read_slice(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        (**self).write(addr, val)
+    }
+
+    fn peek(&mut self, addr: u16) -> Option<u8> {
+        (**self).peek(addr)
+    }
 }
@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+// How to fill the cpu and RAM with garbage at power on, for Cpu::power_on.
+// Real hardware powers up with the registers, flags, stack pointer and RAM
+// holding whatever the chips settle to, so software that relies on them being
+// zero works in the emulator but fails on a real board. The same seed always
+// produces the same state, so a failure can be reproduced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PowerOn {
+    pub seed: u64,
+    // The address ranges to fill with random bytes. Only the machine knows
+    // which addresses are RAM, so this is empty unless set.
+    pub ram: Vec<RangeInclusive<u16>>,
+}
+
+impl PowerOn {
+    pub fn new(seed: u64) -> Self {
+        PowerOn {
+            seed,
+            ram: Vec::new(),
+        }
+    }
+
+    pub fn with_ram(mut self, range: RangeInclusive<u16>) -> Self {
+        self.ram.push(range);
+        self
+    }
+}
+
+// SplitMix64. Small, fast and good enough for garbage; not for anything that
+// needs real randomness.
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    pub(crate) fn next_u16(&mut self) -> u16 {
+        (self.next_u64() >> 48) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut first = Rng::new(42);
+        let mut second = Rng::new(42);
+        let mut other = Rng::new(43);
+        let a: Vec<u64> = (0..8).map(|_| first.next_u64()).collect();
+        let b: Vec<u64> = (0..8).map(|_| second.next_u64()).collect();
+        let c: Vec<u64> = (0..8).map(|_| other.next_u64()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+}
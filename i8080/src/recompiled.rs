@@ -0,0 +1,464 @@
+// Support for code generated by the recompiler. See recompiler.rs.
+//
+// Recompiled code works on a Cpu directly: the registers, flags, stack
+// pointer and cycle counter are its fields, so control can pass back and
+// forth between compiled code and the interpreter at any instruction. The
+// arithmetic, stack and I/O operations below are the interpreter's own, so
+// the flags come out the same, quirks included.
+
+use crate::bus::BusCycleKind;
+use crate::cpu::Cpu;
+use crate::instruction::{Operand, PushPair, RegisterPair};
+use crate::machine::ClockedIO;
+use crate::memory_bus::MemoryMap;
+use crate::state::CpuState;
+
+use core::fmt;
+
+// The compiled blocks of a program.
+pub trait Program {
+    // Run the compiled block starting at the pc until it ends, the cycle
+    // counter reaches `end`, the cpu halts or an interrupt is accepted.
+    // Returns false, having run nothing, if no block starts at the pc or the
+    // code it was compiled from has since been overwritten.
+    fn run_block<M, IO>(&self, cpu: &mut Cpu<M>, machine: &mut IO, end: u64) -> bool
+    where
+        M: MemoryMap,
+        IO: ClockedIO + ?Sized;
+}
+
+// Whether compiled code can run on `cpu`. The sanitizer, call stack, history,
+// self-modifying code detector and breakpoints all watch individual
+// instructions, which compiled code does not report, so while any is enabled
+// only the interpreter is used.
+pub fn can_run<M: MemoryMap>(cpu: &Cpu<M>) -> bool {
+    cpu.sanitizer().is_none()
+        && cpu.call_stack().is_none()
+        && cpu.history().is_none()
+        && cpu.smc_detector().is_none()
+        && cpu.breakpoints().next().is_none()
+}
+
+// Cpu::run_clocked, running compiled blocks where there are any and
+// interpreting the instructions in between.
+pub fn run_clocked<P, M, IO>(program: &P, cpu: &mut Cpu<M>, cycles: u64, machine: &mut IO)
+where
+    P: Program,
+    M: MemoryMap,
+    IO: ClockedIO + ?Sized,
+{
+    if !can_run(cpu) {
+        cpu.run_clocked(cycles, machine);
+        return;
+    }
+    let end = cpu.cycles + cycles;
+    while cpu.cycles < end {
+        if cpu.is_halted || !program.run_block(cpu, machine, end) {
+            cpu.step_clocked(machine);
+        }
+    }
+}
+
+// Finish an instruction as Cpu::step_clocked does: move the pc on, count the
+// cycles, advance the devices, deliver due events and accept a pending
+// interrupt. Returns true if the block must stop here.
+#[inline(always)]
+pub fn retire<M, IO>(cpu: &mut Cpu<M>, machine: &mut IO, next: u16, cycles: u8, end: u64) -> bool
+where
+    M: MemoryMap,
+    IO: ClockedIO + ?Sized,
+{
+    cpu.pc = next;
+    cpu.cycles += cycles as u64;
+    machine.tick(cycles as u32);
+    cpu.deliver_events(machine);
+    cpu.poll_interrupt(machine);
+    cpu.pc != next || cpu.cycles >= end || cpu.is_halted
+}
+
+// Whether memory at `addr` still holds the code a block was compiled from.
+pub fn matches<M: MemoryMap>(cpu: &mut Cpu<M>, addr: u16, code: &[u8]) -> bool {
+    code.iter()
+        .enumerate()
+        .all(|(i, &byte)| cpu.memory.read(addr.wrapping_add(i as u16)) == byte)
+}
+
+// Whether a write of `len` bytes at `addr` landed in the `size` bytes of a
+// block starting at `start`.
+#[inline(always)]
+pub fn touches(addr: u16, len: u16, start: u16, size: u16) -> bool {
+    (0..len).any(|i| addr.wrapping_add(i).wrapping_sub(start) < size)
+}
+
+#[inline(always)]
+pub fn read<M: MemoryMap>(cpu: &mut Cpu<M>, addr: u16) -> u8 {
+    cpu.bus_read(BusCycleKind::MemoryRead, addr)
+}
+
+#[inline(always)]
+pub fn write<M: MemoryMap>(cpu: &mut Cpu<M>, addr: u16, val: u8) {
+    cpu.bus_write(BusCycleKind::MemoryWrite, addr, val)
+}
+
+// Push the return address and return the target. The pc must be that of
+// the calling instruction.
+#[inline(always)]
+pub fn call<M: MemoryMap>(cpu: &mut Cpu<M>, addr: u16) -> u16 {
+    cpu.call(addr)
+}
+
+#[inline(always)]
+pub fn ret<M: MemoryMap>(cpu: &mut Cpu<M>) -> u16 {
+    cpu.ret()
+}
+
+#[inline(always)]
+pub fn rst<M: MemoryMap>(cpu: &mut Cpu<M>, n: u8) -> u16 {
+    cpu.rst(n)
+}
+
+#[inline(always)]
+pub fn push<M: MemoryMap>(cpu: &mut Cpu<M>, pair: PushPair) {
+    cpu.push(pair)
+}
+
+#[inline(always)]
+pub fn pop<M: MemoryMap>(cpu: &mut Cpu<M>, pair: PushPair) {
+    cpu.pop(pair)
+}
+
+#[inline(always)]
+pub fn xthl<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.xthl()
+}
+
+#[inline(always)]
+pub fn dad<M: MemoryMap>(cpu: &mut Cpu<M>, pair: RegisterPair) {
+    cpu.dad(pair)
+}
+
+#[inline(always)]
+pub fn inr<M: MemoryMap>(cpu: &mut Cpu<M>, operand: Operand) {
+    cpu.inr(operand)
+}
+
+#[inline(always)]
+pub fn dcr<M: MemoryMap>(cpu: &mut Cpu<M>, operand: Operand) {
+    cpu.dcr(operand)
+}
+
+#[inline(always)]
+pub fn add<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.add(val)
+}
+
+#[inline(always)]
+pub fn adc<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.adc(val)
+}
+
+#[inline(always)]
+pub fn sub<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.sub(val)
+}
+
+#[inline(always)]
+pub fn sbb<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.sbb(val)
+}
+
+#[inline(always)]
+pub fn ana<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.and(val)
+}
+
+#[inline(always)]
+pub fn xra<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.xor(val)
+}
+
+#[inline(always)]
+pub fn ora<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.or(val)
+}
+
+#[inline(always)]
+pub fn cmp<M: MemoryMap>(cpu: &mut Cpu<M>, val: u8) {
+    cpu.compare(val)
+}
+
+#[inline(always)]
+pub fn rlc<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.rlc()
+}
+
+#[inline(always)]
+pub fn rrc<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.rrc()
+}
+
+#[inline(always)]
+pub fn ral<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.ral()
+}
+
+#[inline(always)]
+pub fn rar<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.rar()
+}
+
+#[inline(always)]
+pub fn daa<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.daa()
+}
+
+pub fn hlt<M: MemoryMap>(cpu: &mut Cpu<M>) {
+    cpu.hlt()
+}
+
+pub fn output<M, IO>(cpu: &mut Cpu<M>, machine: &mut IO, port: u8)
+where
+    M: MemoryMap,
+    IO: ClockedIO + ?Sized,
+{
+    cpu.output(machine, port)
+}
+
+// How a program ran under compare.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    // The number of compiled blocks run.
+    pub blocks: u64,
+    // The number of instructions the interpreter ran in place of compiled
+    // code.
+    pub interpreted: u64,
+    pub cycles: u64,
+}
+
+// The first point at which compiled code and the interpreter disagreed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    // Where the compiled side was before the diverging step, and whether
+    // that step was a compiled block.
+    pub pc: u16,
+    pub compiled_block: bool,
+    pub compiled: CpuState,
+    pub interpreted: CpuState,
+    pub compiled_cycles: u64,
+    pub interpreted_cycles: u64,
+    // The first address at which memory differs, if it does.
+    pub memory: Option<u16>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let step = if self.compiled_block {
+            "the compiled block"
+        } else {
+            "the instruction"
+        };
+        writeln!(f, "diverged after {} at {:04X}H", step, self.pc)?;
+        writeln!(
+            f,
+            "  compiled:    {:?}, cycle {}",
+            self.compiled, self.compiled_cycles
+        )?;
+        write!(
+            f,
+            "  interpreted: {:?}, cycle {}",
+            self.interpreted, self.interpreted_cycles
+        )?;
+        if let Some(addr) = self.memory {
+            write!(f, "\n  memory differs at {:04X}H", addr)?;
+        }
+        Ok(())
+    }
+}
+
+// Run `program` on one copy of a machine and the interpreter on another for
+// `cycles` cycles or until they halt, comparing the cpu states after every
+// compiled block. Memory is compared at the end and whenever either side
+// halts, as comparing all of it after every block would be slow.
+pub fn compare<P, M, IO>(
+    program: &P,
+    cpu: &Cpu<M>,
+    machine: &IO,
+    cycles: u64,
+) -> Result<Comparison, Divergence>
+where
+    P: Program,
+    M: MemoryMap + Clone,
+    IO: ClockedIO + Clone,
+{
+    let (mut compiled, mut compiled_io) = (cpu.clone(), machine.clone());
+    let (mut interpreted, mut interpreted_io) = (cpu.clone(), machine.clone());
+    let mut comparison = Comparison::default();
+    let end = cpu.cycles + cycles;
+
+    while compiled.cycles < end && !compiled.is_halted {
+        let pc = compiled.pc;
+        let compiled_block = program.run_block(&mut compiled, &mut compiled_io, end);
+        if compiled_block {
+            comparison.blocks += 1;
+        } else {
+            compiled.step_clocked(&mut compiled_io);
+            comparison.interpreted += 1;
+        }
+        while interpreted.cycles < compiled.cycles && !interpreted.is_halted {
+            interpreted.step_clocked(&mut interpreted_io);
+        }
+
+        let halted = compiled.is_halted || interpreted.is_halted;
+        let memory = if halted {
+            first_difference(&mut compiled, &mut interpreted)
+        } else {
+            None
+        };
+        if compiled.state() != interpreted.state()
+            || compiled.cycles != interpreted.cycles
+            || memory.is_some()
+        {
+            return Err(Divergence {
+                pc,
+                compiled_block,
+                compiled: compiled.state(),
+                interpreted: interpreted.state(),
+                compiled_cycles: compiled.cycles,
+                interpreted_cycles: interpreted.cycles,
+                memory,
+            });
+        }
+    }
+
+    if let Some(addr) = first_difference(&mut compiled, &mut interpreted) {
+        return Err(Divergence {
+            pc: compiled.pc,
+            compiled_block: false,
+            compiled: compiled.state(),
+            interpreted: interpreted.state(),
+            compiled_cycles: compiled.cycles,
+            interpreted_cycles: interpreted.cycles,
+            memory: Some(addr),
+        });
+    }
+    comparison.cycles = compiled.cycles - cpu.cycles;
+    Ok(comparison)
+}
+
+fn first_difference<M: MemoryMap>(a: &mut Cpu<M>, b: &mut Cpu<M>) -> Option<u16> {
+    (0..=0xFFFF).find(|&addr| a.memory.peek(addr) != b.memory.peek(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, MockMemory, Timer};
+
+    // 0008H: INR B; EI; RET
+    // 0100H: LXI SP,1000H; EI
+    // 0104H: ADI 03H; DCR C; JNZ 0104H
+    // 010AH: DI; OUT 0
+    //
+    // A hand-written compilation of the loop at 0104H, as the recompiler
+    // would write it. `bug` makes it forget to set the carry.
+    struct Loop {
+        bug: bool,
+    }
+
+    impl Program for Loop {
+        fn run_block<M, IO>(&self, cpu: &mut Cpu<M>, machine: &mut IO, end: u64) -> bool
+        where
+            M: MemoryMap,
+            IO: ClockedIO + ?Sized,
+        {
+            if cpu.pc != 0x0104 || !matches(cpu, 0x0104, &[0xC6, 0x03, 0x0D, 0xC2, 0x04, 0x01]) {
+                return false;
+            }
+            add(cpu, 0x03);
+            if self.bug {
+                cpu.condition_codes.carry = false;
+            }
+            if retire(cpu, machine, 0x0106, 7, end) {
+                return true;
+            }
+            dcr(cpu, Operand::C);
+            if retire(cpu, machine, 0x0107, 5, end) {
+                return true;
+            }
+            let next = if !cpu.condition_codes.zero {
+                0x0104
+            } else {
+                0x010A
+            };
+            retire(cpu, machine, next, 10, end);
+            true
+        }
+    }
+
+    fn cpu() -> Cpu<MockMemory> {
+        let mut cpu = test_support::cpu(&[
+            (0x0008, &[0x04, 0xFB, 0xC9]),
+            (
+                0x0100,
+                &[
+                    0x31, 0x00, 0x10, 0xFB, 0xC6, 0x03, 0x0D, 0xC2, 0x04, 0x01, 0xF3, 0xD3, 0x00,
+                ],
+            ),
+        ]);
+        cpu.pc = 0x0100;
+        cpu
+    }
+
+    #[test]
+    fn test_run_clocked() {
+        let mut expected = cpu();
+        expected.run_clocked(1_000_000, &mut Timer::default());
+        assert!(expected.is_halted);
+        // The timer interrupted the loop.
+        assert!(expected.registers.b > 0);
+
+        let mut actual = cpu();
+        run_clocked(
+            &Loop { bug: false },
+            &mut actual,
+            1_000_000,
+            &mut Timer::default(),
+        );
+        assert_eq!(actual.state(), expected.state());
+        assert_eq!(actual.cycles, expected.cycles);
+
+        // Stopping part way through a block.
+        let mut expected = cpu();
+        expected.run_clocked(1000, &mut Timer::default());
+        let mut actual = cpu();
+        run_clocked(
+            &Loop { bug: false },
+            &mut actual,
+            1000,
+            &mut Timer::default(),
+        );
+        assert_eq!(actual.state(), expected.state());
+        assert_eq!(actual.cycles, expected.cycles);
+    }
+
+    #[test]
+    fn test_compare() {
+        let comparison =
+            compare(&Loop { bug: false }, &cpu(), &Timer::default(), 1_000_000).unwrap();
+        assert!(comparison.blocks > 200);
+        assert!(comparison.interpreted > 0);
+
+        let divergence =
+            compare(&Loop { bug: true }, &cpu(), &Timer::default(), 1_000_000).unwrap_err();
+        assert_eq!(divergence.pc, 0x0104);
+        assert!(divergence.compiled_block);
+        assert!(divergence.interpreted.carry() && !divergence.compiled.carry());
+
+        // Code overwritten after it was compiled is interpreted.
+        let mut patched = cpu();
+        patched.memory.write(0x0105, 0x04);
+        let comparison =
+            compare(&Loop { bug: true }, &patched, &Timer::default(), 1_000_000).unwrap();
+        assert_eq!(comparison.blocks, 0);
+    }
+}
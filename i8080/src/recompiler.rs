@@ -0,0 +1,760 @@
+use crate::analysis::{BasicBlock, ControlFlowGraph};
+use crate::instruction::{Instruction, Operand, RegisterPair};
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ops::RangeInclusive;
+
+// Translates the code found by the analyzer into Rust ahead of time, one
+// function per basic block, for a crate that runs it with
+// recompiled::run_clocked. Each instruction becomes the Rust for what it
+// does, with its operands, flag tests and cycle count worked out in advance;
+// the blocks are chosen by the pc, so that RET, PCHL and anything else that
+// lands where no block starts is run by the interpreter until it reaches a
+// block again.
+//
+// Code outside the ranges given with with_rom may be overwritten while the
+// program runs. Such a block checks its bytes before running and stops after
+// any instruction that writes into it, leaving the new code to the
+// interpreter.
+#[derive(Clone, Debug)]
+pub struct Recompiler<'a> {
+    cfg: &'a ControlFlowGraph,
+    rom: Vec<RangeInclusive<u16>>,
+}
+
+// A file of the generated crate, relative to its root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedFile {
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+// Where control goes after an instruction: a known address, or the local
+// `next` the instruction's code computed.
+enum Next {
+    Fixed(u16),
+    Computed,
+}
+
+// What an instruction writes to memory: nothing, `len` bytes at the address
+// an expression gives once it has run, or anything at all, as an OUT handler
+// may.
+enum Writes {
+    Nothing,
+    At(&'static str, u16),
+    Anything,
+}
+
+struct Translation {
+    code: Vec<String>,
+    next: Next,
+    // The cycles taken, or None if the code computed them in `cycles`.
+    cycles: Option<u8>,
+    writes: Writes,
+}
+
+impl<'a> Recompiler<'a> {
+    pub fn new(cfg: &'a ControlFlowGraph) -> Self {
+        Recompiler {
+            cfg,
+            rom: Vec::new(),
+        }
+    }
+
+    // Declare that the code in `range` cannot change, e.g. because the
+    // machine maps ROM there, so blocks in it need no checks.
+    pub fn with_rom(mut self, range: RangeInclusive<u16>) -> Self {
+        self.rom.push(range);
+        self
+    }
+
+    // The files of a crate named `name` holding the compiled program as a
+    // library and a harness binary, `NAME-harness`, that checks it against
+    // the interpreter. `i8080_path` is the path from the crate to the i8080
+    // crate.
+    pub fn generate_crate(&self, name: &str, i8080_path: &str) -> Vec<GeneratedFile> {
+        let file = |path: &str, contents: String| GeneratedFile {
+            path: path.to_string(),
+            contents: contents.into_bytes(),
+        };
+        let manifest = format!(
+            "[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n\
+             [dependencies]\ni8080 = {{ path = \"{path}\" }}\n\n\
+             [[bin]]\nname = \"{name}-harness\"\npath = \"src/main.rs\"\n\n\
+             [profile.release]\ncodegen-units = 1\n",
+            name = name,
+            path = i8080_path.replace('\\', "\\\\")
+        );
+        vec![
+            file("Cargo.toml", manifest),
+            GeneratedFile {
+                path: "image.bin".to_string(),
+                contents: self.cfg.image.clone(),
+            },
+            file("src/lib.rs", self.library()),
+            file(
+                "src/main.rs",
+                HARNESS.replace("PROGRAM", &name.replace('-', "_")),
+            ),
+        ]
+    }
+
+    // The source of the library: the image, its entry point and a Program
+    // with the compiled blocks.
+    pub fn library(&self) -> String {
+        let cfg = self.cfg;
+        let mut blocks = String::new();
+        for block in cfg.blocks.values() {
+            let _ = writeln!(blocks);
+            self.block(block, &mut blocks);
+        }
+        let types: Vec<&str> = ["Operand", "PushPair", "RegisterPair"]
+            .iter()
+            .copied()
+            .filter(|name| blocks.contains(&format!("{}::", name)))
+            .collect();
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "// Generated by the i8080 recompiler from {} bytes loaded at {:04X}H: {} blocks.",
+            cfg.image.len(),
+            cfg.base,
+            cfg.blocks.len()
+        );
+        let _ = writeln!(out, "// Do not edit.");
+        let _ = writeln!(out);
+        let _ = writeln!(out, "#![allow(clippy::all)]");
+        let _ = writeln!(out);
+        if !types.is_empty() {
+            let _ = writeln!(out, "use i8080::instruction::{{{}}};", types.join(", "));
+        }
+        let _ = writeln!(out, "use i8080::machine::ClockedIO;");
+        let _ = writeln!(out, "use i8080::memory_bus::MemoryMap;");
+        let _ = writeln!(out, "use i8080::recompiled::{{self as rt, Program}};");
+        let _ = writeln!(out, "use i8080::Cpu;");
+        let _ = writeln!(out);
+        let _ = writeln!(out, "pub const BASE: u16 = 0x{:04X};", cfg.base);
+        let entry = cfg.entries.first().copied().unwrap_or(cfg.base);
+        let _ = writeln!(out, "pub const ENTRY: u16 = 0x{:04X};", entry);
+        let _ = writeln!(
+            out,
+            "pub static IMAGE: &[u8] = include_bytes!(\"../image.bin\");"
+        );
+        let _ = writeln!(out);
+        let _ = writeln!(out, "pub struct Compiled;");
+        let _ = writeln!(out);
+        let _ = writeln!(out, "impl Program for Compiled {{");
+        let _ = writeln!(
+            out,
+            "    fn run_block<M, IO>(&self, cpu: &mut Cpu<M>, machine: &mut IO, end: u64) -> bool"
+        );
+        let _ = writeln!(out, "    where");
+        let _ = writeln!(out, "        M: MemoryMap,");
+        let _ = writeln!(out, "        IO: ClockedIO + ?Sized,");
+        let _ = writeln!(out, "    {{");
+        let _ = writeln!(out, "        match cpu.pc {{");
+        for &start in cfg.blocks.keys() {
+            let _ = writeln!(
+                out,
+                "            0x{:04X} => block_{:04x}(cpu, machine, end),",
+                start, start
+            );
+        }
+        let _ = writeln!(out, "            _ => false,");
+        let _ = writeln!(out, "        }}");
+        let _ = writeln!(out, "    }}");
+        let _ = writeln!(out, "}}");
+        out.push_str(&blocks);
+        out
+    }
+
+    fn is_rom(&self, block: &BasicBlock) -> bool {
+        let last = block.end().wrapping_sub(1);
+        self.rom
+            .iter()
+            .any(|range| range.contains(&block.start) && range.contains(&last))
+    }
+
+    fn block(&self, block: &BasicBlock, out: &mut String) {
+        let rom = self.is_rom(block);
+        let start = block.start;
+        let size = block.end().wrapping_sub(start);
+        let offset = start.wrapping_sub(self.cfg.base) as usize;
+        let code: Vec<String> = self.cfg.image[offset..offset + size as usize]
+            .iter()
+            .map(|byte| format!("0x{:02X}", byte))
+            .collect();
+
+        let _ = writeln!(
+            out,
+            "fn block_{:04x}<M, IO>(cpu: &mut Cpu<M>, machine: &mut IO, end: u64) -> bool",
+            start
+        );
+        let _ = writeln!(out, "where");
+        let _ = writeln!(out, "    M: MemoryMap,");
+        let _ = writeln!(out, "    IO: ClockedIO + ?Sized,");
+        let _ = writeln!(out, "{{");
+        let check = format!("rt::matches(cpu, 0x{:04X}, CODE)", start);
+        if !rom {
+            let _ = writeln!(out, "    const CODE: &[u8] = &[{}];", code.join(", "));
+            let _ = writeln!(out, "    if !{} {{", check);
+            let _ = writeln!(out, "        return false;");
+            let _ = writeln!(out, "    }}");
+        }
+
+        let count = block.instructions.len();
+        for (i, &(pc, instruction)) in block.instructions.iter().enumerate() {
+            let translation = translate(pc, instruction);
+            let _ = writeln!(out, "    // {:04X}H  {}", pc, instruction);
+            for line in translation.code.iter() {
+                let _ = writeln!(out, "    {}", line);
+            }
+            let next = match translation.next {
+                Next::Fixed(addr) => format!("0x{:04X}", addr),
+                Next::Computed => "next".to_string(),
+            };
+            let cycles = match translation.cycles {
+                Some(cycles) => cycles.to_string(),
+                None => "cycles".to_string(),
+            };
+            let retire = format!("rt::retire(cpu, machine, {}, {}, end)", next, cycles);
+            if i + 1 == count {
+                let _ = writeln!(out, "    {};", retire);
+                let _ = writeln!(out, "    true");
+                break;
+            }
+            let stop = match translation.writes {
+                _ if rom => retire,
+                Writes::Nothing => retire,
+                Writes::At(addr, len) => format!(
+                    "{} || rt::touches({}, {}, 0x{:04X}, {})",
+                    retire, addr, len, start, size
+                ),
+                Writes::Anything => format!("{} || !{}", retire, check),
+            };
+            let _ = writeln!(out, "    if {} {{", stop);
+            let _ = writeln!(out, "        return true;");
+            let _ = writeln!(out, "    }}");
+        }
+        let _ = writeln!(out, "}}");
+    }
+}
+
+fn hex8(val: u8) -> String {
+    format!("0x{:02X}", val)
+}
+
+fn hex16(val: u16) -> String {
+    format!("0x{:04X}", val)
+}
+
+fn register(operand: Operand) -> Option<&'static str> {
+    match operand {
+        Operand::A => Some("cpu.registers.a"),
+        Operand::B => Some("cpu.registers.b"),
+        Operand::C => Some("cpu.registers.c"),
+        Operand::D => Some("cpu.registers.d"),
+        Operand::E => Some("cpu.registers.e"),
+        Operand::H => Some("cpu.registers.h"),
+        Operand::L => Some("cpu.registers.l"),
+        Operand::M => None,
+    }
+}
+
+// The code putting an 8-bit operand in `val`.
+fn load(operand: Operand, code: &mut Vec<String>) {
+    match register(operand) {
+        Some(register) => code.push(format!("let val = {};", register)),
+        None => {
+            code.push("let addr = cpu.registers.get_hl();".to_string());
+            code.push("let val = rt::read(cpu, addr);".to_string());
+        }
+    }
+}
+
+// The condition under which a conditional jump, call or return is taken.
+fn condition(instruction: Instruction) -> &'static str {
+    match instruction {
+        Instruction::JC(_) | Instruction::CC(_) | Instruction::RC => "cpu.condition_codes.carry",
+        Instruction::JNC(_) | Instruction::CNC(_) | Instruction::RNC => {
+            "!cpu.condition_codes.carry"
+        }
+        Instruction::JZ(_) | Instruction::CZ(_) | Instruction::RZ => "cpu.condition_codes.zero",
+        Instruction::JNZ(_) | Instruction::CNZ(_) | Instruction::RNZ => "!cpu.condition_codes.zero",
+        Instruction::JM(_) | Instruction::CM(_) | Instruction::RM => "cpu.condition_codes.sign",
+        Instruction::JP(_) | Instruction::CP(_) | Instruction::RP => "!cpu.condition_codes.sign",
+        Instruction::JPE(_) | Instruction::CPE(_) | Instruction::RPE => {
+            "cpu.condition_codes.parity"
+        }
+        Instruction::JPO(_) | Instruction::CPO(_) | Instruction::RPO => {
+            "!cpu.condition_codes.parity"
+        }
+        _ => unreachable!("{:?} is not conditional", instruction),
+    }
+}
+
+// The Rust for one instruction at `pc`, as Cpu::execute would run it.
+fn translate(pc: u16, instruction: Instruction) -> Translation {
+    let fall_through = pc.wrapping_add(instruction.size());
+    let mut translation = Translation {
+        code: Vec::new(),
+        next: Next::Fixed(fall_through),
+        cycles: Some(instruction.cycles()),
+        writes: Writes::Nothing,
+    };
+    let code = &mut translation.code;
+    // ALU operations take the operand from `val`, or as an immediate.
+    let alu = |code: &mut Vec<String>, function: &str, operand: Operand| {
+        load(operand, code);
+        code.push(format!("rt::{}(cpu, val);", function));
+    };
+    let immediate = |code: &mut Vec<String>, function: &str, val: u8| {
+        code.push(format!("rt::{}(cpu, {});", function, hex8(val)));
+    };
+
+    match instruction {
+        Instruction::NOP => {}
+        Instruction::MOV(dest, src) => match (register(dest), register(src)) {
+            // MOV B,B and the like do nothing.
+            (Some(dest), Some(src)) if dest == src => {}
+            (Some(dest), Some(src)) => code.push(format!("{} = {};", dest, src)),
+            (Some(dest), None) => {
+                load(src, code);
+                code.push(format!("{} = val;", dest));
+            }
+            (None, _) => {
+                load(src, code);
+                code.push("let addr = cpu.registers.get_hl();".to_string());
+                code.push("rt::write(cpu, addr, val);".to_string());
+                translation.writes = Writes::At("addr", 1);
+            }
+        },
+        Instruction::MVI(dest, val) => match register(dest) {
+            Some(dest) => code.push(format!("{} = {};", dest, hex8(val))),
+            None => {
+                code.push("let addr = cpu.registers.get_hl();".to_string());
+                code.push(format!("rt::write(cpu, addr, {});", hex8(val)));
+                translation.writes = Writes::At("addr", 1);
+            }
+        },
+        Instruction::LXI(pair, val) => match pair {
+            RegisterPair::SP => code.push(format!("cpu.sp = {};", hex16(val))),
+            _ => code.push(format!(
+                "cpu.registers.set_{}({});",
+                format!("{:?}", pair).to_ascii_lowercase(),
+                hex16(val)
+            )),
+        },
+        Instruction::STAX(pair) => {
+            let pair = format!("{:?}", pair).to_ascii_lowercase();
+            code.push(format!("let addr = cpu.registers.get_{}();", pair));
+            code.push("let val = cpu.registers.a;".to_string());
+            code.push("rt::write(cpu, addr, val);".to_string());
+            translation.writes = Writes::At("addr", 1);
+        }
+        Instruction::LDAX(pair) => {
+            let pair = format!("{:?}", pair).to_ascii_lowercase();
+            code.push(format!("let addr = cpu.registers.get_{}();", pair));
+            code.push("cpu.registers.a = rt::read(cpu, addr);".to_string());
+        }
+        Instruction::STA(addr) => {
+            code.push(format!("let addr = {};", hex16(addr)));
+            code.push("let val = cpu.registers.a;".to_string());
+            code.push("rt::write(cpu, addr, val);".to_string());
+            translation.writes = Writes::At("addr", 1);
+        }
+        Instruction::LDA(addr) => {
+            code.push(format!("cpu.registers.a = rt::read(cpu, {});", hex16(addr)));
+        }
+        Instruction::SHLD(addr) => {
+            code.push(format!("let addr = {};", hex16(addr)));
+            code.push("let (l, h) = (cpu.registers.l, cpu.registers.h);".to_string());
+            code.push("rt::write(cpu, addr, l);".to_string());
+            code.push("rt::write(cpu, addr.wrapping_add(1), h);".to_string());
+            translation.writes = Writes::At("addr", 2);
+        }
+        Instruction::LHLD(addr) => {
+            code.push(format!("cpu.registers.l = rt::read(cpu, {});", hex16(addr)));
+            code.push(format!(
+                "cpu.registers.h = rt::read(cpu, {});",
+                hex16(addr.wrapping_add(1))
+            ));
+        }
+        Instruction::XCHG => {
+            code.push("let hl = cpu.registers.get_hl();".to_string());
+            code.push("let de = cpu.registers.get_de();".to_string());
+            code.push("cpu.registers.set_hl(de);".to_string());
+            code.push("cpu.registers.set_de(hl);".to_string());
+        }
+        Instruction::XTHL => {
+            code.push("rt::xthl(cpu);".to_string());
+            translation.writes = Writes::At("cpu.sp", 2);
+        }
+        Instruction::SPHL => code.push("cpu.sp = cpu.registers.get_hl();".to_string()),
+        Instruction::PUSH(pair) => {
+            code.push(format!("rt::push(cpu, PushPair::{:?});", pair));
+            translation.writes = Writes::At("cpu.sp", 2);
+        }
+        Instruction::POP(pair) => code.push(format!("rt::pop(cpu, PushPair::{:?});", pair)),
+        Instruction::INX(pair) | Instruction::DCX(pair) => {
+            let op = if let Instruction::INX(_) = instruction {
+                "wrapping_add"
+            } else {
+                "wrapping_sub"
+            };
+            match pair {
+                RegisterPair::SP => code.push(format!("cpu.sp = cpu.sp.{}(1);", op)),
+                _ => {
+                    let pair = format!("{:?}", pair).to_ascii_lowercase();
+                    code.push(format!(
+                        "cpu.registers.set_{}(cpu.registers.get_{}().{}(1));",
+                        pair, pair, op
+                    ));
+                }
+            }
+        }
+        Instruction::DAD(pair) => code.push(format!("rt::dad(cpu, RegisterPair::{:?});", pair)),
+        Instruction::INR(operand) | Instruction::DCR(operand) => {
+            let function = if let Instruction::INR(_) = instruction {
+                "inr"
+            } else {
+                "dcr"
+            };
+            if operand == Operand::M {
+                translation.writes = Writes::At("cpu.registers.get_hl()", 1);
+            }
+            code.push(format!("rt::{}(cpu, Operand::{:?});", function, operand));
+        }
+        Instruction::ADD(operand) => alu(code, "add", operand),
+        Instruction::ADC(operand) => alu(code, "adc", operand),
+        Instruction::SUB(operand) => alu(code, "sub", operand),
+        Instruction::SBB(operand) => alu(code, "sbb", operand),
+        Instruction::ANA(operand) => alu(code, "ana", operand),
+        Instruction::XRA(operand) => alu(code, "xra", operand),
+        Instruction::ORA(operand) => alu(code, "ora", operand),
+        Instruction::CMP(operand) => alu(code, "cmp", operand),
+        Instruction::ADI(val) => immediate(code, "add", val),
+        Instruction::ACI(val) => immediate(code, "adc", val),
+        Instruction::SUI(val) => immediate(code, "sub", val),
+        Instruction::SBI(val) => immediate(code, "sbb", val),
+        Instruction::ANI(val) => immediate(code, "ana", val),
+        Instruction::XRI(val) => immediate(code, "xra", val),
+        Instruction::ORI(val) => immediate(code, "ora", val),
+        Instruction::CPI(val) => immediate(code, "cmp", val),
+        Instruction::RLC => code.push("rt::rlc(cpu);".to_string()),
+        Instruction::RRC => code.push("rt::rrc(cpu);".to_string()),
+        Instruction::RAL => code.push("rt::ral(cpu);".to_string()),
+        Instruction::RAR => code.push("rt::rar(cpu);".to_string()),
+        Instruction::DAA => code.push("rt::daa(cpu);".to_string()),
+        Instruction::CMA => code.push("cpu.registers.a = !cpu.registers.a;".to_string()),
+        Instruction::STC => code.push("cpu.condition_codes.carry = true;".to_string()),
+        Instruction::CMC => {
+            code.push("cpu.condition_codes.carry = !cpu.condition_codes.carry;".to_string())
+        }
+        Instruction::EI => code.push("cpu.interrupts_enabled = true;".to_string()),
+        Instruction::DI => code.push("cpu.interrupts_enabled = false;".to_string()),
+        Instruction::HLT => code.push("rt::hlt(cpu);".to_string()),
+        Instruction::IN(port) => code.push(format!(
+            "cpu.registers.a = machine.machine_in({});",
+            hex8(port)
+        )),
+        Instruction::OUT(port) => {
+            code.push(format!("rt::output(cpu, machine, {});", hex8(port)));
+            translation.writes = Writes::Anything;
+        }
+        Instruction::JMP(addr) => translation.next = Next::Fixed(addr),
+        Instruction::JC(addr)
+        | Instruction::JNC(addr)
+        | Instruction::JZ(addr)
+        | Instruction::JNZ(addr)
+        | Instruction::JM(addr)
+        | Instruction::JP(addr)
+        | Instruction::JPE(addr)
+        | Instruction::JPO(addr) => {
+            code.push(format!(
+                "let next = if {} {{ {} }} else {{ {} }};",
+                condition(instruction),
+                hex16(addr),
+                hex16(fall_through)
+            ));
+            translation.next = Next::Computed;
+        }
+        Instruction::PCHL => {
+            code.push("let next = cpu.registers.get_hl();".to_string());
+            translation.next = Next::Computed;
+        }
+        Instruction::CALL(addr) => {
+            code.push(format!("let next = rt::call(cpu, {});", hex16(addr)));
+            translation.next = Next::Computed;
+        }
+        Instruction::CC(addr)
+        | Instruction::CNC(addr)
+        | Instruction::CZ(addr)
+        | Instruction::CNZ(addr)
+        | Instruction::CM(addr)
+        | Instruction::CP(addr)
+        | Instruction::CPE(addr)
+        | Instruction::CPO(addr) => {
+            code.push(format!(
+                "let (next, cycles) = if {} {{ (rt::call(cpu, {}), {}) }} else {{ ({}, {}) }};",
+                condition(instruction),
+                hex16(addr),
+                instruction.cycles() + 6,
+                hex16(fall_through),
+                instruction.cycles()
+            ));
+            translation.next = Next::Computed;
+            translation.cycles = None;
+        }
+        Instruction::RET => {
+            code.push("let next = rt::ret(cpu);".to_string());
+            translation.next = Next::Computed;
+        }
+        Instruction::RC
+        | Instruction::RNC
+        | Instruction::RZ
+        | Instruction::RNZ
+        | Instruction::RM
+        | Instruction::RP
+        | Instruction::RPE
+        | Instruction::RPO => {
+            code.push(format!(
+                "let (next, cycles) = if {} {{ (rt::ret(cpu), {}) }} else {{ ({}, {}) }};",
+                condition(instruction),
+                instruction.cycles() + 6,
+                hex16(fall_through),
+                instruction.cycles()
+            ));
+            translation.next = Next::Computed;
+            translation.cycles = None;
+        }
+        Instruction::RST(n) => {
+            code.push(format!("let next = rt::rst(cpu, {});", n));
+            translation.next = Next::Computed;
+        }
+    }
+    translation
+}
+
+// The harness binary of a generated crate. PROGRAM is replaced with the name
+// of the library.
+const HARNESS: &str = r#"// Generated by the i8080 recompiler. Runs the compiled program side by side
+// with the interpreter, checking that they agree, then times each alone.
+//
+// usage: harness [--cpm] [--cycles N]
+//
+// The program is loaded into 64K of RAM and started at its first entry
+// point. With --cpm, OUT 0 halts and OUT 1 does the BDOS console calls, as
+// the CP/M machine in i8080-tests patches in at 0000H and 0005H.
+
+use i8080::machine::{ClockedIO, CpuView, MachineIO};
+use i8080::memory_bus::MemoryMap;
+use i8080::recompiled;
+use i8080::Cpu;
+use PROGRAM::{Compiled, BASE, ENTRY, IMAGE};
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::time::Instant;
+
+#[derive(Clone)]
+struct Ram(Vec<u8>);
+
+impl MemoryMap for Ram {
+    fn load_rom(&mut self) {}
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        &self.0[addr as usize..]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.0[addr as usize] = val;
+    }
+}
+
+#[derive(Clone)]
+struct Ports {
+    cpm: bool,
+    echo: bool,
+}
+
+impl MachineIO for Ports {
+    fn machine_in(&mut self, _: u8) -> u8 {
+        0
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, _: u8) {
+        if !self.cpm {
+            return;
+        }
+        if port == 0 {
+            cpu.halt();
+        } else if port == 1 && self.echo {
+            if cpu.registers.c == 9 {
+                let mut addr = cpu.registers.get_de();
+                while cpu.memory.read(addr) != b'$' {
+                    print!("{}", cpu.memory.read(addr) as char);
+                    addr = addr.wrapping_add(1);
+                }
+            } else if cpu.registers.c == 2 {
+                print!("{}", cpu.registers.e as char);
+            }
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+impl ClockedIO for Ports {
+    fn tick(&mut self, _: u32) {}
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn interrupt_acknowledge(&mut self) {}
+}
+
+fn usage() -> ! {
+    eprintln!("usage: harness [--cpm] [--cycles N]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut cpm = false;
+    let mut cycles = 100_000_000;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cpm" => cpm = true,
+            "--cycles" => {
+                cycles = match args.next().map(|n| n.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => usage(),
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut memory = Ram(vec![0; 0x10000]);
+    for (i, &byte) in IMAGE.iter().enumerate() {
+        memory.write(BASE.wrapping_add(i as u16), byte);
+    }
+    if cpm {
+        memory.0[0x0000..0x0002].copy_from_slice(&[0xD3, 0x00]);
+        memory.0[0x0005..0x0008].copy_from_slice(&[0xD3, 0x01, 0xC9]);
+    }
+    let mut cpu = Cpu::new(memory);
+    cpu.pc = ENTRY;
+    let ports = Ports { cpm, echo: false };
+
+    match recompiled::compare(&Compiled, &cpu, &ports, cycles) {
+        Ok(comparison) => println!(
+            "identical over {} cycles: {} compiled blocks, {} instructions interpreted",
+            comparison.cycles, comparison.blocks, comparison.interpreted
+        ),
+        Err(divergence) => {
+            eprintln!("{}", divergence);
+            process::exit(1);
+        }
+    }
+
+    let mut interpreted = cpu.clone();
+    let start = Instant::now();
+    interpreted.run_clocked(cycles, &mut ports.clone());
+    let interpreter_time = start.elapsed();
+
+    let mut compiled = cpu;
+    let start = Instant::now();
+    recompiled::run_clocked(&Compiled, &mut compiled, cycles, &mut Ports { cpm, echo: true });
+    let compiled_time = start.elapsed();
+
+    println!();
+    println!(
+        "interpreter {:.3?}, compiled {:.3?} ({:.1}x)",
+        interpreter_time,
+        compiled_time,
+        interpreter_time.as_secs_f64() / compiled_time.as_secs_f64()
+    );
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Analyzer;
+
+    // 0000H: LXI SP,2000H; MVI B,05H
+    // 0005H: DCR B; CNZ 0010H; JNZ 0005H
+    // 000CH: OUT 00H; RET
+    // 0010H: MOV M,A; RZ; PUSH B; POP B; RET
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 0x15];
+        image[0x00..0x05].copy_from_slice(&[0x31, 0x00, 0x20, 0x06, 0x05]);
+        image[0x05..0x0C].copy_from_slice(&[0x05, 0xC4, 0x10, 0x00, 0xC2, 0x05, 0x00]);
+        image[0x0C..0x0F].copy_from_slice(&[0xD3, 0x00, 0xC9]);
+        image[0x10..0x15].copy_from_slice(&[0x77, 0xC8, 0xC5, 0xC1, 0xC9]);
+        image
+    }
+
+    #[test]
+    fn test_library() {
+        let image = image();
+        let cfg = Analyzer::new(&image, 0).analyze();
+        let library = Recompiler::new(&cfg).library();
+        assert!(library.contains("            0x0005 => block_0005(cpu, machine, end),\n"));
+        assert!(library.contains(
+            "    const CODE: &[u8] = &[0x05, 0xC4, 0x10, 0x00];\n    if !rt::matches(cpu, 0x0005, CODE) {\n        return false;\n    }\n"
+        ), "{}", library);
+        assert!(library.contains(
+            "    // 0005H  DCR B\n    rt::dcr(cpu, Operand::B);\n    if rt::retire(cpu, machine, 0x0006, 5, end) {\n        return true;\n    }\n"
+        ), "{}", library);
+        assert!(library.contains(
+            "    let (next, cycles) = if !cpu.condition_codes.zero { (rt::call(cpu, 0x0010), 17) } else { (0x0009, 11) };\n    rt::retire(cpu, machine, next, cycles, end);\n    true\n}\n"
+        ), "{}", library);
+        // A write ends the block if it lands in the block.
+        assert!(library.contains(
+            "    // 0010H  MOV M,A\n    let val = cpu.registers.a;\n    let addr = cpu.registers.get_hl();\n    rt::write(cpu, addr, val);\n    if rt::retire(cpu, machine, 0x0011, 7, end) || rt::touches(addr, 1, 0x0010, 2) {\n"
+        ), "{}", library);
+        // As does OUT, whose handler may write anywhere.
+        assert!(library.contains(
+            "    if rt::retire(cpu, machine, 0x000E, 10, end) || !rt::matches(cpu, 0x000C, CODE) {\n"
+        ), "{}", library);
+        assert!(library.contains("use i8080::instruction::{Operand, PushPair};\n"));
+
+        // Code in ROM is not checked.
+        let library = Recompiler::new(&cfg).with_rom(0x0000..=0x1FFF).library();
+        assert!(!library.contains("rt::matches"));
+        assert!(!library.contains("rt::touches"));
+    }
+
+    #[test]
+    fn test_generate_crate() {
+        let image = image();
+        let cfg = Analyzer::new(&image, 0).analyze();
+        let files = Recompiler::new(&cfg).generate_crate("tiny-rom", "../i8080");
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["Cargo.toml", "image.bin", "src/lib.rs", "src/main.rs"]
+        );
+        let manifest = String::from_utf8(files[0].contents.clone()).unwrap();
+        assert!(manifest.contains("name = \"tiny-rom\"\n"));
+        assert!(manifest.contains("i8080 = { path = \"../i8080\" }\n"));
+        assert!(manifest.contains("name = \"tiny-rom-harness\"\n"));
+        assert_eq!(files[1].contents, image);
+        let harness = String::from_utf8(files[3].contents.clone()).unwrap();
+        assert!(harness.contains("use tiny_rom::{Compiled, BASE, ENTRY, IMAGE};\n"));
+    }
+}
@@ -0,0 +1,451 @@
+use crate::bus::BusCycleKind;
+use crate::call_stack::{Backtrace, CallStack};
+use crate::instruction::{Instruction, RegisterPair};
+use crate::symbols::{Address, SymbolLookup};
+
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+// What the machine has at an address, as far as the sanitizer is concerned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Region {
+    Unmapped,
+    Rom,
+    Ram,
+}
+
+// The memory map of a machine: which addresses are ROM, RAM or unmapped, and
+// which addresses are mirrors of others. Addresses start out unmapped.
+#[derive(Clone, Debug)]
+pub struct MemoryLayout {
+    regions: Vec<Region>,
+    mirrors: Vec<(RangeInclusive<u16>, RangeInclusive<u16>)>,
+}
+
+impl MemoryLayout {
+    pub fn new() -> Self {
+        MemoryLayout {
+            regions: vec![Region::Unmapped; 0x10000],
+            mirrors: Vec::new(),
+        }
+    }
+
+    pub fn rom(self, range: RangeInclusive<u16>) -> Self {
+        self.region(range, Region::Rom)
+    }
+
+    pub fn ram(self, range: RangeInclusive<u16>) -> Self {
+        self.region(range, Region::Ram)
+    }
+
+    pub fn region(mut self, range: RangeInclusive<u16>, region: Region) -> Self {
+        for addr in range {
+            self.regions[addr as usize] = region;
+        }
+        self
+    }
+
+    // Make `range` a mirror of `target`, repeating it as often as needed to
+    // fill the range.
+    pub fn mirror(mut self, range: RangeInclusive<u16>, target: RangeInclusive<u16>) -> Self {
+        self.mirrors.push((range, target));
+        self
+    }
+
+    // The address that `addr` is a mirror of, or `addr` itself.
+    pub fn resolve(&self, addr: u16) -> u16 {
+        for (range, target) in self.mirrors.iter() {
+            if range.contains(&addr) {
+                let len = *target.end() as u32 - *target.start() as u32 + 1;
+                let offset = (addr - range.start()) as u32 % len;
+                return target.start() + offset as u16;
+            }
+        }
+        addr
+    }
+
+    pub fn region_of(&self, addr: u16) -> Region {
+        self.regions[self.resolve(addr) as usize]
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    // A read of RAM that has not been written since reset.
+    UninitializedRead(u16),
+    // A pop or return from above the top of the stack.
+    StackUnderflow(u16),
+    // A push into the stack guard region.
+    StackOverflow(u16),
+    RomWrite(u16),
+    ExecuteFromRam(u16),
+    ExecuteFromUnmapped(u16),
+}
+
+impl Violation {
+    fn kind(&self) -> u8 {
+        match self {
+            Violation::UninitializedRead(_) => 0,
+            Violation::StackUnderflow(_) => 1,
+            Violation::StackOverflow(_) => 2,
+            Violation::RomWrite(_) => 3,
+            Violation::ExecuteFromRam(_) => 4,
+            Violation::ExecuteFromUnmapped(_) => 5,
+        }
+    }
+}
+
+impl Violation {
+    // The address the violation concerns.
+    pub fn addr(&self) -> u16 {
+        match *self {
+            Violation::UninitializedRead(addr)
+            | Violation::StackUnderflow(addr)
+            | Violation::StackOverflow(addr)
+            | Violation::RomWrite(addr)
+            | Violation::ExecuteFromRam(addr)
+            | Violation::ExecuteFromUnmapped(addr) => addr,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            Violation::UninitializedRead(_) => "read of uninitialised RAM at",
+            Violation::StackUnderflow(_) => "stack underflow reading",
+            Violation::StackOverflow(_) => "stack overflow into the guard region at",
+            Violation::RomWrite(_) => "write to ROM at",
+            Violation::ExecuteFromRam(_) => "execution from RAM at",
+            Violation::ExecuteFromUnmapped(_) => "execution from unmapped memory at",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04X}H", self.description(), self.addr())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub violation: Violation,
+    // The instruction that caused the violation, and its address.
+    pub pc: u16,
+    pub instruction: Instruction,
+    pub backtrace: Backtrace,
+}
+
+impl Report {
+    // Format with symbol names where `symbols` knows them.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> ReportDisplay<'a> {
+        ReportDisplay {
+            report: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+pub struct ReportDisplay<'a> {
+    report: &'a Report,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for ReportDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let report = self.report;
+        write!(
+            f,
+            "{} {} at {} ({})\n{}",
+            report.violation.description(),
+            Address::new(report.violation.addr(), self.symbols),
+            Address::new(report.pc, self.symbols),
+            report.instruction.display(self.symbols),
+            report.backtrace.display(self.symbols)
+        )
+    }
+}
+
+// Checks the memory accesses of a running program against a memory layout.
+// Enable it with Cpu::enable_sanitizer, which also enables the call stack so
+// that reports can include a backtrace. Each kind of violation is reported
+// once per instruction address.
+#[derive(Clone, Debug)]
+pub struct Sanitizer {
+    layout: MemoryLayout,
+    allow_ram_execution: bool,
+    stack_guard: Option<RangeInclusive<u16>>,
+    // The top of the stack: the value the program first loaded into SP, or
+    // the value given to with_stack_top. Held as a u32 so that a stack
+    // starting at 0000H, whose first push wraps to FFFFH, has its top above
+    // every address.
+    stack_top: Option<u32>,
+    fixed_stack_top: Option<u32>,
+    written: Vec<bool>,
+    // The instruction being executed.
+    pc: u16,
+    instruction: Instruction,
+    reports: Vec<Report>,
+    // The kind of violation and the pc of each report made.
+    reported: BTreeSet<(u8, u16)>,
+}
+
+impl Sanitizer {
+    pub fn new(layout: MemoryLayout) -> Self {
+        Sanitizer {
+            layout,
+            allow_ram_execution: false,
+            stack_guard: None,
+            stack_top: None,
+            fixed_stack_top: None,
+            written: vec![false; 0x10000],
+            pc: 0,
+            instruction: Instruction::NOP,
+            reports: Vec::new(),
+            reported: BTreeSet::new(),
+        }
+    }
+
+    // Programs loaded into RAM, such as CP/M programs, execute from RAM.
+    pub fn allow_ram_execution(mut self, allow: bool) -> Self {
+        self.allow_ram_execution = allow;
+        self
+    }
+
+    // Report pushes that write into `range`, typically the memory just below
+    // the space set aside for the stack.
+    pub fn with_stack_guard(mut self, range: RangeInclusive<u16>) -> Self {
+        self.stack_guard = Some(range);
+        self
+    }
+
+    // Use `top` as the top of the stack rather than the first value the
+    // program loads into SP.
+    pub fn with_stack_top(mut self, top: u16) -> Self {
+        self.fixed_stack_top = Some(stack_top(top));
+        self.stack_top = self.fixed_stack_top;
+        self
+    }
+
+    // Treat `range` as written, e.g. for a program loaded into RAM before it
+    // is run.
+    pub fn mark_initialized(&mut self, range: RangeInclusive<u16>) {
+        for addr in range {
+            let addr = self.layout.resolve(addr);
+            self.written[addr as usize] = true;
+        }
+    }
+
+    pub fn layout(&self) -> &MemoryLayout {
+        &self.layout
+    }
+
+    // The violations found so far, oldest first.
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    // Remove and return the violations found so far. A violation that has
+    // already been reported is not reported again.
+    pub fn take_reports(&mut self) -> Vec<Report> {
+        core::mem::take(&mut self.reports)
+    }
+
+    // Forget what has been written and the stack, as after a RESET.
+    pub(crate) fn reset(&mut self) {
+        self.written.iter_mut().for_each(|w| *w = false);
+        self.stack_top = self.fixed_stack_top;
+    }
+
+    // Called before an instruction is executed.
+    pub(crate) fn begin(
+        &mut self,
+        pc: u16,
+        instruction: &Instruction,
+        call_stack: Option<&CallStack>,
+    ) {
+        self.pc = pc;
+        self.instruction = *instruction;
+        match self.layout.region_of(pc) {
+            Region::Rom => {}
+            Region::Ram if self.allow_ram_execution => {}
+            Region::Ram => self.report(Violation::ExecuteFromRam(pc), call_stack),
+            Region::Unmapped => self.report(Violation::ExecuteFromUnmapped(pc), call_stack),
+        }
+    }
+
+    // Called after an instruction has been executed, with the value of SP
+    // afterwards.
+    pub(crate) fn end(&mut self, sp: u16) {
+        let loads_sp = matches!(
+            self.instruction,
+            Instruction::LXI(RegisterPair::SP, _) | Instruction::SPHL
+        );
+        if loads_sp && self.stack_top.is_none() {
+            self.stack_top = Some(stack_top(sp));
+        }
+    }
+
+    // Called when an interrupt is accepted, before the pc is pushed.
+    pub(crate) fn interrupt(&mut self, pc: u16, rst: u8) {
+        self.pc = pc;
+        self.instruction = Instruction::RST(rst);
+    }
+
+    // Called for every memory access made by an instruction.
+    pub(crate) fn access(&mut self, kind: BusCycleKind, addr: u16, call_stack: Option<&CallStack>) {
+        let region = self.layout.region_of(addr);
+        let resolved = self.layout.resolve(addr) as usize;
+        match kind {
+            BusCycleKind::MemoryRead | BusCycleKind::StackRead => {
+                if kind == BusCycleKind::StackRead
+                    && self.stack_top.is_some_and(|top| addr as u32 >= top)
+                {
+                    self.report(Violation::StackUnderflow(addr), call_stack);
+                }
+                if region == Region::Ram && !self.written[resolved] {
+                    self.report(Violation::UninitializedRead(addr), call_stack);
+                }
+            }
+            BusCycleKind::MemoryWrite | BusCycleKind::StackWrite => {
+                if kind == BusCycleKind::StackWrite
+                    && self.stack_guard.as_ref().is_some_and(|g| g.contains(&addr))
+                {
+                    self.report(Violation::StackOverflow(addr), call_stack);
+                }
+                match region {
+                    Region::Rom => self.report(Violation::RomWrite(addr), call_stack),
+                    Region::Ram => self.written[resolved] = true,
+                    Region::Unmapped => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn report(&mut self, violation: Violation, call_stack: Option<&CallStack>) {
+        if !self.reported.insert((violation.kind(), self.pc)) {
+            return;
+        }
+        self.reports.push(Report {
+            violation,
+            pc: self.pc,
+            instruction: self.instruction,
+            backtrace: match call_stack {
+                Some(call_stack) => call_stack.backtrace(self.pc),
+                None => Backtrace {
+                    pc: self.pc,
+                    frames: Vec::new(),
+                },
+            },
+        });
+    }
+}
+
+fn stack_top(sp: u16) -> u32 {
+    if sp == 0 {
+        0x10000
+    } else {
+        sp as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::test_support::{cpu, run, MockMemory};
+
+    fn violations(cpu: &mut Cpu<MockMemory>) -> Vec<(Violation, u16)> {
+        let reports = cpu.sanitizer_mut().unwrap().take_reports();
+        reports.iter().map(|r| (r.violation, r.pc)).collect()
+    }
+
+    #[test]
+    fn test_sanitizer_reports() {
+        let program: &[(u16, &[u8])] = &[
+            // LXI SP,2400H; LDA 2000H; STA 2001H; LDA 2001H; STA 0100H;
+            // CALL 0020H
+            (
+                0x0000,
+                &[
+                    0x31, 0x00, 0x24, 0x3A, 0x00, 0x20, 0x32, 0x01, 0x20, 0x3A, 0x01, 0x20, 0x32,
+                    0x00, 0x01, 0xCD, 0x20, 0x00,
+                ],
+            ),
+            // POP B; POP B; JMP 2002H
+            (0x0020, &[0xC1, 0xC1, 0xC3, 0x02, 0x20]),
+        ];
+        let layout = MemoryLayout::new()
+            .rom(0x0000..=0x1FFF)
+            .ram(0x2000..=0x23FF);
+        let mut cpu = cpu(program);
+        cpu.enable_sanitizer(Sanitizer::new(layout));
+
+        run(&mut cpu, 10);
+        assert_eq!(
+            violations(&mut cpu),
+            vec![
+                (Violation::UninitializedRead(0x2000), 0x0003),
+                (Violation::RomWrite(0x0100), 0x000C),
+                (Violation::StackUnderflow(0x2400), 0x0021),
+                (Violation::ExecuteFromRam(0x2002), 0x2002),
+            ]
+        );
+
+        // A violation at the same place is only reported once.
+        cpu.pc = 0x0021;
+        cpu.sp = 0x2400;
+        run(&mut cpu, 1);
+        assert!(violations(&mut cpu).is_empty());
+    }
+
+    #[test]
+    fn test_sanitizer_backtrace_and_stack_guard() {
+        let program: &[(u16, &[u8])] = &[
+            // LXI SP,2020H; MVI A,1; STA 4005H; LDA 2005H; CALL 0010H
+            (
+                0x0000,
+                &[
+                    0x31, 0x20, 0x20, 0x3E, 0x01, 0x32, 0x05, 0x40, 0x3A, 0x05, 0x20, 0xCD, 0x10,
+                    0x00,
+                ],
+            ),
+            // PUSH B
+            (0x0010, &[0xC5]),
+        ];
+        let layout = MemoryLayout::new()
+            .rom(0x0000..=0x1FFF)
+            .ram(0x2000..=0x23FF)
+            .mirror(0x4000..=0x5FFF, 0x2000..=0x23FF);
+        let sanitizer = Sanitizer::new(layout).with_stack_guard(0x2000..=0x201D);
+        let mut cpu = cpu(program);
+        cpu.enable_sanitizer(sanitizer);
+
+        // The write through the mirror initialises 2005H, and only the push
+        // reaches the guard region.
+        run(&mut cpu, 6);
+        let reports = cpu.sanitizer_mut().unwrap().take_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].violation, Violation::StackOverflow(0x201D));
+        assert_eq!(reports[0].pc, 0x0010);
+        assert_eq!(
+            reports[0].to_string(),
+            "stack overflow into the guard region at 201DH at 0010H (PUSH B)\n#0  0010H\n#1  000BH"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+
+// An identifier for a scheduled event. The meaning of the value is up to the
+// device that scheduled it, e.g. "raise the vblank interrupt" or "UART byte
+// ready". Events are plain data so that the scheduler can be saved along
+// with the rest of the cpu state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventId(pub u32);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledEvent {
+    // The value of the cpu's cycle counter at which the event is due.
+    pub at: u64,
+    pub event: EventId,
+    // Breaks ties between events due on the same cycle so they fire in the
+    // order they were scheduled.
+    seq: u64,
+}
+
+// A queue of events ordered by the cycle at which they are due. Events are
+// kept sorted with the next event due at the end so that popping it is cheap.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    events: Vec<ScheduledEvent>,
+    next_seq: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Schedule an event to become due once the cycle counter reaches `at`.
+    pub fn schedule_at(&mut self, at: u64, event: EventId) {
+        let scheduled = ScheduledEvent {
+            at,
+            event,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+
+        let index = self
+            .events
+            .partition_point(|e| (e.at, e.seq) > (scheduled.at, scheduled.seq));
+        self.events.insert(index, scheduled);
+    }
+
+    // Remove every pending occurrence of an event. Returns true if anything
+    // was removed.
+    pub fn cancel(&mut self, event: EventId) -> bool {
+        let len = self.events.len();
+        self.events.retain(|e| e.event != event);
+        self.events.len() != len
+    }
+
+    // The cycle at which the next event is due, if any are pending.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.events.last().map(|e| e.at)
+    }
+
+    // Remove and return the next event if it is due at or before `now`.
+    pub fn pop_due(&mut self, now: u64) -> Option<ScheduledEvent> {
+        match self.events.last() {
+            Some(e) if e.at <= now => self.events.pop(),
+            _ => None,
+        }
+    }
+
+    // The pending events in the order they will fire.
+    pub fn pending(&self) -> impl Iterator<Item = &ScheduledEvent> {
+        self.events.iter().rev()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pop_due_in_deadline_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(300, EventId(3));
+        scheduler.schedule_at(100, EventId(1));
+        scheduler.schedule_at(200, EventId(2));
+
+        assert_eq!(scheduler.next_deadline(), Some(100));
+        assert_eq!(scheduler.pop_due(99), None);
+        assert_eq!(scheduler.pop_due(250).map(|e| e.event), Some(EventId(1)));
+        assert_eq!(scheduler.pop_due(250).map(|e| e.event), Some(EventId(2)));
+        assert_eq!(scheduler.pop_due(250), None);
+        assert_eq!(scheduler.next_deadline(), Some(300));
+    }
+
+    #[test]
+    fn test_same_cycle_fires_in_scheduled_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(50, EventId(7));
+        scheduler.schedule_at(50, EventId(5));
+        scheduler.schedule_at(50, EventId(6));
+
+        let order: Vec<_> = scheduler.pending().map(|e| e.event.0).collect();
+        assert_eq!(order, vec![7, 5, 6]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(10, EventId(1));
+        scheduler.schedule_at(20, EventId(2));
+        scheduler.schedule_at(30, EventId(1));
+
+        assert!(scheduler.cancel(EventId(1)));
+        assert!(!scheduler.cancel(EventId(1)));
+        assert_eq!(scheduler.pop_due(100).map(|e| e.event), Some(EventId(2)));
+        assert!(scheduler.is_empty());
+    }
+}
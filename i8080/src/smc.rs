@@ -0,0 +1,332 @@
+use crate::instruction::Instruction;
+use crate::symbols::{Address, SymbolLookup};
+
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SmcKind {
+    // A write to a byte that had been fetched as part of an instruction.
+    WriteToCode,
+    // Execution of an instruction containing a byte written at run time.
+    ExecuteWritten,
+    // Cpu::execute was given an instruction other than the one in memory at
+    // the pc, e.g. a decode cache entry that was not invalidated.
+    StaleDecode,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmcReport {
+    pub kind: SmcKind,
+    // The byte written or executed.
+    pub addr: u16,
+    // The instruction that wrote the byte. Only unknown for StaleDecode,
+    // when memory was changed other than by the cpu.
+    pub writer: Option<Writer>,
+    // The instruction the byte was fetched as part of: the one it last
+    // belonged to for WriteToCode, and the one being executed otherwise.
+    pub code: u16,
+    pub instruction: Instruction,
+    // For StaleDecode, the instruction memory actually holds.
+    pub in_memory: Option<Instruction>,
+}
+
+impl SmcReport {
+    // Format with symbol names where `symbols` knows them.
+    pub fn display<'a>(&'a self, symbols: Option<&'a dyn SymbolLookup>) -> SmcReportDisplay<'a> {
+        SmcReportDisplay {
+            report: self,
+            symbols,
+        }
+    }
+}
+
+impl fmt::Display for SmcReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+pub struct SmcReportDisplay<'a> {
+    report: &'a SmcReport,
+    symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl fmt::Display for SmcReportDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let report = self.report;
+        let addr = Address::new(report.addr, self.symbols);
+        let code = Address::new(report.code, self.symbols);
+        let instruction = report.instruction.display(self.symbols);
+        match report.kind {
+            SmcKind::WriteToCode => write!(
+                f,
+                "write to code at {} by {}, fetched as part of {} ({})",
+                addr,
+                WriterDisplay(report.writer, self.symbols),
+                code,
+                instruction
+            ),
+            SmcKind::ExecuteWritten => write!(
+                f,
+                "execution of {} at {} ({}), written by {}",
+                addr,
+                code,
+                instruction,
+                WriterDisplay(report.writer, self.symbols)
+            ),
+            SmcKind::StaleDecode => {
+                write!(f, "stale decode at {}: executed {}", code, instruction)?;
+                if let Some(in_memory) = report.in_memory {
+                    write!(f, " but memory holds {}", in_memory.display(self.symbols))?;
+                }
+                write!(
+                    f,
+                    ", last written by {}",
+                    WriterDisplay(report.writer, self.symbols)
+                )
+            }
+        }
+    }
+}
+
+struct WriterDisplay<'a>(Option<Writer>, Option<&'a dyn SymbolLookup>);
+
+impl fmt::Display for WriterDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(writer) => write!(
+                f,
+                "{} ({})",
+                Address::new(writer.pc, self.1),
+                writer.instruction.display(self.1)
+            ),
+            None => write!(f, "something other than the cpu"),
+        }
+    }
+}
+
+// The instruction that last wrote a byte at run time, and its address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Writer {
+    pub pc: u16,
+    pub instruction: Instruction,
+}
+
+// Detects self-modifying code: writes to bytes that have been executed, and
+// execution of bytes written at run time. Enable it with
+// Cpu::enable_smc_detector. Only the cpu's own writes are seen; memory loaded
+// before the program runs, or changed by OUT handlers, counts as unwritten.
+// Each kind of report is made once per writer and instruction address.
+//
+// It also decodes every executed instruction from memory and reports any
+// difference from the instruction actually executed, which checks that a
+// decode cache is being invalidated when code changes.
+#[derive(Clone, Debug)]
+pub struct SmcDetector {
+    // The instruction each byte was last fetched as part of, and its
+    // address.
+    fetched: Vec<Option<(u16, Instruction)>>,
+    written: Vec<Option<Writer>>,
+    // The instruction being executed.
+    pc: u16,
+    instruction: Instruction,
+    reports: Vec<SmcReport>,
+    reported: BTreeSet<(SmcKind, u16, u16)>,
+}
+
+impl SmcDetector {
+    pub fn new() -> Self {
+        SmcDetector {
+            fetched: vec![None; 0x10000],
+            written: vec![None; 0x10000],
+            pc: 0,
+            instruction: Instruction::NOP,
+            reports: Vec::new(),
+            reported: BTreeSet::new(),
+        }
+    }
+
+    // The address of the instruction that last wrote `addr` at run time.
+    pub fn writer_of(&self, addr: u16) -> Option<u16> {
+        self.written[addr as usize].map(|writer| writer.pc)
+    }
+
+    // Whether `addr` has been fetched as part of an instruction.
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.fetched[addr as usize].is_some()
+    }
+
+    // The reports made so far, oldest first.
+    pub fn reports(&self) -> &[SmcReport] {
+        &self.reports
+    }
+
+    // Remove and return the reports made so far. A site that has already
+    // been reported is not reported again.
+    pub fn take_reports(&mut self) -> Vec<SmcReport> {
+        core::mem::take(&mut self.reports)
+    }
+
+    // Called before an instruction is executed, with the bytes memory holds
+    // at the pc.
+    pub(crate) fn begin(&mut self, pc: u16, instruction: &Instruction, bytes: &[u8; 3]) {
+        self.pc = pc;
+        self.instruction = *instruction;
+
+        let in_memory = Instruction::from(&bytes[..]);
+        // The first byte of the instruction written at run time, if any, to
+        // blame for a stale decode.
+        let mut first_write = (pc, None);
+        for i in 0..in_memory.size().max(instruction.size()) {
+            let addr = pc.wrapping_add(i);
+            if let Some(writer) = self.written[addr as usize] {
+                if i < instruction.size() {
+                    self.report(
+                        SmcKind::ExecuteWritten,
+                        addr,
+                        Some(writer),
+                        pc,
+                        *instruction,
+                        None,
+                    );
+                }
+                if first_write.1.is_none() {
+                    first_write = (addr, Some(writer));
+                }
+            }
+            if i < instruction.size() {
+                self.fetched[addr as usize] = Some((pc, *instruction));
+            }
+        }
+        if in_memory != *instruction {
+            let (addr, writer) = first_write;
+            self.report(
+                SmcKind::StaleDecode,
+                addr,
+                writer,
+                pc,
+                *instruction,
+                Some(in_memory),
+            );
+        }
+    }
+
+    // Called when an interrupt is accepted, before the pc is pushed.
+    pub(crate) fn interrupt(&mut self, pc: u16, rst: u8) {
+        self.pc = pc;
+        self.instruction = Instruction::RST(rst);
+    }
+
+    // Called for every memory write made by an instruction.
+    pub(crate) fn write(&mut self, addr: u16) {
+        let writer = Writer {
+            pc: self.pc,
+            instruction: self.instruction,
+        };
+        self.written[addr as usize] = Some(writer);
+        if let Some((code, instruction)) = self.fetched[addr as usize] {
+            self.report(
+                SmcKind::WriteToCode,
+                addr,
+                Some(writer),
+                code,
+                instruction,
+                None,
+            );
+        }
+    }
+
+    fn report(
+        &mut self,
+        kind: SmcKind,
+        addr: u16,
+        writer: Option<Writer>,
+        code: u16,
+        instruction: Instruction,
+        in_memory: Option<Instruction>,
+    ) {
+        let site = (kind, writer.map_or(code, |writer| writer.pc), code);
+        if !self.reported.insert(site) {
+            return;
+        }
+        self.reports.push(SmcReport {
+            kind,
+            addr,
+            writer,
+            code,
+            instruction,
+            in_memory,
+        });
+    }
+}
+
+impl Default for SmcDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::test_support::{cpu, run, MockMachine, MockMemory};
+
+    use alloc::string::ToString;
+
+    fn sites(cpu: &mut Cpu<MockMemory>) -> Vec<(SmcKind, u16, Option<u16>, u16)> {
+        let reports = cpu.smc_detector_mut().unwrap().take_reports();
+        reports
+            .iter()
+            .map(|r| (r.kind, r.addr, r.writer.map(|w| w.pc), r.code))
+            .collect()
+    }
+
+    #[test]
+    fn test_smc_detector() {
+        // MVI A,07H; STA 0008H; NOP; NOP; MVI B,00H; STA 0007H
+        let program = [
+            0x3E, 0x07, 0x32, 0x08, 0x00, 0x00, 0x00, 0x06, 0x00, 0x32, 0x07, 0x00,
+        ];
+        let mut cpu = cpu(&[(0x0000, &program)]);
+        cpu.enable_smc_detector(SmcDetector::new());
+
+        // The first store patches the operand of MVI B before it runs, and
+        // the second overwrites its opcode afterwards.
+        run(&mut cpu, 6);
+        assert_eq!(
+            sites(&mut cpu),
+            vec![
+                (SmcKind::ExecuteWritten, 0x0008, Some(0x0002), 0x0007),
+                (SmcKind::WriteToCode, 0x0007, Some(0x0009), 0x0007),
+            ]
+        );
+        let detector = cpu.smc_detector().unwrap();
+        assert_eq!(detector.writer_of(0x0008), Some(0x0002));
+        assert!(detector.is_code(0x0008));
+        assert!(!detector.is_code(0x000C));
+
+        // The same sites are not reported twice.
+        cpu.pc = 0x0009;
+        run(&mut cpu, 1);
+        assert!(sites(&mut cpu).is_empty());
+
+        // Executing what memory held before the patch, as a stale decode
+        // cache would, is reported.
+        cpu.pc = 0x0007;
+        cpu.execute(
+            &Instruction::MVI(crate::instruction::Operand::B, 0x07),
+            &mut MockMachine,
+        );
+        let reports = cpu.smc_detector_mut().unwrap().take_reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[1].to_string(),
+            "stale decode at 0007H: executed MVI B,07H but memory holds RLC, \
+             last written by 0009H (STA 0007H)"
+        );
+    }
+}
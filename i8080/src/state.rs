@@ -0,0 +1,264 @@
+use crate::condition_codes::ConditionCodes;
+use crate::registers::Registers;
+use crate::scheduler::EventId;
+
+use alloc::vec::Vec;
+
+// A snapshot of the architectural state of the cpu: the register file, the
+// flags, the stack pointer and program counter, the interrupt enable flip-flop
+// and whether the cpu is halted. The events pending in the scheduler are
+// included too, so that a saved state resumes with them. Memory and the cycle
+// counter are not. Use Cpu::state and Cpu::set_state to read and write it.
+//
+// The fields are private so that the layout of the cpu internals can change
+// without breaking code written against this type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    pub(crate) registers: Registers,
+    pub(crate) condition_codes: ConditionCodes,
+    sp: u16,
+    pc: u16,
+    interrupts_enabled: bool,
+    halted: bool,
+    events: Vec<(u64, EventId)>,
+}
+
+impl CpuState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn from_parts(
+        registers: &Registers,
+        condition_codes: &ConditionCodes,
+        sp: u16,
+        pc: u16,
+        interrupts_enabled: bool,
+        halted: bool,
+        events: Vec<(u64, EventId)>,
+    ) -> Self {
+        CpuState {
+            registers: registers.clone(),
+            condition_codes: condition_codes.clone(),
+            sp,
+            pc,
+            interrupts_enabled,
+            halted,
+            events,
+        }
+    }
+
+    pub fn a(&self) -> u8 {
+        self.registers.a
+    }
+
+    pub fn set_a(&mut self, val: u8) {
+        self.registers.a = val;
+    }
+
+    pub fn b(&self) -> u8 {
+        self.registers.b
+    }
+
+    pub fn set_b(&mut self, val: u8) {
+        self.registers.b = val;
+    }
+
+    pub fn c(&self) -> u8 {
+        self.registers.c
+    }
+
+    pub fn set_c(&mut self, val: u8) {
+        self.registers.c = val;
+    }
+
+    pub fn d(&self) -> u8 {
+        self.registers.d
+    }
+
+    pub fn set_d(&mut self, val: u8) {
+        self.registers.d = val;
+    }
+
+    pub fn e(&self) -> u8 {
+        self.registers.e
+    }
+
+    pub fn set_e(&mut self, val: u8) {
+        self.registers.e = val;
+    }
+
+    pub fn h(&self) -> u8 {
+        self.registers.h
+    }
+
+    pub fn set_h(&mut self, val: u8) {
+        self.registers.h = val;
+    }
+
+    pub fn l(&self) -> u8 {
+        self.registers.l
+    }
+
+    pub fn set_l(&mut self, val: u8) {
+        self.registers.l = val;
+    }
+
+    pub fn bc(&self) -> u16 {
+        self.registers.get_bc()
+    }
+
+    pub fn set_bc(&mut self, val: u16) {
+        self.registers.set_bc(val);
+    }
+
+    pub fn de(&self) -> u16 {
+        self.registers.get_de()
+    }
+
+    pub fn set_de(&mut self, val: u16) {
+        self.registers.set_de(val);
+    }
+
+    pub fn hl(&self) -> u16 {
+        self.registers.get_hl()
+    }
+
+    pub fn set_hl(&mut self, val: u16) {
+        self.registers.set_hl(val);
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, val: u16) {
+        self.sp = val;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, val: u16) {
+        self.pc = val;
+    }
+
+    // The flags byte as pushed by PUSH PSW. Bit 1 is always set and bits 3
+    // and 5 are always clear.
+    pub fn flags(&self) -> u8 {
+        self.condition_codes.flags_to_psw()
+    }
+
+    pub fn set_flags(&mut self, val: u8) {
+        self.condition_codes.psw_to_flags(val);
+    }
+
+    // The accumulator and flags as a register pair, A in the high byte.
+    pub fn psw(&self) -> u16 {
+        (self.registers.a as u16) << 8 | self.flags() as u16
+    }
+
+    pub fn set_psw(&mut self, val: u16) {
+        self.registers.a = (val >> 8) as u8;
+        self.set_flags(val as u8);
+    }
+
+    pub fn carry(&self) -> bool {
+        self.condition_codes.carry
+    }
+
+    pub fn set_carry(&mut self, val: bool) {
+        self.condition_codes.carry = val;
+    }
+
+    pub fn zero(&self) -> bool {
+        self.condition_codes.zero
+    }
+
+    pub fn set_zero(&mut self, val: bool) {
+        self.condition_codes.zero = val;
+    }
+
+    pub fn sign(&self) -> bool {
+        self.condition_codes.sign
+    }
+
+    pub fn set_sign(&mut self, val: bool) {
+        self.condition_codes.sign = val;
+    }
+
+    pub fn parity(&self) -> bool {
+        self.condition_codes.parity
+    }
+
+    pub fn set_parity(&mut self, val: bool) {
+        self.condition_codes.parity = val;
+    }
+
+    pub fn aux_carry(&self) -> bool {
+        self.condition_codes.aux_carry
+    }
+
+    pub fn set_aux_carry(&mut self, val: bool) {
+        self.condition_codes.aux_carry = val;
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts_enabled
+    }
+
+    pub fn set_interrupts_enabled(&mut self, val: bool) {
+        self.interrupts_enabled = val;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_halted(&mut self, val: bool) {
+        self.halted = val;
+    }
+
+    // The pending events in the order they will fire, each with the number
+    // of cycles until it falls due.
+    pub fn events(&self) -> &[(u64, EventId)] {
+        &self.events
+    }
+
+    pub fn set_events(&mut self, events: &[(u64, EventId)]) {
+        self.events = events.to_vec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_pairs() {
+        let mut state = CpuState::new();
+        state.set_bc(0x1234);
+        state.set_e(0x56);
+        state.set_d(0x78);
+        state.set_hl(0x9ABC);
+        assert_eq!((state.b(), state.c()), (0x12, 0x34));
+        assert_eq!(state.de(), 0x7856);
+        assert_eq!((state.h(), state.l()), (0x9A, 0xBC));
+    }
+
+    #[test]
+    fn test_psw() {
+        let mut state = CpuState::new();
+        state.set_psw(0x42FF);
+        assert_eq!(state.a(), 0x42);
+        assert!(state.sign() && state.zero() && state.aux_carry());
+        assert!(state.parity() && state.carry());
+        // The unused flag bits read back as fixed values.
+        assert_eq!(state.psw(), 0x42D7);
+
+        state.set_zero(false);
+        state.set_carry(false);
+        assert_eq!(state.flags(), 0x96);
+    }
+}
@@ -0,0 +1,217 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+// Names addresses, e.g. a symbol table loaded for the program.
+pub trait SymbolLookup {
+    // The name of the closest symbol at or below `addr`, and the distance of
+    // `addr` above it.
+    fn lookup(&self, addr: u16) -> Option<(&str, u16)>;
+
+    // The name of the symbol at exactly `addr`.
+    fn name_at(&self, addr: u16) -> Option<&str> {
+        match self.lookup(addr) {
+            Some((name, 0)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+// The symbols of a program, read from either of two formats:
+//
+// - A map of one `ADDR NAME` pair per line, with `;` or `#` starting a
+//   comment.
+// - The .SYM file written by CP/M assemblers and linkers such as MAC and
+//   LINK-80: `ADDR NAME` pairs separated by tabs or spaces, several to a line,
+//   ending at a ^Z.
+//
+// The second is a generalisation of the first, so both are read by parse.
+// Addresses are hexadecimal, with an optional 0x or $ prefix or H suffix, and
+// a trailing ' or " marking a relocatable address is ignored. Names are
+// matched case-insensitively, as the assemblers fold them to upper case.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    // Where an address has several names, the first one read.
+    by_addr: BTreeMap<u16, String>,
+    by_name: BTreeMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolTable, SymbolError> {
+        let mut table = SymbolTable::new();
+        let text = text.split('\x1A').next().unwrap();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap();
+            let mut words = line.split_whitespace();
+            while let Some(addr) = words.next() {
+                let error = |message: &str| SymbolError {
+                    line: i + 1,
+                    message: message.to_string(),
+                };
+                let addr = parse_address(addr)
+                    .ok_or_else(|| error(&format!("invalid address '{}'", addr)))?;
+                let name = words
+                    .next()
+                    .ok_or_else(|| error("address without a name"))?;
+                table.insert(addr, name);
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_ascii_uppercase(), addr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // The address of the symbol called `name`.
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    // An address written as a symbol name, optionally followed by +OFFSET, or
+    // as a hexadecimal number. A name takes precedence over a number, so a
+    // symbol such as ADD is not read as ADDH.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, parse_address(offset)?),
+            None => (text, 0),
+        };
+        self.address_of(name)
+            .or_else(|| parse_address(name))
+            .map(|addr| addr.wrapping_add(offset))
+    }
+}
+
+impl SymbolLookup for SymbolTable {
+    fn lookup(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), addr - start))
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let text = text.trim_end_matches(['\'', '"']);
+    let digits = if let Some(digits) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+    {
+        digits
+    } else {
+        text.strip_suffix(['H', 'h']).unwrap_or(text)
+    };
+    if digits.is_empty() || digits.len() > 5 {
+        return None;
+    }
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// An error from reading a symbol file, with the line it was found on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SymbolError {}
+
+// Formats an address as XXXXH followed by the symbol it falls in, if any,
+// e.g. `0105H <LOOP+2H>`.
+pub struct Address<'a> {
+    pub addr: u16,
+    pub symbols: Option<&'a dyn SymbolLookup>,
+}
+
+impl<'a> Address<'a> {
+    pub fn new(addr: u16, symbols: Option<&'a dyn SymbolLookup>) -> Self {
+        Address { addr, symbols }
+    }
+}
+
+impl fmt::Display for Address<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}H", self.addr)?;
+        match self.symbols.and_then(|symbols| symbols.lookup(self.addr)) {
+            Some((name, 0)) => write!(f, " <{}>", name),
+            Some((name, offset)) => write!(f, " <{}+{:X}H>", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        let map = "; the program\n0100 START\n0x0105 LOOP # inner loop\n\n$0200 buffer\n";
+        let table = SymbolTable::parse(map).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.address_of("loop"), Some(0x0105));
+        assert_eq!(table.address_of("BUFFER"), Some(0x0200));
+
+        let sym = "0100 START\t0105 LOOP\t0200 BUFFER\r\n0205' DONE\r\n\x1A\x1A\x1A";
+        let cpm = SymbolTable::parse(sym).unwrap();
+        assert_eq!(cpm.len(), 4);
+        assert_eq!(cpm.lookup(0x0107), Some(("LOOP", 2)));
+        assert_eq!(cpm.lookup(0x0205), Some(("DONE", 0)));
+        assert_eq!(cpm.lookup(0x00FF), None);
+        assert_eq!(cpm.name_at(0x0100), Some("START"));
+        assert_eq!(cpm.name_at(0x0101), None);
+
+        assert_eq!(
+            SymbolTable::parse("0100 START\n0105")
+                .unwrap_err()
+                .to_string(),
+            "line 2: address without a name"
+        );
+        assert_eq!(
+            SymbolTable::parse("START 0100").unwrap_err().to_string(),
+            "line 1: invalid address 'START'"
+        );
+    }
+
+    #[test]
+    fn test_resolve_and_address() {
+        let table = SymbolTable::parse("0100 START\n0200 ADD\n").unwrap();
+        assert_eq!(table.resolve("start"), Some(0x0100));
+        assert_eq!(table.resolve("START+3"), Some(0x0103));
+        assert_eq!(table.resolve("ADD"), Some(0x0200));
+        assert_eq!(table.resolve("1234H"), Some(0x1234));
+        assert_eq!(table.resolve("NOWHERE"), None);
+
+        assert_eq!(
+            Address::new(0x0103, Some(&table)).to_string(),
+            "0103H <START+3H>"
+        );
+        assert_eq!(
+            Address::new(0x0200, Some(&table)).to_string(),
+            "0200H <ADD>"
+        );
+        assert_eq!(Address::new(0x0200, None).to_string(), "0200H");
+    }
+}
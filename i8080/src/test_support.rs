@@ -0,0 +1,109 @@
+// Memory, machines and helpers shared by the unit tests.
+use crate::cpu::Cpu;
+use crate::machine::{ClockedIO, CpuView, MachineIO};
+use crate::memory_bus::MemoryMap;
+
+// 64K of RAM.
+#[derive(Clone)]
+pub struct MockMemory {
+    pub memory: [u8; 0x10000],
+}
+
+impl MockMemory {
+    pub fn new() -> Self {
+        Self {
+            memory: [0; 0x10000],
+        }
+    }
+}
+
+impl MemoryMap for MockMemory {
+    fn load_rom(&mut self) {}
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn read_slice(&mut self, addr: u16) -> &[u8] {
+        &self.memory[addr as usize..]
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.memory[addr as usize] = val;
+    }
+}
+
+// Input ports read 0. OUT 0 halts the cpu, which is how test programs stop;
+// the other output ports do nothing.
+pub struct MockMachine;
+
+impl MachineIO for MockMachine {
+    fn machine_in(&mut self, _: u8) -> u8 {
+        0
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, _: u8) {
+        if port == 0 {
+            cpu.halt();
+        }
+    }
+}
+
+// MockMachine with a timer that requests RST 1 every 100 cycles.
+#[derive(Clone, Default)]
+pub struct Timer {
+    cycles: u32,
+    pending: bool,
+}
+
+impl MachineIO for Timer {
+    fn machine_in(&mut self, port: u8) -> u8 {
+        MockMachine.machine_in(port)
+    }
+
+    fn machine_out(&mut self, cpu: &mut CpuView, port: u8, val: u8) {
+        MockMachine.machine_out(cpu, port, val)
+    }
+}
+
+impl ClockedIO for Timer {
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        if self.cycles >= 100 {
+            self.cycles -= 100;
+            self.pending = true;
+        }
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        if self.pending {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        self.pending = false;
+    }
+}
+
+// A cpu with each run of bytes in `program` loaded at its address and the
+// rest of memory zeroed.
+pub fn cpu(program: &[(u16, &[u8])]) -> Cpu<MockMemory> {
+    let mut memory = MockMemory::new();
+    for (addr, bytes) in program {
+        let addr = *addr as usize;
+        memory.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+    }
+    Cpu::new(memory)
+}
+
+// Fetch and execute `instructions` instructions.
+pub fn run<M: MemoryMap>(cpu: &mut Cpu<M>, instructions: usize) {
+    for _ in 0..instructions {
+        let instr = cpu.fetch();
+        let (next_pc, _) = cpu.execute(&instr, &mut MockMachine);
+        cpu.pc = next_pc;
+    }
+}
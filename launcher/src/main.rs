@@ -0,0 +1,423 @@
+mod monitor;
+
+use i8080::analysis::{Analyzer, ControlFlowGraph};
+use i8080::crash_dump::CrashDump;
+use i8080::history::History;
+use i8080::machine::Machine;
+use i8080::power_on::PowerOn;
+use i8080::recompiler::Recompiler;
+use i8080::smc::SmcDetector;
+use i8080::symbols::{Address, SymbolTable};
+use space_invaders::{frontend, memory};
+
+use std::any::Any;
+use std::env;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+
+// The number of cycles to run between checks for the cpu halting.
+const CPM_SLICE: u64 = 100_000;
+
+fn usage() -> ! {
+    eprintln!("usage: launcher [options] space-invaders");
+    eprintln!("       launcher [options] cpm <rom.COM>");
+    eprintln!("       launcher [--symbols FILE] monitor <dump>");
+    eprintln!("       launcher [--symbols FILE] analyze [analyze options] <image>...");
+    eprintln!(
+        "       launcher [--symbols FILE] recompile --out DIR [recompile options] <image>..."
+    );
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("  --power-on-seed N  fill the registers, flags, stack pointer and, for");
+    eprintln!("                     Space Invaders, RAM with garbage generated from N");
+    eprintln!("  --sanitize         report reads of uninitialised RAM, stack underflow");
+    eprintln!("                     and overflow, writes to ROM and execution outside ROM");
+    eprintln!("  --detect-smc       report writes to code that has run and execution of");
+    eprintln!("                     code written at run time, with the writer's address");
+    eprintln!("  --backtrace        track the 8080 call stack and print a backtrace if the");
+    eprintln!("                     emulator panics");
+    eprintln!("  --crash-dump FILE  record recent instructions and I/O and, if the");
+    eprintln!("                     emulator panics, write a crash dump to FILE for");
+    eprintln!("                     `launcher monitor`");
+    eprintln!("  --symbols FILE     name addresses in output using a symbol file, either");
+    eprintln!("                     `ADDR NAME` lines or a CP/M .SYM file");
+    eprintln!("  --break ADDR       for cpm, stop in the monitor when the pc reaches ADDR,");
+    eprintln!("                     a hexadecimal address or a symbol; may be repeated");
+    eprintln!();
+    eprintln!("analyze options:");
+    eprintln!("  --base ADDR        the address the images are loaded at, 0 by default");
+    eprintln!("  --entry ADDR       follow code from ADDR as well as from 0, 08H and 10H;");
+    eprintln!("                     may be repeated");
+    eprintln!("  --listing FILE     write the listing to FILE rather than stdout");
+    eprintln!("  --dot FILE         write the control-flow graph to FILE in Graphviz format");
+    eprintln!();
+    eprintln!("recompile options, besides --base and --entry:");
+    eprintln!("  --out DIR          write the crate to DIR");
+    eprintln!("  --name NAME        name the crate NAME, `recompiled` by default");
+    eprintln!("  --rom START-END    code in START-END cannot change, so is not checked;");
+    eprintln!("                     may be repeated");
+    eprintln!("  --i8080 PATH       the path from DIR to the i8080 crate, by default where");
+    eprintln!("                     the launcher was built from");
+    process::exit(2);
+}
+
+fn print_reports(machine: &mut dyn Machine, symbols: &SymbolTable) {
+    if let Some(sanitizer) = machine.cpu_debug().sanitizer_mut() {
+        for report in sanitizer.take_reports() {
+            eprintln!("{}", report.display(Some(symbols)));
+        }
+    }
+    if let Some(detector) = machine.cpu_debug().smc_detector_mut() {
+        for report in detector.take_reports() {
+            eprintln!("{}", report.display(Some(symbols)));
+        }
+    }
+}
+
+fn load_symbols(path: &str) -> SymbolTable {
+    let bytes = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", path, err);
+        process::exit(1);
+    });
+    SymbolTable::parse(&String::from_utf8_lossy(&bytes)).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
+    })
+}
+
+// Run the machine. If it panics, print the 8080 backtrace and write a crash
+// dump before passing the panic on.
+fn run_guarded(
+    machine: &mut dyn Machine,
+    symbols: &SymbolTable,
+    crash_dump: Option<&str>,
+    run: impl FnOnce(&mut dyn Machine),
+) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| run(&mut *machine))) {
+        if let Some(backtrace) = machine.cpu_debug().backtrace() {
+            eprintln!("8080 backtrace:\n{}", backtrace.display(Some(symbols)));
+        }
+        if let Some(path) = crash_dump {
+            let reason = format!("panic: {}", panic_message(payload.as_ref()));
+            let dump = machine.cpu_debug().crash_dump(&reason);
+            match fs::write(path, dump.to_bytes()) {
+                Ok(()) => eprintln!("crash dump written to {}", path),
+                Err(err) => eprintln!("could not write crash dump to {}: {}", path, err),
+            }
+        }
+        panic::resume_unwind(payload);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+fn run_monitor(path: &str, symbols: &SymbolTable) {
+    let bytes = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", path, err);
+        process::exit(1);
+    });
+    let dump = CrashDump::from_bytes(&bytes).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
+    });
+    let stdin = io::stdin();
+    monitor::run(&dump, Some(symbols), false, stdin.lock(), io::stdout()).unwrap();
+}
+
+// The arguments of analyze and recompile: --base, any number of --entry,
+// the options in `accepted`, which take a value, and then the images, which
+// are concatenated. Exits if any are wrong.
+struct ImageArgs<'a> {
+    base: u16,
+    entries: Vec<u16>,
+    options: Vec<(&'a str, &'a str)>,
+    image: Vec<u8>,
+}
+
+impl<'a> ImageArgs<'a> {
+    fn parse(mut args: &[&'a str], symbols: &SymbolTable, accepted: &[&str]) -> Self {
+        let mut parsed = ImageArgs {
+            base: 0,
+            entries: Vec::new(),
+            options: Vec::new(),
+            image: Vec::new(),
+        };
+        loop {
+            match args {
+                ["--base", addr, rest @ ..] => {
+                    parsed.base = resolve(symbols, addr);
+                    args = rest;
+                }
+                ["--entry", addr, rest @ ..] => {
+                    parsed.entries.push(resolve(symbols, addr));
+                    args = rest;
+                }
+                [option, val, rest @ ..] if accepted.contains(option) => {
+                    parsed.options.push((*option, *val));
+                    args = rest;
+                }
+                [arg, ..] if arg.starts_with("--") => usage(),
+                [] => usage(),
+                _ => break,
+            }
+        }
+        for path in args {
+            match fs::read(path) {
+                Ok(bytes) => parsed.image.extend(bytes),
+                Err(err) => {
+                    eprintln!("could not read {}: {}", path, err);
+                    process::exit(1);
+                }
+            }
+        }
+        parsed
+    }
+
+    // The last value given for `option`.
+    fn option(&self, option: &str) -> Option<&'a str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == option)
+            .map(|(_, val)| *val)
+    }
+
+    fn analyze(&self) -> ControlFlowGraph {
+        let analyzer = self
+            .entries
+            .iter()
+            .fold(Analyzer::new(&self.image, self.base), |analyzer, &entry| {
+                analyzer.with_entry(entry)
+            });
+        let cfg = analyzer.analyze();
+        for &pc in cfg.indirect.iter() {
+            eprintln!("{:04X}H: indirect jump not followed", pc);
+        }
+        cfg
+    }
+}
+
+fn resolve(symbols: &SymbolTable, text: &str) -> u16 {
+    symbols.resolve(text).unwrap_or_else(|| {
+        eprintln!("unknown address or symbol '{}'", text);
+        process::exit(2);
+    })
+}
+
+fn write_file(path: &str, contents: impl AsRef<[u8]>) {
+    if let Err(err) = fs::write(path, contents) {
+        eprintln!("could not write {}: {}", path, err);
+        process::exit(1);
+    }
+}
+
+// Recover the code in the concatenated images, such as invaders.h to
+// invaders.e, and write a listing and optionally a graph of it.
+fn run_analyze(args: &[&str], symbols: &SymbolTable) {
+    let args = ImageArgs::parse(args, symbols, &["--listing", "--dot"]);
+    let cfg = args.analyze();
+    let text = cfg.listing(Some(symbols));
+    match args.option("--listing") {
+        Some(path) => write_file(path, text),
+        None => print!("{}", text),
+    }
+    if let Some(path) = args.option("--dot") {
+        write_file(path, cfg.dot(Some(symbols)));
+    }
+}
+
+// Translate the code in the concatenated images to a Rust crate with a
+// harness that checks it against the interpreter.
+fn run_recompile(args: &[&str], symbols: &SymbolTable) {
+    let args = ImageArgs::parse(args, symbols, &["--rom", "--name", "--out", "--i8080"]);
+    let out = args.option("--out").unwrap_or_else(|| usage());
+    let name = args.option("--name").unwrap_or("recompiled");
+    let i8080 = args
+        .option("--i8080")
+        .unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/../i8080"));
+    let cfg = args.analyze();
+
+    let mut recompiler = Recompiler::new(&cfg);
+    for &(option, range) in args.options.iter() {
+        if option == "--rom" {
+            let (start, end) = range.split_once('-').unwrap_or_else(|| usage());
+            recompiler = recompiler.with_rom(resolve(symbols, start)..=resolve(symbols, end));
+        }
+    }
+    for file in recompiler.generate_crate(name, i8080) {
+        let path = Path::new(out).join(&file.path);
+        if let Err(err) = fs::create_dir_all(path.parent().unwrap()) {
+            eprintln!(
+                "could not create {}: {}",
+                path.parent().unwrap().display(),
+                err
+            );
+            process::exit(1);
+        }
+        write_file(&path.to_string_lossy(), &file.contents);
+    }
+    println!(
+        "{} blocks, {} bytes of code, written to {}",
+        cfg.blocks.len(),
+        cfg.code_bytes(),
+        out
+    );
+}
+
+fn run_cpm(machine: &mut dyn Machine, symbols: &SymbolTable) {
+    while !machine.is_halted() {
+        machine.run_for(CPM_SLICE);
+        print_reports(machine, symbols);
+        if let Some(addr) = machine.cpu_debug().take_breakpoint_hit() {
+            let reason = format!("breakpoint at {}", Address::new(addr, Some(symbols)));
+            let dump = machine.cpu_debug().crash_dump(&reason);
+            let stdin = io::stdin();
+            if !monitor::run(&dump, Some(symbols), true, stdin.lock(), io::stdout()).unwrap() {
+                return;
+            }
+        }
+    }
+    println!();
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let mut seed = None;
+    let mut sanitize = false;
+    let mut detect_smc = false;
+    let mut backtrace = false;
+    let mut crash_dump = None;
+    let mut symbols = SymbolTable::new();
+    let mut breakpoints = Vec::new();
+    loop {
+        match args.first().map(String::as_str) {
+            Some("--power-on-seed") if args.len() >= 2 => {
+                seed = Some(args[1].parse::<u64>().unwrap_or_else(|_| usage()));
+                args.drain(..2);
+            }
+            Some("--sanitize") => {
+                sanitize = true;
+                args.remove(0);
+            }
+            Some("--detect-smc") => {
+                detect_smc = true;
+                args.remove(0);
+            }
+            Some("--backtrace") => {
+                backtrace = true;
+                args.remove(0);
+            }
+            Some("--crash-dump") if args.len() >= 2 => {
+                crash_dump = Some(args[1].clone());
+                args.drain(..2);
+            }
+            Some("--symbols") if args.len() >= 2 => {
+                symbols = load_symbols(&args[1]);
+                args.drain(..2);
+            }
+            Some("--break") if args.len() >= 2 => {
+                breakpoints.push(args[1].clone());
+                args.drain(..2);
+            }
+            Some(arg) if arg.starts_with("--") => usage(),
+            _ => break,
+        }
+    }
+
+    // Resolved once every option is read, as the symbols may come after.
+    let breakpoints: Vec<u16> = breakpoints
+        .iter()
+        .map(|text| {
+            symbols.resolve(text).unwrap_or_else(|| {
+                eprintln!("unknown breakpoint address or symbol '{}'", text);
+                process::exit(2);
+            })
+        })
+        .collect();
+
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["space-invaders"] => {
+            if !breakpoints.is_empty() {
+                eprintln!("--break is only supported for cpm");
+                process::exit(2);
+            }
+            let (mut system, controls) = space_invaders::space_invaders();
+            if sanitize {
+                system.cpu.enable_sanitizer(memory::sanitizer());
+            }
+            if detect_smc {
+                system.cpu.enable_smc_detector(SmcDetector::new());
+            }
+            if backtrace || crash_dump.is_some() {
+                system.cpu.enable_call_stack();
+            }
+            if crash_dump.is_some() {
+                system.cpu.enable_history(History::default());
+            }
+            let mut machine: Box<dyn Machine> = Box::new(system);
+            if let Some(seed) = seed {
+                machine.power_on(&PowerOn::new(seed).with_ram(memory::RAM));
+            }
+            run_guarded(
+                machine.as_mut(),
+                &symbols,
+                crash_dump.as_deref(),
+                |machine| frontend::run(machine, &controls),
+            );
+        }
+        ["cpm", rom] => {
+            let mut system = i8080_tests::cpm_system(rom);
+            if let Some(seed) = seed {
+                // Only the registers are randomised, as memory holds the
+                // program. CP/M programs start at 0x100 rather than at the
+                // reset vector, so the pc is put back afterwards.
+                system.cpu.power_on(&PowerOn::new(seed));
+                system.cpu.pc = 0x100;
+            }
+            if sanitize {
+                let sanitizer = i8080_tests::cpm_sanitizer(&system.cpu.memory);
+                system.cpu.enable_sanitizer(sanitizer);
+            }
+            if detect_smc {
+                system.cpu.enable_smc_detector(SmcDetector::new());
+            }
+            if backtrace || crash_dump.is_some() {
+                system.cpu.enable_call_stack();
+            }
+            if crash_dump.is_some() {
+                system.cpu.enable_history(History::default());
+            }
+            for &addr in breakpoints.iter() {
+                system.cpu.add_breakpoint(addr);
+            }
+            let mut machine: Box<dyn Machine> = Box::new(system);
+            run_guarded(
+                machine.as_mut(),
+                &symbols,
+                crash_dump.as_deref(),
+                |machine| run_cpm(machine, &symbols),
+            );
+        }
+        ["monitor", dump] => run_monitor(dump, &symbols),
+        ["analyze", rest @ ..] => run_analyze(rest, &symbols),
+        ["recompile", rest @ ..] => run_recompile(rest, &symbols),
+        _ => usage(),
+    }
+}
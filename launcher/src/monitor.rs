@@ -0,0 +1,265 @@
+use i8080::crash_dump::CrashDump;
+use i8080::instruction::Instruction;
+use i8080::symbols::{Address, SymbolLookup, SymbolTable};
+
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+r              registers and flags
+m ADDR [LEN]   memory, 64 bytes by default
+d [ADDR] [N]   disassemble N instructions, 16 by default, from ADDR or the pc
+h [N]          the last N instructions executed, all by default
+i [N]          the last N I/O accesses, all by default
+bt             backtrace
+c              continue running, when stopped at a breakpoint
+q              quit
+Numbers are hexadecimal, with or without a trailing H. An address can also be
+a symbol, optionally followed by +OFFSET.";
+
+// A monitor over a snapshot of a machine: a crash dump loaded for post-mortem
+// inspection, or the state of a machine stopped at a breakpoint. Reads
+// commands from `input` until it ends or a c or q command. Returns true if
+// the machine should continue, which is only offered if `live`.
+pub fn run(
+    dump: &CrashDump,
+    symbols: Option<&SymbolTable>,
+    live: bool,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<bool> {
+    let lookup = symbols.map(|symbols| symbols as &dyn SymbolLookup);
+    let address = |text: &str| match symbols {
+        Some(symbols) => symbols.resolve(text),
+        None => number(text),
+    };
+
+    writeln!(output, "{}", dump.reason)?;
+    registers(dump, &mut output)?;
+    prompt(&mut output)?;
+    for line in input.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["q"] => return Ok(false),
+            ["c"] if live => return Ok(true),
+            ["c"] => writeln!(output, "a crash dump cannot be continued")?,
+            ["r"] => registers(dump, &mut output)?,
+            ["m", addr, len @ ..] if len.len() <= 1 => {
+                match (address(addr), optional_number(len, 0x40)) {
+                    (Some(addr), Some(len)) => memory(dump, addr, len, &mut output)?,
+                    _ => writeln!(output, "bad address or length")?,
+                }
+            }
+            ["d", args @ ..] if args.len() <= 2 => {
+                let addr = match args.first() {
+                    Some(addr) => address(addr),
+                    None => Some(dump.state.pc()),
+                };
+                match (addr, optional_number(args.get(1..).unwrap_or(&[]), 16)) {
+                    (Some(addr), Some(n)) => disassemble(dump, lookup, addr, n, &mut output)?,
+                    _ => writeln!(output, "bad address or count")?,
+                }
+            }
+            ["h", n @ ..] if n.len() <= 1 => match optional_number(n, 0xFFFF) {
+                Some(n) => {
+                    let skip = dump.instructions.len().saturating_sub(n as usize);
+                    for executed in dump.instructions.iter().skip(skip) {
+                        writeln!(output, "{}", executed.display(lookup))?;
+                    }
+                }
+                None => writeln!(output, "bad count")?,
+            },
+            ["i", n @ ..] if n.len() <= 1 => match optional_number(n, 0xFFFF) {
+                Some(n) => {
+                    let skip = dump.io.len().saturating_sub(n as usize);
+                    for access in dump.io.iter().skip(skip) {
+                        writeln!(output, "{}", access.display(lookup))?;
+                    }
+                }
+                None => writeln!(output, "bad count")?,
+            },
+            ["bt"] => match dump.backtrace.as_ref() {
+                Some(backtrace) => writeln!(output, "{}", backtrace.display(lookup))?,
+                None => writeln!(output, "the call stack was not recorded")?,
+            },
+            _ => writeln!(output, "{}", HELP)?,
+        }
+        prompt(&mut output)?;
+    }
+    Ok(false)
+}
+
+fn prompt(output: &mut impl Write) -> io::Result<()> {
+    write!(output, "> ")?;
+    output.flush()
+}
+
+fn number(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_end_matches(['H', 'h']), 16).ok()
+}
+
+// The number in `args`, if there is one, or `default`.
+fn optional_number(args: &[&str], default: u16) -> Option<u16> {
+    match args.first() {
+        Some(text) => number(text),
+        None => Some(default),
+    }
+}
+
+fn registers(dump: &CrashDump, output: &mut impl Write) -> io::Result<()> {
+    let state = &dump.state;
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    writeln!(
+        output,
+        "A={:02X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} {}{}{}{}{} {} {} cycle {}",
+        state.a(),
+        state.bc(),
+        state.de(),
+        state.hl(),
+        state.sp(),
+        state.pc(),
+        flag(state.sign(), 'S'),
+        flag(state.zero(), 'Z'),
+        flag(state.aux_carry(), 'A'),
+        flag(state.parity(), 'P'),
+        flag(state.carry(), 'C'),
+        if state.interrupts_enabled() {
+            "EI"
+        } else {
+            "DI"
+        },
+        if state.is_halted() {
+            "halted"
+        } else {
+            "running"
+        },
+        dump.cycles
+    )
+}
+
+fn memory(dump: &CrashDump, addr: u16, len: u16, output: &mut impl Write) -> io::Result<()> {
+    for row in (0..len).step_by(16) {
+        let row_addr = addr.wrapping_add(row);
+        write!(output, "{:04X}H ", row_addr)?;
+        for i in 0..(len - row).min(16) {
+            let addr = row_addr.wrapping_add(i);
+            if dump.is_mapped(addr) {
+                write!(output, " {:02X}", dump.memory[addr as usize])?;
+            } else {
+                write!(output, " --")?;
+            }
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+// A listing of `n` instructions from `addr`, with a label line before each
+// address that has a symbol and the pc marked with =>.
+fn disassemble(
+    dump: &CrashDump,
+    symbols: Option<&dyn SymbolLookup>,
+    mut addr: u16,
+    n: u16,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    for _ in 0..n {
+        if !dump.is_mapped(addr) {
+            writeln!(output, "   {}  unmapped", Address::new(addr, None))?;
+            return Ok(());
+        }
+        if let Some(name) = symbols.and_then(|symbols| symbols.name_at(addr)) {
+            writeln!(output, "{}:", name)?;
+        }
+        let bytes: Vec<u8> = (0..3)
+            .map(|i| dump.memory[addr.wrapping_add(i) as usize])
+            .collect();
+        let instruction = Instruction::from(&bytes[..]);
+        let size = instruction.size();
+        let hex: Vec<String> = bytes[..size as usize]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let marker = if addr == dump.state.pc() { "=>" } else { "  " };
+        writeln!(
+            output,
+            "{} {:04X}H  {:<8}  {}",
+            marker,
+            addr,
+            hex.join(" "),
+            instruction.display(symbols)
+        )?;
+        addr = addr.wrapping_add(size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use i8080::call_stack::Backtrace;
+    use i8080::CpuState;
+
+    fn dump() -> CrashDump {
+        let mut state = CpuState::new();
+        state.set_pc(0x0003);
+        state.set_zero(true);
+        let mut memory = vec![0; 0x10000];
+        memory[..6].copy_from_slice(&[0x31, 0x00, 0x24, 0xCD, 0x10, 0x00]);
+        CrashDump {
+            reason: "panic: test".to_string(),
+            state,
+            cycles: 10,
+            instructions: Vec::new(),
+            io: Vec::new(),
+            backtrace: Some(Backtrace {
+                pc: 0x0003,
+                frames: Vec::new(),
+            }),
+            unmapped: vec![0x8000..=0xFFFF],
+            memory,
+        }
+    }
+
+    fn monitor(symbols: Option<&SymbolTable>, live: bool, input: &str) -> (bool, String) {
+        let mut output = Vec::new();
+        let result = run(&dump(), symbols, live, input.as_bytes(), &mut output).unwrap();
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_monitor_commands() {
+        let (result, output) = monitor(None, false, "r\nm 7FFE 4\nd 0 2\nbt\nx\nc\nq\nr\n");
+        assert!(!result);
+        let registers = "A=00 BC=0000 DE=0000 HL=0000 SP=0000 PC=0003 -Z--- DI running cycle 10";
+        let expected = format!("panic: test\n{}\n> ", registers);
+        assert!(output.starts_with(&expected), "{}", output);
+        assert!(output.contains("> 7FFEH  00 00 -- --\n"), "{}", output);
+        assert!(
+            output.contains("   0000H  31 00 24  LXI SP,2400H\n=> 0003H  CD 10 00  CALL 0010H\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("> #0  0003H\n"), "{}", output);
+        assert!(output.contains(HELP), "{}", output);
+        assert!(output.contains("cannot be continued"), "{}", output);
+        assert_eq!(output.matches(registers).count(), 2);
+    }
+
+    #[test]
+    fn test_monitor_symbols() {
+        let symbols = SymbolTable::parse("0000 START\n0003 MAIN\n0010 PRINT").unwrap();
+        let (result, output) = monitor(Some(&symbols), true, "d start 2\nm main+1 2\nbt\nc\nr\n");
+        assert!(result);
+        assert!(
+            output.contains(
+                "START:\n   0000H  31 00 24  LXI SP,2400H\nMAIN:\n=> 0003H  CD 10 00  CALL PRINT\n"
+            ),
+            "{}",
+            output
+        );
+        assert!(output.contains("> 0004H  10 00\n"), "{}", output);
+        assert!(output.contains("> #0  0003H <MAIN>\n"), "{}", output);
+    }
+}
@@ -1,7 +1,4 @@
-use i8080::cpu::Cpu;
-
-use crate::i8080::memory_bus::MemoryMap;
-use crate::memory::SpaceInvadersMemory;
+use i8080::memory_bus::MemoryMap;
 
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
@@ -27,16 +24,16 @@ impl Display {
         canvas.set_draw_color(Color::BLACK);
         canvas.clear();
         canvas.present();
-        Display { canvas: canvas }
+        Display { canvas }
     }
 
-    pub fn draw_display_whole(&mut self, cpu: &mut Cpu<SpaceInvadersMemory>) {
+    pub fn draw_display_whole(&mut self, memory: &mut dyn MemoryMap) {
         self.canvas.clear();
         for offset in 0x0..0x1C00 {
             let video_ram_byte = offset + 0x2400;
             let x = offset / 32;
             let y = 248 - ((offset % 32) * 8);
-            let byte = cpu.memory.read(video_ram_byte);
+            let byte = memory.read(video_ram_byte);
             if byte > 0 {
                 self.draw_byte(byte, x as u32, y as u32);
             }
@@ -49,9 +46,9 @@ impl Display {
         for bit in (0..8).rev() {
             if byte & cmp_byte != 0 {
                 self.canvas.set_draw_color(
-                    if (y >= 190 && y <= 220) || (y >= 240 && x >= 15 && x <= 135) {
+                    if (190..=220).contains(&y) || (y >= 240 && (15..=135).contains(&x)) {
                         Color::GREEN
-                    } else if y >= 30 && y <= 50 {
+                    } else if (30..=50).contains(&y) {
                         Color::RED
                     } else {
                         Color::WHITE
@@ -0,0 +1,85 @@
+use crate::display::Display;
+use crate::io::{ControllerPort, Controls, Key, CYCLES_PER_FRAME};
+
+use i8080::machine::Machine;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+fn keycode_to_key(keycode: Keycode) -> Option<(Key, ControllerPort)> {
+    let key = match keycode {
+        Keycode::Num0 => (Key::CREDIT, ControllerPort::P1),
+        Keycode::Num2 => (Key::START2P, ControllerPort::P1),
+        Keycode::Num1 => (Key::START1P, ControllerPort::P1),
+        Keycode::W => (Key::SHOOT1P, ControllerPort::P1),
+        Keycode::A => (Key::LEFT1P, ControllerPort::P1),
+        Keycode::D => (Key::RIGHT1P, ControllerPort::P1),
+        Keycode::I => (Key::SHOOT2P, ControllerPort::P2),
+        Keycode::J => (Key::LEFT2P, ControllerPort::P2),
+        Keycode::L => (Key::RIGHT2P, ControllerPort::P2),
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+// Run a Space Invaders machine in an SDL window until the window is closed or
+// escape is pressed.
+pub fn run(machine: &mut dyn Machine, controls: &Controls) {
+    let sdl_context = sdl2::init().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut display = Display::new(sdl_context);
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some((key, port)) = keycode_to_key(keycode) {
+                        controls.press(key, port);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some((key, port)) = keycode_to_key(keycode) {
+                        controls.release(key, port);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Run all the instructions in order to reach the required cycles per
+        // frame. The machine raises the mid-screen and vblank interrupts.
+        machine.run_for(CYCLES_PER_FRAME as u64);
+        // Emulator 101 suggests exiting if HLT is ever executed, as the game
+        // never halts when working.
+        if machine.is_halted() {
+            process::exit(1);
+        }
+        if let Some(sanitizer) = machine.cpu_debug().sanitizer_mut() {
+            for report in sanitizer.take_reports() {
+                eprintln!("{}", report);
+            }
+        }
+        if let Some(detector) = machine.cpu_debug().smc_detector_mut() {
+            for report in detector.take_reports() {
+                eprintln!("{}", report);
+            }
+        }
+        display.draw_display_whole(machine.memory());
+        thread::sleep(Duration::from_millis(16));
+    }
+}
@@ -1,9 +1,10 @@
-use i8080::cpu::Cpu;
-use i8080::machine::MachineIO;
-use i8080::memory_bus::MemoryMap;
+use i8080::machine::{ClockedIO, CpuView, MachineIO};
 
 use crate::sound::AudioMixer;
 
+use std::cell::Cell;
+use std::rc::Rc;
+
 bitflags! {
     pub struct Key: u8 {
         const CREDIT = 1 << 0;
@@ -17,43 +18,92 @@ bitflags! {
         const RIGHT2P = 1 << 6;
     }
 }
+
+pub const HERTZ: u32 = 2_000_000;
+pub const FPS: u32 = 60;
+pub const CYCLES_PER_FRAME: u32 = HERTZ / FPS;
+pub const CYCLES_PER_HALF_FRAME: u32 = CYCLES_PER_FRAME / 2;
+
+#[derive(Copy, Clone)]
 pub enum ControllerPort {
     P1,
     P2,
 }
 
+// The state of the two controller ports. Clones share the same state, so a
+// frontend can keep a Controls to feed key presses to a machine it only holds
+// as a trait object.
+#[derive(Clone)]
+pub struct Controls {
+    ports: Rc<Cell<[u8; 2]>>,
+}
+
+impl Controls {
+    fn new() -> Self {
+        Controls {
+            ports: Rc::new(Cell::new([1, 0])),
+        }
+    }
+
+    fn port(&self, port: ControllerPort) -> u8 {
+        self.ports.get()[port as usize]
+    }
+
+    pub fn press(&self, key: Key, port: ControllerPort) {
+        let mut ports = self.ports.get();
+        ports[port as usize] |= key.bits();
+        self.ports.set(ports);
+    }
+
+    pub fn release(&self, key: Key, port: ControllerPort) {
+        let mut ports = self.ports.get();
+        ports[port as usize] &= !key.bits();
+        self.ports.set(ports);
+    }
+}
+
 pub struct SpaceInvadersIO {
-    first_port: u8,
-    second_port: u8,
+    controls: Controls,
     prev_third_port: u8,
     prev_fifth_port: u8,
     shift0: u8,
     shift1: u8,
     shift_offset: u8,
     audio: AudioMixer,
+    half_frame_cycles: u32,
+    next_interrupt: u8,
+    interrupt: Option<u8>,
 }
 
 impl SpaceInvadersIO {
     pub fn new() -> Self {
         SpaceInvadersIO {
-            first_port: 1,
-            second_port: 0,
+            controls: Controls::new(),
             prev_third_port: 0,
             prev_fifth_port: 0,
             shift0: 0,
             shift1: 0,
             shift_offset: 0,
             audio: AudioMixer::new(),
+            half_frame_cycles: 0,
+            next_interrupt: 1,
+            interrupt: None,
         }
     }
 }
 
+impl Default for SpaceInvadersIO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MachineIO for SpaceInvadersIO {
     fn machine_in(&mut self, port: u8) -> u8 {
         match port {
             0 => 0x0F,
-            1 => self.first_port,
-            2 => self.second_port,
+            1 => self.controls.port(ControllerPort::P1),
+            2 => self.controls.port(ControllerPort::P2),
             3 => {
                 let val = ((self.shift1 as u16) << 8) | self.shift0 as u16;
                 ((val >> (8 - self.shift_offset)) & 0xFF) as u8
@@ -62,7 +112,7 @@ impl MachineIO for SpaceInvadersIO {
         }
     }
 
-    fn machine_out<M: MemoryMap>(&mut self, _: &mut Cpu<M>, port: u8, val: u8) {
+    fn machine_out(&mut self, _: &mut CpuView, port: u8, val: u8) {
         match port {
             2 => self.shift_offset = val & 0x7,
             3 => {
@@ -117,18 +167,37 @@ impl MachineIO for SpaceInvadersIO {
     }
 }
 
+// The video hardware interrupts twice per frame: RST 1 when the beam reaches
+// the middle of the screen and RST 2 at the start of vertical blank.
+impl ClockedIO for SpaceInvadersIO {
+    fn tick(&mut self, cycles: u32) {
+        self.half_frame_cycles += cycles;
+        if self.half_frame_cycles >= CYCLES_PER_HALF_FRAME {
+            self.half_frame_cycles -= CYCLES_PER_HALF_FRAME;
+            self.interrupt = Some(self.next_interrupt);
+            self.next_interrupt = if self.next_interrupt == 1 { 2 } else { 1 };
+        }
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        self.interrupt
+    }
+
+    fn interrupt_acknowledge(&mut self) {
+        self.interrupt = None;
+    }
+}
+
 impl SpaceInvadersIO {
+    pub fn controls(&self) -> Controls {
+        self.controls.clone()
+    }
+
     pub fn press(&mut self, key: Key, port: ControllerPort) {
-        match port {
-            ControllerPort::P1 => self.first_port |= key.bits(),
-            ControllerPort::P2 => self.second_port |= key.bits(),
-        }
+        self.controls.press(key, port)
     }
 
     pub fn release(&mut self, key: Key, port: ControllerPort) {
-        match port {
-            ControllerPort::P1 => self.first_port &= !key.bits(),
-            ControllerPort::P2 => self.second_port &= !key.bits(),
-        }
+        self.controls.release(key, port)
     }
 }
@@ -0,0 +1,23 @@
+#[macro_use]
+extern crate bitflags;
+
+#[cfg(feature = "sdl")]
+pub mod display;
+#[cfg(feature = "sdl")]
+pub mod frontend;
+pub mod io;
+pub mod memory;
+pub mod sound;
+
+use crate::io::{Controls, SpaceInvadersIO};
+use crate::memory::SpaceInvadersMemory;
+
+use i8080::machine::System;
+
+// Build a Space Invaders machine, along with the controls used to feed it key
+// presses.
+pub fn space_invaders() -> (System<SpaceInvadersMemory, SpaceInvadersIO>, Controls) {
+    let io = SpaceInvadersIO::new();
+    let controls = io.controls();
+    (System::new(SpaceInvadersMemory::new(), io), controls)
+}
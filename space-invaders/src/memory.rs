@@ -1,7 +1,10 @@
-use crate::i8080::memory_bus::MemoryMap;
+use i8080::memory_bus::MemoryMap;
+use i8080::sanitizer::{MemoryLayout, Sanitizer};
 
 use std::fs::File;
 use std::io::Read;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 pub const ROM_BEGIN: usize = 0x0000;
 pub const ROM_END: usize = 0x1FFF;
@@ -18,31 +21,63 @@ pub const VIDEO_RAM_SIZE: usize = VIDEO_RAM_END - VIDEO_RAM_BEGIN + 1;
 pub const RAM_MIRROR_BEGIN: usize = 0x4000;
 pub const RAM_MIRROR_END: usize = 0xFFFF;
 
+// Working and video RAM, for randomising at power on.
+pub const RAM: RangeInclusive<u16> = WORKING_RAM_BEGIN as u16..=VIDEO_RAM_END as u16;
+
+// The stack grows down from the top of working RAM towards the second
+// player's data at 2200H-22FFH.
+pub const STACK_GUARD: RangeInclusive<u16> = 0x2200..=0x22FF;
+
+// The memory map as seen by SpaceInvadersMemory, for the sanitizer.
+pub fn sanitizer() -> Sanitizer {
+    let layout = MemoryLayout::new()
+        .rom(ROM_BEGIN as u16..=ROM_END as u16)
+        .ram(RAM)
+        .mirror(
+            RAM_MIRROR_BEGIN as u16..=(RAM_MIRROR_BEGIN + WORKING_RAM_SIZE - 1) as u16,
+            WORKING_RAM_BEGIN as u16..=WORKING_RAM_END as u16,
+        );
+    Sanitizer::new(layout).with_stack_guard(STACK_GUARD)
+}
+
 pub struct SpaceInvadersMemory {
     rom: [u8; ROM_SIZE],
     working_ram: [u8; WORKING_RAM_SIZE],
     video_ram: [u8; VIDEO_RAM_SIZE],
+    rom_dir: PathBuf,
 }
 
 impl SpaceInvadersMemory {
     pub fn new() -> Self {
+        Self::with_rom_dir("roms")
+    }
+
+    // Load invaders.e through invaders.h from the given directory.
+    pub fn with_rom_dir<P: AsRef<Path>>(rom_dir: P) -> Self {
         let buffer = [0; ROM_SIZE];
         let mut memory = Self {
             rom: buffer,
             working_ram: [0; WORKING_RAM_SIZE],
             video_ram: [0; VIDEO_RAM_SIZE],
+            rom_dir: rom_dir.as_ref().to_path_buf(),
         };
         memory.load_rom();
         memory
     }
 }
 
+impl Default for SpaceInvadersMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemoryMap for SpaceInvadersMemory {
     fn load_rom(&mut self) {
         let mut addr = 0x00;
         for f in ['h', 'g', 'f', 'e'].iter() {
-            let mut file = File::open(format!("roms/invaders.{}", f)).unwrap();
-            file.read(&mut self.rom[addr..addr + 0x800]).unwrap();
+            let mut file = File::open(self.rom_dir.join(format!("invaders.{}", f))).unwrap();
+            file.read_exact(&mut self.rom[addr..addr + 0x800]).unwrap();
             addr += 0x800;
         }
     }
@@ -86,4 +121,15 @@ impl MemoryMap for SpaceInvadersMemory {
             _ => (),
         }
     }
+
+    fn peek(&mut self, addr: u16) -> Option<u8> {
+        match addr as usize {
+            RAM_MIRROR_BEGIN..=RAM_MIRROR_END
+                if addr as usize - RAM_MIRROR_BEGIN >= WORKING_RAM_SIZE =>
+            {
+                None
+            }
+            _ => Some(self.read(addr)),
+        }
+    }
 }
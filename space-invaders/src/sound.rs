@@ -1,6 +1,9 @@
+#[cfg(feature = "sdl")]
 use sdl2::mixer::{Channel, Chunk, InitFlag, AUDIO_S8, DEFAULT_CHANNELS};
+#[cfg(feature = "sdl")]
 use std::path::Path;
 
+#[cfg(feature = "sdl")]
 pub struct AudioMixer {
     ufo: Chunk,
     shoot: Chunk,
@@ -12,6 +15,7 @@ pub struct AudioMixer {
     invader_4: Chunk,
 }
 
+#[cfg(feature = "sdl")]
 impl AudioMixer {
     pub fn new() -> Self {
         sdl2::mixer::open_audio(44_100, AUDIO_S8, DEFAULT_CHANNELS, 1_024).unwrap();
@@ -75,3 +79,38 @@ impl AudioMixer {
         Channel(7).play(&self.invader_4, 0).unwrap();
     }
 }
+
+impl Default for AudioMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Without SDL there is nothing to play the sounds on.
+#[cfg(not(feature = "sdl"))]
+pub struct AudioMixer;
+
+#[cfg(not(feature = "sdl"))]
+impl AudioMixer {
+    pub fn new() -> Self {
+        AudioMixer
+    }
+
+    pub fn play_ufo(&self) {}
+
+    pub fn stop_ufo(&self) {}
+
+    pub fn play_shoot(&self) {}
+
+    pub fn play_player_death(&self) {}
+
+    pub fn play_invader_death(&self) {}
+
+    pub fn play_invader_1(&self) {}
+
+    pub fn play_invader_2(&self) {}
+
+    pub fn play_invader_3(&self) {}
+
+    pub fn play_invader_4(&self) {}
+}